@@ -0,0 +1,89 @@
+//! The key value store as a bulletin board.
+//! The store has no notion of rounds or aborts: every payload is stored under the number of
+//! its party and its round, and a round is fetched by reading the key of every party.
+//! The store keeps text, so payloads are stored as JSON
+use reqwest::Client;
+use std::thread;
+use std::time::Duration;
+
+use mpc_peer::board::{BoardError, BoardMessage, BulletinBoard, MAX_POLLS};
+use mpc_peer::{MessagePayload, PeerIdentifier};
+
+use crate::store::{get, send, signup, PartySignup};
+
+pub struct HttpBoard {
+    client: Client,
+    // time to wait between two reads of the store
+    delay: Duration,
+    capacity: u32,
+    party: Option<PartySignup>,
+}
+
+impl HttpBoard {
+    pub fn new(client: Client, delay: Duration) -> HttpBoard {
+        HttpBoard {
+            client,
+            delay,
+            capacity: 0,
+            party: None,
+        }
+    }
+
+    fn party(&self) -> Result<&PartySignup, BoardError> {
+        self.party.as_ref().ok_or(BoardError::NotJoined)
+    }
+}
+
+impl BulletinBoard for HttpBoard {
+    type Error = BoardError;
+
+    fn join(&mut self, capacity: u32) -> Result<PeerIdentifier, BoardError> {
+        let party = signup(&self.client).map_err(|_| BoardError::CantJoin)?;
+        println!("{:?}", party);
+        let number = party.number;
+        self.capacity = capacity;
+        self.party = Some(party);
+        Ok(number)
+    }
+
+    fn post(&mut self, round: u32, payload: MessagePayload) -> Result<(), BoardError> {
+        let party = self.party()?;
+        send(
+            &self.client,
+            party.number,
+            &format!("round{}", round),
+            payload.to_string(),
+            party.uuid.clone(),
+        )
+        .map_err(|_| BoardError::CantPost)
+    }
+
+    fn fetch(&mut self, round: u32) -> Result<Vec<BoardMessage>, BoardError> {
+        let uuid = self.party()?.uuid.clone();
+        let round_key = format!("round{}", round);
+        Ok((1..self.capacity + 1)
+            .filter_map(|from| {
+                get(&self.client, from, &round_key, uuid.clone())
+                    .and_then(|value| serde_json::from_str(&value).ok())
+                    .map(|payload| BoardMessage {
+                        from,
+                        round,
+                        payload,
+                    })
+            })
+            .collect())
+    }
+
+    /// Reads the store every delay until the round has count payloads, at most MAX_POLLS times
+    fn subscribe(&mut self, round: u32, count: usize) -> Result<Vec<BoardMessage>, BoardError> {
+        for _ in 0..MAX_POLLS {
+            // add delay to allow the server to process request:
+            thread::sleep(self.delay);
+            let messages = self.fetch(round)?;
+            if messages.len() >= count {
+                return Ok(messages);
+            }
+        }
+        Err(BoardError::TimedOut)
+    }
+}
@@ -0,0 +1,136 @@
+/// Access to the key value store of the rocket server.
+/// A party signs up for its number in the session, and every payload is stored under the
+/// number of the party that sent it, the round and the session
+use reqwest::Client;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct TupleKey {
+    pub first: String,
+    pub second: String,
+    pub third: String,
+}
+impl TupleKey {
+    pub fn new(first: String, second: String, third: String) -> TupleKey {
+        return TupleKey {
+            first,
+            second,
+            third,
+        };
+    }
+}
+impl fmt::Display for TupleKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}, {}, {})", self.first, self.second, self.third)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PartySignup {
+    pub number: u32,
+    pub uuid: String,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Index {
+    pub key: TupleKey,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: TupleKey,
+    pub value: String,
+}
+
+pub fn postb<T>(client: &Client, path: &str, body: T) -> Option<String>
+where
+    T: serde::ser::Serialize,
+{
+    let res = client
+        .post(&format!("http://127.0.0.1:8001/{}", path))
+        .json(&body)
+        .send();
+    Some(res.unwrap().text().unwrap())
+}
+
+pub fn signup(client: &Client) -> Result<PartySignup, ()> {
+    let key = TupleKey {
+        first: "signup".to_string(),
+        second: "".to_string(),
+        third: "".to_string(),
+    };
+
+    let res_body = postb(&client, "signup", key).unwrap();
+    let answer: Result<PartySignup, ()> = serde_json::from_str(&res_body).unwrap();
+    return answer;
+}
+
+pub fn send(
+    client: &Client,
+    party_num: u32,
+    round: &str,
+    data: String,
+    uuid: String,
+) -> Result<(), ()> {
+    let key = TupleKey {
+        first: party_num.to_string(),
+        second: round.to_string(),
+        third: uuid,
+    };
+    let entry = Entry {
+        key: key.clone(),
+        value: data,
+    };
+
+    let res_body = postb(&client, "set", entry).unwrap();
+    let answer: Result<(), ()> = serde_json::from_str(&res_body).unwrap();
+    return answer;
+}
+
+/// Returns the payload the party stored in the round, if it did already
+pub fn get(client: &Client, party_num: u32, round: &str, uuid: String) -> Option<String> {
+    let key = TupleKey {
+        first: party_num.to_string(),
+        second: round.to_string(),
+        third: uuid,
+    };
+    let res_body = postb(client, "get", Index { key }).unwrap();
+    let answer: Result<Entry, ()> = serde_json::from_str(&res_body).unwrap();
+    answer.ok().map(|entry| entry.value)
+}
+
+pub fn poll_for_peers(
+    client: &Client,
+    party_num: u32,
+    n: u32,
+    delay: Duration,
+    round: &str,
+    uuid: String,
+) -> Vec<String> {
+    let mut ans_vec = Vec::new();
+    for i in 1..n + 1 {
+        if i != party_num {
+            let key = TupleKey {
+                first: i.to_string(),
+                second: round.to_string(),
+                third: uuid.clone(),
+            };
+            let index = Index { key };
+            loop {
+                // add delay to allow the server to process request:
+                thread::sleep(delay);
+                let res_body = postb(client, "get", index.clone()).unwrap();
+                let answer: Result<Entry, ()> = serde_json::from_str(&res_body).unwrap();
+                if answer.is_ok() {
+                    ans_vec.push(answer.unwrap().value);
+                    println!("party {:?} {:?} read success", i, round);
+                    break;
+                }
+            }
+        }
+    }
+    ans_vec
+}
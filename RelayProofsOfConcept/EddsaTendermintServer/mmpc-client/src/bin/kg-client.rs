@@ -1,18 +1,19 @@
 use std::error::Error;
 use std::fs::OpenOptions;
 use std::io;
-use std::net::SocketAddr;
 use std::path::Path;
 use std::process;
-use std::{thread, time};
+use std::time;
 
 use clap::{App, Arg, ArgMatches};
-use log::debug;
+use log::{debug, error, info};
 use serde::Serialize;
 
 use mmpc_client::eddsa_peer_kg::EddsaPeer;
-use mmpc_client::peer::Peer;
 use mmpc_client::tendermint_client::SessionClient;
+use mmpc_server_common::identity::Identity;
+use mmpc_server_common::protocol::{ProtocolRegistry, PROTOCOLS_FILE};
+use mmpc_server_common::ProtocolIdentifier;
 
 #[derive(Debug, Serialize)]
 struct Record {
@@ -20,8 +21,9 @@ struct Record {
     millis: u32,
 }
 
+// key generation runs the single round of its own protocol
+const KEYGEN_PROTOCOL_ID: ProtocolIdentifier = 3;
 const MAX_RETRY: u32 = 64;
-const RETRY_TIMEOUT: u64 = 200;
 
 fn arg_matches<'a>() -> ArgMatches<'a> {
     App::new("relay-server")
@@ -153,43 +155,39 @@ fn main() {
     setup_logging(verbosity, client_index).expect("failed to initialize logging.");
 
     let start_time = time::SystemTime::now();
-    let port = 8080 + client_index;
     let proxy_addr = format!("tcp://{}", proxy);
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    // The identity key is used as a unique identifier to the server
+    let identity = Identity::load_or_generate(format!("identity{}", client_index))
+        .expect("Unable to load identity");
     debug!("Capacity flag is {}", capacity);
-    let mut session: SessionClient<EddsaPeer> = SessionClient::new(
-        client_addr,
+    let mut session = SessionClient::new(
+        identity,
         &proxy_addr.parse().unwrap(),
-        client_index,
-        capacity,
-        Vec::new(),
+        KEYGEN_PROTOCOL_ID,
+        EddsaPeer::new(capacity),
     );
-    // Initially do not request any index, the index is determined by the server
-    let server_response = session.register(client_index, capacity, -1);
-    let next_message = session.generate_client_answer(server_response);
-    debug!("Next message: {:?}", next_message);
-    // TODO The client/server
-    let server_response = session.send_message(next_message.unwrap());
-    session.store_server_response(&server_response);
-
-    debug!("Server Response: {:?}", server_response);
-
-    for _ in { 1..MAX_RETRY } {
-        let round = session.state.data_manager.data_holder.current_step();
-        debug!("Now on round {}", round);
-        if session.state.stored_messages.get_number_messages(round) == capacity as usize {
-            for msg in session
-                .state
-                .stored_messages
-                .get_messages_vector_client_message(round)
-            {
-                session.handle_relay_message(msg.clone());
-            }
-            break;
+    session.set_max_retry(MAX_RETRY);
+    let rounds = match ProtocolRegistry::load(PROTOCOLS_FILE) {
+        Ok(protocols) => protocols
+            .rounds(KEYGEN_PROTOCOL_ID)
+            .expect("Key generation does not declare its rounds"),
+        Err(err) => {
+            error!("Key generation failed: {}", err);
+            process::exit(1);
         }
-        let server_response = session.query();
-        session.store_server_response(&server_response);
-        thread::sleep(time::Duration::from_millis(RETRY_TIMEOUT));
+    };
+    // Initially do not request any index, the index is determined by the server
+    if let Err(err_msg) = session.run(-1, rounds) {
+        error!("Key generation failed: {}", err_msg);
+        process::exit(1);
+    }
+    // the head the server committed to, to compare with the other peers
+    match session.query_chain_head() {
+        Ok(chain_head) => info!(
+            "Chain head {} of length {}, signed by server {}",
+            chain_head.head.hash, chain_head.head.length, chain_head.server
+        ),
+        Err(err_msg) => error!("Unable to verify the chain head: {}", err_msg),
     }
     let total_time = start_time.elapsed().expect("Weird time");
     println!("{:}", total_time.as_millis());
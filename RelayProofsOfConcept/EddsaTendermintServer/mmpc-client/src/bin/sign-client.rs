@@ -2,18 +2,19 @@ use std::error::Error;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io;
-use std::net::SocketAddr;
 use std::path::Path;
 use std::process;
-use std::{thread, time};
+use std::time;
 
 use clap::{App, Arg, ArgMatches};
-use log::debug;
+use log::{error, info};
 use serde::Serialize;
 
 use mmpc_client::eddsa_peer_sign::EddsaPeer;
-use mmpc_client::peer::Peer;
 use mmpc_client::tendermint_client::SessionClient;
+use mmpc_server_common::identity::Identity;
+use mmpc_server_common::protocol::{ProtocolRegistry, PROTOCOLS_FILE};
+use mmpc_server_common::ProtocolIdentifier;
 
 use multi_party_eddsa::protocols::aggsig::{KeyAgg, KeyPair};
 
@@ -23,8 +24,7 @@ struct Record {
     millis: u32,
 }
 
-const MAX_RETRY: u32 = 512;
-const RETRY_TIMEOUT: u64 = 200;
+const SIGN_PROTOCOL_ID: ProtocolIdentifier = 1;
 
 fn arg_matches<'a>() -> ArgMatches<'a> {
     App::new("relay-server")
@@ -162,55 +162,41 @@ fn main() {
         .expect("Unable to load keys, did you run keygen first? ");
     let (_, _, kg_index): (KeyPair, KeyAgg, i32) = serde_json::from_str(&data).unwrap();
 
-    // Port and ip address are used as a unique indetifier to the server
-    // This should be replaced with PKi down the road
     let start_time = time::SystemTime::now();
-    let port = 8080 + client_index;
     let proxy_addr = format!("tcp://{}", proxy);
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let mut session: SessionClient<EddsaPeer> = SessionClient::new(
-        client_addr,
+    // The identity key is used as a unique identifier to the server
+    let identity = Identity::load_or_generate(format!("identity{}", client_index))
+        .expect("Unable to load identity");
+    let peer = EddsaPeer::new(capacity, message_to_sign, client_index);
+    let mut session = SessionClient::new(
+        identity,
         &proxy_addr.parse().unwrap(),
-        client_index,
-        capacity,
-        message_to_sign,
+        SIGN_PROTOCOL_ID,
+        peer,
     );
-    let server_response = session.register(client_index, capacity, kg_index);
-    let mut next_message = session.generate_client_answer(server_response);
-    debug!("Next message: {:?}", next_message);
-    // TODO The client/server response could be an error
-    let mut server_response = session.send_message(next_message.clone().unwrap());
-    session.store_server_response(&server_response);
-    // Number of rounds in signing
-    let rounds = 4;
-    'outer: for _ in 0..rounds {
-        'inner: for _ in { 1..MAX_RETRY } {
-            let round = session.state.data_manager.data_holder.current_step();
-            if session.state.stored_messages.get_number_messages(round) == capacity as usize {
-                for msg in session
-                    .state
-                    .stored_messages
-                    .get_messages_vector_client_message(round)
-                {
-                    next_message = session.handle_relay_message(msg.clone());
-                }
-                // Do not send response on last round
-                if round != rounds - 1 {
-                    server_response = session.send_message(next_message.clone().unwrap());
-                    session.store_server_response(&server_response);
-                }
-                break 'inner;
-            } else {
-                let server_response = session.query();
-                // debug!("Server response {:?}", server_response);
-                // debug!("Server response len {}", server_response.keys().len());
-                session.store_server_response(&server_response);
-                thread::sleep(time::Duration::from_millis(RETRY_TIMEOUT));
-                // debug!("All stored messages {:?}", session.state.stored_messages);
-            }
+    // Number of rounds in signing, as declared in the protocols file
+    let rounds = match ProtocolRegistry::load(PROTOCOLS_FILE) {
+        Ok(protocols) => protocols
+            .rounds(SIGN_PROTOCOL_ID)
+            .expect("Signing does not declare its rounds"),
+        Err(err) => {
+            error!("Signing failed: {}", err);
+            process::exit(1);
         }
+    };
+    if let Err(err_msg) = session.run(kg_index, rounds) {
+        error!("Signing failed: {}", err_msg);
+        process::exit(1);
     }
 
+    // the head the server committed to, to compare with the other peers
+    match session.query_chain_head() {
+        Ok(chain_head) => info!(
+            "Chain head {} of length {}, signed by server {}",
+            chain_head.head.hash, chain_head.head.length, chain_head.server
+        ),
+        Err(err_msg) => error!("Unable to verify the chain head: {}", err_msg),
+    }
     let total_time = start_time.elapsed().expect("Weird time");
     println!("{:}", total_time.as_millis());
 
@@ -1,13 +1,14 @@
 use std::collections::HashMap;
 use std::fs;
+use std::io;
 
 use curv::elliptic::curves::ed25519::*;
 use log::{debug, info};
 use multi_party_eddsa::protocols::aggsig::{EphemeralKey, KeyAgg, KeyPair};
 
-use crate::peer::Peer;
-use mmpc_server_common::common::*;
+use crate::peer::{EddsaPayload, Peer};
 use mmpc_server_common::{MessagePayload, PeerIdentifier};
+use mpc_peer::{AbortReason, Blame};
 
 #[allow(non_snake_case)]
 pub struct EddsaPeer {
@@ -37,6 +38,9 @@ pub struct EddsaPeer {
     pub r_accepted: bool,
     pub sig_accepted: bool,
 
+    // misbehavior found while running the protocol, the session must be aborted
+    pub blame: Option<Blame>,
+
     // messages this peer generates
     pub pk_msg: Option<MessagePayload>,
     pub commitment_msg: Option<MessagePayload>,
@@ -44,8 +48,8 @@ pub struct EddsaPeer {
     pub sig_msg: Option<MessagePayload>,
 }
 
-impl Peer for EddsaPeer {
-    fn new(capacity: u32, _message: Vec<u8>, _index: u32) -> EddsaPeer {
+impl EddsaPeer {
+    pub fn new(capacity: u32) -> EddsaPeer {
         debug!("Capacity is set to {}", capacity);
         EddsaPeer {
             client_key: KeyPair::create(),
@@ -64,6 +68,7 @@ impl Peer for EddsaPeer {
             r_accepted: false,
             sig_accepted: false,
             is_done: false,
+            blame: None,
 
             pk_msg: None,
             commitment_msg: None,
@@ -71,18 +76,16 @@ impl Peer for EddsaPeer {
             sig_msg: None,
         }
     }
+}
 
-    fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
-        self.peer_id = peer_id;
-    }
+impl Peer for EddsaPeer {
+    type Error = io::Error;
 
     fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
         self.peer_id = peer_id;
         let pk = self.client_key.public_key.clone();
 
-        let pk_s = serde_json::to_string(&pk).expect("Failed in serialization");
-
-        self.pk_msg = Some(generate_pk_message_payload(&pk_s));
+        self.pk_msg = Some(EddsaPayload::PublicKey(pk).to_payload());
         return self.pk_msg.clone();
     }
 
@@ -129,18 +132,14 @@ impl Peer for EddsaPeer {
     /// in this case:
     ///     collection all signatures
     ///     and verifying the message
-    fn finalize(&mut self) -> Result<(), &'static str> {
+    fn finalize(&mut self) -> Result<(), io::Error> {
         let key = &self.client_key.clone();
         let apk = &self.aggregate_pks();
         let index = &self.peer_id;
 
         let keygen_json = serde_json::to_string(&(key, apk, index)).unwrap();
 
-        let res = fs::write(format!("keys{}", self.peer_id), keygen_json);
-        match res {
-            Ok(_) => Ok(()),
-            Err(_) => Err("Failed to verify"),
-        }
+        fs::write(format!("keys{}", self.peer_id), keygen_json)
     }
     /// check that the protocol is done
     /// and that this peer can finalize its calculations
@@ -148,6 +147,11 @@ impl Peer for EddsaPeer {
         self.is_done_step_0()
     }
 
+    /// the misbehavior this peer found, if the protocol can not continue
+    fn blame(&self) -> Option<Blame> {
+        self.blame.clone()
+    }
+
     /// get the next item the peer needs to send
     /// depending on the current step and the last message
     /// of the peer that was accepted by the server
@@ -169,46 +173,40 @@ impl EddsaPeer {
     }
     pub fn is_done_step_0(&mut self) -> bool {
         if self.pks.len() == self.capacity() as usize {
-            self.finalize().expect("Finalized falied");
+            if self.finalize().is_err() {
+                // the keys could not be saved, no other peer is to blame
+                self.blame = Some(Blame {
+                    reason: AbortReason::Requested,
+                    accused: None,
+                    round: 0,
+                });
+                return false;
+            }
             return true;
         }
         false
     }
 }
 
-impl EddsaPeer {
-    pub fn resolve_payload_type(message: &MessagePayload) -> MessagePayloadType {
-        let msg_payload = message.clone();
-
-        let split_msg: Vec<&str> = msg_payload.split(RELAY_MESSAGE_DELIMITER).collect();
-        let msg_prefix = split_msg[0];
-        let msg_payload = String::from(split_msg[1].clone());
-        match msg_prefix {
-            pk_prefix if pk_prefix == String::from(PK_MESSAGE_PREFIX) => {
-                return MessagePayloadType::PublicKey(msg_payload);
-            }
-            _ => panic!("Unknown relay message prefix"),
-        }
-    }
-}
-
 impl EddsaPeer {
     /// data updaters for each step
     pub fn update_data_step_0(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::PublicKey(pk) => {
+        match EddsaPayload::from_payload(&payload) {
+            Ok(EddsaPayload::PublicKey(pk)) => {
                 let peer_id = self.peer_id;
                 if from == peer_id {
                     self.pk_accepted = true;
                 }
-                let s_slice: &str = &pk[..]; // take a full slice of the string
-                let _pk = serde_json::from_str(s_slice);
                 info!("-------Got peer # {:} pk! {:?}", from, pk);
-                match _pk {
-                    Ok(_pk) => self.add_pk(from, _pk),
-                    Err(_) => panic!("Could not serialize public key"),
-                }
+                self.add_pk(from, pk);
+            }
+            // a peer sent a payload that is not its public key
+            _ => {
+                self.blame = Some(Blame {
+                    reason: AbortReason::InvalidMessage,
+                    accused: Some(from),
+                    round: 0,
+                })
             }
         }
     }
@@ -235,10 +233,3 @@ impl EddsaPeer {
         return agg_key;
     }
 }
-
-#[derive(Debug)]
-pub enum MessagePayloadType {
-    /// Types of expected relay messages
-    /// for step 0 we expect PUBLIC_KEY_MESSAGE
-    PublicKey(String),
-}
@@ -11,22 +11,9 @@ use multi_party_eddsa::protocols::aggsig::{
     test_com, verify, EphemeralKey, KeyAgg, KeyPair, SignFirstMsg, SignSecondMsg, Signature,
 };
 
-use crate::peer::Peer;
-use mmpc_server_common::common::*;
+use crate::peer::{EddsaPayload, Peer};
 use mmpc_server_common::{MessagePayload, PeerIdentifier};
-
-#[derive(Debug)]
-pub enum MessagePayloadType {
-    /// Types of expected relay messages
-    /// for step 0 we expect PUBLIC_KEY_MESSAGE
-    /// for step 1 we expect Commitment
-    /// for step 2 we expect RMessage
-    /// for step 3 we expect Signature
-    PublicKey(String),
-    Commitment(String),
-    RMessage(String),
-    Signature(String),
-}
+use mpc_peer::{AbortReason, Blame};
 
 #[allow(non_snake_case)]
 pub struct EddsaPeer {
@@ -42,9 +29,9 @@ pub struct EddsaPeer {
     // eddsa data
     pub client_key: KeyPair,
     pub pks: HashMap<PeerIdentifier, Ed25519Point>,
-    pub commitments: HashMap<PeerIdentifier, String>,
-    pub r_s: HashMap<PeerIdentifier, String>,
-    pub sigs: HashMap<PeerIdentifier, String>,
+    pub commitments: HashMap<PeerIdentifier, SignFirstMsg>,
+    pub r_s: HashMap<PeerIdentifier, SignSecondMsg>,
+    pub sigs: HashMap<PeerIdentifier, Signature>,
     pub ephemeral_key: Option<EphemeralKey>,
     // message to sign
     pub message: Vec<u8>,
@@ -59,6 +46,9 @@ pub struct EddsaPeer {
     pub r_accepted: bool,
     pub sig_accepted: bool,
 
+    // misbehavior found while running the protocol, the session must be aborted
+    pub blame: Option<Blame>,
+
     // messages this peer generates
     pub pk_msg: Option<MessagePayload>,
     pub commitment_msg: Option<MessagePayload>,
@@ -71,23 +61,27 @@ impl EddsaPeer {
     fn add_pk(&mut self, peer_id: PeerIdentifier, pk: Ed25519Point) {
         self.pks.insert(peer_id, pk);
     }
-    fn add_commitment(&mut self, peer_id: PeerIdentifier, commitment: String) {
+    fn add_commitment(&mut self, peer_id: PeerIdentifier, commitment: SignFirstMsg) {
         self.commitments.insert(peer_id, commitment);
     }
-    fn add_r(&mut self, peer_id: PeerIdentifier, r: String) {
-        //let v = (r,blind_factor);
+    fn add_r(&mut self, peer_id: PeerIdentifier, r: SignSecondMsg) {
         self.r_s.insert(peer_id, r);
     }
-    fn add_sig(&mut self, peer_id: PeerIdentifier, sig: String) {
+    fn add_sig(&mut self, peer_id: PeerIdentifier, sig: Signature) {
         self.sigs.insert(peer_id, sig);
     }
+    // The peer sent a payload that is not the message of the round
+    fn blame_invalid_message(&mut self, from: PeerIdentifier, round: u32) {
+        self.blame = Some(Blame {
+            reason: AbortReason::InvalidMessage,
+            accused: Some(from),
+            round,
+        });
+    }
     fn compute_r_tot(&mut self) -> GE {
         #[allow(non_snake_case)]
         let mut Ri: Vec<GE> = Vec::new();
-        for (_peer_id, r) in &self.r_s {
-            let r_slice: &str = &r[..];
-            let r: SignSecondMsg =
-                serde_json::from_str(r_slice).unwrap_or_else(|_| panic!("Serialization error"));
+        for r in self.r_s.values() {
             Ri.push(r.R.clone());
         }
         let r_tot = Signature::get_R_tot(Ri);
@@ -111,7 +105,8 @@ impl EddsaPeer {
         KeyPair::key_aggregation_n(&pks, &index)
     }
 
-    fn validate_commitments(&mut self) -> bool {
+    // Returns the first peer whose R does not open the commitment it sent
+    fn validate_commitments(&mut self) -> Result<(), PeerIdentifier> {
         // iterate over all peer Rs
         debug!("----------\nvalidating commitments\n----------");
         let eight: FE = ECScalar::from(&BigInt::from(8));
@@ -119,58 +114,45 @@ impl EddsaPeer {
         let r_s = &self.r_s;
         for (peer_id, r) in r_s {
             debug!("peer: {:}", peer_id);
-            debug!("r: {:}", r);
-            // convert the json_string to a construct
-            let _r: SignSecondMsg = serde_json::from_str(r).unwrap();
+            debug!("r: {:?}", r);
 
             // get the corresponding commitment
-            let k = peer_id.clone();
-            let cmtmnt = self
+            let commitment = self
                 .commitments
-                .get(&k)
+                .get(peer_id)
                 .expect("peer didn't send commitment");
-            debug!("commitment : {:?}", cmtmnt);
-            let commitment: SignFirstMsg = serde_json::from_str(cmtmnt).unwrap();
+            debug!("commitment : {:?}", commitment);
             // if we couldn't validate the commitment - failure
-            if !test_com(
-                &(_r.R * eight_inv),
-                &_r.blind_factor,
-                &commitment.commitment,
-            ) {
-                return false;
+            if !test_com(&(r.R * eight_inv), &r.blind_factor, &commitment.commitment) {
+                return Err(*peer_id);
             }
         }
         debug!("----------\ncommitments valid\n----------");
-        true
+        Ok(())
     }
 }
 
 impl EddsaPeer {
     /// data updaters for each step
     pub fn update_data_step_0(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
         let eight: FE = ECScalar::from(&BigInt::from(8));
         let eight_inv = eight.invert();
-        match payload_type {
-            MessagePayloadType::PublicKey(pk) => {
+        match EddsaPayload::from_payload(&payload) {
+            Ok(EddsaPayload::PublicKey(pk)) => {
                 let peer_id = self.peer_id;
                 if from == peer_id {
                     self.pk_accepted = true;
                 }
-                let s_slice: &str = &pk[..]; // take a full slice of the string
-                let pk: GE = serde_json::from_str(&s_slice)
-                    .unwrap_or_else(|_| panic!("Failed to deserialize R"));
                 info!("-------Got peer # {:} pk! {:?}", from, pk * &eight_inv);
                 self.add_pk(from, pk * &eight_inv);
             }
-            _ => panic!("expected public key message"),
+            _ => self.blame_invalid_message(from, 0),
         }
     }
 
     pub fn update_data_step_1(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::Commitment(t) => {
+        match EddsaPayload::from_payload(&payload) {
+            Ok(EddsaPayload::Commitment(t)) => {
                 info!("-------Got peer # {:} commitment! {:?}", from, t);
                 let peer_id = self.peer_id;
                 if from == peer_id {
@@ -178,14 +160,13 @@ impl EddsaPeer {
                 }
                 self.add_commitment(from, t);
             }
-            _ => {} //panic!("expected commitment message")
+            _ => self.blame_invalid_message(from, 1),
         }
     }
 
     pub fn update_data_step_2(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::RMessage(r) => {
+        match EddsaPayload::from_payload(&payload) {
+            Ok(EddsaPayload::RMessage(r)) => {
                 info!("-------Got peer # {:} R message!", from);
                 let peer_id = self.peer_id;
                 if from == peer_id {
@@ -193,15 +174,14 @@ impl EddsaPeer {
                 }
                 self.add_r(from, r);
             }
-            _ => {} //panic!("expected R message")
+            _ => self.blame_invalid_message(from, 2),
         }
     }
 
     pub fn update_data_step_3(&mut self, from: PeerIdentifier, payload: MessagePayload) {
         debug!("updating data step 3");
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::Signature(s) => {
+        match EddsaPayload::from_payload(&payload) {
+            Ok(EddsaPayload::PartialSignature(s)) => {
                 debug!("-------Got peer # {:} Signature", from);
                 let peer_id = self.peer_id;
                 if from == peer_id {
@@ -209,7 +189,7 @@ impl EddsaPeer {
                 }
                 self.add_sig(from, s);
             }
-            _ => {} //panic!("expected signature message")
+            _ => self.blame_invalid_message(from, 3),
         }
     }
 }
@@ -237,7 +217,15 @@ impl EddsaPeer {
         debug!("Checking if last step is done");
 
         if self.sigs.len() == self.capacity as usize {
-            self.finalize().unwrap();
+            if self.finalize().is_err() {
+                // the signature parts do not add up to a valid signature
+                self.blame = Some(Blame {
+                    reason: AbortReason::InvalidSignature,
+                    accused: None,
+                    round: 3,
+                });
+                return false;
+            }
             return true;
         }
         false
@@ -259,17 +247,9 @@ impl EddsaPeer {
             Signature::create_ephemeral_key_and_commit(&self.client_key, &self.message[..]);
 
         self.ephemeral_key = Some(ephemeral_key);
-        // save the commitment
-        let _peer_id = self.peer_id;
-        match serde_json::to_string(&sign_first_message) {
-            Ok(json_string) => {
-                //                self.add_commitment(peer_id, json_string.clone());
-                let r = serde_json::to_string(&sign_second_message).expect("couldn't create R");
-                self.commitment_msg = Some(generate_commitment_message_payload(&json_string));
-                self.r_msg = Some(generate_R_message_payload(&r));
-            }
-            Err(_) => panic!("Couldn't serialize commitment"),
-        }
+        // the commitment is sent now, the R it commits to in the next round
+        self.commitment_msg = Some(EddsaPayload::Commitment(sign_first_message).to_payload());
+        self.r_msg = Some(EddsaPayload::RMessage(sign_second_message).to_payload());
     }
 
     /// step 2 - return the clients R. No extra calculations
@@ -281,9 +261,14 @@ impl EddsaPeer {
     /// 2. compute R' = sum(Ri)
     /// 3. sign message
     pub fn step_3(&mut self) {
-        if !self.validate_commitments() {
-            // commitments sent by others are not valid. exit
-            panic!("Commitments not valid!")
+        if let Err(peer_id) = self.validate_commitments() {
+            // the R the peer revealed in the previous round does not open its commitment
+            self.blame = Some(Blame {
+                reason: AbortReason::InvalidCommitment,
+                accused: Some(peer_id),
+                round: 2,
+            });
+            return;
         }
         let agg_key = self.aggregate_pks();
         debug!("computed agg_key");
@@ -294,18 +279,13 @@ impl EddsaPeer {
             Some(ref eph_key) => {
                 let k = Signature::k(&r_tot, &agg_key.apk, &self.message[..]);
                 let peer_id = self.peer_id;
-                let r = self
-                    .r_s
-                    .get(&peer_id)
-                    .unwrap_or_else(|| panic!("Client has No R "))
-                    .clone();
-                let _r: SignSecondMsg =
-                    serde_json::from_str(&r).unwrap_or_else(|_| panic!("Failed to deserialize R"));
+                if !self.r_s.contains_key(&peer_id) {
+                    panic!("Client has No R ");
+                }
                 let key = &self.client_key;
                 // sign
                 let s = Signature::partial_sign(&eph_key.r, key, &k, &agg_key.hash, &r_tot);
-                let sig_string = serde_json::to_string(&s).expect("failed to serialize signature");
-                self.sig_msg = Some(generate_signature_message_payload(&sig_string));
+                self.sig_msg = Some(EddsaPayload::PartialSignature(s).to_payload());
             }
             None => {}
         }
@@ -313,32 +293,7 @@ impl EddsaPeer {
 }
 
 impl EddsaPeer {
-    pub fn resolve_payload_type(message: &MessagePayload) -> MessagePayloadType {
-        let msg_payload = message.clone();
-
-        let split_msg: Vec<&str> = msg_payload.split(RELAY_MESSAGE_DELIMITER).collect();
-        let msg_prefix = split_msg[0];
-        let msg_payload = String::from(split_msg[1].clone());
-        match msg_prefix {
-            pk_prefix if pk_prefix == String::from(PK_MESSAGE_PREFIX) => {
-                return MessagePayloadType::PublicKey(msg_payload);
-            }
-            cmtmnt if cmtmnt == String::from(COMMITMENT_MESSAGE_PREFIX) => {
-                return MessagePayloadType::Commitment(msg_payload);
-            }
-            r if r == String::from(R_KEY_MESSAGE_PREFIX) => {
-                return MessagePayloadType::RMessage(msg_payload);
-            }
-            sig if sig == String::from(SIGNATURE_MESSAGE_PREFIX) => {
-                return MessagePayloadType::Signature(msg_payload);
-            }
-            _ => panic!("Unknown relay message prefix"),
-        }
-    }
-}
-
-impl Peer for EddsaPeer {
-    fn new(capacity: u32, _message: Vec<u8>, index: u32) -> EddsaPeer {
+    pub fn new(capacity: u32, _message: Vec<u8>, index: u32) -> EddsaPeer {
         debug!("Index is {:?}", index);
         let data = fs::read_to_string(format!("keys{}", index))
             .expect("Unable to load keys, did you run keygen first? ");
@@ -362,6 +317,7 @@ impl Peer for EddsaPeer {
             r_accepted: false,
             sig_accepted: false,
             is_done: false,
+            blame: None,
 
             pk_msg: None,
             commitment_msg: None,
@@ -369,18 +325,25 @@ impl Peer for EddsaPeer {
             sig_msg: None,
         }
     }
+}
 
-    fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
-        self.peer_id = peer_id;
-    }
+/// Why the signature of the session could not be computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignError {
+    // the aggregated key does not match the key generation output
+    KeyMismatch,
+
+    // the signature parts of the peers do not add up to a valid signature
+    InvalidSignature,
+}
+
+impl Peer for EddsaPeer {
+    type Error = SignError;
 
     fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
         self.peer_id = peer_id;
         let pk = self.client_key.public_key.clone();
-
-        let pk_s = serde_json::to_string(&pk).expect("Failed in serialization");
-
-        self.pk_msg = Some(generate_pk_message_payload(&pk_s));
+        self.pk_msg = Some(EddsaPayload::PublicKey(pk).to_payload());
         return self.pk_msg.clone();
     }
 
@@ -432,13 +395,11 @@ impl Peer for EddsaPeer {
     ///     collection all signatures
     ///     and verifying the message
     #[allow(non_snake_case)]
-    fn finalize(&mut self) -> Result<(), &'static str> {
+    fn finalize(&mut self) -> Result<(), SignError> {
         let mut s: Vec<Signature> = Vec::new();
         let eight: FE = ECScalar::from(&BigInt::from(8));
         let eight_inv = eight.invert();
-        for sig in self.sigs.values() {
-            let signature: Signature =
-                serde_json::from_str(&sig).expect("Could not serialize signature!");
+        for signature in self.sigs.values() {
             s.push(Signature {
                 R: signature.R * eight_inv,
                 s: signature.s * &eight,
@@ -461,7 +422,9 @@ impl Peer for EddsaPeer {
         debug!("Aggregated pk {:?}", apk);
         debug!("Orig pk {:?}", orig_apk);
         // Original apk should be equal to the apk created during signing
-        assert_eq!(orig_apk, apk.apk);
+        if orig_apk != apk.apk {
+            return Err(SignError::KeyMismatch);
+        }
         //assert_eq!(apk, apk.apk);
         // Verify signature against the original! pubkey
         match verify(&signature, &self.message[..], &orig_apk) {
@@ -478,7 +441,7 @@ impl Peer for EddsaPeer {
                 .expect("Unable to save !");
                 Ok(())
             }
-            Err(_) => Err("Failed to verify"),
+            Err(_) => Err(SignError::InvalidSignature),
         }
     }
     /// check that the protocol is done
@@ -487,6 +450,11 @@ impl Peer for EddsaPeer {
         self.is_done_step_3()
     }
 
+    /// the misbehavior this peer found, if the protocol can not continue
+    fn blame(&self) -> Option<Blame> {
+        self.blame.clone()
+    }
+
     /// get the next item the peer needs to send
     /// depending on the current step and the last message
     /// of the peer that was accepted by the server
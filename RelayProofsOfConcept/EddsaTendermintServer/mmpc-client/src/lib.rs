@@ -1,4 +1,5 @@
 pub mod eddsa_peer_kg;
 pub mod eddsa_peer_sign;
 pub mod peer;
+pub mod tendermint_board;
 pub mod tendermint_client;
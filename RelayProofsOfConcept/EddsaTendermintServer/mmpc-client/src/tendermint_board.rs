@@ -0,0 +1,275 @@
+//! The Tendermint application as a bulletin board.
+//! Every payload is a signed relay message committed in a block, and the messages of a round
+//! are read back with the same query `SessionClient` uses for the messages it is missing
+use std::collections::BTreeMap;
+use std::{thread, time};
+
+use log::{debug, warn};
+
+use mpc_peer::board::{BoardMessage, BulletinBoard};
+
+use mmpc_server_common::common::*;
+use mmpc_server_common::encryption::{verify_roster, EncryptionKeypair};
+use mmpc_server_common::error::RelayError;
+use mmpc_server_common::identity::{verify_chain_head, Identity};
+use mmpc_server_common::{
+    AbortEvidence, AbortMessage, Blame, ClientMessage, MessagePayload, MissingMessagesRequest,
+    PeerIdentifier, ProtocolIdentifier, RelayMessage, RelayPayload, Roster, ServerMessage,
+    ServerResponse, SessionIdentifier, StoredMessages,
+};
+
+use crate::peer::MAX_CLIENTS;
+use crate::tendermint_client::rejection;
+
+// Times to query the messages of a round before giving up on it
+const MAX_RETRY: u32 = 512;
+// Milliseconds to wait between queries
+const RETRY_TIMEOUT: u64 = 200;
+
+pub struct TendermintBoard {
+    client: tendermint::rpc::Client,
+    identity: Identity,
+    // the ephemeral key point to point payloads are sealed for this peer with
+    encryption_key: EncryptionKeypair,
+    roster: Roster,
+    protocol_id: ProtocolIdentifier,
+    // the key generation index to register with, or -1 to let the server choose it
+    kg_index: i32,
+    peer_id: PeerIdentifier,
+    // the session the messages are signed for, named by the chain head of the registration
+    session_id: SessionIdentifier,
+    capacity: u32,
+    stored_messages: StoredMessages,
+    max_retry: u32,
+}
+
+impl TendermintBoard {
+    pub fn new(
+        identity: Identity,
+        server_addr: &tendermint::net::Address,
+        protocol_id: ProtocolIdentifier,
+        kg_index: i32,
+    ) -> TendermintBoard {
+        TendermintBoard {
+            client: tendermint::rpc::Client::new(server_addr).unwrap(),
+            identity,
+            encryption_key: EncryptionKeypair::generate(),
+            roster: Roster::new(),
+            protocol_id,
+            kg_index,
+            peer_id: 0,
+            session_id: 0,
+            capacity: 0,
+            stored_messages: StoredMessages::new(),
+            max_retry: MAX_RETRY,
+        }
+    }
+
+    /// Queries the messages of a round at most max_retry times before giving up on it
+    pub fn set_max_retry(&mut self, max_retry: u32) {
+        self.max_retry = max_retry;
+    }
+
+    // Commits a signed message of this peer in a block, returns the response of the server
+    fn broadcast(&self, msg: &ClientMessage) -> Result<String, RelayError> {
+        let tx =
+            tendermint::abci::transaction::Transaction::new(serde_json::to_string(msg).unwrap());
+        let response = self
+            .client
+            .broadcast_tx_commit(tx)
+            .map_err(|_| RelayError::CantRelay)?;
+        if !response.deliver_tx.code.is_ok() {
+            let log = response.deliver_tx.log.map(|log| log.to_string());
+            return Err(rejection(&log.unwrap_or_default()));
+        }
+        Ok(response
+            .deliver_tx
+            .log
+            .map(|log| log.to_string())
+            .unwrap_or_default())
+    }
+
+    // Queries the application at the given path, or for missing messages without one
+    fn query(&self, path: Option<&str>, data: String) -> Result<String, RelayError> {
+        let path = path.map(|path| path.parse().unwrap());
+        let response = self
+            .client
+            .abci_query(path, data, None, false)
+            .map_err(|_| RelayError::CantRelay)?;
+        Ok(response.log.to_string())
+    }
+
+    fn store(&mut self, round: u32, messages: BTreeMap<u32, ClientMessage>) {
+        for (client_idx, msg) in messages {
+            self.stored_messages.update(round, client_idx, msg);
+        }
+    }
+
+    // Returns the payload of a relay message, opened if it was sealed for this peer
+    fn open(&mut self, msg: &RelayMessage) -> Result<MessagePayload, RelayError> {
+        let sealed = match &msg.message {
+            RelayPayload::Plain(payload) => return Ok(payload.clone()),
+            RelayPayload::Sealed(sealed) => sealed,
+        };
+        // peers that registered after us are not in the roster we got when registering
+        if !self.roster.contains_key(&msg.peer_number) {
+            let roster: Roster =
+                serde_json::from_str(&self.query(Some(ROSTER_QUERY_PATH), String::new())?)
+                    .map_err(|_| RelayError::MalformedMessage)?;
+            verify_roster(&roster).map_err(|_| RelayError::InvalidEncryptionKey)?;
+            self.roster = roster;
+        }
+        let sender = self
+            .roster
+            .get(&msg.peer_number)
+            .ok_or(RelayError::NotAPeer)?;
+        self.encryption_key
+            .open(
+                &sender.encryption_key.key,
+                msg.peer_number,
+                self.peer_id,
+                sealed,
+            )
+            .map_err(|_| RelayError::DecryptionFailed)
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.query(Some(ABORT_QUERY_PATH), String::new())
+            .ok()
+            .and_then(|log| serde_json::from_str::<Option<AbortMessage>>(&log).ok())
+            .flatten()
+            .is_some()
+    }
+}
+
+impl BulletinBoard for TendermintBoard {
+    type Error = RelayError;
+
+    fn join(&mut self, capacity: u32) -> Result<PeerIdentifier, RelayError> {
+        // Prove the identity is ours by signing the challenge issued for it
+        let challenge = self.query(Some(CHALLENGE_QUERY_PATH), self.identity.public_key())?;
+        let signature = self.identity.sign_challenge(&challenge);
+        // Other peers seal point to point payloads for us with this key
+        let encryption_key = self.identity.sign_encryption_key(&self.encryption_key);
+        let mut msg = ClientMessage::new();
+        msg.set_register(
+            self.identity.public_key(),
+            self.protocol_id,
+            capacity,
+            self.kg_index,
+            signature,
+            encryption_key,
+        );
+        let response = self.broadcast(&msg)?;
+        let server_msg: ServerMessage =
+            serde_json::from_str(&response).map_err(|_| RelayError::CantRegister)?;
+        match server_msg.response {
+            Some(ServerResponse::Register(peer_id, roster)) => {
+                verify_roster(&roster).map_err(|_| RelayError::InvalidEncryptionKey)?;
+                let chain_head = server_msg.chain_head.ok_or(RelayError::InvalidChainHead)?;
+                verify_chain_head(&chain_head, &chain_head.server)
+                    .map_err(|_| RelayError::InvalidChainHead)?;
+                debug!(
+                    "Registered as peer {} of session {}",
+                    peer_id, chain_head.head.session_id
+                );
+                self.session_id = chain_head.head.session_id;
+                self.peer_id = peer_id;
+                self.roster = roster;
+                self.capacity = capacity;
+                Ok(peer_id)
+            }
+            _ => Err(RelayError::CantRegister),
+        }
+    }
+
+    fn post(&mut self, round: u32, payload: MessagePayload) -> Result<(), RelayError> {
+        let mut relay_message =
+            RelayMessage::new(self.peer_id, self.protocol_id, self.identity.public_key());
+        // the bulletin board shows every message to all the peers
+        relay_message.set_message_params(round, vec![0], payload);
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(relay_message);
+        self.identity.sign_message(&mut msg, self.session_id, round);
+        let response = self.broadcast(&msg)?;
+        // the server answers with the messages of the round it already has
+        let messages: BTreeMap<u32, ClientMessage> =
+            serde_json::from_str(&response).unwrap_or_default();
+        self.store(round, messages);
+        Ok(())
+    }
+
+    fn fetch(&mut self, round: u32) -> Result<Vec<BoardMessage>, RelayError> {
+        let mut missing_clients = self
+            .stored_messages
+            .get_missing_clients_vector(round, self.capacity);
+        if !missing_clients.is_empty() {
+            missing_clients.truncate(MAX_CLIENTS);
+            let request = MissingMessagesRequest {
+                round,
+                missing_clients,
+            };
+            match self.query(None, serde_json::to_string(&request).unwrap()) {
+                Ok(log) => {
+                    let messages = serde_json::from_str(&log).unwrap_or_default();
+                    self.store(round, messages);
+                }
+                Err(_) => warn!("Query not successful"),
+            }
+        }
+        let mut messages = Vec::new();
+        for msg in self
+            .stored_messages
+            .get_messages_vector_client_message(round)
+        {
+            let relay_msg = msg.relay_message.ok_or(RelayError::MalformedMessage)?;
+            match self.open(&relay_msg) {
+                Ok(payload) => messages.push(BoardMessage {
+                    from: relay_msg.peer_number,
+                    round,
+                    payload,
+                }),
+                Err(err) => warn!("Dropping message from {}: {}", relay_msg.peer_number, err),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Queries the round until it has count payloads, or the session was aborted
+    fn subscribe(&mut self, round: u32, count: usize) -> Result<Vec<BoardMessage>, RelayError> {
+        for _ in 0..self.max_retry {
+            let messages = self.fetch(round)?;
+            if messages.len() >= count {
+                return Ok(messages);
+            }
+            // stop waiting once the session is aborted
+            if self.is_aborted() {
+                return Err(RelayError::SessionAborted);
+            }
+            thread::sleep(time::Duration::from_millis(RETRY_TIMEOUT));
+        }
+        Err(RelayError::RoundTimedOut)
+    }
+
+    fn abort(&mut self, blame: Blame) -> Result<(), RelayError> {
+        let evidence = blame
+            .accused
+            .and_then(|accused| self.stored_messages.get_message(blame.round, accused))
+            .and_then(|msg| msg.relay_message)
+            .map(|message| AbortEvidence {
+                round: blame.round,
+                message,
+            });
+        let mut msg = ClientMessage::new();
+        msg.abort = Some(AbortMessage::blame(
+            self.peer_id,
+            self.protocol_id,
+            blame.reason,
+            blame.accused,
+            evidence,
+        ));
+        self.identity
+            .sign_message(&mut msg, self.session_id, blame.round);
+        self.broadcast(&msg).map(|_| ())
+    }
+}
@@ -1,36 +1,120 @@
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::{thread, time};
 
 use crate::peer::{Peer, ProtocolDataManager, MAX_CLIENTS};
 use log::{debug, error, info, warn};
 
 use mmpc_server_common::common::*;
+use mmpc_server_common::encryption::{verify_roster, EncryptionKeypair};
+use mmpc_server_common::error::RelayError;
+use mmpc_server_common::identity::{verify_chain_head, Identity};
 use mmpc_server_common::{
-    ClientMessage, MessagePayload, MissingMessagesRequest, PeerIdentifier, ProtocolIdentifier,
-    RelayMessage, ServerMessage, ServerMessageType, ServerResponse, StoredMessages,
+    AbortEvidence, AbortMessage, ClientMessage, IdentityKey, MessagePayload,
+    MissingMessagesRequest, PeerIdentifier, ProtocolIdentifier, RelayMessage, RelayPayload, Roster,
+    SealedPayload, ServerMessage, ServerMessageType, ServerResponse, SessionIdentifier,
+    SignedChainHead, StoredMessages,
 };
 
+// Times to query the messages of a round before giving up on it
+const MAX_RETRY: u32 = 512;
+// Milliseconds to wait between queries
+const RETRY_TIMEOUT: u64 = 200;
+
 pub struct SessionClient<T>
 where
     T: Peer,
 {
     pub state: State<T>,
     pub client: tendermint::rpc::Client,
+    pub max_retry: u32,
 }
 
 impl<T: Peer> SessionClient<T> {
     pub fn new(
-        client_addr: SocketAddr,
+        identity: Identity,
         server_addr: &tendermint::net::Address,
-        client_index: u32,
-        capacity: u32,
-        message: Vec<u8>,
+        protocol_id: ProtocolIdentifier,
+        peer: T,
     ) -> SessionClient<T> {
-        let protocol_id = 1;
         SessionClient {
-            state: State::new(protocol_id, capacity, client_addr, client_index, message),
+            state: State::new(protocol_id, identity, peer),
             client: tendermint::rpc::Client::new(server_addr).unwrap(),
+            max_retry: MAX_RETRY,
+        }
+    }
+
+    /// Queries the messages of a round at most max_retry times before giving up on it
+    pub fn set_max_retry(&mut self, max_retry: u32) {
+        self.max_retry = max_retry;
+    }
+
+    /// Runs the protocol of the peer over the bulletin board, in as many rounds as given.
+    /// Registers with the key generation index, or -1 to let the server choose it, then
+    /// waits for the messages of every round, feeds them to the peer and sends the payload
+    /// it produces next. Returns an error once the session was aborted, by this peer or another
+    pub fn run(&mut self, kg_index: i32, rounds: u32) -> Result<(), RelayError> {
+        let capacity = self.state.data_manager.data_holder.capacity();
+        let server_response = self.register(capacity, kg_index);
+        let mut next_message = self.generate_client_answer(server_response);
+        debug!("Next message: {:?}", next_message);
+        // TODO The client/server response could be an error
+        let server_response = self.send_message(next_message.clone().ok_or(RelayError::CantRelay)?);
+        self.store_server_response(&server_response);
+        debug!("Server Response: {:?}", server_response);
+
+        for round in 0..rounds {
+            self.wait_for_round(round, capacity)?;
+            for msg in self
+                .state
+                .stored_messages
+                .get_messages_vector_client_message(round)
+            {
+                next_message = self.handle_relay_message(msg.clone());
+            }
+            self.abort_if_blamed()?;
+            // Do not send response on last round
+            if round != rounds - 1 {
+                let server_response =
+                    self.send_message(next_message.clone().ok_or(RelayError::CantRelay)?);
+                self.store_server_response(&server_response);
+            }
+        }
+        Ok(())
+    }
+
+    // Queries the messages of the round until the message of every peer was stored
+    fn wait_for_round(&mut self, round: u32, capacity: u32) -> Result<(), RelayError> {
+        debug!("Now on round {}", round);
+        for _ in 0..self.max_retry {
+            if self.state.stored_messages.get_number_messages(round) == capacity as usize {
+                return Ok(());
+            }
+            let server_response = self.query();
+            self.store_server_response(&server_response);
+            // stop waiting once the session is aborted
+            if let Some(abort) = self.query_abort() {
+                error!(
+                    "Session aborted by peer {}: {:?}, accused {:?}, stalled peers {:?}",
+                    abort.peer_number, abort.reason, abort.accused, abort.stalled_peers
+                );
+                return Err(RelayError::SessionAborted);
+            }
+            thread::sleep(time::Duration::from_millis(RETRY_TIMEOUT));
+        }
+        Err(RelayError::RoundTimedOut)
+    }
+
+    // Aborts the session if this peer found another peer misbehaving,
+    // or could not finish the protocol itself
+    fn abort_if_blamed(&self) -> Result<(), RelayError> {
+        if let Some(abort) = self.state.generate_abort_message() {
+            error!("Aborting the session: {:?}", abort.abort);
+            if let Err(err_msg) = self.send_abort(abort) {
+                error!("Unable to abort the session: {}", err_msg);
+            }
+            return Err(RelayError::SessionAborted);
         }
+        Ok(())
     }
 }
 
@@ -79,12 +163,91 @@ impl<T: Peer> SessionClient<T> {
         }
     }
 
-    pub fn register(&mut self, index: u32, capacity: u32, kg_index: i32) -> ServerMessage {
+    // Requests a registration challenge for the client identity
+    pub fn challenge(&self) -> String {
+        let identity_key = self.state.identity.public_key();
+        let response = self
+            .client
+            .abci_query(
+                Some(CHALLENGE_QUERY_PATH.parse().unwrap()),
+                identity_key,
+                None,
+                false,
+            )
+            .unwrap();
+        response.log.to_string()
+    }
+
+    // Requests the roster of the session, and keeps it if all its encryption keys
+    // were signed by the identities registered with them
+    pub fn refresh_roster(&mut self) -> Result<(), RelayError> {
+        let response = self
+            .client
+            .abci_query(
+                Some(ROSTER_QUERY_PATH.parse().unwrap()),
+                String::new(),
+                None,
+                false,
+            )
+            .map_err(|_| RelayError::CantRelay)?;
+        let roster: Roster =
+            serde_json::from_str(&response.log.to_string()).map_err(|_| RelayError::CantRelay)?;
+        verify_roster(&roster)?;
+        self.state.roster = roster;
+        Ok(())
+    }
+
+    // Requests the abort of the session, returns None if the session was not aborted
+    pub fn query_abort(&self) -> Option<AbortMessage> {
+        let response = self
+            .client
+            .abci_query(
+                Some(ABORT_QUERY_PATH.parse().unwrap()),
+                String::new(),
+                None,
+                false,
+            )
+            .ok()?;
+        serde_json::from_str(&response.log.to_string()).unwrap_or(None)
+    }
+
+    // Requests the head of the chain of the session, and keeps it if it was signed
+    // by the server key pinned at registration and does not roll the chain back
+    pub fn query_chain_head(&mut self) -> Result<SignedChainHead, RelayError> {
+        let response = self
+            .client
+            .abci_query(
+                Some(CHAIN_QUERY_PATH.parse().unwrap()),
+                String::new(),
+                None,
+                false,
+            )
+            .map_err(|_| RelayError::CantRelay)?;
+        let server_msg: ServerMessage =
+            serde_json::from_str(&response.log.to_string()).map_err(|_| RelayError::CantRelay)?;
+        let chain_head = server_msg.chain_head.ok_or(RelayError::CantRelay)?;
+        self.state.update_chain_head(chain_head.clone())?;
+        Ok(chain_head)
+    }
+
+    pub fn register(&mut self, capacity: u32, kg_index: i32) -> ServerMessage {
         let mut msg = ClientMessage::new();
-        let port = 8080 + index;
-        let client_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-        // No index to begin with
-        msg.set_register(client_addr, self.state.protocol_id, capacity, kg_index);
+        // Prove the identity is ours by signing the challenge issued for it
+        let challenge = self.challenge();
+        let signature = self.state.identity.sign_challenge(&challenge);
+        // Other peers seal point to point payloads for us with this key
+        let encryption_key = self
+            .state
+            .identity
+            .sign_encryption_key(&self.state.encryption_key);
+        msg.set_register(
+            self.state.identity.public_key(),
+            self.state.protocol_id,
+            capacity,
+            kg_index,
+            signature,
+            encryption_key,
+        );
 
         debug!("Register message {:?}", msg);
         let tx =
@@ -96,6 +259,12 @@ impl<T: Peer> SessionClient<T> {
         let server_response: ServerMessage =
             serde_json::from_str(&response.deliver_tx.log.unwrap().to_string()).unwrap();
         debug!("ServerResponse {:?}", server_response);
+        // the server key is pinned from the head the server signed at registration
+        if let Some(chain_head) = server_response.chain_head.clone() {
+            if let Err(err_msg) = self.state.update_chain_head(chain_head) {
+                warn!("Invalid chain head in register response: {}", err_msg);
+            }
+        }
         // TODO Add Error checks etc
         self.state.registered = true;
         return server_response;
@@ -106,6 +275,11 @@ impl<T: Peer> SessionClient<T> {
         let tx =
             tendermint::abci::transaction::Transaction::new(serde_json::to_string(&msg).unwrap());
         let server_response = match self.client.broadcast_tx_commit(tx) {
+            Ok(response) if !response.deliver_tx.code.is_ok() => {
+                let log = response.deliver_tx.log.map(|log| log.to_string());
+                warn!("Message rejected: {}", rejection(&log.unwrap_or_default()));
+                BTreeMap::new()
+            }
             Ok(response) => {
                 let server_response = response.clone().deliver_tx.log.unwrap();
                 debug!("ServerResponse {:?}", server_response);
@@ -121,6 +295,17 @@ impl<T: Peer> SessionClient<T> {
         return server_response;
     }
 
+    // Broadcasts an abort of the session, the other peers learn of it by querying the abort
+    pub fn send_abort(&self, msg: ClientMessage) -> Result<(), RelayError> {
+        debug!("Sending abort {:?}", msg);
+        let tx =
+            tendermint::abci::transaction::Transaction::new(serde_json::to_string(&msg).unwrap());
+        match self.client.broadcast_tx_commit(tx) {
+            Ok(response) if response.deliver_tx.code.is_ok() => Ok(()),
+            _ => Err(RelayError::CantRelay),
+        }
+    }
+
     // Stores the server response to the stored messages
     pub fn store_server_response(&mut self, messages: &BTreeMap<u32, ClientMessage>) {
         let round = self.state.data_manager.data_holder.current_step();
@@ -133,6 +318,12 @@ impl<T: Peer> SessionClient<T> {
 
     pub fn handle_relay_message(&mut self, client_msg: ClientMessage) -> Option<ClientMessage> {
         let msg = client_msg.relay_message.unwrap();
+        // peers that registered after us are not in the roster we got when registering
+        if msg.message.is_sealed() && !self.state.roster.contains_key(&msg.peer_number) {
+            if let Err(err_msg) = self.refresh_roster() {
+                warn!("Unable to refresh the roster: {}", err_msg);
+            }
+        }
         let new_message;
         let next = self.state.handle_relay_message(msg.clone());
         match next {
@@ -157,8 +348,8 @@ impl<T: Peer> SessionClient<T> {
                     Ok(next_msg) => {
                         new_message = Some(next_msg.clone());
                     }
-                    Err(_) => {
-                        error!("Error in handle_server_response");
+                    Err(err) => {
+                        error!("Error in handle_server_response: {}", err);
                     }
                 }
             }
@@ -187,36 +378,62 @@ where
 {
     pub registered: bool,
     pub protocol_id: ProtocolIdentifier,
-    pub client_addr: SocketAddr,
+    pub identity: Identity,
+    // the ephemeral key point to point payloads are sealed for this peer with
+    pub encryption_key: EncryptionKeypair,
+    pub roster: Roster,
     pub data_manager: ProtocolDataManager<T>,
     pub last_message: ClientMessage,
     pub bc_dests: Vec<ProtocolIdentifier>,
     pub stored_messages: StoredMessages,
+    // key of the server, pinned from the first chain head it signed
+    pub server_key: Option<IdentityKey>,
+    // latest head of the chain of the session the server committed to
+    pub chain_head: Option<SignedChainHead>,
+    // the session the messages are signed for, named by the first chain head of the server
+    pub session_id: SessionIdentifier,
 }
 
 impl<T: Peer> State<T> {
-    pub fn new(
-        protocol_id: ProtocolIdentifier,
-        capacity: u32,
-        client_addr: SocketAddr,
-        client_index: u32,
-        message: Vec<u8>,
-    ) -> State<T>
+    pub fn new(protocol_id: ProtocolIdentifier, identity: Identity, peer: T) -> State<T>
     where
         T: Peer,
     {
-        let data_m: ProtocolDataManager<T> =
-            ProtocolDataManager::new(capacity, message, client_index);
+        let data_m: ProtocolDataManager<T> = ProtocolDataManager::new(peer);
         State {
             registered: false,
             protocol_id,
-            client_addr,
+            identity,
+            encryption_key: EncryptionKeypair::generate(),
+            roster: Roster::new(),
             last_message: ClientMessage::new(),
             bc_dests: vec![0],
             data_manager: data_m,
             stored_messages: StoredMessages::new(),
+            server_key: None,
+            chain_head: None,
+            session_id: 0,
         }
     }
+
+    // Keeps the head if it was signed by the server, and extends the last head kept.
+    // The first head names the session the messages of this peer are signed for
+    pub fn update_chain_head(&mut self, chain_head: SignedChainHead) -> Result<(), RelayError> {
+        let server_key = self
+            .server_key
+            .get_or_insert_with(|| chain_head.server.clone());
+        verify_chain_head(&chain_head, server_key)?;
+        if let Some(last) = &self.chain_head {
+            if chain_head.head.length < last.head.length
+                || chain_head.head.session_id != last.head.session_id
+            {
+                return Err(RelayError::InvalidChainHead);
+            }
+        }
+        self.session_id = chain_head.head.session_id;
+        self.chain_head = Some(chain_head);
+        Ok(())
+    }
 }
 
 impl<T: Peer> State<T> {
@@ -226,27 +443,81 @@ impl<T: Peer> State<T> {
         if from == self.data_manager.data_holder.peer_id() {
             debug!("-------self message accepted ------\n ");
         }
-        let payload = relay_msg.message;
+        let payload = match relay_msg.message {
+            RelayPayload::Plain(payload) => payload,
+            RelayPayload::Sealed(sealed) => match self.open_payload(from, &sealed) {
+                Ok(payload) => payload,
+                Err(err_msg) => {
+                    warn!("Dropping message from {}: {}", from, err_msg);
+                    return None;
+                }
+            },
+        };
         self.data_manager.get_next_message(from, payload)
     }
 
+    // Decrypts a payload sealed for this peer, with the encryption key the sender registered
+    fn open_payload(
+        &self,
+        from: PeerIdentifier,
+        sealed: &SealedPayload,
+    ) -> Result<MessagePayload, RelayError> {
+        let sender = self.roster.get(&from).ok_or(RelayError::DecryptionFailed)?;
+        self.encryption_key.open(
+            &sender.encryption_key.key,
+            from,
+            self.data_manager.data_holder.peer_id(),
+            sealed,
+        )
+    }
+
     fn generate_relay_message(&self, payload: MessagePayload) -> ClientMessage {
         let _msg = ClientMessage::new();
         // create relay message
         let mut relay_message = RelayMessage::new(
             self.data_manager.data_holder.peer_id(),
             self.protocol_id,
-            self.client_addr,
+            self.identity.public_key(),
         );
         let to: Vec<u32> = self.bc_dests.clone();
 
         let mut client_message = ClientMessage::new();
 
-        relay_message.set_message_params(to, String::from(payload));
+        // the message is sent and signed for the round the peer is currently in
+        let round = self.data_manager.data_holder.current_step();
+        relay_message.set_message_params(round, to, payload);
         client_message.relay_message = Some(relay_message);
+        self.identity
+            .sign_message(&mut client_message, self.session_id, round);
         client_message
     }
 
+    // Returns a signed abort blaming the peer this peer found misbehaving, if any,
+    // with the message the peer is blamed for as evidence
+    pub fn generate_abort_message(&self) -> Option<ClientMessage> {
+        let blame = self.data_manager.data_holder.blame()?;
+        let evidence = blame
+            .accused
+            .and_then(|accused| self.stored_messages.get_message(blame.round, accused))
+            .and_then(|msg| msg.relay_message)
+            .map(|message| AbortEvidence {
+                round: blame.round,
+                message,
+            });
+        let mut client_message = ClientMessage::new();
+        client_message.abort = Some(AbortMessage::blame(
+            self.data_manager.data_holder.peer_id(),
+            self.protocol_id,
+            blame.reason,
+            blame.accused,
+            evidence,
+        ));
+        let round = self.data_manager.data_holder.current_step();
+        self.identity
+            .sign_message(&mut client_message, self.session_id, round);
+        Some(client_message)
+    }
+
     fn handle_register_response(&mut self, peer_id: PeerIdentifier) -> Result<ClientMessage, ()> {
         info!("Peer identifier: {}", peer_id);
         // Set the session parameters
@@ -262,46 +533,32 @@ impl<T: Peer> State<T> {
         return Some(last_msg.clone());
     }
 
-    fn handle_error_response(&mut self, err_msg: &str) -> Result<ClientMessage, &'static str> {
-        match err_msg {
-            resp if resp == String::from(NOT_YOUR_TURN) => {
-                let last_msg = self.get_last_message();
-                match last_msg {
-                    Some(msg) => {
-                        return Ok(msg.clone());
-                    }
-                    None => {
-                        panic!("No message to resend");
-                    }
-                }
-            }
-            not_initialized_resp if not_initialized_resp == String::from(STATE_NOT_INITIALIZED) => {
-                debug!("Not initialized, sending again");
-                let last_msg = self.get_last_message();
-                match last_msg {
-                    Some(_) => {
-                        // If protocol is not initialized, wait for a message from the server
-                        return Ok(ClientMessage::new());
-                    }
-                    None => {
-                        panic!("No message to resend");
-                    }
-                }
+    fn handle_error_response(&mut self, err: RelayError) -> Result<ClientMessage, RelayError> {
+        match err {
+            RelayError::NotYourTurn => self.get_last_message().ok_or(err),
+            RelayError::NotInitialized => {
+                debug!("Not initialized, waiting for a message from the server");
+                // If protocol is not initialized, wait for a message from the server
+                self.get_last_message()
+                    .map(|_| ClientMessage::new())
+                    .ok_or(err)
             }
             _ => {
-                warn!("didn't handle error correctly");
-                return Err("error response handling failed");
+                warn!("Unable to recover from error response: {}", err);
+                Err(err)
             }
         }
     }
 
-    fn handle_server_response(
-        &mut self,
-        msg: &ServerMessage,
-    ) -> Result<ClientMessage, &'static str> {
-        let server_response = msg.response.clone().unwrap();
+    fn handle_server_response(&mut self, msg: &ServerMessage) -> Result<ClientMessage, RelayError> {
+        let server_response = msg.response.clone().ok_or(RelayError::MalformedMessage)?;
         match server_response {
-            ServerResponse::Register(peer_id) => {
+            ServerResponse::Register(peer_id, roster) => {
+                if let Err(err_msg) = verify_roster(&roster) {
+                    error!("Received an invalid roster: {}", err_msg);
+                    return Err(err_msg);
+                }
+                self.roster = roster;
                 let client_message = self.handle_register_response(peer_id);
                 match client_message {
                     Ok(_msg) => {
@@ -314,18 +571,20 @@ impl<T: Peer> State<T> {
                     }
                 }
             }
-            ServerResponse::ErrorResponse(err_msg) => {
-                let err_msg_slice: &str = &err_msg[..];
-                let msg = self.handle_error_response(err_msg_slice);
-                match msg {
-                    Ok(_msg) => return Ok(_msg),
-                    Err(_) => {
-                        error!("error occured");
-                        return Ok(ClientMessage::new());
-                    }
-                }
+            ServerResponse::ErrorResponse(err) => self.handle_error_response(err),
+            // the server did not answer the message, so there is nothing to move on with
+            ServerResponse::NoResponse => {
+                warn!("Server sent no response");
+                Err(RelayError::CantRelay)
             }
-            ServerResponse::NoResponse => unimplemented!(),
         }
     }
 }
+
+// Returns the error a rejected transaction or query carries in its log
+pub(crate) fn rejection(log: &str) -> RelayError {
+    match serde_json::from_str::<ServerMessage>(log).map(|msg| msg.response) {
+        Ok(Some(ServerResponse::ErrorResponse(err))) => err,
+        _ => RelayError::CantRelay,
+    }
+}
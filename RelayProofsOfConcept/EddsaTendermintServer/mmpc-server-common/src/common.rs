@@ -1,55 +1,86 @@
 /// common constants and structures for relay communication
-use super::MessagePayload;
-// Error responses
-pub static CANT_REGISTER_RESPONSE: &str = "Can't register peer";
-pub static RELAY_ERROR_RESPONSE: &str = "Can't relay message";
-pub static STATE_NOT_INITIALIZED: &str = "Relay sessions state is not initialized";
-pub static RELAY_MESSAGE_DELIMITER: &str = ":::";
-pub static NOT_YOUR_TURN: &str = "Not this peers turn";
-pub static NOT_A_PEER: &str = "Not a peer";
-
-/// eddsa constants
-pub static PK_MESSAGE_PREFIX: &str = "PUBLIC_KEY";
-pub static COMMITMENT_MESSAGE_PREFIX: &str = "COMMITMENT";
-pub static R_KEY_MESSAGE_PREFIX: &str = "R_KEY";
-pub static R_KEY_MESSAGE_DELIMITER: &str = "@";
-pub static SIGNATURE_MESSAGE_PREFIX: &str = "SIGNATURE";
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::RelayError;
+use crate::MessagePayload;
+
+// abci query path for requesting a registration challenge
+pub static CHALLENGE_QUERY_PATH: &str = "/challenge";
+// abci query path for requesting the roster of the session
+pub static ROSTER_QUERY_PATH: &str = "/roster";
+// abci query path for requesting the abort of the session, if it was aborted
+pub static ABORT_QUERY_PATH: &str = "/abort";
+// abci query path for requesting the signed head of the chain of the session
+pub static CHAIN_QUERY_PATH: &str = "/chain";
 
 pub static EMPTY_MESSAGE_PAYLOAD: &str = "";
 
-pub fn generate_pk_message_payload(pk: &String) -> MessagePayload {
-    return format!(
-        "{}{}{}",
-        PK_MESSAGE_PREFIX,
-        RELAY_MESSAGE_DELIMITER,
-        pk.clone()
-    );
+/// eddsa messages
+/// The payload peers of multi-party EdDSA commit in each round, tagged with its kind.
+/// The types of the contents are left to the peers, so the app does not depend on the curve
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content")]
+pub enum EddsaMessage<P, C, R, S> {
+    PublicKey(P),
+    Commitment(C),
+    RMessage(R),
+    PartialSignature(S),
 }
 
-pub fn generate_commitment_message_payload(cmtnmt: &String) -> MessagePayload {
-    return format!(
-        "{prefix}{delimiter}{message}",
-        prefix = COMMITMENT_MESSAGE_PREFIX,
-        delimiter = RELAY_MESSAGE_DELIMITER,
-        message = cmtnmt.clone()
-    );
-}
+impl<P, C, R, S> EddsaMessage<P, C, R, S>
+where
+    P: Serialize + DeserializeOwned,
+    C: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+    S: Serialize + DeserializeOwned,
+{
+    /// The round of the protocol the message is sent in
+    pub fn round(&self) -> u32 {
+        match self {
+            EddsaMessage::PublicKey(_) => 0,
+            EddsaMessage::Commitment(_) => 1,
+            EddsaMessage::RMessage(_) => 2,
+            EddsaMessage::PartialSignature(_) => 3,
+        }
+    }
+
+    /// Encodes the message as the payload of a relay message
+    pub fn to_payload(&self) -> MessagePayload {
+        serde_json::to_value(self).expect("Failed to serialize EdDSA message")
+    }
 
-#[allow(non_snake_case)]
-pub fn generate_R_message_payload(r: &String) -> MessagePayload {
-    return format!(
-        "{prefix}{delimiter}{message}",
-        prefix = R_KEY_MESSAGE_PREFIX,
-        delimiter = RELAY_MESSAGE_DELIMITER,
-        message = r.clone()
-    );
+    /// Decodes the payload of a relay message. A payload that is not an EdDSA message,
+    /// or whose content does not decode as the type of its kind, is an invalid payload
+    pub fn from_payload(payload: &MessagePayload) -> Result<Self, RelayError> {
+        Self::deserialize(payload).map_err(|_| RelayError::InvalidPayload)
+    }
 }
 
-pub fn generate_signature_message_payload(sig: &String) -> MessagePayload {
-    return format!(
-        "{prefix}{delimiter}{message}",
-        prefix = SIGNATURE_MESSAGE_PREFIX,
-        delimiter = RELAY_MESSAGE_DELIMITER,
-        message = sig.clone()
-    );
+#[cfg(test)]
+mod tests {
+    use super::EddsaMessage;
+    use crate::error::RelayError;
+    use serde_json::json;
+
+    type TestMessage = EddsaMessage<String, u32, Vec<u32>, String>;
+
+    #[test]
+    fn test_eddsa_message_payload() {
+        let pk = TestMessage::PublicKey("point".to_string());
+        let payload = pk.to_payload();
+        assert_eq!(payload, json!({"type": "PublicKey", "content": "point"}));
+        assert_eq!(TestMessage::from_payload(&payload), Ok(pk));
+        assert_eq!(TestMessage::PartialSignature("sig".to_string()).round(), 3);
+
+        // malformed payloads are decode errors
+        assert_eq!(
+            TestMessage::from_payload(&json!("PUBLIC_KEY:::\"point\"")),
+            Err(RelayError::InvalidPayload)
+        );
+        assert_eq!(
+            TestMessage::from_payload(&json!({"type": "Commitment", "content": "not a number"})),
+            Err(RelayError::InvalidPayload)
+        );
+    }
 }
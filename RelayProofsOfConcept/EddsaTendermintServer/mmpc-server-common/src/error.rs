@@ -0,0 +1,144 @@
+/// Errors the application reports to its peers.
+/// Errors are sent in the log of a rejected transaction or query, and as part of an error
+/// response, so peers handle them by variant rather than by comparing strings
+use mpc_peer::board::BoardError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RelayError {
+    // the peer could not register to the session
+    CantRegister,
+
+    // the message could not be relayed
+    CantRelay,
+
+    // the session does not have all its peers yet
+    NotInitialized,
+
+    // the message was sent out of the peer's turn
+    NotYourTurn,
+
+    // the peer already sent its message in this round
+    AlreadySentInRound,
+
+    // the message is not for the current round
+    WrongRound,
+
+    // the message is for a round beyond the rounds of the protocol
+    RoundOutOfRange,
+
+    // the recipients of the message do not fit the kind of the round, broadcast or point to point
+    WrongRoundKind,
+
+    // the sender is not a peer of the session
+    NotAPeer,
+
+    InvalidIdentityKey,
+
+    ChallengeFailed,
+
+    // another peer of the session registered with the same identity
+    IdentityAlreadyRegistered,
+
+    InvalidSignature,
+
+    InvalidChainHead,
+
+    InvalidEncryptionKey,
+
+    DecryptionFailed,
+
+    // a point to point message was not sealed for its recipient
+    UnsealedMessage,
+
+    // a broadcast message carries a sealed payload, which only a single recipient could open
+    SealedBroadcast,
+
+    // the transaction could not be decoded, or is missing the fields of its type
+    MalformedMessage,
+
+    // the payload is not one the protocol sends in the round of the message
+    InvalidPayload,
+
+    // the session was aborted before the protocol was done
+    SessionAborted,
+
+    // the session relayed the last round of its protocol
+    SessionCompleted,
+
+    // the messages of the round did not all arrive in time
+    RoundTimedOut,
+
+    // the session did not see its first block, so messages can not be signed for it yet
+    SessionNotStarted,
+
+    // the peer found misbehavior, or could not move on with the payloads of the round
+    ProtocolFailed,
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            RelayError::CantRegister => "Can't register peer",
+            RelayError::CantRelay => "Can't relay message",
+            RelayError::NotInitialized => "Relay sessions state is not initialized",
+            RelayError::NotYourTurn => "Not this peers turn",
+            RelayError::AlreadySentInRound => "Peer already sent its message in this round",
+            RelayError::WrongRound => "Message is not for the current round",
+            RelayError::RoundOutOfRange => "Round is beyond the rounds of the protocol",
+            RelayError::WrongRoundKind => "Message does not fit the kind of the round",
+            RelayError::NotAPeer => "Not a peer",
+            RelayError::InvalidIdentityKey => "Invalid identity key",
+            RelayError::ChallengeFailed => "Challenge response verification failed",
+            RelayError::IdentityAlreadyRegistered => "Identity is already registered",
+            RelayError::InvalidSignature => "Invalid message signature",
+            RelayError::InvalidChainHead => "Invalid chain head",
+            RelayError::InvalidEncryptionKey => "Invalid encryption key",
+            RelayError::DecryptionFailed => "Unable to open sealed message",
+            RelayError::UnsealedMessage => "Point to point message is not sealed",
+            RelayError::SealedBroadcast => "Broadcast message is sealed",
+            RelayError::MalformedMessage => "Malformed message",
+            RelayError::InvalidPayload => "Invalid payload for the protocol",
+            RelayError::SessionAborted => "Relay session was aborted",
+            RelayError::SessionCompleted => "Relay session was completed",
+            RelayError::RoundTimedOut => "Timed out waiting for the messages of the round",
+            RelayError::SessionNotStarted => "Relay session did not see its first block yet",
+            RelayError::ProtocolFailed => "Peer could not continue the protocol",
+        };
+        f.write_str(description)
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+// A board over the application reports the errors of the board as the application would
+impl From<BoardError> for RelayError {
+    fn from(err: BoardError) -> RelayError {
+        match err {
+            BoardError::CantJoin => RelayError::CantRegister,
+            BoardError::NotJoined => RelayError::NotAPeer,
+            BoardError::CantPost => RelayError::CantRelay,
+            BoardError::AlreadyPosted => RelayError::AlreadySentInRound,
+            BoardError::ProtocolFailed => RelayError::ProtocolFailed,
+            BoardError::TimedOut => RelayError::RoundTimedOut,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RelayError;
+
+    #[test]
+    fn test_error_in_log() {
+        let encoded = serde_json::to_string(&RelayError::InvalidSignature).unwrap();
+        assert_eq!("\"InvalidSignature\"", encoded);
+        let decoded: RelayError = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(RelayError::InvalidSignature, decoded);
+        assert_eq!(
+            "Invalid message signature",
+            RelayError::InvalidSignature.to_string()
+        );
+    }
+}
@@ -0,0 +1,356 @@
+/// Long term Ed25519 identities of peers.
+/// A peer registers with its identity key, and proves it holds the matching secret key
+/// by signing a challenge issued by the server.
+/// Every message the peer sends afterwards, and the encryption key it registers,
+/// are signed with the same key
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::encryption::{parse_encryption_key, EncryptionKeypair};
+use crate::error::RelayError;
+use crate::{
+    ChainHead, ClientMessage, IdentityKey, MessageSignature, SessionIdentifier, SignedChainHead,
+    SignedEncryptionKey,
+};
+
+// Every challenge is signed with this prefix,
+// so a challenge signature can not be reused in another context
+static CHALLENGE_DOMAIN: &[u8] = b"relay-server-challenge:";
+static MESSAGE_DOMAIN: &[u8] = b"relay-server-message:";
+static ENCRYPTION_KEY_DOMAIN: &[u8] = b"relay-server-encryption-key:";
+static CHAIN_HEAD_DOMAIN: &[u8] = b"relay-server-chain-head:";
+
+pub struct Identity {
+    keypair: Keypair,
+}
+
+impl Identity {
+    /// Generates a new random identity
+    pub fn generate() -> Identity {
+        Identity {
+            keypair: Keypair::generate(&mut OsRng),
+        }
+    }
+
+    /// Loads the identity whose hex encoded secret key is stored in path.
+    /// If there is no such file, a new identity is generated and stored in it
+    pub fn load_or_generate<P: AsRef<Path>>(path: P) -> io::Result<Identity> {
+        let path = path.as_ref();
+        if !path.exists() {
+            let identity = Identity::generate();
+            fs::write(path, hex::encode(identity.keypair.secret.as_bytes()))?;
+            return Ok(identity);
+        }
+        let bytes = hex::decode(fs::read_to_string(path)?.trim()).map_err(invalid_identity_file)?;
+        let secret = SecretKey::from_bytes(&bytes).map_err(invalid_identity_file)?;
+        let public = PublicKey::from(&secret);
+        Ok(Identity {
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    /// Returns the identity key other parties know this identity by
+    pub fn public_key(&self) -> IdentityKey {
+        hex::encode(self.keypair.public.as_bytes())
+    }
+
+    /// Signs a challenge received from the server.
+    /// Returns the hex encoded signature
+    pub fn sign_challenge(&self, challenge: &str) -> String {
+        let signature = self.keypair.sign(&challenge_bytes(challenge));
+        hex::encode(&signature.to_bytes()[..])
+    }
+
+    /// Signs the encryption key this peer registers with,
+    /// so other peers can tell the key belongs to this identity
+    pub fn sign_encryption_key(&self, encryption_key: &EncryptionKeypair) -> SignedEncryptionKey {
+        let key = encryption_key.public_key();
+        let signature = self.keypair.sign(&encryption_key_bytes(&key));
+        SignedEncryptionKey {
+            key,
+            signature: hex::encode(&signature.to_bytes()[..]),
+        }
+    }
+
+    /// Signs the client message as sent in the given session and round.
+    /// The signature envelope of the message is replaced by the new signature
+    pub fn sign_message(&self, msg: &mut ClientMessage, session_id: SessionIdentifier, round: u32) {
+        msg.signature = None;
+        let sender = self.public_key();
+        let signature = self
+            .keypair
+            .sign(&message_bytes(msg, session_id, round, &sender));
+        msg.signature = Some(MessageSignature {
+            session_id,
+            round,
+            sender,
+            signature: hex::encode(&signature.to_bytes()[..]),
+        });
+    }
+
+    /// Signs the head of the hash chain of a session.
+    /// Only the server signs chain heads, with its own identity
+    pub fn sign_chain_head(&self, head: ChainHead) -> SignedChainHead {
+        let signature = self.keypair.sign(&chain_head_bytes(&head));
+        SignedChainHead {
+            head,
+            server: self.public_key(),
+            signature: hex::encode(&signature.to_bytes()[..]),
+        }
+    }
+}
+
+impl fmt::Debug for Identity {
+    // only the public part of the identity is printed
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Identity({})", self.public_key())
+    }
+}
+
+/// Generates a new random challenge
+pub fn new_challenge() -> String {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    hex::encode(nonce)
+}
+
+/// Checks that the identity key is an encoded Ed25519 public key
+pub fn parse_identity_key(identity: &IdentityKey) -> Result<PublicKey, RelayError> {
+    let bytes = hex::decode(identity).map_err(|_| RelayError::InvalidIdentityKey)?;
+    PublicKey::from_bytes(&bytes).map_err(|_| RelayError::InvalidIdentityKey)
+}
+
+/// Verifies the signature over the challenge was made by the holder of the identity
+pub fn verify_challenge_response(
+    identity: &IdentityKey,
+    challenge: &str,
+    signature: &str,
+) -> Result<(), RelayError> {
+    let public = parse_identity_key(identity)?;
+    let bytes = hex::decode(signature).map_err(|_| RelayError::ChallengeFailed)?;
+    let signature = Signature::try_from(&bytes[..]).map_err(|_| RelayError::ChallengeFailed)?;
+    public
+        .verify(&challenge_bytes(challenge), &signature)
+        .map_err(|_| RelayError::ChallengeFailed)
+}
+
+/// Verifies the encryption key was signed by the holder of the identity
+pub fn verify_encryption_key(
+    identity: &IdentityKey,
+    encryption_key: &SignedEncryptionKey,
+) -> Result<(), RelayError> {
+    parse_encryption_key(&encryption_key.key)?;
+    let public = parse_identity_key(identity).map_err(|_| RelayError::InvalidEncryptionKey)?;
+    let bytes =
+        hex::decode(&encryption_key.signature).map_err(|_| RelayError::InvalidEncryptionKey)?;
+    let signature =
+        Signature::try_from(&bytes[..]).map_err(|_| RelayError::InvalidEncryptionKey)?;
+    public
+        .verify(&encryption_key_bytes(&encryption_key.key), &signature)
+        .map_err(|_| RelayError::InvalidEncryptionKey)
+}
+
+/// Verifies the client message was signed by sender, for the given session and round
+pub fn verify_message_signature(
+    msg: &ClientMessage,
+    session_id: SessionIdentifier,
+    round: u32,
+    sender: &IdentityKey,
+) -> Result<(), RelayError> {
+    let envelope = msg.signature.as_ref().ok_or(RelayError::InvalidSignature)?;
+    if envelope.session_id != session_id || envelope.round != round || &envelope.sender != sender {
+        return Err(RelayError::InvalidSignature);
+    }
+    let public = parse_identity_key(sender).map_err(|_| RelayError::InvalidSignature)?;
+    let bytes = hex::decode(&envelope.signature).map_err(|_| RelayError::InvalidSignature)?;
+    let signature = Signature::try_from(&bytes[..]).map_err(|_| RelayError::InvalidSignature)?;
+    let mut unsigned = msg.clone();
+    unsigned.signature = None;
+    public
+        .verify(
+            &message_bytes(&unsigned, session_id, round, sender),
+            &signature,
+        )
+        .map_err(|_| RelayError::InvalidSignature)
+}
+
+/// Verifies the chain head was signed by the server with the given identity
+pub fn verify_chain_head(signed: &SignedChainHead, server: &IdentityKey) -> Result<(), RelayError> {
+    if &signed.server != server {
+        return Err(RelayError::InvalidChainHead);
+    }
+    let public = parse_identity_key(server).map_err(|_| RelayError::InvalidChainHead)?;
+    let bytes = hex::decode(&signed.signature).map_err(|_| RelayError::InvalidChainHead)?;
+    let signature = Signature::try_from(&bytes[..]).map_err(|_| RelayError::InvalidChainHead)?;
+    public
+        .verify(&chain_head_bytes(&signed.head), &signature)
+        .map_err(|_| RelayError::InvalidChainHead)
+}
+
+fn invalid_identity_file<E>(_: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid identity file")
+}
+
+fn challenge_bytes(challenge: &str) -> Vec<u8> {
+    [CHALLENGE_DOMAIN, challenge.as_bytes()].concat()
+}
+
+fn encryption_key_bytes(key: &str) -> Vec<u8> {
+    [ENCRYPTION_KEY_DOMAIN, key.as_bytes()].concat()
+}
+
+fn chain_head_bytes(head: &ChainHead) -> Vec<u8> {
+    let signed = serde_json::to_vec(head).expect("Chain head is always serializable");
+    [CHAIN_HEAD_DOMAIN, &signed[..]].concat()
+}
+
+// The signed bytes of a message without its signature envelope
+fn message_bytes(
+    msg: &ClientMessage,
+    session_id: SessionIdentifier,
+    round: u32,
+    sender: &IdentityKey,
+) -> Vec<u8> {
+    let signed = serde_json::to_vec(&(session_id, round, sender, msg))
+        .expect("Client message is always serializable");
+    [MESSAGE_DOMAIN, &signed[..]].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        new_challenge, parse_identity_key, verify_chain_head, verify_challenge_response,
+        verify_message_signature, Identity,
+    };
+    use crate::error::RelayError;
+    use crate::{ChainHead, ClientMessage, RelayMessage};
+
+    #[test]
+    fn test_challenge_response() {
+        let identity = Identity::generate();
+        let challenge = new_challenge();
+        let signature = identity.sign_challenge(&challenge);
+        assert!(verify_challenge_response(&identity.public_key(), &challenge, &signature).is_ok());
+        // the signature does not hold for another challenge
+        assert_eq!(
+            verify_challenge_response(&identity.public_key(), &new_challenge(), &signature),
+            Err(RelayError::ChallengeFailed)
+        );
+    }
+
+    #[test]
+    fn test_challenge_response_other_identity() {
+        let identity = Identity::generate();
+        let other = Identity::generate();
+        let challenge = new_challenge();
+        let signature = other.sign_challenge(&challenge);
+        assert_eq!(
+            verify_challenge_response(&identity.public_key(), &challenge, &signature),
+            Err(RelayError::ChallengeFailed)
+        );
+    }
+
+    #[test]
+    fn test_invalid_identity_key() {
+        assert!(parse_identity_key(&Identity::generate().public_key()).is_ok());
+        assert_eq!(
+            parse_identity_key(&String::from("127.0.0.1:8080")).err(),
+            Some(RelayError::InvalidIdentityKey)
+        );
+        assert_eq!(
+            parse_identity_key(&String::from("abcd")).err(),
+            Some(RelayError::InvalidIdentityKey)
+        );
+    }
+
+    fn relay_message(payload: &str) -> ClientMessage {
+        let mut msg = ClientMessage::new();
+        let mut relay_message = RelayMessage::new(1, 1, String::from("sender"));
+        relay_message.set_message_params(0, vec![1, 2], payload);
+        msg.relay_message = Some(relay_message);
+        msg
+    }
+
+    #[test]
+    fn test_message_signature() {
+        let identity = Identity::generate();
+        let mut msg = relay_message("payload");
+        identity.sign_message(&mut msg, 3, 1);
+        let sender = identity.public_key();
+        assert_eq!(verify_message_signature(&msg, 3, 1, &sender), Ok(()));
+        // the signature is bound to the session and the round
+        assert_eq!(
+            verify_message_signature(&msg, 4, 1, &sender),
+            Err(RelayError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_message_signature(&msg, 3, 2, &sender),
+            Err(RelayError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_message_signature_forged() {
+        let identity = Identity::generate();
+        let other = Identity::generate();
+        let mut msg = relay_message("payload");
+        identity.sign_message(&mut msg, 0, 0);
+        // the message is not signed by the claimed sender
+        assert_eq!(
+            verify_message_signature(&msg, 0, 0, &other.public_key()),
+            Err(RelayError::InvalidSignature)
+        );
+        // the payload was changed after signing
+        let mut tampered = relay_message("other payload");
+        tampered.signature = msg.signature.clone();
+        assert_eq!(
+            verify_message_signature(&tampered, 0, 0, &identity.public_key()),
+            Err(RelayError::InvalidSignature)
+        );
+        // unsigned messages are rejected
+        assert_eq!(
+            verify_message_signature(&relay_message("payload"), 0, 0, &identity.public_key()),
+            Err(RelayError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_chain_head_signature() {
+        let server = Identity::generate();
+        let head = ChainHead {
+            session_id: 1,
+            length: 2,
+            prev: String::from("00"),
+            hash: String::from("11"),
+        };
+        let signed = server.sign_chain_head(head);
+        assert_eq!(verify_chain_head(&signed, &server.public_key()), Ok(()));
+        // the head is only trusted if signed by the expected server
+        let other = Identity::generate();
+        assert_eq!(
+            verify_chain_head(&signed, &other.public_key()),
+            Err(RelayError::InvalidChainHead)
+        );
+        let mut tampered = signed.clone();
+        tampered.head.length = 3;
+        assert_eq!(
+            verify_chain_head(&tampered, &server.public_key()),
+            Err(RelayError::InvalidChainHead)
+        );
+    }
+
+    #[test]
+    fn test_load_or_generate() {
+        let path = std::env::temp_dir().join(format!("identity-test-{}", new_challenge()));
+        let identity = Identity::load_or_generate(&path).unwrap();
+        let loaded = Identity::load_or_generate(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(identity.public_key(), loaded.public_key());
+    }
+}
@@ -1,15 +1,32 @@
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::fmt;
 use std::vec::Vec;
 use tokio_jsoncodec::Codec as JsonCodec;
 
+use crate::error::RelayError;
+
+pub mod chain;
 pub mod common;
+pub mod encryption;
+pub mod error;
+pub mod identity;
 pub mod protocol;
+pub mod validator;
+
+pub use mpc_peer::{AbortReason, Blame};
 
 pub type ProtocolIdentifier = u32;
 pub type PeerIdentifier = u32;
-pub type MessagePayload = String;
+pub type SessionIdentifier = u32;
+// A payload is a structured value rather than text, the peers decode it into their messages
+pub type MessagePayload = serde_json::Value;
+// Hex encoded Ed25519 public key identifying a peer
+pub type IdentityKey = String;
+// Hex encoded X25519 public key peers seal point to point payloads with
+pub type EncryptionKey = String;
+// The peers of the session, with the keys they registered
+pub type Roster = BTreeMap<PeerIdentifier, RosterEntry>;
 
 const MAX_CLIENTS: u32 = 12;
 
@@ -17,49 +34,165 @@ const MAX_CLIENTS: u32 = 12;
 pub struct RelayMessage {
     pub peer_number: PeerIdentifier,
     pub protocol_id: ProtocolIdentifier,
-    pub from: SocketAddr,
+    pub from: IdentityKey,
+    // the round of the protocol the message is sent in
+    pub round: u32,
     pub to: Vec<PeerIdentifier>,
-    pub message: MessagePayload,
+    pub message: RelayPayload,
 }
 
 impl RelayMessage {
     pub fn new(
         peer_number: PeerIdentifier,
         protocol_id: ProtocolIdentifier,
-        from: SocketAddr,
+        from: IdentityKey,
     ) -> RelayMessage {
         RelayMessage {
             peer_number,
             protocol_id,
             from,
+            round: 0,
             to: Vec::new(),
-            message: String::from(""),
+            message: RelayPayload::default(),
         }
     }
 
-    pub fn set_message_params<S: Into<String>>(&mut self, to: Vec<PeerIdentifier>, message: S) {
-        //self.round = round_number;
+    pub fn set_message_params<P: Into<RelayPayload>>(
+        &mut self,
+        round_number: u32,
+        to: Vec<PeerIdentifier>,
+        message: P,
+    ) {
+        self.round = round_number;
         self.to = to;
         self.message = message.into();
     }
+
+    /// Returns true if the message is addressed to a single peer other than the sender.
+    /// The payload of such a message must be sealed for the recipient
+    pub fn is_point_to_point(&self) -> bool {
+        self.to.len() == 1 && self.to[0] != self.peer_number
+    }
+
+    /// Returns true if the message is addressed to every peer of a session of the given capacity,
+    /// the sender may or may not address the message to itself
+    pub fn is_addressed_to_all(&self, capacity: u32) -> bool {
+        (1..=capacity)
+            .filter(|peer_id| *peer_id != self.peer_number)
+            .all(|peer_id| self.to.contains(&peer_id))
+    }
+}
+
+/// The payload of a relay message. A point to point payload is sealed for its recipient,
+/// so the application only ever reads the payloads that every peer of the session can read
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RelayPayload {
+    // a payload every recipient reads as the protocol sent it
+    Plain(MessagePayload),
+
+    // a payload encrypted for a single recipient, see `encryption`
+    Sealed(SealedPayload),
+}
+
+impl RelayPayload {
+    pub fn is_sealed(&self) -> bool {
+        matches!(self, RelayPayload::Sealed(_))
+    }
+}
+
+// Only the payloads the application can read are printed
+impl fmt::Display for RelayPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RelayPayload::Plain(payload) => write!(f, "{}", payload),
+            RelayPayload::Sealed(_) => f.write_str("<sealed>"),
+        }
+    }
+}
+
+impl Default for RelayPayload {
+    fn default() -> RelayPayload {
+        RelayPayload::Plain(MessagePayload::Null)
+    }
+}
+
+impl From<MessagePayload> for RelayPayload {
+    fn from(payload: MessagePayload) -> RelayPayload {
+        RelayPayload::Plain(payload)
+    }
+}
+
+impl From<&str> for RelayPayload {
+    fn from(payload: &str) -> RelayPayload {
+        RelayPayload::Plain(payload.into())
+    }
+}
+
+impl From<SealedPayload> for RelayPayload {
+    fn from(sealed: SealedPayload) -> RelayPayload {
+        RelayPayload::Sealed(sealed)
+    }
+}
+
+/// A payload sealed by its sender for a single recipient
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SealedPayload {
+    // hex encoded nonce the payload was encrypted with
+    pub nonce: String,
+
+    // hex encoded ciphertext of the payload, with its authentication tag
+    pub ciphertext: String,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SignedEncryptionKey {
+    pub key: EncryptionKey,
+
+    // signature over the encryption key, made with the identity key of the peer
+    pub signature: String,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RosterEntry {
+    pub identity: IdentityKey,
+
+    pub encryption_key: SignedEncryptionKey,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ServerResponse {
-    // Register response containing peer number
-    Register(PeerIdentifier),
+    // Register response containing peer number, and the roster of the session
+    Register(PeerIdentifier, Roster),
 
     // Error message
-    ErrorResponse(String),
+    ErrorResponse(RelayError),
 
     // No response
     NoResponse,
 }
 
+/// The message an abort blames a peer for
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AbortEvidence {
+    pub round: u32,
+    pub message: RelayMessage,
+}
+
 #[derive(Default, Clone, Debug, Deserialize, Serialize)]
 pub struct AbortMessage {
+    // the peer that aborted the session, 0 if the server aborted it
     pub peer_number: PeerIdentifier,
     pub protocol_id: ProtocolIdentifier,
+    #[serde(default)]
+    pub reason: AbortReason,
+    // the peer blamed for the abort, if any
+    #[serde(default)]
+    pub accused: Option<PeerIdentifier>,
+    #[serde(default)]
+    pub evidence: Option<AbortEvidence>,
+    // the peers that did not send their message before the deadline of the round
+    #[serde(default)]
+    pub stalled_peers: Vec<PeerIdentifier>,
 }
 
 impl AbortMessage {
@@ -67,19 +200,96 @@ impl AbortMessage {
         AbortMessage {
             peer_number,
             protocol_id,
+            ..Default::default()
+        }
+    }
+
+    /// An abort of the given peer, blaming another peer (or itself) for the given reason
+    pub fn blame(
+        peer_number: PeerIdentifier,
+        protocol_id: ProtocolIdentifier,
+        reason: AbortReason,
+        accused: Option<PeerIdentifier>,
+        evidence: Option<AbortEvidence>,
+    ) -> AbortMessage {
+        AbortMessage {
+            peer_number,
+            protocol_id,
+            reason,
+            accused,
+            evidence,
+            stalled_peers: Vec::new(),
+        }
+    }
+
+    /// An abort of the server, after the given peers missed the deadline to send their message
+    pub fn timeout(
+        protocol_id: ProtocolIdentifier,
+        stalled_peers: Vec<PeerIdentifier>,
+    ) -> AbortMessage {
+        AbortMessage {
+            peer_number: 0,
+            protocol_id,
+            reason: AbortReason::Timeout,
+            accused: None,
+            evidence: None,
+            stalled_peers,
         }
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RegisterMessage {
-    pub addr: SocketAddr,
+    pub identity: IdentityKey,
 
     pub protocol_id: ProtocolIdentifier,
 
     pub capacity: u32,
 
     pub index: i32,
+
+    // signature over the registration challenge of the identity
+    pub signature: String,
+
+    pub encryption_key: SignedEncryptionKey,
+}
+
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct MessageSignature {
+    pub session_id: SessionIdentifier,
+
+    pub round: u32,
+
+    pub sender: IdentityKey,
+
+    // signature over the session id, round, sender and the signed client message
+    pub signature: String,
+}
+
+/// The head of the hash chain over the messages the server relayed in a session
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ChainHead {
+    pub session_id: SessionIdentifier,
+
+    // number of messages in the chain
+    pub length: u64,
+
+    // hex encoded hash of the chain before its last message
+    pub prev: String,
+
+    // hex encoded hash of the chain
+    pub hash: String,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SignedChainHead {
+    pub head: ChainHead,
+
+    // identity key of the server that signed the head
+    pub server: IdentityKey,
+
+    // signature over the head, made with the identity key of the server
+    pub signature: String,
 }
 
 #[derive(Debug, PartialEq)]
@@ -100,6 +310,10 @@ pub struct ServerMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relay_message: Option<RelayMessage>,
+
+    // head of the hash chain of the session, signed by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_head: Option<SignedChainHead>,
 }
 
 #[derive(Default, Clone, Debug, Deserialize, Serialize)]
@@ -121,6 +335,8 @@ impl ServerMessage {
             abort: None,
 
             relay_message: None,
+
+            chain_head: None,
         }
     }
 
@@ -161,6 +377,19 @@ impl StoredMessages {
         }
     }
 
+    // Returns true if a message of the party is stored for the given round
+    pub fn has_message(&self, round: u32, party: u32) -> bool {
+        match self.messages.get(&round) {
+            Some(messages) => messages.contains_key(&party),
+            None => false,
+        }
+    }
+
+    // Returns the message of the party stored for the given round, if any
+    pub fn get_message(&self, round: u32, party: u32) -> Option<ClientMessage> {
+        self.messages.get(&round)?.get(&party).cloned()
+    }
+
     // Return the current number of stored messages
     pub fn get_number_messages(&self, round: u32) -> usize {
         match self.messages.get(&round) {
@@ -256,6 +485,9 @@ pub struct ClientMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relay_message: Option<RelayMessage>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<MessageSignature>,
 }
 
 impl ClientMessage {
@@ -266,21 +498,27 @@ impl ClientMessage {
             abort: None,
 
             relay_message: None,
+
+            signature: None,
         }
     }
 
     pub fn set_register(
         &mut self,
-        addr: SocketAddr,
+        identity: IdentityKey,
         protocol_id: ProtocolIdentifier,
         capacity: u32,
         index: i32,
+        signature: String,
+        encryption_key: SignedEncryptionKey,
     ) {
         self.register = Some(RegisterMessage {
-            addr,
+            identity,
             protocol_id,
             capacity,
             index,
+            signature,
+            encryption_key,
         });
     }
 
@@ -335,6 +573,7 @@ pub type ClientToServerCodec = JsonCodec<ServerMessage, ClientMessage>;
 
 #[cfg(test)]
 mod tests {
+    use super::AbortMessage;
     use super::ClientMessage;
     use super::StoredMessages;
 
@@ -356,6 +595,27 @@ mod tests {
         assert_eq!(stored_messages.get_number_messages(3), 0);
     }
 
+    #[test]
+    fn test_has_message() {
+        let mut stored_messages = StoredMessages::new();
+        let round = 1;
+        stored_messages.update(round, 3, ClientMessage::new());
+        assert!(stored_messages.has_message(round, 3));
+        assert!(!stored_messages.has_message(round, 2));
+        assert!(!stored_messages.has_message(round + 1, 3));
+    }
+
+    #[test]
+    fn test_get_message() {
+        let mut stored_messages = StoredMessages::new();
+        let mut msg = ClientMessage::new();
+        msg.abort = Some(AbortMessage::new(3, 1));
+        stored_messages.update(1, 3, msg);
+        assert!(stored_messages.get_message(1, 3).unwrap().abort.is_some());
+        assert!(stored_messages.get_message(1, 2).is_none());
+        assert!(stored_messages.get_message(2, 3).is_none());
+    }
+
     #[test]
     fn test_get_missing_clients_vector() {
         let mut stored_messages = StoredMessages::new();
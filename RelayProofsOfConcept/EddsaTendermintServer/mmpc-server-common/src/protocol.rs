@@ -1,19 +1,48 @@
 /// Structures for supported protocols for relay-server
 use log::debug;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
-use crate::ProtocolIdentifier;
+use crate::{ProtocolIdentifier, RelayMessage};
 
-static PROTOCOLS_F: &str = r#"./protocols.json"#;
+// The protocols file used if no other is given
+pub static PROTOCOLS_FILE: &str = r#"./protocols.json"#;
+
+/// The messages the peers send in a round of a protocol
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundKind {
+    // every peer sends a single message, addressed to all the peers
+    Broadcast,
+
+    // every peer sends a single message, sealed for one other peer
+    PointToPoint,
+}
+
+impl RoundKind {
+    /// Returns true if the recipients of the message fit the kind of the round,
+    /// in a session of the given capacity
+    pub fn admits(&self, msg: &RelayMessage, capacity: u32) -> bool {
+        match self {
+            RoundKind::Broadcast => msg.is_addressed_to_all(capacity),
+            // the single recipient must be another peer of the session
+            RoundKind::PointToPoint => {
+                msg.is_point_to_point() && (1..=capacity).contains(&msg.to[0])
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ProtocolDescriptor {
     pub id: ProtocolIdentifier,
     pub capacity: u32,
+    // the rounds the protocol runs, if declared
+    pub schedule: Option<Vec<RoundKind>>,
     pub turn: Arc<RwLock<u32>>,
 }
 
@@ -22,6 +51,7 @@ impl ProtocolDescriptor {
         ProtocolDescriptor {
             id,
             capacity,
+            schedule: None,
             turn: Arc::new(RwLock::new(1)),
         }
     }
@@ -43,54 +73,98 @@ impl ProtocolDescriptor {
     pub fn next(&self) -> u32 {
         *self.turn.read().unwrap()
     }
-}
 
-/// Returns true if the protocol is a valid protocol as determined by the
-/// protocols.json file
-pub fn is_valid_protocol(p: &ProtocolDescriptor) -> bool {
-    let all_protocols = get_protocols();
-    match all_protocols {
-        Ok(_protocols) => {
-            for prot in _protocols.protocols {
-                debug!("Checking if fits protocol: {:?}", prot);
-                if prot.id == p.id {
-                    if prot.capacities.contains(&(p.capacity)) {
-                        return true;
-                    } else {
-                        return false;
-                    }
-                }
-            }
-            return false;
+    // Get the number of rounds the protocol runs, or None if it runs any number of rounds
+    pub fn rounds(&self) -> Option<u32> {
+        self.schedule.as_ref().map(|schedule| schedule.len() as u32)
+    }
+
+    // Returns true if a message can be sent in the round,
+    // a protocol that does not declare its number of rounds runs any number of rounds
+    pub fn is_valid_round(&self, round: u32) -> bool {
+        match self.rounds() {
+            Some(rounds) => round < rounds,
+            None => true,
         }
-        Err(_) => panic!("Corrupt protocols file"),
+    }
+
+    // Get the kind of messages sent in the round, or None if the protocol does not declare it
+    pub fn round_kind(&self, round: u32) -> Option<RoundKind> {
+        self.schedule.as_ref()?.get(round as usize).cloned()
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Protocolss {
+/// The protocols a relay supports, as declared in a protocols file
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ProtocolRegistry {
     pub protocols: Vec<Protocol>,
 }
 
-#[derive(Deserialize, Debug, Serialize)]
-struct Protocol {
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct Protocol {
     pub id: u32,
     pub capacities: Vec<u32>,
     pub names: Vec<String>,
+    // the kind of every round the protocol runs, in order
+    #[serde(default)]
+    pub rounds: Option<Vec<RoundKind>>,
 }
 
-// Reutrn all avaliable protocols
-fn get_protocols() -> Result<Protocolss, Box<dyn Error>> {
-    debug!("Getting protocols");
+/// Why a protocols file could not be loaded
+#[derive(Debug)]
+pub enum ProtocolsError {
+    // the file could not be read
+    Io(PathBuf, io::Error),
 
-    // Open the file in read-only mode with buffer.
-    let path = PROTOCOLS_F;
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+    // the file is not a valid JSON list of protocols
+    Parse(PathBuf, serde_json::Error),
+}
+
+impl fmt::Display for ProtocolsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolsError::Io(path, err) => {
+                write!(f, "unable to read protocols file {:?}: {}", path, err)
+            }
+            ProtocolsError::Parse(path, err) => {
+                write!(f, "protocols file {:?} is malformed: {}", path, err)
+            }
+        }
+    }
+}
 
-    // Read the JSON contents of the file as an instance of `Protocols`.
-    let p = serde_json::from_reader(reader)?;
+impl std::error::Error for ProtocolsError {}
 
-    //debug!("Got protocols: {:?}", p);
-    Ok(p)
+impl ProtocolRegistry {
+    /// Loads the protocols declared in the file in path
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ProtocolRegistry, ProtocolsError> {
+        let path = path.as_ref().to_path_buf();
+        debug!("Loading protocols from {:?}", path);
+        let file = File::open(&path).map_err(|err| ProtocolsError::Io(path.clone(), err))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|err| ProtocolsError::Parse(path, err))
+    }
+
+    /// Returns true if the protocol is supported with the capacity it requests
+    pub fn is_valid_protocol(&self, p: &ProtocolDescriptor) -> bool {
+        debug!("Checking if fits protocol: {:?}", p);
+        self.protocols
+            .iter()
+            .any(|prot| prot.id == p.id && prot.capacities.contains(&p.capacity))
+    }
+
+    /// Returns the number of rounds the protocol runs,
+    /// or None if the protocol does not declare it
+    pub fn rounds(&self, id: ProtocolIdentifier) -> Option<u32> {
+        self.schedule(id).map(|schedule| schedule.len() as u32)
+    }
+
+    /// Returns the kind of every round the protocol runs,
+    /// or None if the protocol does not declare it
+    pub fn schedule(&self, id: ProtocolIdentifier) -> Option<Vec<RoundKind>> {
+        self.protocols
+            .iter()
+            .find(|prot| prot.id == id)
+            .and_then(|prot| prot.rounds.clone())
+    }
 }
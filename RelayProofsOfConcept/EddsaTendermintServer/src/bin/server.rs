@@ -10,9 +10,17 @@
 //! this will run a client that utilizes the server in some way
 //!
 use clap::{App, Arg, ArgMatches};
+use log::{error, info};
+use mmpc_server::eddsa_validator::{
+    EddsaValidator, EDDSA_KEYGEN_PROTOCOL_ID, EDDSA_SIGN_PROTOCOL_ID,
+};
 use mmpc_server::RelayApp;
+use mmpc_server_common::identity::Identity;
+use mmpc_server_common::protocol::{ProtocolRegistry, PROTOCOLS_FILE};
+use mmpc_server_common::validator::PayloadValidators;
 use std::io;
 use std::net::SocketAddr;
+use std::process;
 
 fn arg_matches<'a>() -> ArgMatches<'a> {
     App::new("relay-server")
@@ -30,6 +38,20 @@ fn arg_matches<'a>() -> ArgMatches<'a> {
                 .short("P")
                 .long("participants"),
         )
+        .arg(
+            Arg::with_name("round-timeout")
+                .short("t")
+                .long("round-timeout")
+                .value_name("BLOCKS")
+                .help("Aborts the session when a round is not completed within this many blocks"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .value_name("FILE")
+                .help("Signs chain heads with the key in this file, generated if missing"),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -114,11 +136,40 @@ fn main() {
         .parse()
         .expect("Invalid number of participants");
 
+    let round_timeout: Option<u64> = matches
+        .value_of("round-timeout")
+        .map(|blocks| blocks.parse().expect("Invalid round timeout in blocks"));
+
+    let identity = match matches.value_of("key") {
+        Some(path) => Identity::load_or_generate(path).expect("Unable to load server key"),
+        None => Identity::generate(),
+    };
+
     let port = addr.port().to_string();
 
     let verbosity: u64 = matches.occurrences_of("verbose");
 
     setup_logging(verbosity, port).expect("failed to initialize logging.");
 
-    abci::run(addr, RelayApp::new(capacity));
+    info!(
+        "Signing chain heads with server key {}",
+        identity.public_key()
+    );
+    // the protocols are loaded once, a broken protocols file is reported before any peer registers
+    let protocols = match ProtocolRegistry::load(PROTOCOLS_FILE) {
+        Ok(protocols) => protocols,
+        Err(err) => {
+            error!("Unable to start the relay server: {}", err);
+            process::exit(1);
+        }
+    };
+    // malformed payloads of multi-party EdDSA are rejected rather than committed
+    let mut validators = PayloadValidators::default();
+    validators.register(EDDSA_SIGN_PROTOCOL_ID, EddsaValidator);
+    validators.register(EDDSA_KEYGEN_PROTOCOL_ID, EddsaValidator);
+
+    abci::run(
+        addr,
+        RelayApp::new(capacity, round_timeout, identity, protocols, validators),
+    );
 }
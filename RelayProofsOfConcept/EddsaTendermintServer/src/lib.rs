@@ -1,3 +1,4 @@
+pub mod eddsa_validator;
 mod relay_app;
 mod relay_session;
 
@@ -1,161 +1,372 @@
 use crate::relay_session::RelaySession;
 use abci::{
-    RequestCheckTx, RequestDeliverTx, RequestQuery, ResponseCheckTx, ResponseDeliverTx,
-    ResponseQuery,
+    RequestBeginBlock, RequestCheckTx, RequestDeliverTx, RequestQuery, ResponseBeginBlock,
+    ResponseCheckTx, ResponseDeliverTx, ResponseQuery,
 };
 use log::{debug, info, warn};
-use mmpc_server_common::protocol::ProtocolDescriptor;
+use mmpc_server_common::common::{
+    ABORT_QUERY_PATH, CHAIN_QUERY_PATH, CHALLENGE_QUERY_PATH, ROSTER_QUERY_PATH,
+};
+use mmpc_server_common::error::RelayError;
+use mmpc_server_common::identity::{self, Identity};
+use mmpc_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry};
+use mmpc_server_common::validator::PayloadValidators;
 use mmpc_server_common::{
-    ClientMessage, ClientMessageType, MissingMessagesRequest, ServerMessage, ServerResponse,
+    ClientMessage, ClientMessageType, IdentityKey, MissingMessagesRequest, RegisterMessage,
+    RosterEntry, ServerMessage, ServerResponse,
 };
 
 const MAX_CLIENTS: usize = 12;
 
+// abci response codes of rejected transactions
+const REJECTED_CODE: u32 = 1;
+const INVALID_SIGNATURE_CODE: u32 = 2;
+
 pub struct RelayApp {
     relay_session: RelaySession,
 }
 
 impl RelayApp {
-    pub fn new(capacity: u32) -> RelayApp {
-        RelayApp {
-            relay_session: RelaySession::new(capacity),
-        }
+    /// Creates the app serving a single relay session.
+    /// If a round timeout is given, the session is aborted when a round
+    /// is not completed within that number of blocks.
+    /// The heads of the chain of the session are signed with the given identity.
+    /// Peers can register with the given protocols.
+    /// The payloads of the protocols are checked with the given validators before they are committed
+    pub fn new(
+        capacity: u32,
+        round_timeout: Option<u64>,
+        identity: Identity,
+        protocols: ProtocolRegistry,
+        validators: PayloadValidators,
+    ) -> RelayApp {
+        let mut relay_session = RelaySession::with_timeout(capacity, round_timeout);
+        relay_session.set_server_identity(identity);
+        relay_session.set_protocols(protocols);
+        relay_session.set_validators(validators);
+        RelayApp { relay_session }
     }
 }
 
-// Convert incoming tx data to the proper BigEndian size. txs.len() > 8 will return 0
-fn convert_tx(bytes: &[u8]) -> String {
-    String::from_utf8(bytes.to_vec()).expect("Found invalid UTF-8")
+// Decodes a transaction into the client message it carries.
+// Anyone can submit a transaction, so a malformed one is rejected rather than trusted
+fn decode_tx(bytes: &[u8]) -> Result<ClientMessage, RelayError> {
+    serde_json::from_slice(bytes).map_err(|_| RelayError::MalformedMessage)
+}
+
+// Returns the abci code of a transaction rejected with the given error.
+// Forged messages are distinguished from messages that are just invalid
+fn response_code(err: RelayError) -> u32 {
+    match err {
+        RelayError::InvalidSignature => INVALID_SIGNATURE_CODE,
+        _ => REJECTED_CODE,
+    }
+}
+
+// Returns the log of a transaction or query rejected with the given error
+fn error_log(err: RelayError) -> String {
+    let mut server_msg = ServerMessage::new();
+    server_msg.response = Some(ServerResponse::ErrorResponse(err));
+    serde_json::to_string(&server_msg).unwrap()
 }
 
 impl RelayApp {
-    fn can_relay(&self, client_message: &ClientMessage) -> u32 {
+    fn can_relay(&self, client_message: &ClientMessage) -> Result<(), RelayError> {
+        let msg = client_message
+            .relay_message
+            .as_ref()
+            .ok_or(RelayError::MalformedMessage)?;
+        match self.relay_session.can_relay(client_message) {
+            Ok(()) => {
+                debug!("Can relay this message");
+                Ok(())
+            }
+            Err(err) => {
+                warn!("Can not relay message from {}: {}", msg.from, err);
+                Err(err)
+            }
+        }
+    }
+
+    fn can_register(&self, register: &RegisterMessage) -> Result<(), RelayError> {
+        info!(
+            "Got register message. protocol id requested: {}",
+            register.protocol_id
+        );
+        if let Err(err) = self.relay_session.verify_registration(register) {
+            warn!("Registration of {} rejected: {}", register.identity, err);
+            return Err(err);
+        }
+        let protocol_descriptor = ProtocolDescriptor::new(register.protocol_id, register.capacity);
+        // a peer can not take the number of another peer, or one out of the session
+        if self
+            .relay_session
+            .assign_peer_id(register.index, register.capacity)
+            .is_none()
+        {
+            warn!(
+                "Registration of {} rejected: index {} is not available",
+                register.identity, register.index
+            );
+            return Err(RelayError::CantRegister);
+        }
+        if !self
+            .relay_session
+            .can_register(&register.identity, protocol_descriptor)
+        {
+            return Err(RelayError::CantRegister);
+        }
+        Ok(())
+    }
+
+    fn is_valid(&self, client_message: &ClientMessage) -> Result<(), RelayError> {
         match client_message.msg_type() {
-            ClientMessageType::RelayMessage => {
-                let msg = client_message.clone().relay_message.unwrap();
-                let can_relay = self.relay_session.can_relay(&msg.from, &msg);
-                match can_relay {
-                    Ok(()) => debug!("Can relay this message"),
-                    _ => (),
-                }
+            ClientMessageType::Register => {
+                let register = client_message
+                    .register
+                    .as_ref()
+                    .ok_or(RelayError::MalformedMessage)?;
+                self.can_register(register)
+            }
+            ClientMessageType::RelayMessage => self.can_relay(client_message),
+            ClientMessageType::Abort => {
+                self.relay_session.can_abort(client_message).map_err(|err| {
+                    warn!("Abort rejected: {}", err);
+                    err
+                })
             }
-            _ => (),
+            // a transaction that is none of the messages a peer sends
+            _ => Err(RelayError::MalformedMessage),
         }
-        0
     }
 
-    fn is_valid(&self, client_message: &ClientMessage) -> u32 {
+    // Registers the peer of a valid register message, returns the log of the transaction
+    fn deliver_register(&mut self, register: RegisterMessage) -> Result<String, RelayError> {
+        self.can_register(&register)?;
+        let entry = RosterEntry {
+            identity: register.identity,
+            encryption_key: register.encryption_key,
+        };
+        let client_index = self
+            .relay_session
+            .register_new_peer(
+                entry,
+                register.protocol_id,
+                register.capacity,
+                register.index,
+            )
+            .ok_or(RelayError::CantRegister)?;
+        let mut server_msg = ServerMessage::new();
+        server_msg.response = Some(ServerResponse::Register(
+            client_index,
+            self.relay_session.roster(),
+        ));
+        server_msg.chain_head = Some(self.relay_session.signed_head());
+        // TODO: Currently using log and not data, data is expecting a different encoding,
+        // sigh
+        Ok(serde_json::to_string(&server_msg).unwrap())
+    }
+
+    // Stores a valid relay message, returns the log of the transaction
+    fn deliver_relay_message(
+        &mut self,
+        client_message: ClientMessage,
+    ) -> Result<String, RelayError> {
+        // messages are only stored if signed by the peer registered with the sending identity
+        self.can_relay(&client_message)?;
+        let peer_id = client_message
+            .relay_message
+            .as_ref()
+            .ok_or(RelayError::MalformedMessage)?
+            .peer_number;
+        info!("Got relay message from {}", peer_id);
+        let round = self.relay_session.round();
+        self.relay_session
+            .update_stored_messages(round, peer_id, client_message);
+        info!("Stored message of client {}", peer_id);
+
+        let response = self
+            .relay_session
+            .stored_messages()
+            .get_messages_map_client_message(round);
+        // If received a message from each party, increase round
+        self.relay_session
+            .try_increase_round(self.relay_session.protocol().capacity);
+        Ok(serde_json::to_string(&response).unwrap())
+    }
+
+    // Aborts the session with a valid abort message, returns the log of the transaction
+    fn deliver_abort(&mut self, client_message: ClientMessage) -> Result<String, RelayError> {
+        // only a registered peer can abort, and only in its own name
+        self.is_valid(&client_message)?;
+        let abort = client_message.abort.ok_or(RelayError::MalformedMessage)?;
+        let log = serde_json::to_string(&abort).unwrap();
+        self.relay_session.abort(abort);
+        Ok(log)
+    }
+
+    fn deliver(&mut self, client_message: ClientMessage) -> Result<String, RelayError> {
+        debug!("Message type is {:?}", client_message.msg_type());
         match client_message.msg_type() {
             ClientMessageType::Register => {
-                let register = client_message.clone().register.unwrap();
-                info!(
-                    "Got register message. protocol id requested: {}",
-                    register.protocol_id
-                );
-                let protocol_descriptor =
-                    ProtocolDescriptor::new(register.protocol_id, register.capacity);
-                if self
-                    .relay_session
-                    .can_register(&register.addr, protocol_descriptor)
-                {
-                    0
-                } else {
-                    1
-                }
+                let register = client_message
+                    .register
+                    .ok_or(RelayError::MalformedMessage)?;
+                self.deliver_register(register)
             }
-            ClientMessageType::RelayMessage => {
-                // TODO: Check validity of relay message here
-                0
+            ClientMessageType::RelayMessage => self.deliver_relay_message(client_message),
+            ClientMessageType::Abort => self.deliver_abort(client_message),
+            _ => Err(RelayError::MalformedMessage),
+        }
+    }
+
+    // Returns the registration challenge of the identity in the query data
+    fn query_challenge(&self, req: &RequestQuery) -> ResponseQuery {
+        let mut resp = ResponseQuery::new();
+        let identity: IdentityKey = String::from_utf8_lossy(&req.data).into_owned();
+        debug!("Query: Challenge requested for {}", identity);
+        match identity::parse_identity_key(&identity)
+            .and_then(|_| self.relay_session.challenge(&identity))
+        {
+            Ok(challenge) => {
+                resp.set_log(challenge);
+                resp.set_code(0);
+            }
+            Err(err) => {
+                resp.set_log(error_log(err));
+                resp.set_code(REJECTED_CODE);
             }
-            _ => unimplemented!("This is not yet implemented"),
         }
+        resp.set_index(-1);
+        resp.set_height(1_i64);
+        resp
+    }
+
+    // Returns the identity and encryption key of every registered peer.
+    // Peers verify the keys themselves, the relay is not trusted to hand them out
+    fn query_roster(&self) -> ResponseQuery {
+        let mut resp = ResponseQuery::new();
+        debug!("Query: Roster requested");
+        resp.set_log(serde_json::to_string(&self.relay_session.roster()).unwrap());
+        resp.set_code(0);
+        resp.set_index(-1);
+        resp.set_height(1_i64);
+        resp
+    }
+
+    // Returns the abort message of the session, or null if it was not aborted,
+    // so peers waiting for a round can stop
+    fn query_abort(&self) -> ResponseQuery {
+        let mut resp = ResponseQuery::new();
+        debug!("Query: Abort requested");
+        resp.set_log(serde_json::to_string(&self.relay_session.abort_message()).unwrap());
+        resp.set_code(0);
+        resp.set_index(-1);
+        resp.set_height(1_i64);
+        resp
+    }
+
+    // Returns the current head of the chain over the stored messages, signed by the server.
+    // Peers that got heads of the same length with different hashes can prove equivocation
+    fn query_chain(&self) -> ResponseQuery {
+        let mut resp = ResponseQuery::new();
+        debug!("Query: Chain head requested");
+        let mut server_msg = ServerMessage::new();
+        server_msg.chain_head = Some(self.relay_session.signed_head());
+        resp.set_log(serde_json::to_string(&server_msg).unwrap());
+        resp.set_code(0);
+        resp.set_index(-1);
+        resp.set_height(1_i64);
+        resp
     }
 }
 
 impl abci::Application for RelayApp {
+    fn begin_block(&mut self, req: &RequestBeginBlock) -> ResponseBeginBlock {
+        let height = req.get_header().get_height() as u64;
+        if let Some(abort) = self.relay_session.begin_block(height) {
+            warn!(
+                "Session aborted at block {}, peers {:?} did not send in time",
+                height, abort.stalled_peers
+            );
+        }
+        ResponseBeginBlock::new()
+    }
+
     fn check_tx(&mut self, req: &RequestCheckTx) -> ResponseCheckTx {
         let mut resp = ResponseCheckTx::new();
-        let c = convert_tx(req.get_tx());
-        debug!("CheckTX: Received {:?}", c);
-        let client_message: ClientMessage = serde_json::from_slice(req.get_tx()).unwrap();
-        debug!("Value is {:?}", client_message);
-        resp.set_code(self.is_valid(&client_message));
+        debug!(
+            "CheckTX: Received {:?}",
+            String::from_utf8_lossy(req.get_tx())
+        );
+        let result = decode_tx(req.get_tx()).and_then(|client_message| {
+            debug!("Value is {:?}", client_message);
+            self.is_valid(&client_message)
+        });
+        if let Err(err) = result {
+            warn!("CheckTX: Transaction rejected: {}", err);
+            resp.set_code(response_code(err));
+            resp.set_log(error_log(err));
+        }
         resp
     }
 
     fn deliver_tx(&mut self, req: &RequestDeliverTx) -> ResponseDeliverTx {
         let mut resp = ResponseDeliverTx::new();
-        let c = convert_tx(req.get_tx());
-        info!("DeliverTX: Received {:?}", c);
-        let client_message: ClientMessage = serde_json::from_slice(req.get_tx()).unwrap();
-        info!("Value is {:?} In DeliverTx", client_message);
-
-        debug!("Message type is {:?}", client_message.msg_type());
-
-        match client_message.msg_type() {
-            ClientMessageType::Register => {
-                if self.is_valid(&client_message) != 0 {
-                    resp.set_code(1);
-                    return resp;
-                }
-                let register = client_message.register.unwrap();
-                warn!(
-                    "Got register message. protocol id requested: {}",
-                    register.protocol_id
-                );
-                let client_index = self
-                    .relay_session
-                    .register_new_peer(
-                        register.addr,
-                        register.protocol_id,
-                        register.capacity,
-                        register.index,
-                    )
-                    .unwrap();
+        info!(
+            "DeliverTX: Received {:?}",
+            String::from_utf8_lossy(req.get_tx())
+        );
+        let result = decode_tx(req.get_tx()).and_then(|client_message| {
+            info!("Value is {:?} In DeliverTx", client_message);
+            self.deliver(client_message)
+        });
+        match result {
+            Ok(log) => {
                 resp.set_code(0);
-                info!("Setting data to {:?}", resp.data);
-                let mut server_msg = ServerMessage::new();
-                server_msg.response = Some(ServerResponse::Register(client_index));
-                // TODO: Currently using log and not data, data is expecting a different encoding,
-                // sigh
-                resp.set_log(serde_json::to_string(&server_msg).unwrap().to_owned());
-            }
-            ClientMessageType::RelayMessage => {
-                let relay_msg = client_message.clone().relay_message.unwrap();
-                let peer_id = relay_msg.peer_number;
-                info!("Got relay message from {}", peer_id);
-                if self.can_relay(&client_message) == 0 {
-                    debug!("I can relay this")
-                }
-                let round = self.relay_session.round();
-                self.relay_session
-                    .update_stored_messages(round, peer_id, client_message);
-                info!("Stored message of client {}", peer_id);
-
-                let response = self
-                    .relay_session
-                    .stored_messages()
-                    .get_messages_map_client_message(round);
-                resp.set_log(serde_json::to_string(&response).unwrap().to_owned());
-                debug!("Response log {:?}", resp.log);
-                self.relay_session
-                    .try_increase_round(self.relay_session.protocol().capacity);
-                // If received a message from each party, increase round
+                resp.set_log(log);
                 debug!("Response log {:?}", resp.log);
             }
-            _ => unimplemented!("This is not yet implemented"),
+            Err(err) => {
+                warn!("DeliverTX: Transaction rejected: {}", err);
+                resp.set_code(response_code(err));
+                resp.set_log(error_log(err));
+            }
         }
-
         resp
     }
 
     fn query(&mut self, req: &RequestQuery) -> ResponseQuery {
+        if req.path == CHALLENGE_QUERY_PATH {
+            return self.query_challenge(req);
+        }
+        if req.path == ROSTER_QUERY_PATH {
+            return self.query_roster();
+        }
+        if req.path == ABORT_QUERY_PATH {
+            return self.query_abort();
+        }
+        if req.path == CHAIN_QUERY_PATH {
+            return self.query_chain();
+        }
         let mut resp = ResponseQuery::new();
+        resp.set_index(-1);
+        resp.set_height(1_i64);
 
-        let missing_messages: MissingMessagesRequest = serde_json::from_slice(&req.data).unwrap();
+        let missing_messages: MissingMessagesRequest = match serde_json::from_slice(&req.data) {
+            Ok(missing_messages) => missing_messages,
+            Err(_) => {
+                warn!(
+                    "Query: Malformed request {:?}",
+                    String::from_utf8_lossy(&req.data)
+                );
+                resp.set_log(error_log(RelayError::MalformedMessage));
+                resp.set_code(REJECTED_CODE);
+                return resp;
+            }
+        };
         debug!("Query: Received {:?}", missing_messages);
 
-        // TODO: Error handle
         let requested_round = missing_messages.round;
         let mut missing_clients = missing_messages.missing_clients;
         debug!("Requested round {}", requested_round);
@@ -175,8 +386,6 @@ impl abci::Application for RelayApp {
         debug!("Response log {:?}", resp.log);
 
         resp.set_code(0);
-        resp.set_index(-1);
-        resp.set_height(1_i64);
         resp
     }
 }
@@ -1,25 +1,34 @@
 use log::{debug, info, warn};
 use std::collections::HashMap;
-use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 
-use mmpc_server_common::{ClientMessage, StoredMessages};
-use mmpc_server_common::{PeerIdentifier, ProtocolIdentifier, RelayMessage};
+use mmpc_server_common::chain::HashChain;
+use mmpc_server_common::error::RelayError;
+use mmpc_server_common::identity::{self, Identity};
+use mmpc_server_common::{AbortMessage, ClientMessage, StoredMessages};
+use mmpc_server_common::{
+    IdentityKey, PeerIdentifier, ProtocolIdentifier, RegisterMessage, Roster, RosterEntry,
+    SessionIdentifier, SignedChainHead, SignedEncryptionKey,
+};
 
-use mmpc_server_common::protocol::ProtocolDescriptor;
+use mmpc_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry};
+use mmpc_server_common::validator::PayloadValidators;
 
 #[derive(Clone, Debug)]
 pub struct Peer {
     pub peer_id: PeerIdentifier,
-    pub addr: SocketAddr,
+    pub identity: IdentityKey,
+    // the key other peers seal point to point payloads for this peer with
+    pub encryption_key: SignedEncryptionKey,
     pub registered: bool,
 }
 
 impl Peer {
-    pub fn new(addr: SocketAddr) -> Peer {
+    pub fn new(identity: IdentityKey, encryption_key: SignedEncryptionKey) -> Peer {
         Peer {
             peer_id: 0,
-            addr: addr,
+            identity,
+            encryption_key,
             registered: false,
         }
     }
@@ -32,11 +41,21 @@ pub enum RelaySessionState {
     Uninitialized,
 
     Initialized,
+
+    Aborted,
+
+    Completed,
 }
 
 #[derive(Debug, Clone)]
 pub struct RelaySession {
-    peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
+    // height of the first block the session saw, challenges and signatures are bound to it
+    session_id: Arc<RwLock<Option<SessionIdentifier>>>,
+
+    peers: Arc<RwLock<HashMap<IdentityKey, Peer>>>,
+
+    // number of times each identity registered, used to derive fresh challenges
+    challenge_nonces: Arc<RwLock<HashMap<IdentityKey, u64>>>,
 
     active_peers: Arc<RwLock<u32>>,
 
@@ -47,6 +66,27 @@ pub struct RelaySession {
     round: Arc<RwLock<u32>>,
 
     stored_messages: Arc<RwLock<StoredMessages>>,
+
+    // number of blocks the peers have to send their message in each round, no deadline if None
+    round_timeout: Option<u64>,
+
+    // height of the block the current round started at
+    round_start: Arc<RwLock<Option<u64>>>,
+
+    // the abort of the session, once a round was not completed in time
+    abort: Arc<RwLock<Option<AbortMessage>>>,
+
+    // hash chain over every message stored in the session, in the order it was delivered
+    chain: Arc<RwLock<HashChain>>,
+
+    // the identity the server signs the heads of the chain with
+    server_identity: Arc<Identity>,
+
+    // the protocols peers can register to the session with
+    protocols: Arc<ProtocolRegistry>,
+
+    // the validators of the payloads of the protocols
+    validators: Arc<PayloadValidators>,
 }
 
 impl RelaySession {
@@ -62,34 +102,50 @@ impl RelaySession {
     }
 
     /// Register a new peer to this relay session
-    /// after adding this address as a peer,
+    /// after adding this identity and its encryption key as a peer,
     /// the state might change to either Uninitialized (if this is the first peer registering)
     /// or Initialized (meaning session has reached the required # of participants)
     pub fn register_new_peer(
         &self,
-        addr: SocketAddr,
+        entry: RosterEntry,
         protocol_id: ProtocolIdentifier,
         capacity: u32,
         index: i32,
     ) -> Option<u32> {
-        let _addr = &addr;
+        let identity = entry.identity;
         let number_of_active_peers = self.get_number_of_active_peers();
 
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
         debug!("-----------------PEERS: {:?}---------------", self.peers);
-        if self.can_register(_addr, protocol_descriptor) {
-            let mut peer = Peer::new(addr);
+        let peer_id = match self.assign_peer_id(index, capacity) {
+            Some(peer_id) => peer_id,
+            None => {
+                warn!("Unable to register {:} as peer {}", identity, index);
+                return None;
+            }
+        };
+        if self.can_register(&identity, protocol_descriptor) {
+            let mut peer = Peer::new(identity.clone(), entry.encryption_key);
             peer.registered = true;
-            peer.peer_id = number_of_active_peers + 1;
+            peer.peer_id = peer_id;
 
-            self.peers.write().unwrap().insert(addr, peer);
+            self.peers.write().unwrap().insert(identity.clone(), peer);
+            // the challenge used for this registration can not be used again
+            *self
+                .challenge_nonces
+                .write()
+                .unwrap()
+                .entry(identity)
+                .or_insert(0) += 1;
 
             // activate this connection as a peer
             // if needed, set the ProtocolDescriptor for this sessuib
             // and change the state
             let state = self.state();
             if let RelaySessionState::Empty = state {
-                self.set_protocol(ProtocolDescriptor::new(protocol_id, capacity));
+                let mut protocol = ProtocolDescriptor::new(protocol_id, capacity);
+                protocol.schedule = self.protocols.schedule(protocol_id);
+                self.set_protocol(protocol);
                 info!("Relay session state is now Uninitialized");
                 self.set_state(RelaySessionState::Uninitialized);
             }
@@ -98,27 +154,69 @@ impl RelaySession {
                 info!("Relay session state is now Initialized");
                 self.set_state(RelaySessionState::Initialized);
             }
-            info!("Registered peer {}", number_of_active_peers + 1);
-            if index == -1 {
-                Some(number_of_active_peers + 1) //peer_id
-            } else {
-                Some(index as u32) //peer_id
-            }
+            info!("Registered peer {}", peer_id);
+            Some(peer_id)
         } else {
-            warn!("Unable to register {:}", addr); // error
+            warn!("Unable to register {:}", identity); // error
             None
         }
     }
 
-    /// Checks if it is possible for this address
+    /// Returns the peer number a registration with the given index gets.
+    /// A peer can request the index it had in a previous session, if it is in 1..=capacity
+    /// and no other peer holds it. Without an index, the lowest free number is assigned
+    pub fn assign_peer_id(&self, index: i32, capacity: u32) -> Option<PeerIdentifier> {
+        let peers = self.peers.read().unwrap();
+        let is_free = |peer_id: u32| {
+            !peers
+                .values()
+                .any(|peer| peer.registered && peer.peer_id == peer_id)
+        };
+        if index <= 0 {
+            return (1..=capacity).find(|peer_id| is_free(*peer_id));
+        }
+        let peer_id = index as u32;
+        if peer_id > capacity || !is_free(peer_id) {
+            return None;
+        }
+        Some(peer_id)
+    }
+
+    /// Returns the challenge the given identity has to sign in order to register.
+    /// The challenge is derived from the session state, so all nodes agree on it,
+    /// and names the session, so a registration can not be replayed in another session
+    pub fn challenge(&self, identity: &IdentityKey) -> Result<String, RelayError> {
+        let session_id = self.session_id().ok_or(RelayError::SessionNotStarted)?;
+        let nonce = self
+            .challenge_nonces
+            .read()
+            .unwrap()
+            .get(identity)
+            .cloned()
+            .unwrap_or(0);
+        Ok(format!("{}:{}:{}", session_id, identity, nonce))
+    }
+
+    /// Verifies the register message is signed by the identity it registers,
+    /// and so is the encryption key it registers with
+    pub fn verify_registration(&self, register: &RegisterMessage) -> Result<(), RelayError> {
+        identity::verify_challenge_response(
+            &register.identity,
+            &self.challenge(&register.identity)?,
+            &register.signature,
+        )?;
+        identity::verify_encryption_key(&register.identity, &register.encryption_key)
+    }
+
+    /// Checks if it is possible for this identity
     /// to register as a peer in this session
-    pub fn can_register(&self, _addr: &SocketAddr, protocol: ProtocolDescriptor) -> bool {
+    pub fn can_register(&self, identity: &IdentityKey, protocol: ProtocolDescriptor) -> bool {
         match self.state() {
             // if this is the first peer to register
             // check that the protocol is valid
             RelaySessionState::Empty => {
                 debug!("Checking if protocol description is valid");
-                if !mmpc_server_common::protocol::is_valid_protocol(&protocol) {
+                if !self.protocols.is_valid_protocol(&protocol) {
                     warn!("Protocol is invalid");
 
                     return false;
@@ -139,6 +237,11 @@ impl RelaySession {
                 return false;
             }
         }
+        // an identity can be registered only once in a session
+        if self.get_peer_by_identity(identity).is_some() {
+            warn!("Identity {:} is already registered", identity);
+            return false;
+        }
         true
     }
 }
@@ -147,9 +250,19 @@ impl RelaySession {
     /// Creates a new Relay Session with default (empty) fields
     /// and an Empty state
     pub fn new(capacity: u32) -> RelaySession {
+        RelaySession::with_timeout(capacity, None)
+    }
+
+    /// Creates a new Relay Session in which every round must be completed
+    /// within the given number of blocks, or the session is aborted
+    pub fn with_timeout(capacity: u32, round_timeout: Option<u64>) -> RelaySession {
         RelaySession {
+            session_id: Arc::new(RwLock::new(None)),
+
             peers: Arc::new(RwLock::new(HashMap::new())),
 
+            challenge_nonces: Arc::new(RwLock::new(HashMap::new())),
+
             active_peers: Arc::new(RwLock::new(0)),
 
             protocol: Arc::new(RwLock::new(
@@ -161,21 +274,211 @@ impl RelaySession {
             round: Arc::new(RwLock::new(0)),
 
             stored_messages: Arc::new(RwLock::new(StoredMessages::new())),
+
+            round_timeout,
+
+            round_start: Arc::new(RwLock::new(None)),
+
+            abort: Arc::new(RwLock::new(None)),
+
+            // the chain starts over once the session is started with its identifier
+            chain: Arc::new(RwLock::new(HashChain::new(0))),
+
+            server_identity: Arc::new(Identity::generate()),
+
+            protocols: Arc::new(ProtocolRegistry::default()),
+
+            validators: Arc::new(PayloadValidators::default()),
         }
     }
 
-    /// Check if this relay message sent from the given SocketAddr
+    /// Starts the session at the block of the given height, which identifies the session.
+    /// A session is started once, by the first block it sees
+    pub fn start(&self, height: u64) {
+        let mut session_id = self.session_id.write().unwrap();
+        if session_id.is_none() {
+            let id = height as SessionIdentifier;
+            info!("Relay session {} started", id);
+            *session_id = Some(id);
+            *self.chain.write().unwrap() = HashChain::new(id);
+        }
+    }
+
+    /// Returns the identifier of the session, None until it saw its first block
+    pub fn session_id(&self) -> Option<SessionIdentifier> {
+        *self.session_id.read().unwrap()
+    }
+
+    /// Signs the heads of the chain of the session with the given server identity
+    pub fn set_server_identity(&mut self, identity: Identity) {
+        self.server_identity = Arc::new(identity);
+    }
+
+    /// Lets peers register to the session with any of the given protocols.
+    /// A new session supports no protocols
+    pub fn set_protocols(&mut self, protocols: ProtocolRegistry) {
+        self.protocols = Arc::new(protocols);
+    }
+
+    /// Checks the payloads stored in the session with the given validators.
+    /// A new session stores the payloads of every protocol unchecked
+    pub fn set_validators(&mut self, validators: PayloadValidators) {
+        self.validators = Arc::new(validators);
+    }
+
+    /// Check if this relay message is signed by the identity it is sent from
     /// and is valid to send to rest of the peers
-    pub fn can_relay(&self, _from: &SocketAddr, msg: &RelayMessage) -> Result<(), &'static str> {
+    pub fn can_relay(&self, client_msg: &ClientMessage) -> Result<(), RelayError> {
+        let msg = client_msg
+            .relay_message
+            .as_ref()
+            .ok_or(RelayError::CantRelay)?;
+        let from = &msg.from;
         debug!("Checking if {:} can relay", msg.peer_number);
         debug!("Server state: {:?}", self.state());
         debug!("Turn of peer #: {:}", self.protocol().next());
 
-        // TODO: Add some checks of what messages can be stored
+        // messages are only relayed once every peer registered
+        match self.state() {
+            RelaySessionState::Initialized => {}
+            RelaySessionState::Aborted => return Err(RelayError::SessionAborted),
+            RelaySessionState::Completed => return Err(RelayError::SessionCompleted),
+            _ => return Err(RelayError::NotInitialized),
+        }
+        // the sender in the message (peer_number field) must be the peer registered with this identity
+        match self.get_peer_by_identity(from) {
+            Some(peer) if peer.peer_id == msg.peer_number => {}
+            _ => return Err(RelayError::NotAPeer),
+        }
+        // the message must be sent in the current round, which the protocol runs
+        if !self.protocol().is_valid_round(msg.round) {
+            return Err(RelayError::RoundOutOfRange);
+        }
+        let round = self.round();
+        if msg.round != round {
+            return Err(RelayError::WrongRound);
+        }
+        // the recipients must fit the kind of the round, if the protocol declares it
+        let protocol = self.protocol();
+        if let Some(kind) = protocol.round_kind(round) {
+            if !kind.admits(msg, protocol.capacity) {
+                return Err(RelayError::WrongRoundKind);
+            }
+        }
+        // a stored message can not be replaced by another message for the same round
+        if self
+            .stored_messages
+            .read()
+            .unwrap()
+            .has_message(round, msg.peer_number)
+        {
+            return Err(RelayError::AlreadySentInRound);
+        }
+        // the message must be signed by the sending identity, for the current round
+        let session_id = self.session_id().ok_or(RelayError::SessionNotStarted)?;
+        identity::verify_message_signature(client_msg, session_id, round, from)?;
+        // the relay must only ever see the ciphertext of point to point payloads
+        if msg.is_point_to_point() && !msg.message.is_sealed() {
+            return Err(RelayError::UnsealedMessage);
+        }
+        // the payload must be one the protocol sends in the round
+        self.validators.validate(protocol.id, msg)?;
 
         return Ok(());
     }
 
+    /// Called at the beginning of every block with its height.
+    /// The first block starts the session.
+    /// The deadline of a round starts at the first block after the round begins,
+    /// if the round is not completed within the timeout the session is aborted.
+    /// Returns the abort message naming the peers that did not send, once the session is aborted
+    pub fn begin_block(&self, height: u64) -> Option<AbortMessage> {
+        self.start(height);
+        let round_timeout = self.round_timeout?;
+        if self.state() != RelaySessionState::Initialized {
+            return None;
+        }
+        let round_start = *self.round_start.write().unwrap().get_or_insert(height);
+        if height < round_start + round_timeout {
+            return None;
+        }
+        let stalled_peers = self
+            .stored_messages
+            .read()
+            .unwrap()
+            .get_missing_clients_vector(self.round(), self.protocol().capacity);
+        warn!(
+            "Round {} not completed by block {}, waiting for peers {:?}",
+            self.round(),
+            height,
+            stalled_peers
+        );
+        let abort = AbortMessage::timeout(self.protocol().id, stalled_peers);
+        self.abort(abort.clone());
+        Some(abort)
+    }
+
+    /// Check if this abort message is signed by the peer it is sent from,
+    /// while the session is running.
+    /// A peer can abort in any round, the abort is signed for the round the peer is in
+    pub fn can_abort(&self, client_msg: &ClientMessage) -> Result<(), RelayError> {
+        let abort = client_msg.abort.as_ref().ok_or(RelayError::CantRelay)?;
+        match self.state() {
+            RelaySessionState::Initialized => {}
+            RelaySessionState::Aborted => return Err(RelayError::SessionAborted),
+            _ => return Err(RelayError::NotInitialized),
+        }
+        let envelope = client_msg.signature.as_ref().ok_or(RelayError::NotAPeer)?;
+        match self.get_peer_by_identity(&envelope.sender) {
+            Some(peer) if peer.peer_id == abort.peer_number => {}
+            _ => return Err(RelayError::NotAPeer),
+        }
+        let session_id = self.session_id().ok_or(RelayError::SessionNotStarted)?;
+        identity::verify_message_signature(client_msg, session_id, envelope.round, &envelope.sender)
+    }
+
+    /// Abort the session. Peers learn of the abort, and who is blamed for it, by querying it
+    pub fn abort(&self, abort: AbortMessage) {
+        warn!(
+            "Session aborted by peer {}: {:?}, accused {:?}",
+            abort.peer_number, abort.reason, abort.accused
+        );
+        *self.abort.write().unwrap() = Some(abort);
+        self.set_state(RelaySessionState::Aborted);
+    }
+
+    /// Returns the abort message of the session, if it was aborted
+    pub fn abort_message(&self) -> Option<AbortMessage> {
+        self.abort.read().unwrap().clone()
+    }
+
+    /// Returns the registered peer with the given identity
+    pub fn get_peer_by_identity(&self, identity: &IdentityKey) -> Option<Peer> {
+        self.peers
+            .read()
+            .unwrap()
+            .get(identity)
+            .filter(|peer| peer.registered)
+            .cloned()
+    }
+
+    /// Returns the identity and encryption key of every registered peer
+    pub fn roster(&self) -> Roster {
+        self.peers
+            .read()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.registered)
+            .map(|peer| {
+                let entry = RosterEntry {
+                    identity: peer.identity.clone(),
+                    encryption_key: peer.encryption_key.clone(),
+                };
+                (peer.peer_id, entry)
+            })
+            .collect()
+    }
+
     // Return the current state of the relay session
     pub fn state(&self) -> RelaySessionState {
         self.state.read().unwrap().clone()
@@ -198,7 +501,11 @@ impl RelaySession {
         self.round.read().unwrap().clone()
     }
 
+    /// Stores the relay message of the party in the round, and appends it to the chain
     pub fn update_stored_messages(&mut self, round: u32, party: u32, msg: ClientMessage) {
+        if let Some(relay_message) = &msg.relay_message {
+            self.chain.write().unwrap().append(relay_message);
+        }
         self.stored_messages
             .write()
             .unwrap()
@@ -209,6 +516,14 @@ impl RelaySession {
         self.stored_messages.read().unwrap().clone()
     }
 
+    /// Returns the current head of the chain, signed by the server
+    pub fn signed_head(&self) -> SignedChainHead {
+        let head = self.chain.read().unwrap().head();
+        self.server_identity.sign_chain_head(head)
+    }
+
+    /// Starts the next round once every peer stored its message for the current one.
+    /// Once the last round the protocol declares is completed, the session is Completed
     pub fn try_increase_round(&self, capacity: u32) {
         if self
             .stored_messages
@@ -218,6 +533,14 @@ impl RelaySession {
             == capacity as usize
         {
             *self.round.write().unwrap() += 1;
+            // the deadline of the next round starts with the next block
+            *self.round_start.write().unwrap() = None;
+            if let Some(rounds) = self.protocol().rounds() {
+                if self.round() >= rounds {
+                    info!("Last round of the protocol is completed, session is completed");
+                    self.set_state(RelaySessionState::Completed);
+                }
+            }
         }
     }
 }
@@ -227,21 +550,45 @@ mod tests {
     use super::RelaySession;
     use super::RelaySessionState;
 
-    use mmpc_server_common::protocol::ProtocolDescriptor;
-    use mmpc_server_common::ProtocolIdentifier;
-
-    use std::net::SocketAddr;
+    use mmpc_server_common::chain::verify_message_head;
+    use mmpc_server_common::encryption::EncryptionKeypair;
+    use mmpc_server_common::error::RelayError;
+    use mmpc_server_common::identity::{verify_chain_head, Identity};
+    use mmpc_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry, PROTOCOLS_FILE};
+    use mmpc_server_common::validator::{PayloadValidator, PayloadValidators};
+    use mmpc_server_common::{
+        AbortMessage, AbortReason, ClientMessage, IdentityKey, MessagePayload, PeerIdentifier,
+        ProtocolIdentifier, RelayMessage, RosterEntry, SessionIdentifier, SignedEncryptionKey,
+    };
     use std::sync::Arc;
     use std::thread;
 
+    // the sessions of the tests are started by the block of this height
+    const SESSION_ID: SessionIdentifier = 7;
+
+    // A session started by the block of SESSION_ID, with the protocols of the protocols file
+    fn started_session(capacity: u32) -> RelaySession {
+        let mut rs = RelaySession::new(capacity);
+        rs.set_protocols(ProtocolRegistry::load(PROTOCOLS_FILE).unwrap());
+        rs.start(SESSION_ID as u64);
+        rs
+    }
+
+    fn roster_entry(identity: IdentityKey) -> RosterEntry {
+        RosterEntry {
+            identity,
+            encryption_key: SignedEncryptionKey::default(),
+        }
+    }
+
     #[test]
     fn test_add_peer() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 1;
-        let rs = RelaySession::new(capacity);
-        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 0).parse().unwrap();
+        let rs = started_session(capacity);
+        let identity: IdentityKey = format!("identity-808{}", 0);
 
-        let peer_num = rs.register_new_peer(client_addr, protocol_id, capacity, 0);
+        let peer_num = rs.register_new_peer(roster_entry(identity), protocol_id, capacity, 0);
         assert_eq!(peer_num, Some(1));
     }
 
@@ -249,13 +596,13 @@ mod tests {
     fn test_add_multi_peers() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 5;
-        let rs = RelaySession::new(capacity);
+        let rs = started_session(capacity);
 
         let mut peer_num: u32 = 0;
         for i in 0..capacity {
-            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
+            let identity: IdentityKey = format!("identity-808{}", i);
             peer_num = rs
-                .register_new_peer(client_addr, protocol_id, capacity, 0)
+                .register_new_peer(roster_entry(identity), protocol_id, capacity, 0)
                 .expect("Unable to register");
             println!("Peer number is {}", peer_num);
         }
@@ -263,21 +610,41 @@ mod tests {
         assert_eq!(peer_num, capacity);
     }
 
+    #[test]
+    fn test_add_peer_with_index() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let rs = started_session(capacity);
+        let register = |i: u32, index: i32| {
+            let identity: IdentityKey = format!("identity-808{}", i);
+            rs.register_new_peer(roster_entry(identity), protocol_id, capacity, index)
+        };
+
+        assert_eq!(register(0, 2), Some(2));
+        // the index of another peer, or one out of the session, can not be taken
+        assert_eq!(register(1, 2), None);
+        assert_eq!(register(1, 4), None);
+        // peers without an index get the numbers that are left
+        assert_eq!(register(1, 0), Some(1));
+        assert_eq!(register(2, -1), Some(3));
+        assert_eq!(rs.state(), RelaySessionState::Initialized);
+    }
+
     #[test]
     fn test_add_multi_peers_in_parallel() {
         let mut children = vec![];
 
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 50;
-        let rs = Arc::new(RelaySession::new(capacity));
+        let rs = Arc::new(started_session(capacity));
 
         for i in 0..capacity {
             let rs_inner = Arc::clone(&rs);
 
-            let client_addr: SocketAddr = format!("127.0.0.1:80{}", 30 + i).parse().unwrap();
+            let identity: IdentityKey = format!("identity-80{}", 30 + i);
             children.push(thread::spawn(move || {
                 rs_inner
-                    .register_new_peer(client_addr, protocol_id, capacity, -1)
+                    .register_new_peer(roster_entry(identity), protocol_id, capacity, -1)
                     .expect("Unable to register");
             }));
         }
@@ -292,22 +659,549 @@ mod tests {
 
     #[test]
     fn test_can_register_protocol_valid() {
-        let client_addr: SocketAddr = "127.0.0.1:8081".to_string().parse().unwrap();
+        let identity: IdentityKey = String::from("identity-8081");
         let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
-        assert!(rs.can_register(&client_addr, protocol_descriptor))
+        let rs = started_session(capacity);
+        assert!(rs.can_register(&identity, protocol_descriptor))
     }
 
     #[test]
     fn test_can_register_protocol_invalid() {
-        let client_addr: SocketAddr = "127.0.0.1:8081".to_string().parse().unwrap();
+        let identity: IdentityKey = String::from("identity-8081");
         let protocol_id: ProtocolIdentifier = 100 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
-        assert!(!rs.can_register(&client_addr, protocol_descriptor))
+        let rs = started_session(capacity);
+        assert!(!rs.can_register(&identity, protocol_descriptor))
+    }
+
+    #[test]
+    fn test_can_register_identity_in_use() {
+        let identity: IdentityKey = String::from("identity-8081");
+        let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
+        let capacity: u32 = 5;
+        let rs = started_session(capacity);
+        rs.register_new_peer(roster_entry(identity.clone()), protocol_id, capacity, -1);
+        let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
+        assert!(!rs.can_register(&identity, protocol_descriptor))
+    }
+
+    /////////////////////////// test challenge ///////////////////////////////////
+    #[test]
+    fn test_verify_registration() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let rs = started_session(capacity);
+        let peer_identity = Identity::generate();
+        let identity = peer_identity.public_key();
+
+        let mut msg = ClientMessage::new();
+        let signature = peer_identity.sign_challenge(&rs.challenge(&identity).unwrap());
+        let encryption_key = peer_identity.sign_encryption_key(&EncryptionKeypair::generate());
+        msg.set_register(
+            identity.clone(),
+            protocol_id,
+            capacity,
+            -1,
+            signature,
+            encryption_key,
+        );
+        let register = msg.register.unwrap();
+        assert_eq!(Ok(()), rs.verify_registration(&register));
+
+        // a registration with an encryption key not signed by the identity is rejected
+        let mut forged_key = register.clone();
+        forged_key.encryption_key =
+            Identity::generate().sign_encryption_key(&EncryptionKeypair::generate());
+        assert_eq!(
+            Err(RelayError::InvalidEncryptionKey),
+            rs.verify_registration(&forged_key)
+        );
+
+        // a registration signed by another identity is rejected
+        let mut forged = register.clone();
+        forged.signature = Identity::generate().sign_challenge(&rs.challenge(&identity).unwrap());
+        assert_eq!(
+            Err(RelayError::ChallengeFailed),
+            rs.verify_registration(&forged)
+        );
+
+        // once registered, the same signature can not be replayed
+        rs.register_new_peer(roster_entry(identity), protocol_id, capacity, -1);
+        assert_eq!(
+            Err(RelayError::ChallengeFailed),
+            rs.verify_registration(&register)
+        );
+    }
+
+    #[test]
+    fn test_challenge_names_session() {
+        let identity: IdentityKey = String::from("identity-8081");
+        let rs = RelaySession::new(2);
+        // no challenge is issued before the session saw its first block
+        assert_eq!(Err(RelayError::SessionNotStarted), rs.challenge(&identity));
+        rs.begin_block(12);
+        assert_eq!(rs.session_id(), Some(12));
+        let challenge = rs.challenge(&identity).unwrap();
+        assert!(challenge.starts_with("12:"));
+
+        // a later block does not restart the session
+        rs.begin_block(13);
+        assert_eq!(rs.session_id(), Some(12));
+        assert_eq!(rs.signed_head().head.session_id, 12);
+
+        // the same identity gets another challenge in a session started by another block
+        let other = RelaySession::new(2);
+        other.begin_block(20);
+        assert_ne!(other.challenge(&identity).unwrap(), challenge);
+    }
+
+    /////////////////////////// test can_relay ///////////////////////////////////
+    fn prepare_relay_message(
+        identity: &Identity,
+        peer_id: PeerIdentifier,
+        protocol_id: ProtocolIdentifier,
+    ) -> ClientMessage {
+        prepare_round_message(identity, peer_id, protocol_id, 0)
+    }
+
+    fn prepare_round_message(
+        identity: &Identity,
+        peer_id: PeerIdentifier,
+        protocol_id: ProtocolIdentifier,
+        round: u32,
+    ) -> ClientMessage {
+        let mut client_message = ClientMessage::new();
+        let mut relay_message = RelayMessage::new(peer_id, protocol_id, identity.public_key());
+        relay_message.set_message_params(round, vec![1, 2], "test");
+        client_message.relay_message = Some(relay_message);
+        identity.sign_message(&mut client_message, SESSION_ID, round);
+        client_message
+    }
+
+    #[test]
+    fn test_can_relay_identity() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let rs = started_session(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        rs.register_new_peer(
+            roster_entry(identities[0].public_key()),
+            protocol_id,
+            capacity,
+            -1,
+        );
+        let identity = &identities[0];
+        let peer_id = rs
+            .get_peer_by_identity(&identity.public_key())
+            .unwrap()
+            .peer_id;
+        let msg = prepare_relay_message(identity, peer_id, protocol_id);
+        // nothing is relayed before every peer registered
+        assert_eq!(Err(RelayError::NotInitialized), rs.can_relay(&msg));
+
+        rs.register_new_peer(
+            roster_entry(identities[1].public_key()),
+            protocol_id,
+            capacity,
+            -1,
+        );
+        assert_eq!(Ok(()), rs.can_relay(&msg));
+
+        // a message can not claim the peer number of another identity
+        let other = prepare_relay_message(&identities[1], peer_id, protocol_id);
+        assert_eq!(Err(RelayError::NotAPeer), rs.can_relay(&other));
+        // nor be sent by an unregistered identity
+        let unknown = prepare_relay_message(&Identity::generate(), peer_id, protocol_id);
+        assert_eq!(Err(RelayError::NotAPeer), rs.can_relay(&unknown));
+    }
+
+    #[test]
+    fn test_can_relay_round() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let mut rs = started_session(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in identities.iter() {
+            rs.register_new_peer(
+                roster_entry(identity.public_key()),
+                protocol_id,
+                capacity,
+                -1,
+            );
+        }
+        let identity = &identities[0];
+        let peer_id = rs
+            .get_peer_by_identity(&identity.public_key())
+            .unwrap()
+            .peer_id;
+
+        // a message for a later round is rejected
+        let msg = prepare_round_message(identity, peer_id, protocol_id, 1);
+        assert_eq!(Err(RelayError::WrongRound), rs.can_relay(&msg));
+        // so is a message for a round the protocol does not run
+        let msg = prepare_round_message(identity, peer_id, protocol_id, 4);
+        assert_eq!(Err(RelayError::RoundOutOfRange), rs.can_relay(&msg));
+        // the rounds of the protocol are broadcast rounds
+        let mut msg = prepare_relay_message(identity, peer_id, protocol_id);
+        msg.relay_message
+            .as_mut()
+            .unwrap()
+            .set_message_params(0, vec![peer_id], "test");
+        identity.sign_message(&mut msg, SESSION_ID, 0);
+        assert_eq!(Err(RelayError::WrongRoundKind), rs.can_relay(&msg));
+
+        // once the message of the peer is stored, it can not send another in the same round
+        let msg = prepare_relay_message(identity, peer_id, protocol_id);
+        assert_eq!(Ok(()), rs.can_relay(&msg));
+        rs.update_stored_messages(0, peer_id, msg.clone());
+        assert_eq!(Err(RelayError::AlreadySentInRound), rs.can_relay(&msg));
+    }
+
+    #[test]
+    fn test_complete_after_last_round() {
+        // multi party EdDSA runs four rounds
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let mut rs = started_session(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in identities.iter() {
+            rs.register_new_peer(
+                roster_entry(identity.public_key()),
+                protocol_id,
+                capacity,
+                -1,
+            );
+        }
+        for round in 0..4 {
+            assert_eq!(RelaySessionState::Initialized, rs.state());
+            for identity in identities.iter() {
+                let peer_id = rs
+                    .get_peer_by_identity(&identity.public_key())
+                    .unwrap()
+                    .peer_id;
+                let msg = prepare_round_message(identity, peer_id, protocol_id, round);
+                assert_eq!(Ok(()), rs.can_relay(&msg));
+                rs.update_stored_messages(round, peer_id, msg);
+            }
+            rs.try_increase_round(capacity);
+        }
+        // the session is completed once the last round is stored
+        assert_eq!(RelaySessionState::Completed, rs.state());
+        let msg = prepare_round_message(&identities[0], 1, protocol_id, 4);
+        assert_eq!(Err(RelayError::SessionCompleted), rs.can_relay(&msg));
+    }
+
+    #[test]
+    fn test_chain_head() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let server = Identity::generate();
+        let server_key = server.public_key();
+        let mut rs = started_session(capacity);
+        rs.set_server_identity(server);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in identities.iter() {
+            rs.register_new_peer(
+                roster_entry(identity.public_key()),
+                protocol_id,
+                capacity,
+                -1,
+            );
+        }
+        let empty = rs.signed_head();
+        assert_eq!(empty.head.length, 0);
+        assert_eq!(Ok(()), verify_chain_head(&empty, &server_key));
+
+        // every stored message extends the chain, and the head commits to it
+        let mut last = empty;
+        for identity in identities.iter() {
+            let peer_id = rs
+                .get_peer_by_identity(&identity.public_key())
+                .unwrap()
+                .peer_id;
+            let msg = prepare_relay_message(identity, peer_id, protocol_id);
+            rs.update_stored_messages(0, peer_id, msg.clone());
+            let head = rs.signed_head();
+            assert_eq!(head.head.length, last.head.length + 1);
+            assert_eq!(head.head.prev, last.head.hash);
+            assert_eq!(
+                Ok(()),
+                verify_message_head(&head, &server_key, &msg.relay_message.unwrap())
+            );
+            last = head;
+        }
+        // a head is only valid under the key of the server
+        assert_eq!(
+            Err(RelayError::InvalidChainHead),
+            verify_chain_head(&last, &identities[0].public_key())
+        );
+    }
+
+    #[test]
+    fn test_begin_block_round_timeout() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let round_timeout: u64 = 5;
+        let mut rs = RelaySession::with_timeout(capacity, Some(round_timeout));
+        rs.set_protocols(ProtocolRegistry::load(PROTOCOLS_FILE).unwrap());
+        rs.start(SESSION_ID as u64);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        // the deadline does not run before the session is initialized
+        assert!(rs.begin_block(1).is_none());
+        for identity in identities.iter() {
+            rs.register_new_peer(
+                roster_entry(identity.public_key()),
+                protocol_id,
+                capacity,
+                -1,
+            );
+        }
+
+        // all the peers send in time, the deadline of the next round starts with the next block
+        assert!(rs.begin_block(10).is_none());
+        for (i, identity) in identities.iter().enumerate() {
+            let peer_id = i as u32 + 1;
+            let msg = prepare_round_message(identity, peer_id, protocol_id, 0);
+            rs.update_stored_messages(0, peer_id, msg);
+        }
+        rs.try_increase_round(capacity);
+        assert_eq!(rs.round(), 1);
+        assert!(rs.begin_block(14).is_none());
+
+        // only the second peer sends in the next round
+        let msg = prepare_round_message(&identities[1], 2, protocol_id, 1);
+        rs.update_stored_messages(1, 2, msg);
+        assert!(rs.begin_block(18).is_none());
+        let abort = rs.begin_block(19).expect("Session was not aborted");
+        assert_eq!(abort.peer_number, 0);
+        assert_eq!(abort.reason, AbortReason::Timeout);
+        assert_eq!(abort.stalled_peers, vec![1, 3]);
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+        assert_eq!(rs.abort_message().unwrap().stalled_peers, vec![1, 3]);
+
+        // nothing is relayed once the session is aborted
+        let msg = prepare_round_message(&identities[0], 1, protocol_id, 1);
+        assert_eq!(Err(RelayError::SessionAborted), rs.can_relay(&msg));
+        assert!(rs.begin_block(20).is_none());
+    }
+
+    #[test]
+    fn test_begin_block_without_timeout() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 1;
+        let rs = started_session(capacity);
+        rs.register_new_peer(
+            roster_entry(Identity::generate().public_key()),
+            protocol_id,
+            capacity,
+            -1,
+        );
+        assert!(rs.begin_block(1).is_none());
+        assert!(rs.begin_block(1000).is_none());
+        assert_eq!(RelaySessionState::Initialized, rs.state());
+    }
+
+    #[test]
+    fn test_can_abort() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let rs = started_session(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let blame = |identity: &Identity, peer_id: PeerIdentifier| {
+            let mut msg = ClientMessage::new();
+            msg.abort = Some(AbortMessage::blame(
+                peer_id,
+                protocol_id,
+                AbortReason::InvalidCommitment,
+                Some(3 - peer_id),
+                None,
+            ));
+            identity.sign_message(&mut msg, SESSION_ID, 0);
+            msg
+        };
+        rs.register_new_peer(
+            roster_entry(identities[0].public_key()),
+            protocol_id,
+            capacity,
+            -1,
+        );
+        // a session can not be aborted before it starts
+        assert_eq!(
+            Err(RelayError::NotInitialized),
+            rs.can_abort(&blame(&identities[0], 1))
+        );
+        rs.register_new_peer(
+            roster_entry(identities[1].public_key()),
+            protocol_id,
+            capacity,
+            -1,
+        );
+
+        // a peer can only abort in its own name
+        assert_eq!(
+            Err(RelayError::NotAPeer),
+            rs.can_abort(&blame(&identities[0], 2))
+        );
+        assert_eq!(
+            Err(RelayError::NotAPeer),
+            rs.can_abort(&blame(&Identity::generate(), 1))
+        );
+        let mut forged = blame(&identities[0], 1);
+        forged.abort.as_mut().unwrap().accused = Some(1);
+        assert_eq!(Err(RelayError::InvalidSignature), rs.can_abort(&forged));
+
+        let msg = blame(&identities[0], 1);
+        assert_eq!(Ok(()), rs.can_abort(&msg));
+        rs.abort(msg.abort.clone().unwrap());
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+        let abort = rs.abort_message().unwrap();
+        assert_eq!(abort.reason, AbortReason::InvalidCommitment);
+        assert_eq!(abort.accused, Some(2));
+        assert_eq!(Err(RelayError::SessionAborted), rs.can_abort(&msg));
+    }
+
+    #[test]
+    fn test_can_relay_forged_signature() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let rs = started_session(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in identities.iter() {
+            rs.register_new_peer(
+                roster_entry(identity.public_key()),
+                protocol_id,
+                capacity,
+                -1,
+            );
+        }
+        let victim = identities[0].public_key();
+        let peer_id = rs.get_peer_by_identity(&victim).unwrap().peer_id;
+
+        // a message claiming to be from another identity, signed by the forger
+        let mut forged = prepare_relay_message(&identities[1], peer_id, protocol_id);
+        forged.relay_message.as_mut().unwrap().from = victim.clone();
+        identities[1].sign_message(&mut forged, SESSION_ID, 0);
+        assert_eq!(Err(RelayError::InvalidSignature), rs.can_relay(&forged));
+
+        // a message whose payload was changed after it was signed
+        let mut tampered = prepare_relay_message(&identities[0], peer_id, protocol_id);
+        tampered.relay_message.as_mut().unwrap().message = "forged".into();
+        assert_eq!(Err(RelayError::InvalidSignature), rs.can_relay(&tampered));
+
+        // a message signed for another round
+        let mut replayed = prepare_relay_message(&identities[0], peer_id, protocol_id);
+        identities[0].sign_message(&mut replayed, SESSION_ID, 1);
+        assert_eq!(Err(RelayError::InvalidSignature), rs.can_relay(&replayed));
+
+        // a message signed for another session
+        let mut replayed = prepare_relay_message(&identities[0], peer_id, protocol_id);
+        identities[0].sign_message(&mut replayed, SESSION_ID + 1, 0);
+        assert_eq!(Err(RelayError::InvalidSignature), rs.can_relay(&replayed));
+
+        // an unsigned message
+        replayed.signature = None;
+        assert_eq!(Err(RelayError::InvalidSignature), rs.can_relay(&replayed));
+    }
+
+    // Accepts only the payloads the test messages carry
+    struct TestPayloadValidator;
+
+    impl PayloadValidator for TestPayloadValidator {
+        fn validate(
+            &self,
+            _msg: &RelayMessage,
+            payload: &MessagePayload,
+        ) -> Result<(), RelayError> {
+            if payload == "test" {
+                Ok(())
+            } else {
+                Err(RelayError::InvalidPayload)
+            }
+        }
+    }
+
+    #[test]
+    fn test_can_relay_invalid_payload() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let mut rs = started_session(capacity);
+        let mut validators = PayloadValidators::default();
+        validators.register(protocol_id, TestPayloadValidator);
+        rs.set_validators(validators);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in identities.iter() {
+            rs.register_new_peer(
+                roster_entry(identity.public_key()),
+                protocol_id,
+                capacity,
+                -1,
+            );
+        }
+        let peer_id = rs
+            .get_peer_by_identity(&identities[0].public_key())
+            .unwrap()
+            .peer_id;
+        let msg = prepare_relay_message(&identities[0], peer_id, protocol_id);
+        assert_eq!(Ok(()), rs.can_relay(&msg));
+
+        // a payload the validator rejects is not committed
+        let mut msg = prepare_relay_message(&identities[0], peer_id, protocol_id);
+        msg.relay_message
+            .as_mut()
+            .unwrap()
+            .set_message_params(0, vec![1, 2], "garbage");
+        identities[0].sign_message(&mut msg, SESSION_ID, 0);
+        assert_eq!(Err(RelayError::InvalidPayload), rs.can_relay(&msg));
+    }
+
+    #[test]
+    fn test_can_relay_unsealed_point_to_point() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let rs = started_session(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let keys: Vec<EncryptionKeypair> = (0..capacity)
+            .map(|_| EncryptionKeypair::generate())
+            .collect();
+        for (identity, key) in identities.iter().zip(keys.iter()) {
+            let entry = RosterEntry {
+                identity: identity.public_key(),
+                encryption_key: identity.sign_encryption_key(key),
+            };
+            rs.register_new_peer(entry, protocol_id, capacity, -1);
+        }
+        let peer_id = rs
+            .get_peer_by_identity(&identities[0].public_key())
+            .unwrap()
+            .peer_id;
+        let recipient_id = rs
+            .get_peer_by_identity(&identities[1].public_key())
+            .unwrap()
+            .peer_id;
+
+        // a plaintext payload addressed to a single peer is rejected
+        let mut msg = prepare_relay_message(&identities[0], peer_id, protocol_id);
+        msg.relay_message
+            .as_mut()
+            .unwrap()
+            .set_message_params(0, vec![recipient_id], "test");
+        identities[0].sign_message(&mut msg, SESSION_ID, 0);
+        assert_eq!(Err(RelayError::UnsealedMessage), rs.can_relay(&msg));
+
+        // the payload sealed for the recipient is relayed
+        let recipient = &rs.roster()[&recipient_id].encryption_key.key;
+        let sealed = keys[0]
+            .seal(recipient, peer_id, recipient_id, &"test".into())
+            .unwrap();
+        msg.relay_message
+            .as_mut()
+            .unwrap()
+            .set_message_params(0, vec![recipient_id], sealed);
+        identities[0].sign_message(&mut msg, SESSION_ID, 0);
+        assert_eq!(Ok(()), rs.can_relay(&msg));
     }
 
     /////////////////////////// test register ///////////////////////////////////
@@ -315,19 +1209,23 @@ mod tests {
     fn test_register_state() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
-        let rs = RelaySession::new(capacity);
+        let rs = started_session(capacity);
 
         // State is empty at first
         assert_eq!(RelaySessionState::Empty, rs.state());
         for i in 0..capacity - 1 {
-            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
-            rs.register_new_peer(client_addr, protocol_id, capacity, -1);
+            let identity: IdentityKey = format!("identity-808{}", i);
+            rs.register_new_peer(roster_entry(identity), protocol_id, capacity, -1);
             // State is not initialized when not all are connected
             assert_eq!(RelaySessionState::Uninitialized, rs.state());
         }
-        let client_addr: SocketAddr = format!("127.0.0.1:808{}", capacity - 1).parse().unwrap();
-        let messages = rs.register_new_peer(client_addr, protocol_id, capacity, -1);
+        let identity: IdentityKey = format!("identity-808{}", capacity - 1);
+        let messages = rs.register_new_peer(roster_entry(identity), protocol_id, capacity, -1);
         // Once all are connected, state should initialize
         assert_eq!(RelaySessionState::Initialized, rs.state());
+        // and the roster holds every peer
+        let roster = rs.roster();
+        assert_eq!(roster.len(), capacity as usize);
+        assert_eq!(roster[&1].identity, format!("identity-808{}", 0));
     }
 }
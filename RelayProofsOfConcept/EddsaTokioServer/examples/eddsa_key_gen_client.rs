@@ -6,22 +6,13 @@ use std::cell::RefCell;
 use std::env;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 
-use tokio::codec::Framed;
-use tokio_core::net::TcpStream;
-use tokio_core::reactor::Core;
-
-use futures::sync::mpsc;
-use futures::{Future, Sink, Stream};
-
 use structopt::StructOpt;
 
-use relay_server_common::{
-    ClientMessage, ClientToServerCodec, MessagePayload, PeerIdentifier, ProtocolIdentifier,
-    RelayMessage, ServerMessage, ServerMessageType, ServerResponse,
-};
+use relay_client::{Client, Peer, RelayConnection};
+use relay_server::eddsa_validator::EddsaPayload;
+use relay_server_common::{AbortReason, Blame, MessagePayload, PeerIdentifier, SessionIdentifier};
 
 use curv::elliptic::curves::ed25519::*;
 
@@ -29,10 +20,12 @@ use curv::GE;
 
 use multi_party_ed25519::protocols::aggsig::{EphemeralKey, KeyAgg, KeyPair};
 
-use relay_server_common::common::*;
+use relay_server_common::identity::Identity;
+use relay_server_common::protocol::{ProtocolRegistry, PROTOCOLS_FILE};
 
 use std::collections::HashMap;
 use std::fs;
+use std::io;
 
 // Arguments parsing
 #[derive(StructOpt, Debug)]
@@ -42,6 +35,15 @@ struct Opt {
     #[structopt(short = "P", long = "participants", default_value = "2")]
     capacity: u32,
 
+    /// Identifier of the relay session to join
+    #[structopt(short = "S", long = "session", default_value = "0")]
+    session_id: SessionIdentifier,
+
+    /// File holding the identity key of this peer, created if missing.
+    /// A new identity is generated for every run if not given
+    #[structopt(short = "i", long = "identity", parse(from_os_str))]
+    identity: Option<PathBuf>,
+
     /// Address the server listens on
     #[structopt(name = "ADDRESS")]
     address: String,
@@ -57,6 +59,8 @@ struct EddsaPeer {
     pub peer_id: RefCell<PeerIdentifier>,
     // # of participants
     pub capacity: u32,
+    // # of rounds the protocol runs, as declared in the protocols file
+    pub rounds: u32,
 
     pub current_step: u32,
     // is peer done with all calculations
@@ -79,6 +83,9 @@ struct EddsaPeer {
     pub r_accepted: bool,
     pub sig_accepted: bool,
 
+    // misbehavior found while running the protocol, the session must be aborted
+    pub blame: Option<Blame>,
+
     // messages this peer generates
     pub pk_msg: Option<MessagePayload>,
     pub commitment_msg: Option<MessagePayload>,
@@ -111,20 +118,22 @@ impl EddsaPeer {
 impl EddsaPeer {
     /// data updaters for each step
     pub fn update_data_step_0(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::PublicKey(pk) => {
+        match EddsaPayload::from_payload(&payload) {
+            Ok(EddsaPayload::PublicKey(pk)) => {
                 let peer_id = self.peer_id.clone().into_inner();
                 if from == peer_id {
                     self.pk_accepted = true;
                 }
-                let s_slice: &str = &pk[..]; // take a full slice of the string
-                let _pk = serde_json::from_str(s_slice);
                 println!("-------Got peer # {:} pk! {:?}", from, pk);
-                match _pk {
-                    Ok(_pk) => self.add_pk(from, _pk),
-                    Err(_) => panic!("Could not serialize public key"),
-                }
+                self.add_pk(from, pk);
+            }
+            // a peer sent a payload that is not its public key
+            _ => {
+                self.blame = Some(Blame {
+                    reason: AbortReason::InvalidMessage,
+                    accused: Some(from),
+                    round: 0,
+                })
             }
         }
     }
@@ -139,7 +148,15 @@ impl EddsaPeer {
     }
     pub fn is_done_step_0(&mut self) -> bool {
         if self.pks.len() == self.capacity as usize {
-            self.finalize().expect("Finalized falied");
+            if self.finalize().is_err() {
+                // the keys could not be saved, no other peer is to blame
+                self.blame = Some(Blame {
+                    reason: AbortReason::Requested,
+                    accused: None,
+                    round: 0,
+                });
+                return false;
+            }
             return true;
         }
         false
@@ -147,23 +164,7 @@ impl EddsaPeer {
 }
 
 impl EddsaPeer {
-    pub fn resolve_payload_type(message: &MessagePayload) -> MessagePayloadType {
-        let msg_payload = message.clone();
-
-        let split_msg: Vec<&str> = msg_payload.split(RELAY_MESSAGE_DELIMITER).collect();
-        let msg_prefix = split_msg[0];
-        let msg_payload = String::from(split_msg[1].clone());
-        match msg_prefix {
-            pk_prefix if pk_prefix == String::from(PK_MESSAGE_PREFIX) => {
-                return MessagePayloadType::PublicKey(msg_payload);
-            }
-            _ => panic!("Unknown relay message prefix"),
-        }
-    }
-}
-
-impl Peer for EddsaPeer {
-    fn new(capacity: u32) -> EddsaPeer {
+    pub fn new(capacity: u32, rounds: u32) -> EddsaPeer {
         EddsaPeer {
             client_key: KeyPair::create(),
             pks: HashMap::new(),
@@ -171,6 +172,7 @@ impl Peer for EddsaPeer {
             r_s: HashMap::new(),
             sigs: HashMap::new(),
             capacity,
+            rounds,
             peer_id: RefCell::new(0),
             agg_key: None,
             current_step: 0,
@@ -181,6 +183,7 @@ impl Peer for EddsaPeer {
             r_accepted: false,
             sig_accepted: false,
             is_done: false,
+            blame: None,
 
             pk_msg: None,
             commitment_msg: None,
@@ -188,14 +191,16 @@ impl Peer for EddsaPeer {
             sig_msg: None,
         }
     }
+}
+
+impl Peer for EddsaPeer {
+    type Error = io::Error;
 
     fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
         self.peer_id.replace(peer_id);
         let pk = self.client_key.public_key.clone();
 
-        let pk_s = serde_json::to_string(&pk).expect("Failed in serialization");
-
-        self.pk_msg = Some(generate_pk_message_payload(&pk_s));
+        self.pk_msg = Some(EddsaPayload::PublicKey(pk).to_payload());
         return self.pk_msg.clone();
     }
 
@@ -206,7 +211,7 @@ impl Peer for EddsaPeer {
             println!("step {:} done!", self.current_step);
             self.current_step += 1;
             match self.current_step {
-                1 => {
+                step if step == self.rounds => {
                     println!("----------\nDone.\n----------");
                     self.is_done = true;
                 }
@@ -229,18 +234,14 @@ impl Peer for EddsaPeer {
     /// in this case:
     ///     collection all signatures
     ///     and verifying the message
-    fn finalize(&mut self) -> Result<(), &'static str> {
+    fn finalize(&mut self) -> Result<(), io::Error> {
         let key = &self.client_key.clone();
         let apk = &self.aggregate_pks();
         let index = &self.peer_id;
 
         let keygen_json = serde_json::to_string(&(key, apk, index)).unwrap();
 
-        let res = fs::write(env::args().nth(2).unwrap(), keygen_json);
-        match res {
-            Ok(_) => Ok(()),
-            Err(_) => Err("Failed to verify"),
-        }
+        fs::write(env::args().nth(2).unwrap(), keygen_json)
     }
     /// check that the protocol is done
     /// and that this peer can finalize its calculations
@@ -248,6 +249,23 @@ impl Peer for EddsaPeer {
         self.is_done_step_0()
     }
 
+    /// the misbehavior this peer found, if the protocol can not continue
+    fn blame(&self) -> Option<Blame> {
+        self.blame.clone()
+    }
+
+    fn current_step(&self) -> u32 {
+        self.current_step
+    }
+
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn peer_id(&self) -> PeerIdentifier {
+        self.peer_id.clone().into_inner()
+    }
+
     /// get the next item the peer needs to send
     /// depending on the current step and the last message
     /// of the peer that was accepted by the server
@@ -260,350 +278,45 @@ impl Peer for EddsaPeer {
         None
     }
 }
-pub trait Peer {
-    fn new(capacity: u32) -> Self;
-    fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload>;
-    fn do_step(&mut self);
-    fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload);
-    fn get_next_item(&mut self) -> Option<MessagePayload>;
-    fn finalize(&mut self) -> Result<(), &'static str>;
-    fn is_done(&mut self) -> bool;
-}
-
-struct ProtocolDataManager<T: Peer> {
-    pub peer_id: RefCell<PeerIdentifier>,
-    pub capacity: u32,
-    pub current_step: u32,
-    pub data_holder: T, // will be filled when initializing, and on each new step
-    pub client_data: Option<MessagePayload>, // new data calculated by this peer at the beginning of a step (that needs to be sent to other peers)
-    pub new_client_data: bool,
-}
-
-impl<T: Peer> ProtocolDataManager<T> {
-    pub fn new(capacity: u32) -> ProtocolDataManager<T>
-    where
-        T: Peer,
-    {
-        ProtocolDataManager {
-            peer_id: RefCell::new(0),
-            current_step: 0,
-            capacity,
-            data_holder: Peer::new(capacity),
-            client_data: None,
-            new_client_data: false,
-        }
-    }
-
-    /// set manager with the initial values that a local peer holds at the beginning of
-    /// the protocol session
-    /// return: first message
-    pub fn initialize_data(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
-        self.peer_id.replace(peer_id);
-        let zero_step_data = self.data_holder.zero_step(peer_id);
-        self.client_data = zero_step_data;
-        return self.client_data.clone();
-    }
-
-    /// Get the next message this client needs to send
-    pub fn get_next_message(
-        &mut self,
-        from: PeerIdentifier,
-        payload: MessagePayload,
-    ) -> Option<MessagePayload> {
-        self.data_holder.update_data(from, payload);
-        self.data_holder.do_step();
-        self.data_holder.get_next_item()
-    }
-}
-
-struct Client<T>
-where
-    T: Peer,
-{
-    pub registered: bool,
-    pub protocol_id: ProtocolIdentifier,
-    pub data_manager: ProtocolDataManager<T>,
-    pub last_message: RefCell<ClientMessage>,
-    pub bc_dests: Vec<ProtocolIdentifier>,
-}
-
-impl<T: Peer> Client<T> {
-    pub fn new(protocol_id: ProtocolIdentifier, capacity: u32) -> Client<T>
-    where
-        T: Peer,
-    {
-        let data_m: ProtocolDataManager<T> = ProtocolDataManager::new(capacity);
-        Client {
-            registered: false,
-            protocol_id,
-            last_message: RefCell::new(ClientMessage::new()),
-            bc_dests: (1..(capacity + 1)).collect(),
-            data_manager: data_m,
-        }
-    }
-
-    // Determines the response that should be sent to the server and returns a future
-    // for sending a response, or an ok() future if no action is needed
-    pub fn respond_to_server<E: 'static>(
-        &mut self,
-        msg: ServerMessage,
-        // A sender to pass messages to be written back to the server
-        tx: mpsc::Sender<ClientMessage>,
-    ) -> Box<dyn Future<Item = (), Error = E>> {
-        let response = self.generate_client_answer(msg).unwrap();
-        println!("Returning {:?}", response);
-        if response.is_empty() {
-            Box::new(futures::future::ok(()))
-        } else {
-            Box::new(tx.clone().send(response.clone()).then(|_| Ok(())))
-        }
-    }
-
-    pub fn generate_client_answer(&mut self, msg: ServerMessage) -> Option<ClientMessage> {
-        let last_message = self.last_message.clone().into_inner();
-        let mut new_message = None;
-        let msg_type = msg.msg_type();
-        match msg_type {
-            ServerMessageType::Response => {
-                let next = self.handle_server_response(&msg);
-                match next {
-                    Ok(next_msg) => {
-                        new_message = Some(next_msg.clone());
-                    }
-                    Err(_) => {
-                        println!("Error in handle_server_response");
-                    }
-                }
-            }
-            ServerMessageType::RelayMessage => {
-                let next = self.handle_relay_message(msg.clone());
-                match next {
-                    Some(next_msg) => {
-                        //println!("next message to send is {:}", next_msg);
-                        new_message = Some(self.generate_relay_message(next_msg.clone()));
-                    }
-                    None => {
-                        println!("next item is None. Client is finished.");
-                        new_message = Some(ClientMessage::new());
-                    }
-                }
-            }
-            ServerMessageType::Abort => {
-                println!("Got abort message");
-                //Ok(MessageProcessResult::NoMessage)
-                new_message = Some(ClientMessage::new());
-            }
-            ServerMessageType::Undefined => {
-                new_message = Some(ClientMessage::new());
-                //panic!("Got undefined message: {:?}",msg);
-            }
-        };
-        if last_message.is_empty() {
-            match new_message {
-                Some(msg) => {
-                    self.last_message.replace(msg.clone());
-                    return Some(msg.clone());
-                }
-                None => return None,
-            }
-        } else {
-            let _new_message = new_message.clone().unwrap();
-            if !last_message.are_equal_payloads(&_new_message) {
-                println!("last message changed");
-                self.last_message.replace(_new_message.clone());
-            }
-            return Some(self.last_message.clone().into_inner());
-        }
-    }
-
-    pub fn generate_register_message(&mut self) -> ClientMessage {
-        let mut msg = ClientMessage::new();
-        msg.register(self.protocol_id.clone(), self.data_manager.capacity.clone());
-        msg
-    }
-}
-
-impl<T: Peer> Client<T> {
-    fn handle_relay_message(&mut self, msg: ServerMessage) -> Option<MessagePayload> {
-        // parse relay message
-        let relay_msg = msg.relay_message.unwrap();
-        let from = relay_msg.peer_number;
-        if from == self.data_manager.peer_id.clone().into_inner() {
-            println!("-------self message accepted ------\n ");
-        }
-        let payload = relay_msg.message;
-        self.data_manager.get_next_message(from, payload)
-    }
-
-    fn generate_relay_message(&self, payload: MessagePayload) -> ClientMessage {
-        let _msg = ClientMessage::new();
-        // create relay message
-        let mut relay_message = RelayMessage::new(
-            self.data_manager.peer_id.clone().into_inner(),
-            self.protocol_id.clone(),
-        );
-        let to: Vec<u32> = self.bc_dests.clone();
-
-        let mut client_message = ClientMessage::new();
-
-        relay_message.set_message_params(to, String::from(payload));
-        client_message.relay_message = Some(relay_message);
-        client_message
-    }
-
-    fn handle_register_response(&mut self, peer_id: PeerIdentifier) -> Result<ClientMessage, ()> {
-        println!("Peer identifier: {}", peer_id);
-        // Set the session parameters
-        let message = self
-            .data_manager
-            .initialize_data(peer_id)
-            .unwrap_or_else(|| panic!("failed to initialize"));
-        Ok(self.generate_relay_message(message.clone()))
-    }
-
-    fn get_last_message(&self) -> Option<ClientMessage> {
-        let last_msg = self.last_message.clone().into_inner();
-        return Some(last_msg.clone());
-    }
-
-    fn handle_error_response(&mut self, err_msg: &str) -> Result<ClientMessage, &'static str> {
-        match err_msg {
-            resp if resp == String::from(NOT_YOUR_TURN) => {
-                let last_msg = self.get_last_message();
-                match last_msg {
-                    Some(msg) => {
-                        return Ok(msg.clone());
-                    }
-                    None => {
-                        panic!("No message to resend");
-                    }
-                }
-            }
-            not_initialized_resp if not_initialized_resp == String::from(STATE_NOT_INITIALIZED) => {
-                println!("Not initialized, sending again");
-                let last_msg = self.get_last_message();
-                match last_msg {
-                    Some(_) => {
-                        // If protocol is not initialized, wait for a message from the server
-                        return Ok(ClientMessage::new());
-                    }
-                    None => {
-                        panic!("No message to resend");
-                    }
-                }
-            }
-            _ => {
-                println!("didn't handle error correctly");
-                return Err("error response handling failed");
-            }
-        }
-    }
-
-    fn handle_server_response(
-        &mut self,
-        msg: &ServerMessage,
-    ) -> Result<ClientMessage, &'static str> {
-        let server_response = msg.response.clone().unwrap();
-        match server_response {
-            ServerResponse::Register(peer_id) => {
-                let client_message = self.handle_register_response(peer_id);
-                match client_message {
-                    Ok(_msg) => {
-                        println!("sending peers first message: {:#?}", _msg);
-                        return Ok(_msg.clone());
-                    }
-                    Err(_) => {
-                        println!("error occured");
-                        return Ok(ClientMessage::new());
-                    }
-                }
-            }
-            ServerResponse::ErrorResponse(err_msg) => {
-                //  println!("got error response");
-                let err_msg_slice: &str = &err_msg[..];
-                let msg = self.handle_error_response(err_msg_slice);
-                match msg {
-                    Ok(_msg) => return Ok(_msg),
-                    Err(_) => {
-                        println!("error occured");
-                        return Ok(ClientMessage::new());
-                    }
-                }
-            }
-            ServerResponse::NoResponse => unimplemented!(),
-        }
-    }
-}
-
-pub enum MessageProcessResult {
-    Message,
-    NoMessage,
-    Abort,
-}
-
-#[derive(Debug)]
-enum MessagePayloadType {
-    /// Types of expected relay messages
-    /// for step 0 we expect PUBLIC_KEY_MESSAGE
-    /// for step 1 we expect Commitment
-    /// for step 2 we expect RMessage
-    /// for step 3 we expect Signature
-    PublicKey(String),
-    // Commitment(String),
-    // RMessage(String),
-    // Signature(String),
-}
-
-fn main() {
+#[tokio::main]
+async fn main() {
     let opt = Opt::from_args();
 
     let addr = opt.address;
 
-    let protocol_identifier_arg = 1;
+    // key generation runs the single round of its own protocol
+    let protocol_identifier_arg = 3;
     let protocol_capacity_arg = opt.capacity;
 
     let addr = addr.parse::<SocketAddr>().unwrap();
 
-    // Create the event loop and initiate the connection to the remote server
-    let mut core = Core::new().unwrap();
-    let handle = core.handle();
-    let tcp = TcpStream::connect(&addr, &handle);
-
-    let session: std::sync::Arc<std::sync::Mutex<Client<EddsaPeer>>> = Arc::new(Mutex::new(
-        Client::new(protocol_identifier_arg, protocol_capacity_arg),
-    ));
-
-    let handshake = tcp.and_then(|stream| {
-        let handshake_io = Framed::new(stream, ClientToServerCodec::new(false));
-        let mut client = session.lock().unwrap();
-        let msg = client.generate_register_message();
-        handshake_io
-            .send(msg)
-            .map(|handshake_io| handshake_io.into_inner())
-            .map_err(|e| e.into())
-    });
-
-    let client = handshake.and_then(|socket| {
-        let mut client = session.lock().unwrap();
-        let _msg = client.generate_register_message();
-
-        let (to_server, from_server) = Framed::new(socket, ClientToServerCodec::new(false)).split();
-        let (tx, rx) = mpsc::channel(0);
-        let reader = from_server.for_each(move |msg| {
-            println!("Received {:?}", msg);
-            client.respond_to_server(msg, tx.clone())
+    let identity = match opt.identity {
+        Some(path) => Identity::load_or_generate(path).expect("Unable to load identity"),
+        None => Identity::generate(),
+    };
+
+    let protocols = ProtocolRegistry::load(PROTOCOLS_FILE).unwrap_or_else(|err| panic!("{}", err));
+    // the peer runs as many steps as the protocol declares rounds
+    let rounds = protocols
+        .protocol_rounds(protocol_identifier_arg)
+        .unwrap_or_else(|| {
+            panic!(
+                "Protocol {} does not declare its rounds",
+                protocol_identifier_arg
+            )
         });
-
-        let writer = rx
-            .map_err(|()| unreachable!("rx can't fail"))
-            .fold(to_server, |to_server, msg| to_server.send(msg))
-            .map(|_| ());
-
-        reader
-            .select(writer)
-            .map(|_| println!("Closing connection"))
-            .map_err(|(err, _)| err.into())
-    });
-
-    core.run(client).unwrap();
+    let peer = EddsaPeer::new(protocol_capacity_arg, rounds);
+    let mut client = Client::new(&protocols, protocol_identifier_arg, peer)
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    // Initiate the connection to the remote server
+    let mut connection = RelayConnection::connect(addr, identity, opt.session_id)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to connect to {}: {}", addr, e));
+
+    match client.run(&mut connection).await {
+        Ok(()) => println!("Session completed"),
+        Err(err) => println!("Error: {}", err),
+    }
+    println!("Closing connection");
 }
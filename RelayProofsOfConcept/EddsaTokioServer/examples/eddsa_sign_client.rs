@@ -3,29 +3,16 @@ use rand::Rng;
 /// This client represents eddsa peer
 ///
 ///
-use std::cell::RefCell;
 use std::env;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::vec::Vec;
-use std::{thread, time};
-
-use std::sync::Arc;
-use std::sync::Mutex;
-
-use tokio::codec::Framed;
-use tokio_core::net::TcpStream;
-use tokio_core::reactor::Core;
-
-use futures::sync::mpsc;
-use futures::{Future, Sink, Stream};
 
 use structopt::StructOpt;
 
-use relay_server_common::{
-    ClientMessage, ClientToServerCodec, MessagePayload, PeerIdentifier, ProtocolIdentifier,
-    RelayMessage, ServerMessage, ServerMessageType, ServerResponse,
-};
+use relay_client::{Client, Peer, RelayConnection};
+use relay_server::eddsa_validator::EddsaPayload;
+use relay_server_common::{AbortReason, Blame, MessagePayload, PeerIdentifier, SessionIdentifier};
 
 use curv::arithmetic::traits::Converter;
 use curv::elliptic::curves::ed25519::*;
@@ -36,7 +23,8 @@ use multi_party_ed25519::protocols::aggsig::{
     test_com, verify, EphemeralKey, KeyAgg, KeyPair, SignFirstMsg, SignSecondMsg, Signature,
 };
 
-use relay_server_common::common::*;
+use relay_server_common::identity::Identity;
+use relay_server_common::protocol::{ProtocolRegistry, PROTOCOLS_FILE};
 
 use std::collections::HashMap;
 use std::fs;
@@ -49,6 +37,15 @@ struct Opt {
     #[structopt(short = "P", long = "participants", default_value = "2")]
     capacity: u32,
 
+    /// Identifier of the relay session to join
+    #[structopt(short = "S", long = "session", default_value = "0")]
+    session_id: SessionIdentifier,
+
+    /// File holding the identity key of this peer, created if missing.
+    /// A new identity is generated for every run if not given
+    #[structopt(short = "i", long = "identity", parse(from_os_str))]
+    identity: Option<PathBuf>,
+
     /// Address the server listens on
     #[structopt(name = "ADDRESS")]
     address: String,
@@ -68,6 +65,8 @@ struct EddsaPeer {
     pub peer_id: PeerIdentifier,
     // # of participants
     pub capacity: u32,
+    // # of rounds the protocol runs, as declared in the protocols file
+    pub rounds: u32,
 
     pub current_step: u32,
     // is peer done with all calculations
@@ -76,9 +75,9 @@ struct EddsaPeer {
     // eddsa data
     pub client_key: KeyPair,
     pub pks: HashMap<PeerIdentifier, Ed25519Point>,
-    pub commitments: HashMap<PeerIdentifier, String>,
-    pub r_s: HashMap<PeerIdentifier, String>,
-    pub sigs: HashMap<PeerIdentifier, String>,
+    pub commitments: HashMap<PeerIdentifier, SignFirstMsg>,
+    pub r_s: HashMap<PeerIdentifier, SignSecondMsg>,
+    pub sigs: HashMap<PeerIdentifier, Signature>,
     pub ephemeral_key: Option<EphemeralKey>,
     // message to sign
     pub message: Vec<u8>,
@@ -93,6 +92,9 @@ struct EddsaPeer {
     pub r_accepted: bool,
     pub sig_accepted: bool,
 
+    // misbehavior found while running the protocol, the session must be aborted
+    pub blame: Option<Blame>,
+
     // messages this peer generates
     pub pk_msg: Option<MessagePayload>,
     pub commitment_msg: Option<MessagePayload>,
@@ -105,23 +107,27 @@ impl EddsaPeer {
     fn add_pk(&mut self, peer_id: PeerIdentifier, pk: Ed25519Point) {
         self.pks.insert(peer_id, pk);
     }
-    fn add_commitment(&mut self, peer_id: PeerIdentifier, commitment: String) {
+    fn add_commitment(&mut self, peer_id: PeerIdentifier, commitment: SignFirstMsg) {
         self.commitments.insert(peer_id, commitment);
     }
-    fn add_r(&mut self, peer_id: PeerIdentifier, r: String) {
-        //let v = (r,blind_factor);
+    fn add_r(&mut self, peer_id: PeerIdentifier, r: SignSecondMsg) {
         self.r_s.insert(peer_id, r);
     }
-    fn add_sig(&mut self, peer_id: PeerIdentifier, sig: String) {
+    fn add_sig(&mut self, peer_id: PeerIdentifier, sig: Signature) {
         self.sigs.insert(peer_id, sig);
     }
+    // A peer sent a payload that is not the message of the round
+    fn blame_invalid_message(&mut self, from: PeerIdentifier, round: u32) {
+        self.blame = Some(Blame {
+            reason: AbortReason::InvalidMessage,
+            accused: Some(from),
+            round,
+        });
+    }
     fn compute_r_tot(&mut self) -> GE {
         #[allow(non_snake_case)]
         let mut Ri: Vec<GE> = Vec::new();
-        for (_peer_id, r) in &self.r_s {
-            let r_slice: &str = &r[..];
-            let r: SignSecondMsg =
-                serde_json::from_str(r_slice).unwrap_or_else(|_| panic!("Serialization error"));
+        for r in self.r_s.values() {
             Ri.push(r.R.clone());
         }
         let r_tot = Signature::get_R_tot(Ri);
@@ -148,7 +154,8 @@ impl EddsaPeer {
         return agg_key;
     }
 
-    fn validate_commitments(&mut self) -> bool {
+    // Returns the first peer whose R does not open the commitment it sent
+    fn validate_commitments(&mut self) -> Result<(), PeerIdentifier> {
         // iterate over all peer Rs
         println!("----------\nvalidating commitments\n----------");
         let eight: FE = ECScalar::from(&BigInt::from(8));
@@ -156,73 +163,60 @@ impl EddsaPeer {
         let r_s = &self.r_s;
         for (peer_id, r) in r_s {
             println!("peer: {:}", peer_id);
-            println!("r: {:}", r);
-            // convert the json_string to a construct
-            let _r: SignSecondMsg = serde_json::from_str(r).unwrap();
-
             // get the corresponding commitment
-            let k = peer_id.clone();
-            let cmtmnt = self
+            let commitment = self
                 .commitments
-                .get(&k)
+                .get(peer_id)
                 .expect("peer didn't send commitment");
-            println!("commitment : {:?}", cmtmnt);
-            let commitment: SignFirstMsg = serde_json::from_str(cmtmnt).unwrap();
             // if we couldn't validate the commitment - failure
             if !test_com(
-                &(_r.R * eight_inv),
-                &_r.blind_factor,
+                &(r.R * eight_inv),
+                &r.blind_factor,
                 &commitment.commitment,
             ) {
-                return false;
+                return Err(*peer_id);
             }
         }
         println!("----------\ncommitments valid\n----------");
-        true
+        Ok(())
     }
 }
 
 impl EddsaPeer {
     /// data updaters for each step
     pub fn update_data_step_0(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
         let eight: FE = ECScalar::from(&BigInt::from(8));
         let eight_inv = eight.invert();
-        match payload_type {
-            MessagePayloadType::PublicKey(pk) => {
+        match EddsaPayload::from_payload(&payload) {
+            Ok(EddsaPayload::PublicKey(pk)) => {
                 let peer_id = self.peer_id;
                 if from == peer_id {
                     self.pk_accepted = true;
                 }
-                let s_slice: &str = &pk[..]; // take a full slice of the string
-                let pk: GE = serde_json::from_str(&s_slice)
-                    .unwrap_or_else(|_| panic!("Failed to deserialize R"));
                 println!("-------Got peer # {:} pk! {:?}", from, pk * &eight_inv);
                 self.add_pk(from, pk * &eight_inv);
             }
-            _ => panic!("expected public key message"),
+            _ => self.blame_invalid_message(from, 0),
         }
     }
 
     pub fn update_data_step_1(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::Commitment(t) => {
-                println!("-------Got peer # {:} commitment! {:?}", from, t);
+        match EddsaPayload::from_payload(&payload) {
+            Ok(EddsaPayload::Commitment(t)) => {
+                println!("-------Got peer # {:} commitment!", from);
                 let peer_id = self.peer_id;
                 if from == peer_id {
                     self.commitment_accepted = true;
                 }
                 self.add_commitment(from, t);
             }
-            _ => {} //panic!("expected commitment message")
+            _ => self.blame_invalid_message(from, 1),
         }
     }
 
     pub fn update_data_step_2(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::RMessage(r) => {
+        match EddsaPayload::from_payload(&payload) {
+            Ok(EddsaPayload::RMessage(r)) => {
                 println!("-------Got peer # {:} R message!", from);
                 let peer_id = self.peer_id;
                 if from == peer_id {
@@ -230,15 +224,14 @@ impl EddsaPeer {
                 }
                 self.add_r(from, r);
             }
-            _ => {} //panic!("expected R message")
+            _ => self.blame_invalid_message(from, 2),
         }
     }
 
     pub fn update_data_step_3(&mut self, from: PeerIdentifier, payload: MessagePayload) {
         println!("updating data step 3");
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::Signature(s) => {
+        match EddsaPayload::from_payload(&payload) {
+            Ok(EddsaPayload::PartialSignature(s)) => {
                 println!("-------Got peer # {:} Signature", from);
                 let peer_id = self.peer_id;
                 if from == peer_id {
@@ -246,7 +239,7 @@ impl EddsaPeer {
                 }
                 self.add_sig(from, s);
             }
-            _ => {} //panic!("expected signature message")
+            _ => self.blame_invalid_message(from, 3),
         }
     }
 }
@@ -274,7 +267,15 @@ impl EddsaPeer {
         println!("Checking if last step is done");
 
         if self.sigs.len() == self.capacity as usize {
-            self.finalize().unwrap();
+            if self.finalize().is_err() {
+                // the signature parts do not add up to a valid signature
+                self.blame = Some(Blame {
+                    reason: AbortReason::InvalidSignature,
+                    accused: None,
+                    round: 3,
+                });
+                return false;
+            }
             return true;
         }
         false
@@ -295,17 +296,9 @@ impl EddsaPeer {
             Signature::create_ephemeral_key_and_commit(&self.client_key, &self.message[..]);
 
         self.ephemeral_key = Some(ephemeral_key);
-        // save the commitment
-        let _peer_id = self.peer_id;
-        match serde_json::to_string(&sign_first_message) {
-            Ok(json_string) => {
-                //                self.add_commitment(peer_id, json_string.clone());
-                let r = serde_json::to_string(&sign_second_message).expect("couldn't create R");
-                self.commitment_msg = Some(generate_commitment_message_payload(&json_string));
-                self.r_msg = Some(generate_R_message_payload(&r));
-            }
-            Err(_) => panic!("Couldn't serialize commitment"),
-        }
+        // the commitment is sent now, R is revealed in the next round
+        self.commitment_msg = Some(EddsaPayload::Commitment(sign_first_message).to_payload());
+        self.r_msg = Some(EddsaPayload::RMessage(sign_second_message).to_payload());
     }
 
     /// step 2 - return the clients R. No extra calculations
@@ -317,9 +310,14 @@ impl EddsaPeer {
     /// 2. compute R' = sum(Ri)
     /// 3. sign message
     pub fn step_3(&mut self) {
-        if !self.validate_commitments() {
-            // commitments sent by others are not valid. exit
-            panic!("Commitments not valid!")
+        if let Err(peer_id) = self.validate_commitments() {
+            // the R the peer revealed in the previous round does not open its commitment
+            self.blame = Some(Blame {
+                reason: AbortReason::InvalidCommitment,
+                accused: Some(peer_id),
+                round: 2,
+            });
+            return;
         }
         let agg_key = self.aggregate_pks();
         println!("computed agg_key");
@@ -330,18 +328,13 @@ impl EddsaPeer {
             Some(ref eph_key) => {
                 let k = Signature::k(&r_tot, &agg_key.apk, &self.message[..]);
                 let peer_id = self.peer_id;
-                let r = self
-                    .r_s
-                    .get(&peer_id)
-                    .unwrap_or_else(|| panic!("Client has No R "))
-                    .clone();
-                let _r: SignSecondMsg =
-                    serde_json::from_str(&r).unwrap_or_else(|_| panic!("Failed to deserialize R"));
+                if !self.r_s.contains_key(&peer_id) {
+                    panic!("Client has No R ");
+                }
                 let key = &self.client_key;
                 // sign
                 let s = Signature::partial_sign(&eph_key.r, key, &k, &agg_key.hash, &r_tot);
-                let sig_string = serde_json::to_string(&s).expect("failed to serialize signature");
-                self.sig_msg = Some(generate_signature_message_payload(&sig_string));
+                self.sig_msg = Some(EddsaPayload::PartialSignature(s).to_payload());
             }
             None => {}
         }
@@ -349,32 +342,7 @@ impl EddsaPeer {
 }
 
 impl EddsaPeer {
-    pub fn resolve_payload_type(message: &MessagePayload) -> MessagePayloadType {
-        let msg_payload = message.clone();
-
-        let split_msg: Vec<&str> = msg_payload.split(RELAY_MESSAGE_DELIMITER).collect();
-        let msg_prefix = split_msg[0];
-        let msg_payload = String::from(split_msg[1].clone());
-        match msg_prefix {
-            pk_prefix if pk_prefix == String::from(PK_MESSAGE_PREFIX) => {
-                return MessagePayloadType::PublicKey(msg_payload);
-            }
-            cmtmnt if cmtmnt == String::from(COMMITMENT_MESSAGE_PREFIX) => {
-                return MessagePayloadType::Commitment(msg_payload);
-            }
-            r if r == String::from(R_KEY_MESSAGE_PREFIX) => {
-                return MessagePayloadType::RMessage(msg_payload);
-            }
-            sig if sig == String::from(SIGNATURE_MESSAGE_PREFIX) => {
-                return MessagePayloadType::Signature(msg_payload);
-            }
-            _ => panic!("Unknown relay message prefix"),
-        }
-    }
-}
-
-impl Peer for EddsaPeer {
-    fn new(capacity: u32, _message: Vec<u8>) -> EddsaPeer {
+    pub fn new(capacity: u32, rounds: u32, message: Vec<u8>) -> EddsaPeer {
         let data = fs::read_to_string(env::args().nth(2).unwrap())
             .expect("Unable to load keys, did you run keygen first? ");
         let (key, _apk, kg_index): (KeyPair, KeyAgg, u32) = serde_json::from_str(&data).unwrap();
@@ -385,7 +353,8 @@ impl Peer for EddsaPeer {
             r_s: HashMap::new(),
             sigs: HashMap::new(),
             capacity,
-            message: _message,
+            rounds,
+            message,
             peer_id: 0,
             agg_key: None,
             kg_index,
@@ -397,6 +366,7 @@ impl Peer for EddsaPeer {
             r_accepted: false,
             sig_accepted: false,
             is_done: false,
+            blame: None,
 
             pk_msg: None,
             commitment_msg: None,
@@ -404,15 +374,21 @@ impl Peer for EddsaPeer {
             sig_msg: None,
         }
     }
+}
+
+/// The signature parts of the peers do not add up to a valid signature
+#[derive(Debug)]
+pub struct InvalidSignature;
+
+impl Peer for EddsaPeer {
+    type Error = InvalidSignature;
 
     fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
         self.peer_id = peer_id;
         let pk/*:Ed25519Point */= self.client_key.public_key.clone();
         //self.add_pk(peer_id, pk);
 
-        let pk_s = serde_json::to_string(&pk).expect("Failed in serialization");
-
-        self.pk_msg = Some(generate_pk_message_payload(&pk_s));
+        self.pk_msg = Some(EddsaPayload::PublicKey(pk).to_payload());
         return self.pk_msg.clone();
     }
 
@@ -423,13 +399,13 @@ impl Peer for EddsaPeer {
             println!("step {:} done!", self.current_step);
             self.current_step += 1;
             match self.current_step {
-                1 => self.step_1(),
-                2 => self.step_2(),
-                3 => self.step_3(),
-                4 => {
+                step if step == self.rounds => {
                     println!("----------\nDone.\n----------");
                     self.is_done = true;
                 }
+                1 => self.step_1(),
+                2 => self.step_2(),
+                3 => self.step_3(),
                 _ => panic!("Unsupported step"),
             }
         } else {
@@ -452,13 +428,11 @@ impl Peer for EddsaPeer {
     ///     collection all signatures
     ///     and verifying the message
     #[allow(non_snake_case)]
-    fn finalize(&mut self) -> Result<(), &'static str> {
+    fn finalize(&mut self) -> Result<(), InvalidSignature> {
         let mut s: Vec<Signature> = Vec::new();
         let eight: FE = ECScalar::from(&BigInt::from(8));
         let eight_inv = eight.invert();
-        for sig in self.sigs.values() {
-            let signature: Signature =
-                serde_json::from_str(&sig).expect("Could not serialize signature!");
+        for signature in self.sigs.values() {
             s.push(Signature {
                 R: signature.R * eight_inv,
                 s: signature.s * &eight,
@@ -482,7 +456,7 @@ impl Peer for EddsaPeer {
                 .expect("Unable to save !");
                 Ok(())
             }
-            Err(_) => Err("Failed to verify"),
+            Err(_) => Err(InvalidSignature),
         }
     }
     /// check that the protocol is done
@@ -491,6 +465,23 @@ impl Peer for EddsaPeer {
         self.is_done_step_3()
     }
 
+    /// the misbehavior this peer found, if the protocol can not continue
+    fn blame(&self) -> Option<Blame> {
+        self.blame.clone()
+    }
+
+    fn current_step(&self) -> u32 {
+        self.current_step
+    }
+
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn peer_id(&self) -> PeerIdentifier {
+        self.peer_id
+    }
+
     /// get the next item the peer needs to send
     /// depending on the current step and the last message
     /// of the peer that was accepted by the server
@@ -515,305 +506,8 @@ impl Peer for EddsaPeer {
         None
     }
 }
-pub trait Peer {
-    fn new(capacity: u32, _message: Vec<u8>) -> Self;
-    fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload>;
-    fn do_step(&mut self);
-    fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload);
-    fn get_next_item(&mut self) -> Option<MessagePayload>;
-    fn finalize(&mut self) -> Result<(), &'static str>;
-    fn is_done(&mut self) -> bool;
-}
-
-struct ProtocolDataManager<T: Peer> {
-    pub peer_id: PeerIdentifier,
-    pub capacity: u32,
-    pub current_step: u32,
-    pub data_holder: T, // will be filled when initializing, and on each new step
-    pub client_data: Option<MessagePayload>, // new data calculated by this peer at the beginning of a step (that needs to be sent to other peers)
-    pub new_client_data: bool,
-}
-
-impl<T: Peer> ProtocolDataManager<T> {
-    pub fn new(capacity: u32, message: Vec<u8>) -> ProtocolDataManager<T>
-    where
-        T: Peer,
-    {
-        ProtocolDataManager {
-            peer_id: 0,
-            current_step: 0,
-            capacity,
-            data_holder: Peer::new(capacity, message),
-            client_data: None,
-            new_client_data: false,
-            //message: message.clone(),
-        }
-    }
-
-    /// set manager with the initial values that a local peer holds at the beginning of
-    /// the protocol session
-    /// return: first message
-    pub fn initialize_data(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
-        self.peer_id = peer_id;
-        let zero_step_data = self.data_holder.zero_step(peer_id);
-        self.client_data = zero_step_data;
-        return self.client_data.clone();
-    }
-
-    /// Get the next message this client needs to send
-    pub fn get_next_message(
-        &mut self,
-        from: PeerIdentifier,
-        payload: MessagePayload,
-    ) -> Option<MessagePayload> {
-        self.data_holder.update_data(from, payload);
-        self.data_holder.do_step();
-        self.data_holder.get_next_item()
-    }
-}
-
-struct Client<T>
-where
-    T: Peer,
-{
-    pub registered: bool,
-    pub protocol_id: ProtocolIdentifier,
-    pub data_manager: ProtocolDataManager<T>,
-    pub last_message: RefCell<ClientMessage>,
-    pub bc_dests: Vec<ProtocolIdentifier>,
-    pub timeout: u32,
-}
-
-impl<T: Peer> Client<T> {
-    pub fn new(protocol_id: ProtocolIdentifier, capacity: u32, message: Vec<u8>) -> Client<T>
-    where
-        T: Peer,
-    {
-        let data_m: ProtocolDataManager<T> = ProtocolDataManager::new(capacity, message);
-        Client {
-            registered: false,
-            protocol_id,
-            last_message: RefCell::new(ClientMessage::new()),
-            bc_dests: (1..(capacity + 1)).collect(),
-            timeout: 100, // 3 second delay in sending messages
-            data_manager: data_m,
-        }
-    }
-
-    pub fn respond_to_server<E: 'static>(
-        &mut self,
-        msg: ServerMessage,
-        // A sender to pass messages to be written back to the server
-        tx: mpsc::Sender<ClientMessage>,
-    ) -> Box<dyn Future<Item = (), Error = E>> {
-        let response = self.generate_client_answer(msg).unwrap();
-        println!("Returning {:?}", response);
-        if response.is_empty() {
-            Box::new(futures::future::ok(()))
-        } else {
-            Box::new(tx.clone().send(response.clone()).then(|_| Ok(())))
-        }
-    }
-
-    pub fn generate_client_answer(&mut self, msg: ServerMessage) -> Option<ClientMessage> {
-        let last_message = self.last_message.clone().into_inner();
-        let mut new_message = None;
-        let msg_type = msg.msg_type();
-        match msg_type {
-            ServerMessageType::Response => {
-                let next = self.handle_server_response(&msg);
-                match next {
-                    Ok(next_msg) => {
-                        new_message = Some(next_msg.clone());
-                    }
-                    Err(_) => {
-                        println!("Error in handle_server_response");
-                    }
-                }
-            }
-            ServerMessageType::RelayMessage => {
-                let next = self.handle_relay_message(msg.clone());
-                match next {
-                    Some(next_msg) => {
-                        //println!("next message to send is {:}", next_msg);
-                        new_message = Some(self.generate_relay_message(next_msg.clone()));
-                    }
-                    None => {
-                        println!("next item is None. Client is finished.");
-                        new_message = Some(ClientMessage::new());
-                    }
-                }
-            }
-            ServerMessageType::Abort => {
-                println!("Got abort message");
-                //Ok(MessageProcessResult::NoMessage)
-                new_message = Some(ClientMessage::new());
-            }
-            ServerMessageType::Undefined => {
-                new_message = Some(ClientMessage::new());
-                //panic!("Got undefined message: {:?}",msg);
-            }
-        };
-        if last_message.is_empty() {
-            match new_message {
-                Some(msg) => {
-                    self.last_message.replace(msg.clone());
-                    return Some(msg.clone());
-                }
-                None => return None,
-            }
-        } else {
-            let _new_message = new_message.clone().unwrap();
-            if !last_message.are_equal_payloads(&_new_message) {
-                println!("last message changed");
-                self.last_message.replace(_new_message.clone());
-            }
-            self.wait_timeout();
-            return Some(self.last_message.clone().into_inner());
-        }
-    }
-
-    pub fn generate_register_message(&mut self) -> ClientMessage {
-        let mut msg = ClientMessage::new();
-        msg.register(self.protocol_id.clone(), self.data_manager.capacity.clone());
-        msg
-    }
-}
-
-impl<T: Peer> Client<T> {
-    fn handle_relay_message(&mut self, msg: ServerMessage) -> Option<MessagePayload> {
-        // parse relay message
-        let relay_msg = msg.relay_message.unwrap();
-        let from = relay_msg.peer_number;
-        if from == self.data_manager.peer_id {
-            println!("-------self message accepted ------\n ");
-        }
-        let payload = relay_msg.message;
-        self.data_manager.get_next_message(from, payload)
-    }
-
-    fn generate_relay_message(&self, payload: MessagePayload) -> ClientMessage {
-        let _msg = ClientMessage::new();
-        // create relay message
-        let mut relay_message =
-            RelayMessage::new(self.data_manager.peer_id, self.protocol_id.clone());
-        let to: Vec<u32> = self.bc_dests.clone();
-
-        let mut client_message = ClientMessage::new();
-
-        relay_message.set_message_params(to, String::from(payload));
-        client_message.relay_message = Some(relay_message);
-        client_message
-    }
-
-    fn wait_timeout(&self) {
-        //    println!("Waiting timeout..");
-        let wait_time = time::Duration::from_millis(self.timeout as u64);
-        thread::sleep(wait_time);
-    }
-
-    fn handle_register_response(&mut self, peer_id: PeerIdentifier) -> Result<ClientMessage, ()> {
-        println!("Peer identifier: {}", peer_id);
-        // Set the session parameters
-        let message = self
-            .data_manager
-            .initialize_data(peer_id)
-            .unwrap_or_else(|| panic!("Failed to initialize"));
-        Ok(self.generate_relay_message(message.clone()))
-    }
-
-    fn get_last_message(&self) -> Option<ClientMessage> {
-        let last_msg = self.last_message.clone().into_inner();
-        return Some(last_msg.clone());
-    }
-
-    fn handle_error_response(&mut self, err_msg: &str) -> Result<ClientMessage, &'static str> {
-        match err_msg {
-            resp if resp == String::from(NOT_YOUR_TURN) => {
-                let last_msg = self.get_last_message();
-                match last_msg {
-                    Some(msg) => {
-                        return Ok(msg.clone());
-                    }
-                    None => {
-                        panic!("No message to resend");
-                    }
-                }
-            }
-            not_initialized_resp if not_initialized_resp == String::from(STATE_NOT_INITIALIZED) => {
-                let last_msg = self.get_last_message();
-                match last_msg {
-                    Some(msg) => {
-                        return Ok(msg.clone());
-                    }
-                    None => {
-                        panic!("No message to resend");
-                    }
-                }
-            }
-            _ => {
-                println!("Didn't handle error correctly");
-                return Err("Error response handling failed");
-            }
-        }
-    }
-
-    fn handle_server_response(
-        &mut self,
-        msg: &ServerMessage,
-    ) -> Result<ClientMessage, &'static str> {
-        let server_response = msg.response.clone().unwrap();
-        match server_response {
-            ServerResponse::Register(peer_id) => {
-                let client_message = self.handle_register_response(peer_id);
-                match client_message {
-                    Ok(_msg) => {
-                        println!("sending peers first message: {:#?}", _msg);
-                        return Ok(_msg.clone());
-                    }
-                    Err(_) => {
-                        println!("error occured");
-                        return Ok(ClientMessage::new());
-                    }
-                }
-            }
-            ServerResponse::ErrorResponse(err_msg) => {
-                //  println!("got error response");
-                let err_msg_slice: &str = &err_msg[..];
-                let msg = self.handle_error_response(err_msg_slice);
-                match msg {
-                    Ok(_msg) => return Ok(_msg),
-                    Err(_) => {
-                        println!("Error occured");
-                        return Ok(ClientMessage::new());
-                    }
-                }
-            }
-            ServerResponse::NoResponse => unimplemented!(),
-        }
-    }
-}
-
-pub enum MessageProcessResult {
-    Message,
-    NoMessage,
-    Abort,
-}
-
-#[derive(Debug)]
-enum MessagePayloadType {
-    /// Types of expected relay messages
-    /// for step 0 we expect PublicKeyMessate
-    /// for step 1 we expect Commitment
-    /// for step 2 we expect RMessage
-    /// for step 3 we expect Signature
-    PublicKey(String),
-    Commitment(String),
-    RMessage(String),
-    Signature(String),
-}
-
-fn main() {
+#[tokio::main]
+async fn main() {
     let opt = Opt::from_args();
 
     let addr = opt.address;
@@ -829,49 +523,33 @@ fn main() {
         Err(_) => message_str.as_bytes().to_vec(),
     };
 
-    // Create the event loop and initiate the connection to the remote server
-    let mut core = Core::new().unwrap();
-    let handle = core.handle();
-    let tcp = TcpStream::connect(&addr, &handle);
-
-    let session: std::sync::Arc<std::sync::Mutex<Client<EddsaPeer>>> =
-        Arc::new(Mutex::new(Client::new(
-            protocol_identifier_arg,
-            protocol_capapcity_arg,
-            message_to_sign,
-        )));
-
-    let handshake = tcp.and_then(|stream| {
-        let handshake_io = Framed::new(stream, ClientToServerCodec::new(false));
-        let mut client = session.lock().unwrap();
-        let msg = client.generate_register_message();
-        handshake_io
-            .send(msg)
-            .map(|handshake_io| handshake_io.into_inner())
-            .map_err(|e| e.into())
-    });
-
-    let client = handshake.and_then(|socket| {
-        let mut client = session.lock().unwrap();
-        let _msg = client.generate_register_message();
-
-        let (to_server, from_server) = Framed::new(socket, ClientToServerCodec::new(false)).split();
-        let (tx, rx) = mpsc::channel(0);
-        let reader = from_server.for_each(move |msg| {
-            println!("Received {:?}", msg);
-            client.respond_to_server(msg, tx.clone())
-        });
+    let identity = match opt.identity {
+        Some(path) => Identity::load_or_generate(path).expect("Unable to load identity"),
+        None => Identity::generate(),
+    };
 
-        let writer = rx
-            .map_err(|()| unreachable!("rx can't fail"))
-            .fold(to_server, |to_server, msg| to_server.send(msg))
-            .map(|_| ());
+    let protocols = ProtocolRegistry::load(PROTOCOLS_FILE).unwrap_or_else(|err| panic!("{}", err));
+    // the peer runs as many steps as the protocol declares rounds
+    let rounds = protocols
+        .protocol_rounds(protocol_identifier_arg)
+        .unwrap_or_else(|| {
+            panic!(
+                "Protocol {} does not declare its rounds",
+                protocol_identifier_arg
+            )
+        });
+    let peer = EddsaPeer::new(protocol_capapcity_arg, rounds, message_to_sign);
+    let mut client = Client::new(&protocols, protocol_identifier_arg, peer)
+        .unwrap_or_else(|err| panic!("{}", err));
 
-        reader
-            .select(writer)
-            .map(|_| println!("Closing connection"))
-            .map_err(|(err, _)| err.into())
-    });
+    // Initiate the connection to the remote server
+    let mut connection = RelayConnection::connect(addr, identity, opt.session_id)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to connect to {}: {}", addr, e));
 
-    core.run(client).unwrap();
+    match client.run(&mut connection).await {
+        Ok(()) => println!("Session completed"),
+        Err(err) => println!("Error: {}", err),
+    }
+    println!("Closing connection");
 }
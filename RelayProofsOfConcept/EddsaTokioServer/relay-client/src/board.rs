@@ -0,0 +1,219 @@
+//! A bulletin board over a connection to the relay server.
+//! Posting relays a payload to all the peers of the session, and the payloads the server
+//! relays back are kept by round. The board blocks on its own runtime, so a peer can be run
+//! with `board::run` outside of any async context. It does not echo broadcast rounds, so the
+//! server does not complete the sessions of protocols that echo; `Client` runs those
+use futures::{FutureExt, StreamExt};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use tokio::runtime::{Builder, Runtime};
+
+use relay_server_common::board::{BoardMessage, BulletinBoard};
+use relay_server_common::codec::WireFormat;
+use relay_server_common::error::RelayError;
+use relay_server_common::identity::Identity;
+use relay_server_common::{
+    Blame, ClientMessage, MessagePayload, PeerIdentifier, ProtocolIdentifier, ServerMessage,
+    ServerResponse, SessionIdentifier,
+};
+
+use crate::connection::RelayConnection;
+use crate::error::ClientError;
+
+// Time to wait before posting a payload the server was not ready for again
+const RESEND_DELAY: Duration = Duration::from_millis(100);
+
+pub struct TcpBoard {
+    runtime: Runtime,
+    connection: RelayConnection,
+    protocol_id: ProtocolIdentifier,
+    capacity: u32,
+    // payloads relayed by the server, by round and sender
+    received: BTreeMap<u32, BTreeMap<PeerIdentifier, MessagePayload>>,
+    // the server completed the session
+    completed: bool,
+    resend_delay: Duration,
+}
+
+impl TcpBoard {
+    /// Connects to the server at addr, to run the given protocol in the given session
+    pub fn connect(
+        addr: SocketAddr,
+        identity: Identity,
+        session_id: SessionIdentifier,
+        protocol_id: ProtocolIdentifier,
+    ) -> Result<TcpBoard, ClientError> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let connection = runtime.block_on(RelayConnection::connect(addr, identity, session_id))?;
+        Ok(TcpBoard {
+            runtime,
+            connection,
+            protocol_id,
+            capacity: 0,
+            received: BTreeMap::new(),
+            completed: false,
+            resend_delay: RESEND_DELAY,
+        })
+    }
+
+    /// Waits the given time before posting a payload the server was not ready for again
+    pub fn set_resend_delay(&mut self, delay: Duration) {
+        self.resend_delay = delay;
+    }
+
+    /// Switches the connection to the given wire format, before joining the session
+    pub fn set_wire_format(&mut self, format: WireFormat) -> Result<(), ClientError> {
+        self.runtime.block_on(self.connection.hello(format))
+    }
+
+    pub fn connection(&self) -> &RelayConnection {
+        &self.connection
+    }
+
+    /// Reads what the server sends until it completes the session, or an error once the
+    /// session was aborted. Sessions of protocols that echo are never completed over the board
+    pub fn wait_completed(&mut self) -> Result<(), ClientError> {
+        while !self.completed {
+            if let Some(server_msg) = self.next_message(true)? {
+                if let Some(err) = self.receive(server_msg)? {
+                    warn!("Got error response: {}", err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send_message(&mut self, msg: ClientMessage) -> Result<(), ClientError> {
+        self.runtime.block_on(self.connection.send_message(msg))
+    }
+
+    // Returns the next message of the server. Unless wait is set, returns None rather than
+    // waiting for a message that did not arrive yet
+    fn next_message(&mut self, wait: bool) -> Result<Option<ServerMessage>, ClientError> {
+        let connection = &mut self.connection;
+        let msg = if wait {
+            Some(self.runtime.block_on(connection.next()))
+        } else {
+            self.runtime
+                .block_on(async { connection.next().now_or_never() })
+        };
+        match msg {
+            Some(Some(msg)) => msg.map(Some),
+            Some(None) => Err(ClientError::Closed),
+            None => Ok(None),
+        }
+    }
+
+    // Keeps the payload of a relay message, and returns the error the server answered with
+    fn receive(&mut self, msg: ServerMessage) -> Result<Option<RelayError>, ClientError> {
+        if let Some(abort) = msg.abort {
+            return Err(ClientError::Aborted(Box::new(abort)));
+        }
+        if let Some(relay_msg) = msg.relay_message {
+            let payload = self.connection.open(&relay_msg)?;
+            self.received
+                .entry(relay_msg.round)
+                .or_default()
+                .insert(relay_msg.peer_number, payload);
+        }
+        match msg.response {
+            Some(ServerResponse::ErrorResponse(err)) => Ok(Some(err)),
+            Some(ServerResponse::Completed) => {
+                self.completed = true;
+                Ok(None)
+            }
+            response => {
+                debug!("Ignoring response {:?}", response);
+                Ok(None)
+            }
+        }
+    }
+
+    fn round_messages(&self, round: u32) -> Vec<BoardMessage> {
+        self.received
+            .get(&round)
+            .map(|messages| {
+                messages
+                    .iter()
+                    .map(|(from, payload)| BoardMessage {
+                        from: *from,
+                        round,
+                        payload: payload.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn is_relayed(&self, round: u32) -> bool {
+        let peer_id = self.connection.peer_id();
+        self.received
+            .get(&round)
+            .is_some_and(|messages| messages.contains_key(&peer_id))
+    }
+}
+
+impl BulletinBoard for TcpBoard {
+    type Error = ClientError;
+
+    fn join(&mut self, capacity: u32) -> Result<PeerIdentifier, ClientError> {
+        self.capacity = capacity;
+        let protocol_id = self.protocol_id;
+        self.runtime
+            .block_on(self.connection.register(protocol_id, capacity))
+    }
+
+    /// Sends the payload to all the peers, until the server relays it back
+    fn post(&mut self, round: u32, payload: MessagePayload) -> Result<(), ClientError> {
+        let to = (1..(self.capacity + 1)).collect();
+        let msg = self.connection.relay_message(round, to, payload)?;
+        self.send_message(msg.clone())?;
+        while !self.is_relayed(round) {
+            let server_msg = match self.next_message(true)? {
+                Some(server_msg) => server_msg,
+                None => continue,
+            };
+            match self.receive(server_msg)? {
+                // the server was not ready for the payload, send it again
+                Some(RelayError::NotYourTurn) | Some(RelayError::NotInitialized) => {
+                    thread::sleep(self.resend_delay);
+                    self.send_message(msg.clone())?;
+                }
+                Some(RelayError::AlreadySentInRound) => return Ok(()),
+                Some(err) => return Err(ClientError::Relay(err)),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn fetch(&mut self, round: u32) -> Result<Vec<BoardMessage>, ClientError> {
+        while let Some(server_msg) = self.next_message(false)? {
+            if let Some(err) = self.receive(server_msg)? {
+                warn!("Got error response: {}", err);
+            }
+        }
+        Ok(self.round_messages(round))
+    }
+
+    /// Reads what the server relays until the round has count payloads
+    fn subscribe(&mut self, round: u32, count: usize) -> Result<Vec<BoardMessage>, ClientError> {
+        while self.received.get(&round).map_or(0, BTreeMap::len) < count {
+            if let Some(server_msg) = self.next_message(true)? {
+                if let Some(err) = self.receive(server_msg)? {
+                    warn!("Got error response: {}", err);
+                }
+            }
+        }
+        Ok(self.round_messages(round))
+    }
+
+    fn abort(&mut self, blame: Blame) -> Result<(), ClientError> {
+        self.runtime
+            .block_on(self.connection.abort(blame.reason, blame.accused, None))
+    }
+}
@@ -0,0 +1,320 @@
+/// Runs a protocol over a relay connection.
+/// The client registers the peer, sends the payloads the peer produces, feeds it the payloads
+/// the server relays, and echoes the broadcast rounds of protocols that opt in, until the
+/// session is completed or aborted
+use futures::StreamExt;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use relay_server_common::echo::{is_broadcast, round_digest, verify_echo, EchoRounds};
+use relay_server_common::error::RelayError;
+use relay_server_common::peer::{Peer, ProtocolDataManager};
+use relay_server_common::protocol::ProtocolRegistry;
+use relay_server_common::{
+    AbortEvidence, AbortReason, Blame, ClientMessage, MessagePayload, PeerIdentifier, ProtocolIdentifier,
+    RelayMessage, ServerMessage, ServerMessageType, ServerResponse,
+};
+
+use crate::connection::RelayConnection;
+use crate::error::ClientError;
+
+// Time to wait before sending a message to the server again
+const RESEND_DELAY: Duration = Duration::from_millis(100);
+
+pub struct Client<T>
+where
+    T: Peer,
+{
+    pub protocol_id: ProtocolIdentifier,
+    pub data_manager: ProtocolDataManager<T>,
+    // the number of peers running the protocol
+    capacity: u32,
+    // the message this peer sent last, sent again until the server accepts it
+    last_message: ClientMessage,
+    bc_dests: Vec<PeerIdentifier>,
+    // relay messages received from the peers by round and sender, kept as evidence
+    received: HashMap<(u32, PeerIdentifier), RelayMessage>,
+    // does the protocol echo the digest of the broadcast messages of every round
+    echo: bool,
+    echo_rounds: EchoRounds,
+    // set once the peers echoed different digests of a round, the client fails with it
+    equivocation: Option<Blame>,
+    resend_delay: Duration,
+}
+
+impl<T: Peer> Client<T> {
+    /// Creates the client of a peer running the given protocol.
+    /// Whether the peers of the protocol echo broadcast messages is looked up in the given
+    /// protocols
+    pub fn new(
+        protocols: &ProtocolRegistry,
+        protocol_id: ProtocolIdentifier,
+        peer: T,
+    ) -> Result<Client<T>, ClientError> {
+        if protocols.protocol_rounds(protocol_id).is_none() {
+            return Err(RelayError::UnknownProtocol.into());
+        }
+        let capacity = peer.capacity();
+        Ok(Client {
+            protocol_id,
+            data_manager: ProtocolDataManager::new(peer),
+            capacity,
+            last_message: ClientMessage::new(),
+            bc_dests: (1..(capacity + 1)).collect(),
+            received: HashMap::new(),
+            // the peers of a protocol that opts in echo the broadcast messages of every round
+            echo: protocols.echo_broadcast(protocol_id),
+            echo_rounds: EchoRounds::new(),
+            equivocation: None,
+            resend_delay: RESEND_DELAY,
+        })
+    }
+
+    /// Waits the given time before sending a message the server was not ready for again
+    pub fn set_resend_delay(&mut self, delay: Duration) {
+        self.resend_delay = delay;
+    }
+
+    /// The local state of the peer, holding the output of the protocol once it is done
+    pub fn peer(&self) -> &T {
+        &self.data_manager.data_holder
+    }
+
+    /// Registers the peer over the connection and runs the protocol.
+    /// Returns once the server completed the session, or an error once it was aborted.
+    /// If the protocol echoes broadcast messages, an error is returned as well if the peers
+    /// echoed different digests of a round, or the session was completed before they all did
+    pub async fn run(&mut self, connection: &mut RelayConnection) -> Result<(), ClientError> {
+        let peer_id = connection.register(self.protocol_id, self.capacity).await?;
+        let payload = self
+            .data_manager
+            .initialize_data(peer_id)
+            .ok_or(ClientError::Protocol("Failed to initialize"))?;
+        let first_message = self.generate_relay_message(connection, payload)?;
+        self.last_message = first_message.clone();
+        connection.send_message(first_message).await?;
+
+        while let Some(msg) = connection.next().await {
+            let msg = msg?;
+            // the server has nothing more to relay once the session is completed or aborted
+            if let Some(abort) = msg.abort {
+                return Err(ClientError::Aborted(Box::new(abort)));
+            }
+            if let Some(ServerResponse::Completed) = msg.response {
+                if self.echo && !self.is_echo_consistent() {
+                    warn!("Session completed before the other peers echoed all the rounds");
+                    return Err(ClientError::EchoIncomplete);
+                }
+                info!("Session completed");
+                return Ok(());
+            }
+            if let Some(response) = self.respond_to_server(connection, msg).await? {
+                connection.send_message(response).await?;
+            }
+            for echo in self.echo_step(connection) {
+                connection.send_message(echo).await?;
+            }
+            // the abort of an equivocation was sent, the output of the protocol can not be trusted
+            if let Some(blame) = self.equivocation.take() {
+                return Err(ClientError::Equivocation(blame));
+            }
+        }
+        Err(ClientError::Closed)
+    }
+
+    // Determines the response that should be sent to the server, if any
+    async fn respond_to_server(
+        &mut self,
+        connection: &RelayConnection,
+        msg: ServerMessage,
+    ) -> Result<Option<ClientMessage>, ClientError> {
+        let new_message = match msg.msg_type() {
+            ServerMessageType::Response => self.handle_server_response(msg.response),
+            ServerMessageType::RelayMessage => {
+                let relay_msg = msg.relay_message.ok_or(RelayError::MalformedMessage)?;
+                let next = self.handle_relay_message(connection, relay_msg)?;
+                match self.generate_abort_message(connection) {
+                    Some(abort) => {
+                        info!("Aborting the session: {:?}", abort.abort);
+                        abort
+                    }
+                    None => match next {
+                        Some(payload) => self.generate_relay_message(connection, payload)?,
+                        // the server completes the session once the last round is relayed,
+                        // and every peer echoed it if the protocol echoes broadcast messages
+                        None => {
+                            debug!("Peer has nothing more to send");
+                            ClientMessage::new()
+                        }
+                    },
+                }
+            }
+            ServerMessageType::Echo => {
+                let echo_msg = msg.echo.ok_or(RelayError::MalformedMessage)?;
+                match verify_echo(&echo_msg, connection.session_id(), connection.roster()) {
+                    Ok(echo) => match self.echo_rounds.receive(echo) {
+                        Ok(()) => ClientMessage::new(),
+                        Err(peer_id) => {
+                            self.generate_equivocation_abort(connection, echo.round, peer_id)
+                        }
+                    },
+                    Err(err) => {
+                        warn!("Ignoring invalid echo: {}", err);
+                        ClientMessage::new()
+                    }
+                }
+            }
+            ServerMessageType::Abort | ServerMessageType::Undefined => ClientMessage::new(),
+        };
+        // keep sending the last message until the server accepts it, or the peer moves on
+        if self.last_message.is_empty() {
+            self.last_message = new_message;
+        } else {
+            if !self.last_message.are_equal_payloads(&new_message) {
+                debug!("Last message changed");
+                self.last_message = new_message;
+            }
+            tokio::time::sleep(self.resend_delay).await;
+        }
+        if self.last_message.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(self.last_message.clone()))
+        }
+    }
+
+    fn handle_server_response(&mut self, response: Option<ServerResponse>) -> ClientMessage {
+        match response {
+            Some(ServerResponse::ErrorResponse(err)) => {
+                warn!("Got error response: {}", err);
+                self.handle_error_response(err)
+            }
+            Some(ServerResponse::ResumeToken(token)) => {
+                // this client does not reconnect, so it has no use for the token
+                debug!("Got resumption token {}", token);
+                ClientMessage::new()
+            }
+            response => {
+                debug!("Ignoring response {:?}", response);
+                ClientMessage::new()
+            }
+        }
+    }
+
+    fn handle_error_response(&mut self, err: RelayError) -> ClientMessage {
+        match err {
+            // the server was not ready for the message, send it again
+            RelayError::NotYourTurn | RelayError::NotInitialized => self.last_message.clone(),
+            // the message was accepted, wait for the rest of the round to be relayed
+            RelayError::AlreadySentInRound => ClientMessage::new(),
+            _ => {
+                warn!("Not sending the last message again after: {}", err);
+                ClientMessage::new()
+            }
+        }
+    }
+
+    fn handle_relay_message(
+        &mut self,
+        connection: &RelayConnection,
+        relay_msg: RelayMessage,
+    ) -> Result<Option<MessagePayload>, ClientError> {
+        let from = relay_msg.peer_number;
+        let payload = connection.open(&relay_msg)?;
+        self.received.insert((relay_msg.round, from), relay_msg);
+        Ok(self.data_manager.get_next_message(from, payload))
+    }
+
+    fn generate_relay_message(
+        &self,
+        connection: &RelayConnection,
+        payload: MessagePayload,
+    ) -> Result<ClientMessage, ClientError> {
+        // the message is sent and signed for the step the peer is currently in
+        let round = self.data_manager.data_holder.current_step();
+        connection.relay_message(round, self.bc_dests.clone(), payload)
+    }
+
+    // Returns an abort blaming the peer this peer found misbehaving, if any,
+    // with the message the peer is blamed for as evidence
+    fn generate_abort_message(&self, connection: &RelayConnection) -> Option<ClientMessage> {
+        let blame = self.data_manager.data_holder.blame()?;
+        let evidence = blame
+            .accused
+            .and_then(|accused| self.received.get(&(blame.round, accused)))
+            .map(|message| AbortEvidence {
+                round: blame.round,
+                message: message.clone(),
+            });
+        Some(connection.abort_message(blame.reason, blame.accused, evidence))
+    }
+
+    // Returns the rounds this peer received the messages of all the peers in
+    fn completed_rounds(&self) -> Vec<u32> {
+        let mut senders: HashMap<u32, usize> = HashMap::new();
+        for (round, _) in self.received.keys() {
+            *senders.entry(*round).or_insert(0) += 1;
+        }
+        let mut rounds: Vec<u32> = senders
+            .into_iter()
+            .filter(|(_, count)| *count == self.capacity as usize)
+            .map(|(round, _)| round)
+            .collect();
+        rounds.sort_unstable();
+        rounds
+    }
+
+    // Returns true if the other peers echoed the same digest for every round this peer completed
+    fn is_echo_consistent(&self) -> bool {
+        let rounds = self.completed_rounds().len() as u32;
+        self.echo_rounds.is_consistent(rounds, self.capacity)
+    }
+
+    // Echoes the digest of every round this peer completed since it was last called.
+    // Returns an abort instead if an echoed digest is different
+    fn echo_step(&mut self, connection: &RelayConnection) -> Vec<ClientMessage> {
+        if !self.echo {
+            return vec![];
+        }
+        let capacity = self.capacity;
+        let mut messages = Vec::new();
+        for round in self.completed_rounds() {
+            if self.echo_rounds.digest(round).is_some() {
+                continue;
+            }
+            let broadcasts = self
+                .received
+                .iter()
+                .filter(|((r, _), msg)| *r == round && is_broadcast(msg, capacity))
+                .map(|(_, msg)| msg);
+            let digest = round_digest(connection.session_id(), round, broadcasts);
+            if let Err(peer_id) = self.echo_rounds.complete_round(round, digest.clone()) {
+                return vec![self.generate_equivocation_abort(connection, round, peer_id)];
+            }
+            messages.push(connection.echo_message(round, digest));
+        }
+        messages
+    }
+
+    // Returns an abort of the session, after a peer echoed a different digest of the round.
+    // The relay sent different messages to the peers, so no peer is blamed in the abort.
+    // The client fails with the echo that differs, once the abort is sent
+    fn generate_equivocation_abort(
+        &mut self,
+        connection: &RelayConnection,
+        round: u32,
+        peer_id: PeerIdentifier,
+    ) -> ClientMessage {
+        warn!(
+            "Peer {} echoed a different digest of round {}, the relay equivocated",
+            peer_id, round
+        );
+        self.equivocation = Some(Blame {
+            reason: AbortReason::Equivocation,
+            accused: None,
+            round,
+        });
+        connection.abort_message(AbortReason::Equivocation, None, None)
+    }
+}
@@ -0,0 +1,322 @@
+/// The connection of a single peer to the relay server.
+/// It registers the peer to a session, signs and seals the messages the peer sends,
+/// and verifies what the server relays back before handing it to the peer
+use futures::{SinkExt, Stream, StreamExt};
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+use relay_server_common::chain::verify_message_head;
+use relay_server_common::codec::WireFormat;
+use relay_server_common::encryption::{verify_roster, EncryptionKeypair};
+use relay_server_common::error::RelayError;
+use relay_server_common::identity::Identity;
+use relay_server_common::{
+    AbortEvidence, AbortMessage, AbortReason, ClientMessage, ClientToServerCodec, IdentityKey,
+    MessagePayload, PeerIdentifier, ProtocolIdentifier, RelayMessage, RelayPayload, Roster,
+    ServerMessage, ServerResponse, SessionIdentifier, SignedChainHead,
+};
+
+use crate::error::ClientError;
+
+pub struct RelayConnection {
+    framed: Framed<TcpStream, ClientToServerCodec>,
+
+    identity: Identity,
+
+    // ephemeral key point to point payloads are sealed with in this session
+    encryption_key: EncryptionKeypair,
+
+    session_id: SessionIdentifier,
+
+    protocol_id: ProtocolIdentifier,
+
+    // the number of this peer in the session, 0 until it is registered
+    peer_id: PeerIdentifier,
+
+    // the peers of the session with their keys, handed out on registration
+    roster: Roster,
+
+    // token the peer can resume its registration with, if the server gave one
+    resume_token: Option<String>,
+
+    // key of the server, pinned from the first chain head it signed
+    server_key: Option<IdentityKey>,
+
+    // latest head of the chain of the session the server committed to
+    chain_head: Option<SignedChainHead>,
+}
+
+impl RelayConnection {
+    /// Connects to the server at addr, to join the given session with the given identity
+    pub async fn connect(
+        addr: SocketAddr,
+        identity: Identity,
+        session_id: SessionIdentifier,
+    ) -> Result<RelayConnection, ClientError> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(RelayConnection {
+            framed: Framed::new(stream, ClientToServerCodec::new(false)),
+            identity,
+            encryption_key: EncryptionKeypair::generate(),
+            session_id,
+            protocol_id: 0,
+            peer_id: 0,
+            roster: Roster::new(),
+            resume_token: None,
+            server_key: None,
+            chain_head: None,
+        })
+    }
+
+    /// Asks the server to switch the connection to the given wire format, and switches it
+    /// once the server agreed. Meant to be sent first, before registering
+    pub async fn hello(&mut self, format: WireFormat) -> Result<(), ClientError> {
+        let mut msg = ClientMessage::new();
+        msg.hello(format);
+        self.send_message(msg).await?;
+
+        while let Some(msg) = self.framed.next().await {
+            match msg?.response {
+                Some(ServerResponse::Hello(accepted)) if accepted == format => {
+                    debug!("Switched to {:?}", format);
+                    self.framed.codec_mut().set_format(format);
+                    return Ok(());
+                }
+                Some(ServerResponse::ErrorResponse(err)) => return Err(ClientError::Relay(err)),
+                response => debug!("Ignoring {:?} while saying hello", response),
+            }
+        }
+        Err(ClientError::Closed)
+    }
+
+    /// Registers to the session to run the given protocol with capacity peers.
+    /// Answers the challenge of the server, and returns the number of this peer once the
+    /// session is full. The keys of the other peers are checked before the roster is kept
+    pub async fn register(
+        &mut self,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> Result<PeerIdentifier, ClientError> {
+        self.protocol_id = protocol_id;
+        let mut msg = ClientMessage::new();
+        msg.register(
+            self.session_id,
+            protocol_id,
+            capacity,
+            self.identity.public_key(),
+            self.identity.sign_encryption_key(&self.encryption_key),
+        );
+        self.send_message(msg).await?;
+
+        while let Some(msg) = self.framed.next().await {
+            let msg = msg?;
+            if let Some(abort) = msg.abort {
+                return Err(ClientError::Aborted(Box::new(abort)));
+            }
+            match msg.response {
+                Some(ServerResponse::Challenge(challenge)) => {
+                    // prove this peer holds the identity it registers with
+                    let mut response = ClientMessage::new();
+                    response.challenge_response(self.identity.sign_challenge(&challenge));
+                    self.send_message(response).await?;
+                }
+                Some(ServerResponse::Register(peer_id, roster)) => {
+                    // the relay is not trusted to hand out the keys of the peers
+                    verify_roster(&roster)?;
+                    info!("Registered as peer {}", peer_id);
+                    self.peer_id = peer_id;
+                    self.roster = roster;
+                    return Ok(peer_id);
+                }
+                Some(ServerResponse::ResumeToken(token)) => self.resume_token = Some(token),
+                Some(ServerResponse::ErrorResponse(err)) => return Err(ClientError::Relay(err)),
+                response => debug!("Ignoring {:?} while registering", response),
+            }
+        }
+        Err(ClientError::Closed)
+    }
+
+    /// Returns a relay message of this peer, signed for the given round.
+    /// A payload addressed to a single other peer is sealed for it
+    pub fn relay_message(
+        &self,
+        round: u32,
+        to: Vec<PeerIdentifier>,
+        payload: MessagePayload,
+    ) -> Result<ClientMessage, ClientError> {
+        let mut relay_message = RelayMessage::new(self.peer_id, self.protocol_id);
+        relay_message.set_message_params(round, to, payload.clone());
+        if relay_message.is_point_to_point() {
+            let recipient = relay_message.to[0];
+            let recipient_key = &self
+                .roster
+                .get(&recipient)
+                .ok_or(RelayError::NotAPeer)?
+                .encryption_key
+                .key;
+            relay_message.message = self
+                .encryption_key
+                .seal(recipient_key, self.peer_id, recipient, &payload)?
+                .into();
+        }
+        let mut client_message = ClientMessage::new();
+        client_message.relay_message = Some(relay_message);
+        self.identity
+            .sign_message(&mut client_message, self.session_id, round);
+        Ok(client_message)
+    }
+
+    /// Returns an echo of the digest of the broadcast messages this peer received in the round
+    pub fn echo_message(&self, round: u32, digest: String) -> ClientMessage {
+        let mut client_message = ClientMessage::new();
+        client_message.echo(self.peer_id, self.protocol_id, round, digest);
+        self.identity
+            .sign_message(&mut client_message, self.session_id, round);
+        client_message
+    }
+
+    /// Returns an abort of the session, blaming the accused peer if one is given
+    pub fn abort_message(
+        &self,
+        reason: AbortReason,
+        accused: Option<PeerIdentifier>,
+        evidence: Option<AbortEvidence>,
+    ) -> ClientMessage {
+        let mut client_message = ClientMessage::new();
+        client_message.abort = Some(AbortMessage::blame(
+            self.peer_id,
+            self.protocol_id,
+            reason,
+            accused,
+            evidence,
+        ));
+        client_message
+    }
+
+    /// Sends a payload to the given peers in the given round
+    pub async fn send(
+        &mut self,
+        round: u32,
+        to: Vec<PeerIdentifier>,
+        payload: MessagePayload,
+    ) -> Result<(), ClientError> {
+        let msg = self.relay_message(round, to, payload)?;
+        self.send_message(msg).await
+    }
+
+    /// Aborts the session, blaming the accused peer if one is given
+    pub async fn abort(
+        &mut self,
+        reason: AbortReason,
+        accused: Option<PeerIdentifier>,
+        evidence: Option<AbortEvidence>,
+    ) -> Result<(), ClientError> {
+        let msg = self.abort_message(reason, accused, evidence);
+        self.send_message(msg).await
+    }
+
+    /// Sends a message to the server as is
+    pub async fn send_message(&mut self, msg: ClientMessage) -> Result<(), ClientError> {
+        debug!("Sending {:?}", msg);
+        self.framed.send(msg).await?;
+        Ok(())
+    }
+
+    /// Returns the payload of a relay message, opened if it was sealed for this peer
+    pub fn open(&self, msg: &RelayMessage) -> Result<MessagePayload, ClientError> {
+        let sealed = match &msg.message {
+            RelayPayload::Plain(payload) => return Ok(payload.clone()),
+            RelayPayload::Sealed(sealed) => sealed,
+        };
+        let sender = self
+            .roster
+            .get(&msg.peer_number)
+            .ok_or(RelayError::NotAPeer)?;
+        let payload = self.encryption_key.open(
+            &sender.encryption_key.key,
+            msg.peer_number,
+            self.peer_id,
+            sealed,
+        )?;
+        Ok(payload)
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    pub fn session_id(&self) -> SessionIdentifier {
+        self.session_id
+    }
+
+    pub fn protocol_id(&self) -> ProtocolIdentifier {
+        self.protocol_id
+    }
+
+    pub fn peer_id(&self) -> PeerIdentifier {
+        self.peer_id
+    }
+
+    pub fn roster(&self) -> &Roster {
+        &self.roster
+    }
+
+    pub fn resume_token(&self) -> Option<&String> {
+        self.resume_token.as_ref()
+    }
+
+    /// Returns the latest head of the chain of the session the server committed to,
+    /// kept as evidence of what the server relayed
+    pub fn chain_head(&self) -> Option<&SignedChainHead> {
+        self.chain_head.as_ref()
+    }
+
+    // Verifies the server committed to the relay message in the chain of the session,
+    // and keeps the head as evidence of what the server relayed
+    fn check_chain_head(&mut self, chain_head: Option<&SignedChainHead>, relay_msg: &RelayMessage) {
+        let chain_head = match chain_head {
+            Some(chain_head) => chain_head,
+            None => {
+                warn!("Relay message arrived without a chain head");
+                return;
+            }
+        };
+        let server_key = self
+            .server_key
+            .get_or_insert_with(|| chain_head.server.clone());
+        match verify_message_head(chain_head, server_key, relay_msg) {
+            Ok(()) => self.chain_head = Some(chain_head.clone()),
+            Err(err) => warn!(
+                "Invalid chain head {} for message of peer {}: {}",
+                chain_head.head.length, relay_msg.peer_number, err
+            ),
+        }
+    }
+}
+
+/// The messages the server sends this peer, after it registered.
+/// The chain head of every relay message is checked as it arrives
+impl Stream for RelayConnection {
+    type Item = Result<ServerMessage, ClientError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let connection = self.get_mut();
+        match connection.framed.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(msg))) => {
+                debug!("Received {:?}", msg);
+                if let Some(relay_msg) = &msg.relay_message {
+                    connection.check_chain_head(msg.chain_head.as_ref(), relay_msg);
+                }
+                Poll::Ready(Some(Ok(msg)))
+            }
+            Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(ClientError::Io(err)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,73 @@
+/// Errors a relay client runs into while talking to the server
+use std::fmt;
+use std::io;
+
+use relay_server_common::board::BoardError;
+use relay_server_common::error::RelayError;
+use relay_server_common::{AbortMessage, Blame};
+
+#[derive(Debug)]
+pub enum ClientError {
+    // the connection to the server failed
+    Io(io::Error),
+
+    // the server rejected a request, or sent something the client could not verify
+    Relay(RelayError),
+
+    // the peer could not run the protocol
+    Protocol(&'static str),
+
+    // the session was aborted, by the server or by one of the peers
+    Aborted(Box<AbortMessage>),
+
+    // the peers echoed different digests of a round, the relay sent them different messages
+    Equivocation(Blame),
+
+    // the server completed the session before the peers echoed every round to each other
+    EchoIncomplete,
+
+    // the server closed the connection before the session was completed
+    Closed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(err) => write!(f, "Connection to the server failed: {}", err),
+            ClientError::Relay(err) => write!(f, "{}", err),
+            ClientError::Protocol(err) => write!(f, "Protocol failed: {}", err),
+            ClientError::Aborted(abort) => write!(
+                f,
+                "Session aborted by peer {}: {:?}, accused {:?}, stalled peers {:?}",
+                abort.peer_number, abort.reason, abort.accused, abort.stalled_peers
+            ),
+            ClientError::Equivocation(blame) => {
+                write!(f, "Relay equivocated in round {}", blame.round)
+            }
+            ClientError::EchoIncomplete => {
+                f.write_str("Session completed before all the rounds were echoed")
+            }
+            ClientError::Closed => f.write_str("Server closed the connection"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        ClientError::Io(err)
+    }
+}
+
+impl From<RelayError> for ClientError {
+    fn from(err: RelayError) -> ClientError {
+        ClientError::Relay(err)
+    }
+}
+
+impl From<BoardError> for ClientError {
+    fn from(err: BoardError) -> ClientError {
+        ClientError::Relay(err.into())
+    }
+}
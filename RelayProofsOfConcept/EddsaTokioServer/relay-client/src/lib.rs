@@ -0,0 +1,14 @@
+//! Client library of the relay server.
+//! A `RelayConnection` registers a peer to a session and carries its messages,
+//! and a `Client` runs any protocol that implements `Peer` over it.
+//! A `TcpBoard` offers the same connection as a bulletin board
+pub mod board;
+pub mod client;
+pub mod connection;
+pub mod error;
+
+pub use crate::board::TcpBoard;
+pub use crate::client::Client;
+pub use crate::connection::RelayConnection;
+pub use crate::error::ClientError;
+pub use relay_server_common::peer::{Peer, ProtocolDataManager};
@@ -0,0 +1,146 @@
+/// Hash chain over the messages relayed in a session.
+/// The server extends the chain with every message it relays, and signs the new head
+/// with its identity key. A peer that got a message with the head that follows it
+/// can check the head commits to that exact message. Two heads of the same session and length,
+/// both signed by the server but with different hashes, prove the server equivocated,
+/// meaning it sent different content to different peers
+use sha2::{Digest, Sha256};
+
+use crate::error::RelayError;
+use crate::identity::verify_chain_head;
+use crate::{ChainHead, IdentityKey, RelayMessage, SessionIdentifier, SignedChainHead};
+
+// The chain of every session starts from a hash of this prefix and the session identifier
+static CHAIN_DOMAIN: &[u8] = b"relay-server-chain:";
+
+#[derive(Clone, Debug)]
+pub struct HashChain {
+    session_id: SessionIdentifier,
+    length: u64,
+    prev: [u8; 32],
+    hash: [u8; 32],
+}
+
+impl HashChain {
+    /// Creates the empty chain of the session
+    pub fn new(session_id: SessionIdentifier) -> HashChain {
+        let genesis: [u8; 32] = Sha256::new()
+            .chain(CHAIN_DOMAIN)
+            .chain(session_id.to_be_bytes())
+            .finalize()
+            .into();
+        HashChain {
+            session_id,
+            length: 0,
+            prev: genesis,
+            hash: genesis,
+        }
+    }
+
+    /// Extends the chain with the relayed message, and returns the new head
+    pub fn append(&mut self, msg: &RelayMessage) -> ChainHead {
+        self.prev = self.hash;
+        self.hash = link(&self.prev, msg);
+        self.length += 1;
+        self.head()
+    }
+
+    /// Returns the current head of the chain
+    pub fn head(&self) -> ChainHead {
+        ChainHead {
+            session_id: self.session_id,
+            length: self.length,
+            prev: hex::encode(self.prev),
+            hash: hex::encode(self.hash),
+        }
+    }
+}
+
+/// Verifies the head was signed by the server and that msg is the last message it commits to
+pub fn verify_message_head(
+    signed: &SignedChainHead,
+    server: &IdentityKey,
+    msg: &RelayMessage,
+) -> Result<(), RelayError> {
+    verify_chain_head(signed, server)?;
+    let prev = hex::decode(&signed.head.prev).map_err(|_| RelayError::InvalidChainHead)?;
+    if hex::encode(link(&prev, msg)) != signed.head.hash {
+        return Err(RelayError::InvalidChainHead);
+    }
+    Ok(())
+}
+
+/// Returns true if the two heads, both signed by the same server for the same position
+/// in the chain of a session, commit to different chains
+pub fn is_equivocation(a: &SignedChainHead, b: &SignedChainHead) -> bool {
+    verify_chain_head(a, &a.server).is_ok()
+        && verify_chain_head(b, &a.server).is_ok()
+        && a.head.session_id == b.head.session_id
+        && a.head.length == b.head.length
+        && a.head.hash != b.head.hash
+}
+
+// The hash of the chain after msg is appended to the chain with the hash prev
+fn link(prev: &[u8], msg: &RelayMessage) -> [u8; 32] {
+    let bytes = serde_json::to_vec(msg).expect("Relay message is always serializable");
+    Sha256::new().chain(prev).chain(&bytes).finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_equivocation, verify_message_head, HashChain};
+    use crate::error::RelayError;
+    use crate::identity::Identity;
+    use crate::RelayMessage;
+
+    fn relay_message(payload: &str) -> RelayMessage {
+        let mut msg = RelayMessage::new(1, 1);
+        msg.set_message_params(0, vec![1, 2], payload);
+        msg
+    }
+
+    #[test]
+    fn test_append() {
+        let mut chain = HashChain::new(1);
+        let genesis = chain.head();
+        assert_eq!(genesis.length, 0);
+        let head = chain.append(&relay_message("first"));
+        assert_eq!(head.length, 1);
+        assert_eq!(head.prev, genesis.hash);
+        // the chain depends on the session
+        assert_ne!(HashChain::new(2).head().hash, genesis.hash);
+    }
+
+    #[test]
+    fn test_verify_message_head() {
+        let server = Identity::generate();
+        let mut chain = HashChain::new(1);
+        chain.append(&relay_message("first"));
+        let msg = relay_message("second");
+        let signed = server.sign_chain_head(chain.append(&msg));
+        assert_eq!(
+            verify_message_head(&signed, &server.public_key(), &msg),
+            Ok(())
+        );
+        // the head does not commit to other content
+        assert_eq!(
+            verify_message_head(&signed, &server.public_key(), &relay_message("other")),
+            Err(RelayError::InvalidChainHead)
+        );
+    }
+
+    #[test]
+    fn test_equivocation() {
+        let server = Identity::generate();
+        let mut chain = HashChain::new(1);
+        let mut forked = chain.clone();
+        let first = server.sign_chain_head(chain.append(&relay_message("to peer 1")));
+        let second = server.sign_chain_head(forked.append(&relay_message("to peer 2")));
+        assert!(is_equivocation(&first, &second));
+        // the same chain is not an equivocation
+        assert!(!is_equivocation(&first, &first.clone()));
+        // neither are heads not signed by the server
+        let other = Identity::generate().sign_chain_head(second.head.clone());
+        assert!(!is_equivocation(&first, &other));
+    }
+}
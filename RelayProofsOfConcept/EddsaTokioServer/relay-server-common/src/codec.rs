@@ -0,0 +1,314 @@
+/// Codecs for framing messages over a byte stream.
+/// A connection starts in JSON, written as consecutive JSON values with no additional framing,
+/// and a client can switch it to CBOR, where every value is prefixed with its length
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::marker::PhantomData;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+/// The encoding of the messages on a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum WireFormat {
+    // consecutive JSON values, easy to read when debugging
+    #[default]
+    Json,
+
+    // CBOR values, each prefixed with its length as a 4 byte big endian integer
+    Cbor,
+}
+
+/// Decodes values of type D and encodes values of type E
+#[derive(Debug)]
+pub struct JsonCodec<D, E> {
+    pretty: bool,
+    _marker: PhantomData<fn() -> (D, E)>,
+}
+
+impl<D, E> JsonCodec<D, E> {
+    /// Creates a new codec. If pretty is set, encoded values are pretty printed
+    pub fn new(pretty: bool) -> JsonCodec<D, E> {
+        JsonCodec {
+            pretty,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<D, E> Clone for JsonCodec<D, E> {
+    fn clone(&self) -> Self {
+        JsonCodec::new(self.pretty)
+    }
+}
+
+impl<D: DeserializeOwned, E> Decoder for JsonCodec<D, E> {
+    type Item = D;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>, io::Error> {
+        let (item, offset) = {
+            let mut values = serde_json::Deserializer::from_slice(&src[..]).into_iter::<D>();
+            match values.next() {
+                Some(Ok(item)) => (item, values.byte_offset()),
+                // the value is not complete yet, wait for more bytes
+                Some(Err(ref e)) if e.is_eof() => return Ok(None),
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(None),
+            }
+        };
+        src.advance(offset);
+        Ok(Some(item))
+    }
+}
+
+impl<D, E: Serialize> Encoder<E> for JsonCodec<D, E> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: E, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let writer = dst.writer();
+        if self.pretty {
+            serde_json::to_writer_pretty(writer, &item)?;
+        } else {
+            serde_json::to_writer(writer, &item)?;
+        }
+        Ok(())
+    }
+}
+
+/// A CBOR codec, decodes values of type D and encodes values of type E
+#[derive(Debug)]
+pub struct CborCodec<D, E> {
+    frames: LengthDelimitedCodec,
+    _marker: PhantomData<fn() -> (D, E)>,
+}
+
+impl<D, E> CborCodec<D, E> {
+    pub fn new() -> CborCodec<D, E> {
+        CborCodec {
+            frames: LengthDelimitedCodec::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<D, E> Default for CborCodec<D, E> {
+    fn default() -> Self {
+        CborCodec::new()
+    }
+}
+
+impl<D, E> Clone for CborCodec<D, E> {
+    fn clone(&self) -> Self {
+        CborCodec::new()
+    }
+}
+
+impl<D: DeserializeOwned, E> Decoder for CborCodec<D, E> {
+    type Item = D;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>, io::Error> {
+        // the frame is not complete yet, wait for more bytes
+        let frame = match self.frames.decode(src)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        ciborium::de::from_reader(&frame[..])
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+impl<D, E: Serialize> Encoder<E> for CborCodec<D, E> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: E, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let mut frame = Vec::new();
+        ciborium::ser::into_writer(&item, &mut frame)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.frames.encode(Bytes::from(frame), dst)
+    }
+}
+
+/// A codec in one of the wire formats, which can be switched while the connection is open.
+/// Bytes already read are decoded in the new format, so the format must only be switched
+/// between two messages
+#[derive(Debug, Clone)]
+pub struct WireCodec<D, E> {
+    format: WireFormat,
+    json: JsonCodec<D, E>,
+    cbor: CborCodec<D, E>,
+}
+
+impl<D, E> WireCodec<D, E> {
+    /// Creates a new codec in JSON. If pretty is set, encoded values are pretty printed
+    pub fn new(pretty: bool) -> WireCodec<D, E> {
+        WireCodec {
+            format: WireFormat::Json,
+            json: JsonCodec::new(pretty),
+            cbor: CborCodec::new(),
+        }
+    }
+
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// Decodes and encodes the following messages in the given format
+    pub fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
+}
+
+impl<D: DeserializeOwned, E> Decoder for WireCodec<D, E> {
+    type Item = D;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>, io::Error> {
+        match self.format {
+            WireFormat::Json => self.json.decode(src),
+            WireFormat::Cbor => self.cbor.decode(src),
+        }
+    }
+}
+
+impl<D, E: Serialize> Encoder<E> for WireCodec<D, E> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: E, dst: &mut BytesMut) -> Result<(), io::Error> {
+        match self.format {
+            WireFormat::Json => self.json.encode(item, dst),
+            WireFormat::Cbor => self.cbor.encode(item, dst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CborCodec, JsonCodec, WireCodec, WireFormat};
+    use crate::{ClientMessage, ClientMessageType, RelayMessage, SignedEncryptionKey};
+    use bytes::BytesMut;
+    use serde_json::json;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    #[test]
+    fn test_encode_decode() {
+        let mut codec: JsonCodec<ClientMessage, ClientMessage> = JsonCodec::new(false);
+        let mut buf = BytesMut::new();
+        let mut register = ClientMessage::new();
+        register.register(
+            1,
+            1,
+            2,
+            String::from("identity"),
+            SignedEncryptionKey::default(),
+        );
+        let mut done = ClientMessage::new();
+        done.done(1, 1);
+        codec.encode(register, &mut buf).unwrap();
+        codec.encode(done, &mut buf).unwrap();
+
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.msg_type(), ClientMessageType::Register);
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.msg_type(), ClientMessageType::Done);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_partial_message() {
+        let mut codec: JsonCodec<ClientMessage, ClientMessage> = JsonCodec::new(false);
+        let mut encoded = BytesMut::new();
+        let mut register = ClientMessage::new();
+        register.register(
+            1,
+            1,
+            2,
+            String::from("identity"),
+            SignedEncryptionKey::default(),
+        );
+        codec.encode(register, &mut encoded).unwrap();
+
+        let mut buf = BytesMut::from(&encoded[..encoded.len() / 2]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&encoded[encoded.len() / 2..]);
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.msg_type(), ClientMessageType::Register);
+    }
+
+    #[test]
+    fn test_decode_invalid_message() {
+        let mut codec: JsonCodec<ClientMessage, ClientMessage> = JsonCodec::new(false);
+        let mut buf = BytesMut::from(&b"{\"register\": 3}"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_cbor_encode_decode() {
+        let mut codec: CborCodec<ClientMessage, ClientMessage> = CborCodec::new();
+        let mut relay_message = RelayMessage::new(1, 1);
+        relay_message.set_message_params(0, vec![2], "payload");
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(relay_message);
+        let mut encoded = BytesMut::new();
+        codec.encode(msg, &mut encoded).unwrap();
+
+        let mut buf = BytesMut::from(&encoded[..encoded.len() / 2]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&encoded[encoded.len() / 2..]);
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        let relay_message = msg.relay_message.unwrap();
+        assert_eq!(relay_message.to, vec![2]);
+        assert_eq!(relay_message.message, "payload".into());
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"\x00\x00\x00\x01\xff"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_cbor_frame_has_no_embedded_json() {
+        let mut codec: CborCodec<ClientMessage, ClientMessage> = CborCodec::new();
+        let payload = json!({"type": "PublicKey", "content": {"x": "0a1b", "y": "2c3d"}});
+        let mut relay_message = RelayMessage::new(1, 1);
+        relay_message.set_message_params(0, vec![2], payload.clone());
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(relay_message);
+        let mut encoded = BytesMut::new();
+        codec.encode(msg, &mut encoded).unwrap();
+
+        // the payload is a CBOR map, its keys are CBOR text strings rather than JSON text
+        let contains = |needle: &[u8]| encoded.windows(needle.len()).any(|w| w == needle);
+        assert!(!contains(b"{\""));
+        assert!(!contains(b"\":"));
+        assert!(contains(b"\x64type"));
+        assert!(contains(b"\x67content"));
+
+        let msg = codec.decode(&mut encoded).unwrap().unwrap();
+        assert_eq!(msg.relay_message.unwrap().message, payload.into());
+    }
+
+    #[test]
+    fn test_switch_wire_format() {
+        let mut codec: WireCodec<ClientMessage, ClientMessage> = WireCodec::new(false);
+        assert_eq!(codec.format(), WireFormat::Json);
+        let mut buf = BytesMut::new();
+        let mut done = ClientMessage::new();
+        done.done(1, 1);
+        codec.encode(done.clone(), &mut buf).unwrap();
+        codec.set_format(WireFormat::Cbor);
+        codec.encode(done, &mut buf).unwrap();
+
+        // the first message is read back as JSON, the second as CBOR
+        codec.set_format(WireFormat::Json);
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.msg_type(), ClientMessageType::Done);
+        assert_eq!(buf[..4], [0, 0, 0, buf.len() as u8 - 4]);
+        codec.set_format(WireFormat::Cbor);
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.msg_type(), ClientMessageType::Done);
+        assert!(buf.is_empty());
+    }
+}
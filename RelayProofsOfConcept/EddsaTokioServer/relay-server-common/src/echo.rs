@@ -0,0 +1,224 @@
+/// Echo broadcast consistency check.
+/// The relay could send different payloads to different recipients of the same broadcast.
+/// At the end of each round, every peer echoes a digest of the broadcast messages it received
+/// in the round to the other peers, signed with its identity. A peer whose digest differs from
+/// an echo it receives knows the peers were not sent the same messages, and aborts the session
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::error::RelayError;
+use crate::identity::verify_message_signature;
+use crate::{ClientMessage, EchoMessage, PeerIdentifier, RelayMessage, Roster, SessionIdentifier};
+
+// Every digest starts with this prefix, the session and the round
+static ECHO_DOMAIN: &[u8] = b"relay-server-echo:";
+
+/// Returns true if the message was sent to every peer of the session.
+/// Only those messages must be the same for all the peers
+pub fn is_broadcast(msg: &RelayMessage, capacity: u32) -> bool {
+    (1..=capacity).all(|peer_id| msg.to.contains(&peer_id))
+}
+
+/// Returns the hex encoded digest of the broadcast messages of a round.
+/// The messages are hashed in the order of their senders, whatever order they were received in
+pub fn round_digest<'a, I>(session_id: SessionIdentifier, round: u32, messages: I) -> String
+where
+    I: IntoIterator<Item = &'a RelayMessage>,
+{
+    let by_sender: BTreeMap<PeerIdentifier, &RelayMessage> = messages
+        .into_iter()
+        .map(|msg| (msg.peer_number, msg))
+        .collect();
+    let digest = by_sender.values().fold(
+        Sha256::new()
+            .chain(ECHO_DOMAIN)
+            .chain(session_id.to_be_bytes())
+            .chain(round.to_be_bytes()),
+        |hasher, msg| hasher.chain(serde_json::to_vec(msg).unwrap()),
+    );
+    hex::encode(digest.finalize())
+}
+
+/// Verifies the echo was signed by the peer it claims to be from, as registered in the roster.
+/// Returns the echo message
+pub fn verify_echo<'a>(
+    msg: &'a ClientMessage,
+    session_id: SessionIdentifier,
+    roster: &Roster,
+) -> Result<&'a EchoMessage, RelayError> {
+    let echo = msg.echo.as_ref().ok_or(RelayError::CantRelay)?;
+    let entry = roster.get(&echo.peer_number).ok_or(RelayError::NotAPeer)?;
+    verify_message_signature(msg, session_id, echo.round, &entry.identity)?;
+    Ok(echo)
+}
+
+/// The digests of the rounds this peer completed, and the digests the other peers echoed
+#[derive(Debug, Default, Clone)]
+pub struct EchoRounds {
+    // digest of the broadcast messages this peer received, by round
+    digests: BTreeMap<u32, String>,
+
+    // digests echoed by the other peers, by round and peer
+    echoes: BTreeMap<u32, BTreeMap<PeerIdentifier, String>>,
+}
+
+impl EchoRounds {
+    pub fn new() -> EchoRounds {
+        EchoRounds::default()
+    }
+
+    /// Returns the digest of the round, if this peer completed it
+    pub fn digest(&self, round: u32) -> Option<&String> {
+        self.digests.get(&round)
+    }
+
+    /// Keeps the digest of a round this peer completed.
+    /// Returns the first peer that already echoed a different digest for the round, if any
+    pub fn complete_round(&mut self, round: u32, digest: String) -> Result<(), PeerIdentifier> {
+        let mismatch = self.echoes.get(&round).and_then(|echoes| {
+            echoes
+                .iter()
+                .find(|(_, echoed)| **echoed != digest)
+                .map(|(peer_id, _)| *peer_id)
+        });
+        self.digests.insert(round, digest);
+        match mismatch {
+            Some(peer_id) => Err(peer_id),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps the digest another peer echoed.
+    /// Returns the peer if this peer completed the round with a different digest
+    pub fn receive(&mut self, echo: &EchoMessage) -> Result<(), PeerIdentifier> {
+        self.echoes
+            .entry(echo.round)
+            .or_default()
+            .insert(echo.peer_number, echo.digest.clone());
+        match self.digests.get(&echo.round) {
+            Some(digest) if *digest != echo.digest => Err(echo.peer_number),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns true if this peer completed each of the given number of rounds,
+    /// and all the other peers echoed the same digest for it
+    pub fn is_consistent(&self, rounds: u32, capacity: u32) -> bool {
+        let no_echoes = BTreeMap::new();
+        (0..rounds).all(|round| match self.digests.get(&round) {
+            Some(digest) => {
+                let echoes = self.echoes.get(&round).unwrap_or(&no_echoes);
+                echoes.len() + 1 >= capacity as usize
+                    && echoes.values().all(|echoed| echoed == digest)
+            }
+            None => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_broadcast, round_digest, verify_echo, EchoRounds};
+    use crate::encryption::EncryptionKeypair;
+    use crate::error::RelayError;
+    use crate::identity::Identity;
+    use crate::{ClientMessage, EchoMessage, RelayMessage, Roster, RosterEntry};
+
+    fn relay_message(peer_id: u32, payload: &str) -> RelayMessage {
+        let mut msg = RelayMessage::new(peer_id, 1);
+        msg.set_message_params(0, vec![1, 2, 3], payload);
+        msg
+    }
+
+    fn echo(peer_number: u32, round: u32, digest: &str) -> EchoMessage {
+        EchoMessage {
+            peer_number,
+            protocol_id: 1,
+            round,
+            digest: String::from(digest),
+        }
+    }
+
+    #[test]
+    fn test_round_digest() {
+        let first = relay_message(1, "first");
+        let second = relay_message(2, "second");
+        // the order the messages were received in does not matter
+        assert_eq!(
+            round_digest(0, 0, vec![&first, &second]),
+            round_digest(0, 0, vec![&second, &first])
+        );
+        // their content, the session and the round do
+        let forged = relay_message(2, "forged");
+        assert_ne!(
+            round_digest(0, 0, vec![&first, &second]),
+            round_digest(0, 0, vec![&first, &forged])
+        );
+        assert_ne!(
+            round_digest(0, 0, vec![&first]),
+            round_digest(1, 0, vec![&first])
+        );
+        assert_ne!(
+            round_digest(0, 0, vec![&first]),
+            round_digest(0, 1, vec![&first])
+        );
+
+        assert!(is_broadcast(&first, 3));
+        let mut point_to_point = first.clone();
+        point_to_point.to = vec![2];
+        assert!(!is_broadcast(&point_to_point, 3));
+    }
+
+    #[test]
+    fn test_echo_rounds() {
+        let mut rounds = EchoRounds::new();
+        // an echo that arrives before the round is completed is checked once it is
+        assert_eq!(Ok(()), rounds.receive(&echo(2, 0, "digest")));
+        assert_eq!(Ok(()), rounds.receive(&echo(3, 0, "other")));
+        assert_eq!(Err(3), rounds.complete_round(0, String::from("digest")));
+        assert!(!rounds.is_consistent(1, 3));
+
+        let mut rounds = EchoRounds::new();
+        assert_eq!(Ok(()), rounds.complete_round(0, String::from("digest")));
+        assert!(!rounds.is_consistent(1, 3));
+        assert_eq!(Ok(()), rounds.receive(&echo(2, 0, "digest")));
+        assert_eq!(Ok(()), rounds.receive(&echo(3, 0, "digest")));
+        assert!(rounds.is_consistent(1, 3));
+        // a round this peer did not complete is not consistent yet
+        assert!(!rounds.is_consistent(2, 3));
+        assert_eq!(Err(2), rounds.receive(&echo(2, 0, "other")));
+    }
+
+    #[test]
+    fn test_verify_echo() {
+        let identity = Identity::generate();
+        let mut roster = Roster::new();
+        roster.insert(
+            1,
+            RosterEntry {
+                identity: identity.public_key(),
+                encryption_key: identity.sign_encryption_key(&EncryptionKeypair::generate()),
+            },
+        );
+        let mut msg = ClientMessage::new();
+        msg.echo(1, 1, 0, String::from("digest"));
+        identity.sign_message(&mut msg, 0, 0);
+        assert_eq!(Ok(&echo(1, 0, "digest")), verify_echo(&msg, 0, &roster));
+
+        // the relay can not change the digest of the echo
+        let mut forged = msg.clone();
+        forged.echo.as_mut().unwrap().digest = String::from("forged");
+        assert_eq!(
+            Err(RelayError::InvalidSignature),
+            verify_echo(&forged, 0, &roster)
+        );
+        // nor send it in the name of another peer
+        let mut other = ClientMessage::new();
+        other.echo(1, 1, 0, String::from("digest"));
+        Identity::generate().sign_message(&mut other, 0, 0);
+        assert_eq!(
+            Err(RelayError::InvalidSignature),
+            verify_echo(&other, 0, &roster)
+        );
+    }
+}
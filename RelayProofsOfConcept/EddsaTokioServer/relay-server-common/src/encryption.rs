@@ -0,0 +1,211 @@
+/// End to end encryption of point to point payloads.
+/// Every peer generates an ephemeral X25519 key for the session, and registers it signed
+/// with its identity key. A payload addressed to a single peer is encrypted with
+/// ChaCha20-Poly1305 under the X25519 shared secret of the sender and the recipient,
+/// so the relay only ever sees the ciphertext
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::error::RelayError;
+use crate::identity::verify_encryption_key;
+use crate::{EncryptionKey, MessagePayload, PeerIdentifier, Roster, SealedPayload};
+
+// The symmetric key of two peers is derived from their shared secret with this prefix
+static KEY_DERIVATION_DOMAIN: &[u8] = b"relay-server-p2p-key:";
+const NONCE_SIZE: usize = 12;
+
+pub struct EncryptionKeypair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl EncryptionKeypair {
+    /// Generates a new ephemeral encryption key, to be used for a single session
+    pub fn generate() -> EncryptionKeypair {
+        let secret = StaticSecret::new(OsRng);
+        let public = PublicKey::from(&secret);
+        EncryptionKeypair { secret, public }
+    }
+
+    /// Returns the encryption key other peers seal payloads for this peer with
+    pub fn public_key(&self) -> EncryptionKey {
+        hex::encode(self.public.as_bytes())
+    }
+
+    /// Encrypts the payload peer `from` sends to peer `to`, whose encryption key is recipient.
+    /// Returns the sealed payload to put in the relay message
+    pub fn seal(
+        &self,
+        recipient: &EncryptionKey,
+        from: PeerIdentifier,
+        to: PeerIdentifier,
+        payload: &MessagePayload,
+    ) -> Result<SealedPayload, RelayError> {
+        let cipher = self.cipher(recipient)?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let aad = associated_data(from, to);
+        let plaintext = serde_json::to_vec(payload).map_err(|_| RelayError::InvalidPayload)?;
+        let ciphertext = cipher
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| RelayError::InvalidEncryptionKey)?;
+        Ok(SealedPayload {
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    /// Decrypts a payload peer `from` sealed for peer `to`, with the sender's encryption key
+    pub fn open(
+        &self,
+        sender: &EncryptionKey,
+        from: PeerIdentifier,
+        to: PeerIdentifier,
+        sealed: &SealedPayload,
+    ) -> Result<MessagePayload, RelayError> {
+        let nonce = hex::decode(&sealed.nonce).map_err(|_| RelayError::DecryptionFailed)?;
+        let nonce =
+            <[u8; NONCE_SIZE]>::try_from(&nonce[..]).map_err(|_| RelayError::DecryptionFailed)?;
+        let ciphertext =
+            hex::decode(&sealed.ciphertext).map_err(|_| RelayError::DecryptionFailed)?;
+        let cipher = self.cipher(sender)?;
+        let aad = associated_data(from, to);
+        let plaintext = cipher
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| RelayError::DecryptionFailed)?;
+        serde_json::from_slice(&plaintext).map_err(|_| RelayError::DecryptionFailed)
+    }
+
+    // The cipher shared by this peer and the owner of the other key
+    fn cipher(&self, other: &EncryptionKey) -> Result<ChaCha20Poly1305, RelayError> {
+        let other = parse_encryption_key(other)?;
+        let shared_secret = self.secret.diffie_hellman(&other);
+        let key = Sha256::new()
+            .chain(KEY_DERIVATION_DOMAIN)
+            .chain(shared_secret.as_bytes())
+            .finalize();
+        Ok(ChaCha20Poly1305::new(&Key::from(key)))
+    }
+}
+
+impl fmt::Debug for EncryptionKeypair {
+    // only the public part of the key is printed
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionKeypair({})", self.public_key())
+    }
+}
+
+/// Checks that every encryption key in the roster was signed by the identity registered with it.
+/// Peers verify the roster themselves, as the relay is not trusted to hand out the keys
+pub fn verify_roster(roster: &Roster) -> Result<(), RelayError> {
+    roster
+        .values()
+        .try_for_each(|entry| verify_encryption_key(&entry.identity, &entry.encryption_key))
+}
+
+/// Checks that the encryption key is an encoded X25519 public key
+pub fn parse_encryption_key(key: &EncryptionKey) -> Result<PublicKey, RelayError> {
+    let bytes = hex::decode(key).map_err(|_| RelayError::InvalidEncryptionKey)?;
+    let bytes = <[u8; 32]>::try_from(&bytes[..]).map_err(|_| RelayError::InvalidEncryptionKey)?;
+    Ok(PublicKey::from(bytes))
+}
+
+// A sealed payload is bound to its sender and recipient,
+// so it can not be reflected back or passed on to another peer
+fn associated_data(from: PeerIdentifier, to: PeerIdentifier) -> Vec<u8> {
+    format!("{}:{}", from, to).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_roster, EncryptionKeypair};
+    use crate::error::RelayError;
+    use crate::identity::Identity;
+    use crate::{Roster, RosterEntry, SealedPayload};
+
+    #[test]
+    fn test_seal_open() {
+        let sender = EncryptionKeypair::generate();
+        let recipient = EncryptionKeypair::generate();
+        let sealed = sender
+            .seal(&recipient.public_key(), 1, 2, &"secret share".into())
+            .unwrap();
+        assert_eq!(
+            recipient.open(&sender.public_key(), 1, 2, &sealed),
+            Ok("secret share".into())
+        );
+    }
+
+    #[test]
+    fn test_open_wrong_recipient() {
+        let sender = EncryptionKeypair::generate();
+        let recipient = EncryptionKeypair::generate();
+        let other = EncryptionKeypair::generate();
+        let sealed = sender
+            .seal(&recipient.public_key(), 1, 2, &"secret share".into())
+            .unwrap();
+        assert_eq!(
+            other.open(&sender.public_key(), 1, 2, &sealed),
+            Err(RelayError::DecryptionFailed)
+        );
+        // the payload is bound to the sender and the recipient peer numbers
+        assert_eq!(
+            recipient.open(&sender.public_key(), 2, 1, &sealed),
+            Err(RelayError::DecryptionFailed)
+        );
+        // the ciphertext only opens with the nonce it was sealed with
+        let other_sealed = sender
+            .seal(&recipient.public_key(), 1, 2, &"other share".into())
+            .unwrap();
+        let tampered = SealedPayload {
+            nonce: sealed.nonce,
+            ciphertext: other_sealed.ciphertext,
+        };
+        assert_eq!(
+            recipient.open(&sender.public_key(), 1, 2, &tampered),
+            Err(RelayError::DecryptionFailed)
+        );
+    }
+
+    #[test]
+    fn test_verify_roster() {
+        let mut roster = Roster::new();
+        for peer_id in 1..3 {
+            let identity = Identity::generate();
+            let key = EncryptionKeypair::generate();
+            roster.insert(
+                peer_id,
+                RosterEntry {
+                    identity: identity.public_key(),
+                    encryption_key: identity.sign_encryption_key(&key),
+                },
+            );
+        }
+        assert_eq!(verify_roster(&roster), Ok(()));
+
+        // a key substituted by the relay is detected
+        roster.get_mut(&1).unwrap().encryption_key.key = EncryptionKeypair::generate().public_key();
+        assert_eq!(
+            verify_roster(&roster),
+            Err(RelayError::InvalidEncryptionKey)
+        );
+    }
+}
@@ -1,21 +1,45 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::vec::Vec;
-use tokio_jsoncodec::Codec as JsonCodec;
 
+use crate::codec::{WireCodec, WireFormat};
+use crate::error::RelayError;
+
+pub mod chain;
+pub mod codec;
 pub mod common;
+pub mod echo;
+pub mod encryption;
+pub mod error;
+pub mod identity;
 pub mod protocol;
+pub mod validator;
+
+// the peer trait and the bulletin board are shared with the clients of the other backends
+pub use mpc_peer::{board, peer, AbortReason, Blame, MessagePayload};
 
 pub type ProtocolIdentifier = u32;
 pub type PeerIdentifier = u32;
-pub type MessagePayload = String;
+pub type SessionIdentifier = u32;
+// Hex encoded Ed25519 public key identifying a peer
+pub type IdentityKey = String;
+// Hex encoded X25519 public key peers seal point to point payloads with
+pub type EncryptionKey = String;
+// The peers of a session, with the keys they registered
+pub type Roster = BTreeMap<PeerIdentifier, RosterEntry>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RelayMessage {
     pub peer_number: PeerIdentifier,
     pub protocol_id: ProtocolIdentifier,
-    //pub round: u32,
+    // identity of the sender, set by the server when relaying
+    #[serde(default)]
+    pub from: IdentityKey,
+    // the round of the protocol the message is sent in
+    pub round: u32,
     pub to: Vec<PeerIdentifier>,
-    pub message: MessagePayload,
+    pub message: RelayPayload,
 }
 
 impl RelayMessage {
@@ -23,34 +47,164 @@ impl RelayMessage {
         RelayMessage {
             peer_number,
             protocol_id,
+            from: IdentityKey::new(),
+            round: 0,
             to: Vec::new(),
-            message: String::from(""),
+            message: RelayPayload::default(),
         }
     }
 
-    pub fn set_message_params<S: Into<String>>(&mut self, to: Vec<PeerIdentifier>, message: S) {
-        //self.round = round_number;
+    pub fn set_message_params<P: Into<RelayPayload>>(
+        &mut self,
+        round_number: u32,
+        to: Vec<PeerIdentifier>,
+        message: P,
+    ) {
+        self.round = round_number;
         self.to = to;
         self.message = message.into();
     }
+
+    /// Returns true if the message is addressed to a single peer other than the sender.
+    /// The payload of such a message must be sealed for the recipient
+    pub fn is_point_to_point(&self) -> bool {
+        self.to.len() == 1 && self.to[0] != self.peer_number
+    }
+
+    /// Returns true if the message is addressed to every peer of a session of the given capacity,
+    /// the sender may or may not address the message to itself
+    pub fn is_addressed_to_all(&self, capacity: u32) -> bool {
+        (1..=capacity)
+            .filter(|peer_id| *peer_id != self.peer_number)
+            .all(|peer_id| self.to.contains(&peer_id))
+    }
+}
+
+/// The payload of a relay message. A point to point payload is sealed for its recipient,
+/// so the relay only ever reads the payloads that every peer of the session can read
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RelayPayload {
+    // a payload every recipient reads as the protocol sent it
+    Plain(MessagePayload),
+
+    // a payload encrypted for a single recipient, see `encryption`
+    Sealed(SealedPayload),
+}
+
+impl RelayPayload {
+    pub fn is_sealed(&self) -> bool {
+        matches!(self, RelayPayload::Sealed(_))
+    }
+}
+
+// Only the payloads the relay can read are printed
+impl fmt::Display for RelayPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RelayPayload::Plain(payload) => write!(f, "{}", payload),
+            RelayPayload::Sealed(_) => f.write_str("<sealed>"),
+        }
+    }
+}
+
+impl Default for RelayPayload {
+    fn default() -> RelayPayload {
+        RelayPayload::Plain(MessagePayload::Null)
+    }
+}
+
+impl From<MessagePayload> for RelayPayload {
+    fn from(payload: MessagePayload) -> RelayPayload {
+        RelayPayload::Plain(payload)
+    }
+}
+
+impl From<&str> for RelayPayload {
+    fn from(payload: &str) -> RelayPayload {
+        RelayPayload::Plain(payload.into())
+    }
+}
+
+impl From<SealedPayload> for RelayPayload {
+    fn from(sealed: SealedPayload) -> RelayPayload {
+        RelayPayload::Sealed(sealed)
+    }
+}
+
+/// A payload sealed by its sender for a single recipient
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SealedPayload {
+    // hex encoded nonce the payload was encrypted with
+    pub nonce: String,
+
+    // hex encoded ciphertext of the payload, with its authentication tag
+    pub ciphertext: String,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SignedEncryptionKey {
+    pub key: EncryptionKey,
+
+    // signature over the encryption key, made with the identity key of the peer
+    pub signature: String,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RosterEntry {
+    pub identity: IdentityKey,
+
+    pub encryption_key: SignedEncryptionKey,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ServerResponse {
-    // Register response containing peer number
-    Register(PeerIdentifier),
+    // Register response containing peer number, and the roster of the session
+    Register(PeerIdentifier, Roster),
+
+    // Challenge to sign with the identity key the peer registers with
+    Challenge(String),
 
-    // Error message
-    ErrorResponse(String),
+    // The request of the peer failed
+    ErrorResponse(RelayError),
+
+    // All peers declared they are done, the session is completed
+    Completed,
+
+    // Token the peer can resume its registration with, if it reconnects
+    ResumeToken(String),
+
+    // The peer took back its peer number, the relay messages it missed follow
+    Resumed(PeerIdentifier, Roster),
+
+    // The server switched the connection to the wire format, the following messages use it
+    Hello(WireFormat),
 
     // No response
     NoResponse,
 }
 
+/// The message an abort blames a peer for
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AbortEvidence {
+    pub round: u32,
+    pub message: RelayMessage,
+}
+
 #[derive(Default, Clone, Debug, Deserialize, Serialize)]
 pub struct AbortMessage {
+    // the peer that aborted the session, 0 if the server aborted it
     pub peer_number: PeerIdentifier,
     pub protocol_id: ProtocolIdentifier,
+    #[serde(default)]
+    pub reason: AbortReason,
+    // the peer blamed for the abort, if any
+    #[serde(default)]
+    pub accused: Option<PeerIdentifier>,
+    #[serde(default)]
+    pub evidence: Option<AbortEvidence>,
+    // the peers that did not send their message before the deadline of the turn or round
+    #[serde(default)]
+    pub stalled_peers: Vec<PeerIdentifier>,
 }
 
 impl AbortMessage {
@@ -58,15 +212,154 @@ impl AbortMessage {
         AbortMessage {
             peer_number,
             protocol_id,
+            ..Default::default()
+        }
+    }
+
+    /// An abort of the given peer, blaming another peer (or itself) for the given reason
+    pub fn blame(
+        peer_number: PeerIdentifier,
+        protocol_id: ProtocolIdentifier,
+        reason: AbortReason,
+        accused: Option<PeerIdentifier>,
+        evidence: Option<AbortEvidence>,
+    ) -> AbortMessage {
+        AbortMessage {
+            peer_number,
+            protocol_id,
+            reason,
+            accused,
+            evidence,
+            stalled_peers: Vec::new(),
+        }
+    }
+
+    /// An abort of the server, after the given peers missed the deadline to send their message
+    pub fn timeout(
+        protocol_id: ProtocolIdentifier,
+        stalled_peers: Vec<PeerIdentifier>,
+    ) -> AbortMessage {
+        AbortMessage {
+            peer_number: 0,
+            protocol_id,
+            reason: AbortReason::Timeout,
+            accused: None,
+            evidence: None,
+            stalled_peers,
         }
     }
 }
 
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct DoneMessage {
+    pub peer_number: PeerIdentifier,
+    pub protocol_id: ProtocolIdentifier,
+}
+
+impl DoneMessage {
+    pub fn new(peer_number: PeerIdentifier, protocol_id: ProtocolIdentifier) -> DoneMessage {
+        DoneMessage {
+            peer_number,
+            protocol_id,
+        }
+    }
+}
+
+/// Digest of the broadcast messages a peer received in a round.
+/// Peers echo it to each other, to check they all received the same messages
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct EchoMessage {
+    pub peer_number: PeerIdentifier,
+    pub protocol_id: ProtocolIdentifier,
+    pub round: u32,
+    // hex encoded digest of the broadcast messages of the round
+    pub digest: String,
+}
+
 #[derive(Default, Clone, Debug, Deserialize, Serialize)]
 pub struct RegisterMessage {
+    pub session_id: SessionIdentifier,
+
     pub protocol_id: ProtocolIdentifier,
 
     pub capacity: u32,
+
+    pub identity: IdentityKey,
+
+    pub encryption_key: SignedEncryptionKey,
+
+    // protocol to register with by name, the server resolves it to its protocol_id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<ProtocolVersion>,
+}
+
+/// A protocol requested by name, in a semantic version
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ProtocolVersion {
+    pub name: String,
+
+    pub version: String,
+}
+
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct ResumeMessage {
+    pub session_id: SessionIdentifier,
+
+    // the token the peer got when it registered
+    pub token: String,
+
+    // number of relay messages and echoes the peer received in the session before it disconnected
+    pub received: u32,
+}
+
+/// Asks the server to switch the connection to another wire format
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct HelloMessage {
+    pub format: WireFormat,
+}
+
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct MessageSignature {
+    pub session_id: SessionIdentifier,
+
+    pub round: u32,
+
+    pub sender: IdentityKey,
+
+    // signature over the session id, round, sender and the signed client message
+    pub signature: String,
+}
+
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct ChallengeResponse {
+    // signature over the challenge, made with the identity key of the register message
+    pub signature: String,
+}
+
+/// The head of the hash chain over the messages the server relayed in a session
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ChainHead {
+    pub session_id: SessionIdentifier,
+
+    // number of messages in the chain
+    pub length: u64,
+
+    // hex encoded hash of the chain before its last message
+    pub prev: String,
+
+    // hex encoded hash of the chain
+    pub hash: String,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SignedChainHead {
+    pub head: ChainHead,
+
+    // identity key of the server that signed the head
+    pub server: IdentityKey,
+
+    // signature over the head, made with the identity key of the server
+    pub signature: String,
 }
 
 #[derive(Debug, PartialEq)]
@@ -74,6 +367,7 @@ pub enum ServerMessageType {
     Response,
     Abort,
     RelayMessage,
+    Echo,
     Undefined,
 }
 
@@ -87,6 +381,14 @@ pub struct ServerMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relay_message: Option<RelayMessage>,
+
+    // head of the hash chain of the session, signed by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_head: Option<SignedChainHead>,
+
+    // echo of another peer, as it signed it, so the recipient can verify it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub echo: Option<ClientMessage>,
 }
 
 impl ServerMessage {
@@ -97,6 +399,10 @@ impl ServerMessage {
             abort: None,
 
             relay_message: None,
+
+            chain_head: None,
+
+            echo: None,
         }
     }
 
@@ -110,7 +416,10 @@ impl ServerMessage {
         if self.abort.is_some() {
             return ServerMessageType::Abort;
         }
-        return ServerMessageType::Undefined;
+        if self.echo.is_some() {
+            return ServerMessageType::Echo;
+        }
+        ServerMessageType::Undefined
     }
 }
 
@@ -124,6 +433,24 @@ pub struct ClientMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relay_message: Option<RelayMessage>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done: Option<DoneMessage>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub challenge_response: Option<ChallengeResponse>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume: Option<ResumeMessage>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub echo: Option<EchoMessage>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hello: Option<HelloMessage>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<MessageSignature>,
 }
 
 impl ClientMessage {
@@ -134,31 +461,116 @@ impl ClientMessage {
             abort: None,
 
             relay_message: None,
+
+            done: None,
+
+            challenge_response: None,
+
+            resume: None,
+
+            echo: None,
+
+            hello: None,
+
+            signature: None,
         }
     }
 
-    pub fn register(&mut self, protocol_id: ProtocolIdentifier, capacity: u32) {
+    pub fn register(
+        &mut self,
+        session_id: SessionIdentifier,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        identity: IdentityKey,
+        encryption_key: SignedEncryptionKey,
+    ) {
         self.register = Some(RegisterMessage {
+            session_id,
             protocol_id,
             capacity,
+            identity,
+            encryption_key,
+            protocol: None,
         });
     }
 
+    /// Registers with the protocol of the given name, in a version compatible with the given one
+    pub fn register_protocol(
+        &mut self,
+        session_id: SessionIdentifier,
+        protocol: ProtocolVersion,
+        capacity: u32,
+        identity: IdentityKey,
+        encryption_key: SignedEncryptionKey,
+    ) {
+        self.register(session_id, 0, capacity, identity, encryption_key);
+        if let Some(register) = self.register.as_mut() {
+            register.protocol = Some(protocol);
+        }
+    }
+
+    pub fn challenge_response(&mut self, signature: String) {
+        self.challenge_response = Some(ChallengeResponse { signature });
+    }
+
+    pub fn resume(&mut self, session_id: SessionIdentifier, token: String, received: u32) {
+        self.resume = Some(ResumeMessage {
+            session_id,
+            token,
+            received,
+        });
+    }
+
+    pub fn echo(
+        &mut self,
+        peer_number: PeerIdentifier,
+        protocol_id: ProtocolIdentifier,
+        round: u32,
+        digest: String,
+    ) {
+        self.echo = Some(EchoMessage {
+            peer_number,
+            protocol_id,
+            round,
+            digest,
+        });
+    }
+
+    pub fn hello(&mut self, format: WireFormat) {
+        self.hello = Some(HelloMessage { format });
+    }
+
+    pub fn done(&mut self, peer_number: PeerIdentifier, protocol_id: ProtocolIdentifier) {
+        self.done = Some(DoneMessage::new(peer_number, protocol_id));
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.relay_message.is_none() && self.abort.is_none() && self.register.is_none()
+        self.relay_message.is_none()
+            && self.abort.is_none()
+            && self.register.is_none()
+            && self.done.is_none()
+            && self.challenge_response.is_none()
+            && self.resume.is_none()
+            && self.echo.is_none()
+            && self.hello.is_none()
     }
 
     pub fn are_equal_payloads(&self, msg: &ClientMessage) -> bool {
-        if self.register.is_some() && msg.register.is_some() {
-            return true;
-        } else if self.relay_message.is_some() && msg.relay_message.is_some() {
-            let self_message = self.relay_message.clone().unwrap().message;
-            let message = msg.relay_message.clone().unwrap().message;
-            return self_message == message;
-        } else if self.abort.is_some() && msg.abort.is_some() {
-            return true;
+        if let (Some(relay_message), Some(other)) = (&self.relay_message, &msg.relay_message) {
+            return relay_message.message == other.message;
+        }
+        if self.echo.is_some() && msg.echo.is_some() {
+            return self.echo == msg.echo;
         }
-        false
+        if self.hello.is_some() && msg.hello.is_some() {
+            return self.hello == msg.hello;
+        }
+        // messages of the other kinds are equal if they are of the same kind
+        (self.register.is_some() && msg.register.is_some())
+            || (self.abort.is_some() && msg.abort.is_some())
+            || (self.done.is_some() && msg.done.is_some())
+            || (self.challenge_response.is_some() && msg.challenge_response.is_some())
+            || (self.resume.is_some() && msg.resume.is_some())
     }
 
     pub fn msg_type(&self) -> ClientMessageType {
@@ -171,24 +583,39 @@ impl ClientMessage {
         if self.abort.is_some() {
             return ClientMessageType::Abort;
         }
-        return ClientMessageType::Undefined;
+        if self.done.is_some() {
+            return ClientMessageType::Done;
+        }
+        if self.challenge_response.is_some() {
+            return ClientMessageType::ChallengeResponse;
+        }
+        if self.resume.is_some() {
+            return ClientMessageType::Resume;
+        }
+        if self.echo.is_some() {
+            return ClientMessageType::Echo;
+        }
+        if self.hello.is_some() {
+            return ClientMessageType::Hello;
+        }
+        ClientMessageType::Undefined
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ClientMessageType {
     Register,
     Abort,
     RelayMessage,
+    Done,
+    ChallengeResponse,
+    Resume,
+    Echo,
+    Hello,
     Undefined,
     Test,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
-struct RegisterResponse {
-    peer_number: PeerIdentifier,
-}
-
 // in: clientMessage out:serverMessage
-pub type ServerToClientCodec = JsonCodec<ClientMessage, ServerMessage>;
-pub type ClientToServerCodec = JsonCodec<ServerMessage, ClientMessage>;
+pub type ServerToClientCodec = WireCodec<ClientMessage, ServerMessage>;
+pub type ClientToServerCodec = WireCodec<ServerMessage, ClientMessage>;
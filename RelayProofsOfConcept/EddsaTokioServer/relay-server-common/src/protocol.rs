@@ -1,96 +1,577 @@
 /// Structures for supported protocols for relay-server
 use log::debug;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::collections::HashSet;
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
-use crate::ProtocolIdentifier;
+use crate::error::RelayError;
+use crate::{ProtocolIdentifier, RelayMessage};
 
-static PROTOCOLS_F: &str = r#"./protocols.json"#;
+// The protocols file used if no other is given
+pub static PROTOCOLS_FILE: &str = r#"./protocols.json"#;
+
+/// The order in which the relay accepts the messages of the peers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayMode {
+    // peers send one at a time, in a fixed 1..n order,
+    // and every message is relayed as soon as it is accepted
+    #[default]
+    Turn,
+
+    // every peer sends its message for the round whenever it is ready,
+    // and the whole round is relayed once all the peers sent their message
+    Round,
+}
+
+/// The messages the peers send in a round of a protocol
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundKind {
+    // every peer sends a single message, addressed to all the peers
+    Broadcast,
+
+    // every peer sends a single message, sealed for one other peer
+    PointToPoint,
+}
+
+impl RoundKind {
+    /// Returns true if the recipients of the message fit the kind of the round,
+    /// in a session of the given capacity
+    pub fn admits(&self, msg: &RelayMessage, capacity: u32) -> bool {
+        match self {
+            RoundKind::Broadcast => msg.is_addressed_to_all(capacity),
+            // the single recipient must be another peer of the session
+            RoundKind::PointToPoint => {
+                msg.is_point_to_point() && (1..=capacity).contains(&msg.to[0])
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ProtocolDescriptor {
     pub id: ProtocolIdentifier,
     pub capacity: u32,
+    pub mode: RelayMode,
+    // the rounds the protocol runs, if declared
+    pub schedule: Option<Vec<RoundKind>>,
+    // peers echo the digest of the broadcast messages of every round to each other
+    pub echo: bool,
     pub turn: Arc<RwLock<u32>>,
+    // number of completed rounds, a round is completed once every peer had its turn
+    pub round: Arc<RwLock<u32>>,
 }
 
 impl ProtocolDescriptor {
     pub fn new(id: ProtocolIdentifier, capacity: u32) -> ProtocolDescriptor {
+        ProtocolDescriptor::with_mode(id, capacity, RelayMode::Turn)
+    }
+
+    pub fn with_mode(id: ProtocolIdentifier, capacity: u32, mode: RelayMode) -> ProtocolDescriptor {
         ProtocolDescriptor {
             id,
             capacity,
+            mode,
+            schedule: None,
+            echo: false,
             turn: Arc::new(RwLock::new(1)),
+            round: Arc::new(RwLock::new(0)),
         }
     }
 
     // Advances the peer whose turn it is to transmit.
-    // If the peer is 0, initializes state to 1, else, advances turn by 1
+    // If the peer is 0, initializes state to 1 and starts a new round, else, advances turn by 1
     pub fn advance_turn(&self) -> u32 {
         let mut turn = self.turn.write().unwrap();
         let peer_number = (*turn + 1) % (self.capacity + 1);
         if peer_number == 0 {
             *turn = 1;
+            *self.round.write().unwrap() += 1;
         } else {
             *turn = peer_number;
         }
         *turn
     }
 
+    // Starts a new round, once the messages of all peers for the current round were relayed
+    pub fn advance_round(&self) -> u32 {
+        let mut round = self.round.write().unwrap();
+        *round += 1;
+        *round
+    }
+
     // Get the # of next peer that can send a message
     pub fn next(&self) -> u32 {
         *self.turn.read().unwrap()
     }
-}
 
-/// Returns true if the protocol is a valid protocol as determined by the
-/// protocols.json file
-pub fn is_valid_protocol(p: &ProtocolDescriptor) -> bool {
-    let all_protocols = get_protocols();
-    match all_protocols {
-        Ok(_protocols) => {
-            for prot in _protocols.protocols {
-                debug!("Checking if fits protocol: {:?}", prot);
-                if prot.id == p.id {
-                    if prot.capacities.contains(&(p.capacity)) {
-                        return true;
-                    } else {
-                        return false;
-                    }
-                }
-            }
-            return false;
+    // Get the current round of the protocol
+    pub fn round(&self) -> u32 {
+        *self.round.read().unwrap()
+    }
+
+    // Get the number of rounds the protocol runs, or None if it runs any number of rounds
+    pub fn rounds(&self) -> Option<u32> {
+        self.schedule.as_ref().map(|schedule| schedule.len() as u32)
+    }
+
+    // Returns true if a message can be sent in the round,
+    // a protocol that does not declare its number of rounds runs any number of rounds
+    pub fn is_valid_round(&self, round: u32) -> bool {
+        match self.rounds() {
+            Some(rounds) => round < rounds,
+            None => true,
+        }
+    }
+
+    // Get the kind of messages sent in the round, or None if the protocol does not declare it
+    pub fn round_kind(&self, round: u32) -> Option<RoundKind> {
+        self.schedule.as_ref()?.get(round as usize).cloned()
+    }
+
+    // Get the number of messages every peer sends in the round.
+    // In a point to point round a peer seals a message for each of the other peers
+    pub fn messages_per_peer(&self, round: u32) -> u32 {
+        match self.round_kind(round) {
+            Some(RoundKind::PointToPoint) => self.capacity - 1,
+            _ => 1,
+        }
+    }
+
+    // Returns true once the last round the protocol declares is completed
+    pub fn is_last_round_completed(&self) -> bool {
+        match self.rounds() {
+            Some(rounds) => self.round() >= rounds,
+            None => false,
         }
-        Err(_) => panic!("Corrupt protocols file"),
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Protocolss {
+/// The protocols a relay supports, as declared in a protocols file
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ProtocolRegistry {
     pub protocols: Vec<Protocol>,
+
+    // the file the protocols were loaded from, if any
+    #[serde(skip)]
+    path: Option<PathBuf>,
 }
 
-#[derive(Deserialize, Debug, Serialize)]
-struct Protocol {
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct Protocol {
     pub id: u32,
     pub capacities: Vec<u32>,
     pub names: Vec<String>,
+    // peers can register with any version compatible with this one
+    pub version: Version,
+    #[serde(default)]
+    pub mode: RelayMode,
+    // the kind of every round the protocol runs, in order
+    #[serde(default)]
+    pub rounds: Option<Vec<RoundKind>>,
+    #[serde(default)]
+    pub echo: bool,
 }
 
-// Reutrn all avaliable protocols
-fn get_protocols() -> Result<Protocolss, Box<dyn Error>> {
-    debug!("Getting protocols");
+/// Why a protocols file could not be loaded
+#[derive(Debug)]
+pub enum ProtocolsError {
+    // the file could not be read
+    Io(PathBuf, io::Error),
+
+    // the file is not a valid JSON list of protocols
+    Parse(PathBuf, serde_json::Error),
+
+    // the file declares protocols that can not be run
+    Invalid(PathBuf, String),
+}
 
-    // Open the file in read-only mode with buffer.
-    let path = PROTOCOLS_F;
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+impl fmt::Display for ProtocolsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolsError::Io(path, err) => {
+                write!(f, "unable to read protocols file {:?}: {}", path, err)
+            }
+            ProtocolsError::Parse(path, err) => {
+                write!(f, "protocols file {:?} is malformed: {}", path, err)
+            }
+            ProtocolsError::Invalid(path, reason) => {
+                write!(f, "protocols file {:?} is invalid: {}", path, reason)
+            }
+        }
+    }
+}
 
-    // Read the JSON contents of the file as an instance of `Protocols`.
-    let p = serde_json::from_reader(reader)?;
+impl std::error::Error for ProtocolsError {}
 
-    //debug!("Got protocols: {:?}", p);
-    Ok(p)
+impl ProtocolRegistry {
+    /// Loads the protocols declared in the file in path, and checks they can be run
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ProtocolRegistry, ProtocolsError> {
+        let path = path.as_ref().to_path_buf();
+        debug!("Loading protocols from {:?}", path);
+        let file = File::open(&path).map_err(|err| ProtocolsError::Io(path.clone(), err))?;
+        let mut registry: ProtocolRegistry = serde_json::from_reader(BufReader::new(file))
+            .map_err(|err| ProtocolsError::Parse(path.clone(), err))?;
+        registry
+            .validate()
+            .map_err(|reason| ProtocolsError::Invalid(path.clone(), reason))?;
+        registry.path = Some(path);
+        Ok(registry)
+    }
+
+    /// Loads the protocols again from the file they were loaded from
+    pub fn reload(&self) -> Result<ProtocolRegistry, ProtocolsError> {
+        match &self.path {
+            Some(path) => ProtocolRegistry::load(path),
+            None => Ok(self.clone()),
+        }
+    }
+
+    /// Returns the file the protocols were loaded from, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // Checks every protocol has a unique identifier, no two protocols share a name and version,
+    // and every protocol can be run by some number of peers
+    fn validate(&self) -> Result<(), String> {
+        if self.protocols.is_empty() {
+            return Err(String::from("no protocols are declared"));
+        }
+        let mut ids = HashSet::new();
+        let mut versions = HashSet::new();
+        for prot in &self.protocols {
+            if !ids.insert(prot.id) {
+                return Err(format!("protocol {} is declared more than once", prot.id));
+            }
+            if let Some(name) = prot
+                .names
+                .iter()
+                .find(|name| !versions.insert((name.as_str(), &prot.version)))
+            {
+                return Err(format!(
+                    "version {} of {} is declared more than once",
+                    prot.version, name
+                ));
+            }
+            if prot.capacities.is_empty() || prot.capacities.contains(&0) {
+                return Err(format!("protocol {} has no valid capacities", prot.id));
+            }
+            match &prot.rounds {
+                Some(rounds) if rounds.is_empty() => {
+                    return Err(format!("protocol {} declares no rounds", prot.id));
+                }
+                // a single peer has no other peer to send point to point messages to
+                Some(rounds)
+                    if rounds.contains(&RoundKind::PointToPoint)
+                        && prot.capacities.contains(&1) =>
+                {
+                    return Err(format!(
+                        "protocol {} has point to point rounds, but can be run by a single peer",
+                        prot.id
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the protocol with the given identifier, if it is supported
+    pub fn get(&self, id: ProtocolIdentifier) -> Option<&Protocol> {
+        self.protocols.iter().find(|prot| prot.id == id)
+    }
+
+    /// Returns true if the protocol is supported with the capacity it requests
+    pub fn is_valid_protocol(&self, p: &ProtocolDescriptor) -> bool {
+        debug!("Checking if fits protocol: {:?}", p);
+        self.check(p.id, p.capacity).is_ok()
+    }
+
+    /// Checks the protocol with the given identifier is supported with the given capacity
+    pub fn check(&self, id: ProtocolIdentifier, capacity: u32) -> Result<(), RelayError> {
+        match self.get(id) {
+            Some(prot) if prot.capacities.contains(&capacity) => Ok(()),
+            Some(_) => Err(RelayError::UnsupportedCapacity),
+            None => Err(RelayError::UnknownProtocol),
+        }
+    }
+
+    /// Returns the identifier of the protocol with the given name, in the newest version that is
+    /// compatible with the requested one and supports the given capacity.
+    /// Versions are compatible as in semantic versioning, so 1.2.0 can serve a request for 1.1.0
+    pub fn resolve(
+        &self,
+        name: &str,
+        version: &str,
+        capacity: u32,
+    ) -> Result<ProtocolIdentifier, RelayError> {
+        let requested = Version::parse(version).map_err(|_| RelayError::UnsupportedVersion)?;
+        let compatible = VersionReq::parse(&format!("^{}", requested))
+            .map_err(|_| RelayError::UnsupportedVersion)?;
+        let named: Vec<&Protocol> = self
+            .protocols
+            .iter()
+            .filter(|prot| prot.names.iter().any(|prot_name| prot_name == name))
+            .collect();
+        if named.is_empty() {
+            return Err(RelayError::UnknownProtocol);
+        }
+        let versions: Vec<&Protocol> = named
+            .into_iter()
+            .filter(|prot| compatible.matches(&prot.version))
+            .collect();
+        if versions.is_empty() {
+            return Err(RelayError::UnsupportedVersion);
+        }
+        versions
+            .into_iter()
+            .filter(|prot| prot.capacities.contains(&capacity))
+            .max_by(|first, second| first.version.cmp(&second.version))
+            .map(|prot| prot.id)
+            .ok_or(RelayError::UnsupportedCapacity)
+    }
+
+    /// Returns the relay mode of the protocol.
+    /// Protocols that do not declare a mode are relayed in turns
+    pub fn relay_mode(&self, id: ProtocolIdentifier) -> RelayMode {
+        self.get(id).map(|prot| prot.mode).unwrap_or_default()
+    }
+
+    /// Returns the number of rounds the protocol runs, or None if the protocol does not declare it
+    pub fn protocol_rounds(&self, id: ProtocolIdentifier) -> Option<u32> {
+        self.schedule(id).map(|schedule| schedule.len() as u32)
+    }
+
+    /// Returns the kind of every round the protocol runs, or None if the protocol does not declare it
+    pub fn schedule(&self, id: ProtocolIdentifier) -> Option<Vec<RoundKind>> {
+        self.get(id).and_then(|prot| prot.rounds.clone())
+    }
+
+    /// Returns true if the peers of the protocol echo the digest of the broadcast messages
+    /// of every round to each other
+    pub fn echo_broadcast(&self, id: ProtocolIdentifier) -> bool {
+        self.protocols.iter().any(|prot| prot.id == id && prot.echo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProtocolDescriptor, ProtocolRegistry, ProtocolsError, RelayMode, RoundKind};
+    use crate::error::RelayError;
+    use crate::RelayMessage;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn protocols_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "relay-protocols-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_protocols() {
+        let path = protocols_file(
+            "valid",
+            r#"{"protocols": [
+                {"id": 1, "names": ["turns"], "version": "1.0.0", "capacities": [2, 3]},
+                {"id": 2, "names": ["rounds"], "version": "1.0.0", "capacities": [2], "mode": "round", "rounds": ["broadcast", "point_to_point"], "echo": true}
+            ]}"#,
+        );
+        let registry = ProtocolRegistry::load(&path).unwrap();
+        assert_eq!(registry.path(), Some(path.as_path()));
+        assert!(registry.is_valid_protocol(&ProtocolDescriptor::new(1, 3)));
+        assert!(!registry.is_valid_protocol(&ProtocolDescriptor::new(1, 4)));
+        assert!(!registry.is_valid_protocol(&ProtocolDescriptor::new(3, 2)));
+        assert_eq!(registry.relay_mode(1), RelayMode::Turn);
+        assert_eq!(registry.relay_mode(2), RelayMode::Round);
+        assert_eq!(registry.protocol_rounds(1), None);
+        assert_eq!(registry.protocol_rounds(2), Some(2));
+        assert_eq!(
+            registry.schedule(2),
+            Some(vec![RoundKind::Broadcast, RoundKind::PointToPoint])
+        );
+        assert!(!registry.echo_broadcast(1));
+        assert!(registry.echo_broadcast(2));
+
+        // a reload picks up changes to the file
+        fs::write(
+            &path,
+            r#"{"protocols": [{"id": 1, "names": ["turns"], "version": "1.0.0", "capacities": [4]}]}"#,
+        )
+        .unwrap();
+        let reloaded = registry.reload().unwrap();
+        assert!(reloaded.is_valid_protocol(&ProtocolDescriptor::new(1, 4)));
+        assert!(reloaded.get(2).is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_invalid_protocols() {
+        let missing = std::env::temp_dir().join("relay-protocols-missing.json");
+        match ProtocolRegistry::load(&missing) {
+            Err(ProtocolsError::Io(..)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let path = protocols_file("malformed", r#"{"protocols": [{"id": 1}"#);
+        match ProtocolRegistry::load(&path) {
+            Err(ProtocolsError::Parse(..)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+        fs::remove_file(path).unwrap();
+
+        let path = protocols_file(
+            "duplicate",
+            r#"{"protocols": [
+                {"id": 1, "names": ["first"], "version": "1.0.0", "capacities": [2]},
+                {"id": 1, "names": ["second"], "version": "1.0.0", "capacities": [3]}
+            ]}"#,
+        );
+        match ProtocolRegistry::load(&path) {
+            Err(ProtocolsError::Invalid(..)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+        fs::remove_file(path).unwrap();
+
+        let path = protocols_file(
+            "capacity",
+            r#"{"protocols": [{"id": 1, "names": ["empty"], "version": "1.0.0", "capacities": []}]}"#,
+        );
+        match ProtocolRegistry::load(&path) {
+            Err(ProtocolsError::Invalid(..)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+        fs::remove_file(path).unwrap();
+
+        let path = protocols_file(
+            "rounds",
+            r#"{"protocols": [{"id": 1, "names": ["none"], "version": "1.0.0", "capacities": [2], "rounds": []}]}"#,
+        );
+        match ProtocolRegistry::load(&path) {
+            Err(ProtocolsError::Invalid(..)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+        fs::remove_file(path).unwrap();
+
+        let path = protocols_file(
+            "point-to-point",
+            r#"{"protocols": [{"id": 1, "names": ["alone"], "version": "1.0.0", "capacities": [1, 2], "rounds": ["point_to_point"]}]}"#,
+        );
+        match ProtocolRegistry::load(&path) {
+            Err(ProtocolsError::Invalid(..)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+        fs::remove_file(path).unwrap();
+
+        let path = protocols_file(
+            "version",
+            r#"{"protocols": [
+                {"id": 1, "names": ["same"], "version": "1.0.0", "capacities": [2]},
+                {"id": 2, "names": ["same"], "version": "1.0.0", "capacities": [3]}
+            ]}"#,
+        );
+        match ProtocolRegistry::load(&path) {
+            Err(ProtocolsError::Invalid(..)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_round_schedule() {
+        let mut protocol = ProtocolDescriptor::with_mode(1, 2, RelayMode::Round);
+        assert_eq!(protocol.rounds(), None);
+        assert!(protocol.is_valid_round(5));
+        assert!(!protocol.is_last_round_completed());
+
+        protocol.schedule = Some(vec![RoundKind::Broadcast, RoundKind::PointToPoint]);
+        assert_eq!(protocol.rounds(), Some(2));
+        assert_eq!(protocol.round_kind(0), Some(RoundKind::Broadcast));
+        assert_eq!(protocol.round_kind(1), Some(RoundKind::PointToPoint));
+        assert_eq!(protocol.round_kind(2), None);
+        assert!(!protocol.is_valid_round(2));
+        // a peer sends one message in a broadcast round, and one to each other peer otherwise
+        assert_eq!(protocol.messages_per_peer(0), 1);
+        assert_eq!(protocol.messages_per_peer(1), 1);
+        protocol.capacity = 4;
+        assert_eq!(protocol.messages_per_peer(1), 3);
+
+        let mut msg = RelayMessage::new(1, 1);
+        msg.set_message_params(0, vec![2], "");
+        assert!(RoundKind::Broadcast.admits(&msg, 2));
+        assert!(!RoundKind::Broadcast.admits(&msg, 3));
+        assert!(RoundKind::PointToPoint.admits(&msg, 3));
+        // a point to point message is for another peer of the session
+        msg.set_message_params(0, vec![0], "");
+        assert!(!RoundKind::PointToPoint.admits(&msg, 3));
+        msg.set_message_params(0, vec![4], "");
+        assert!(!RoundKind::PointToPoint.admits(&msg, 3));
+        msg.set_message_params(0, vec![1], "");
+        assert!(!RoundKind::PointToPoint.admits(&msg, 3));
+        msg.set_message_params(0, vec![1, 2, 3], "");
+        assert!(RoundKind::Broadcast.admits(&msg, 3));
+        assert!(!RoundKind::PointToPoint.admits(&msg, 3));
+
+        protocol.advance_round();
+        assert!(!protocol.is_last_round_completed());
+        protocol.advance_round();
+        assert!(protocol.is_last_round_completed());
+    }
+
+    #[test]
+    fn test_resolve_protocol() {
+        let path = protocols_file(
+            "resolve",
+            r#"{"protocols": [
+                {"id": 1, "names": ["eddsa", "ed25519"], "version": "1.0.0", "capacities": [2, 3]},
+                {"id": 2, "names": ["eddsa"], "version": "1.2.0", "capacities": [2]},
+                {"id": 3, "names": ["eddsa"], "version": "2.0.0", "capacities": [2]}
+            ]}"#,
+        );
+        let registry = ProtocolRegistry::load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        // the newest compatible version that supports the capacity is chosen
+        assert_eq!(registry.resolve("eddsa", "1.0.0", 2), Ok(2));
+        assert_eq!(registry.resolve("eddsa", "1.1.0", 2), Ok(2));
+        assert_eq!(registry.resolve("eddsa", "1.0.0", 3), Ok(1));
+        assert_eq!(registry.resolve("ed25519", "1.0.0", 2), Ok(1));
+        assert_eq!(registry.resolve("eddsa", "2.0.0", 2), Ok(3));
+
+        assert_eq!(
+            registry.resolve("ecdsa", "1.0.0", 2),
+            Err(RelayError::UnknownProtocol)
+        );
+        assert_eq!(
+            registry.resolve("eddsa", "1.3.0", 2),
+            Err(RelayError::UnsupportedVersion)
+        );
+        assert_eq!(
+            registry.resolve("eddsa", "3.0.0", 2),
+            Err(RelayError::UnsupportedVersion)
+        );
+        assert_eq!(
+            registry.resolve("eddsa", "not a version", 2),
+            Err(RelayError::UnsupportedVersion)
+        );
+        assert_eq!(
+            registry.resolve("eddsa", "2.0.0", 3),
+            Err(RelayError::UnsupportedCapacity)
+        );
+
+        assert_eq!(registry.check(1, 3), Ok(()));
+        assert_eq!(registry.check(2, 3), Err(RelayError::UnsupportedCapacity));
+        assert_eq!(registry.check(4, 2), Err(RelayError::UnknownProtocol));
+    }
 }
@@ -0,0 +1,192 @@
+/// Validation of the payloads peers relay.
+/// The relay can not tell a well formed payload of a protocol from garbage on its own.
+/// A protocol can register a validator with the server, which checks every payload sent in a
+/// session of that protocol before it is relayed
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::common::EddsaMessage;
+use crate::error::RelayError;
+use crate::{MessagePayload, ProtocolIdentifier, RelayMessage, RelayPayload};
+
+/// Checks the payloads of the relay messages of a protocol
+pub trait PayloadValidator: Send + Sync {
+    /// Returns an error if the plain payload of the message is not one the protocol sends
+    /// in the round of the message
+    fn validate(&self, msg: &RelayMessage, payload: &MessagePayload) -> Result<(), RelayError>;
+}
+
+/// The payload validators of the protocols the server relays, by protocol identifier.
+/// The payloads of a protocol without a validator are relayed unchecked
+#[derive(Clone, Default)]
+pub struct PayloadValidators {
+    validators: HashMap<ProtocolIdentifier, Arc<dyn PayloadValidator>>,
+}
+
+impl PayloadValidators {
+    /// Checks the payloads of the given protocol with the validator,
+    /// replacing the validator it had, if any
+    pub fn register<V>(&mut self, protocol_id: ProtocolIdentifier, validator: V)
+    where
+        V: PayloadValidator + 'static,
+    {
+        self.validators.insert(protocol_id, Arc::new(validator));
+    }
+
+    /// Returns true if the payloads of the protocol are checked
+    pub fn contains(&self, protocol_id: ProtocolIdentifier) -> bool {
+        self.validators.contains_key(&protocol_id)
+    }
+
+    /// Checks the payload of the message with the validator of the given protocol.
+    /// Sealed payloads can only be checked by their recipient, so they are accepted
+    /// unchecked in point to point messages, and never in broadcast messages
+    pub fn validate(
+        &self,
+        protocol_id: ProtocolIdentifier,
+        msg: &RelayMessage,
+    ) -> Result<(), RelayError> {
+        let payload = match &msg.message {
+            RelayPayload::Plain(payload) => payload,
+            RelayPayload::Sealed(_) => {
+                return match msg.is_point_to_point() {
+                    true => Ok(()),
+                    false => Err(RelayError::SealedBroadcast),
+                };
+            }
+        };
+        match self.validators.get(&protocol_id) {
+            Some(validator) => validator.validate(msg, payload),
+            None => Ok(()),
+        }
+    }
+}
+
+// Validators can not be printed, only the protocols they check are
+impl fmt::Debug for PayloadValidators {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.validators.keys()).finish()
+    }
+}
+
+/// Decodes a multi-party EdDSA payload with the given types of contents, after checking it is
+/// the kind of message sent in the given round
+pub fn eddsa_payload<P, C, R, S>(
+    round: u32,
+    payload: &MessagePayload,
+) -> Result<EddsaMessage<P, C, R, S>, RelayError>
+where
+    P: Serialize + DeserializeOwned,
+    C: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+    S: Serialize + DeserializeOwned,
+{
+    let message = EddsaMessage::from_payload(payload)?;
+    if message.round() != round {
+        return Err(RelayError::InvalidPayload);
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{eddsa_payload, PayloadValidator, PayloadValidators};
+    use crate::common::EddsaMessage;
+    use crate::error::RelayError;
+    use crate::{MessagePayload, RelayMessage, RelayPayload, SealedPayload};
+
+    type TestMessage = EddsaMessage<String, String, String, String>;
+
+    // Accepts only the EdDSA messages of the round they are sent in
+    struct RoundValidator;
+
+    impl PayloadValidator for RoundValidator {
+        fn validate(&self, msg: &RelayMessage, payload: &MessagePayload) -> Result<(), RelayError> {
+            eddsa_payload::<String, String, String, String>(msg.round, payload).map(|_| ())
+        }
+    }
+
+    fn relay_message<P: Into<RelayPayload>>(
+        protocol_id: u32,
+        round: u32,
+        payload: P,
+    ) -> RelayMessage {
+        let mut msg = RelayMessage::new(1, protocol_id);
+        msg.set_message_params(round, vec![2], payload);
+        msg
+    }
+
+    #[test]
+    fn test_eddsa_payload() {
+        let pk = TestMessage::PublicKey("point".to_string()).to_payload();
+        assert_eq!(
+            Ok(TestMessage::PublicKey("point".to_string())),
+            eddsa_payload(0, &pk)
+        );
+        // the payload of another round
+        assert_eq!(
+            Err(RelayError::InvalidPayload),
+            eddsa_payload::<String, String, String, String>(1, &pk)
+        );
+        // EdDSA runs four rounds
+        assert_eq!(
+            Err(RelayError::InvalidPayload),
+            eddsa_payload::<String, String, String, String>(4, &pk)
+        );
+        assert_eq!(
+            Err(RelayError::InvalidPayload),
+            eddsa_payload::<String, String, String, String>(0, &"PUBLIC_KEY:::\"point\"".into())
+        );
+        let commitment = TestMessage::Commitment("{}".to_string()).to_payload();
+        assert_eq!(
+            Ok(TestMessage::Commitment("{}".to_string())),
+            eddsa_payload(1, &commitment)
+        );
+    }
+
+    #[test]
+    fn test_validators() {
+        let mut validators = PayloadValidators::default();
+        validators.register(1, RoundValidator);
+        assert!(validators.contains(1));
+        assert!(!validators.contains(0));
+
+        let pk = TestMessage::PublicKey("point".to_string()).to_payload();
+        assert_eq!(Ok(()), validators.validate(1, &relay_message(1, 0, pk)));
+        assert_eq!(
+            Err(RelayError::InvalidPayload),
+            validators.validate(1, &relay_message(1, 0, "garbage"))
+        );
+        // protocols without a validator are not checked
+        assert_eq!(
+            Ok(()),
+            validators.validate(0, &relay_message(0, 0, "garbage"))
+        );
+        // nor are sealed payloads
+        assert_eq!(
+            Ok(()),
+            validators.validate(1, &relay_message(1, 0, SealedPayload::default()))
+        );
+    }
+
+    #[test]
+    fn test_sealed_broadcast() {
+        let mut validators = PayloadValidators::default();
+        validators.register(1, RoundValidator);
+        // a sealed payload is not relayed unchecked to all the peers
+        let mut msg = relay_message(1, 0, SealedPayload::default());
+        msg.to = vec![1, 2, 3];
+        assert_eq!(
+            Err(RelayError::SealedBroadcast),
+            validators.validate(1, &msg)
+        );
+        assert_eq!(
+            Err(RelayError::SealedBroadcast),
+            validators.validate(0, &msg)
+        );
+    }
+}
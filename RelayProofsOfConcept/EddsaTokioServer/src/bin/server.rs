@@ -6,26 +6,103 @@
 //!
 //!
 //! To run the server: run this file and in another terminal, run:
-//!     cargo +nightly run --example connect 127.0.0.1:8080
+//!     cargo run --example connect 127.0.0.1:8080
 //! this will run a client that utilizes the server in some way
 //!
 use clap::{App, Arg, ArgMatches};
-use relay_server::RelayServer;
-use std::io;
+use log::{error, info};
+use relay_server::eddsa_validator::{
+    EddsaValidator, EDDSA_KEYGEN_PROTOCOL_ID, EDDSA_SIGN_PROTOCOL_ID,
+};
+use relay_server::{RelayServer, ServerConfig};
+use relay_server_common::identity::Identity;
+use relay_server_common::protocol::{ProtocolRegistry, PROTOCOLS_FILE};
+use relay_server_common::validator::PayloadValidators;
+use serde_derive::Deserialize;
+use std::fs::File;
+use std::io::{self, BufReader};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+/// Options of the server that can be given in a JSON config file.
+/// Options given on the command line take precedence
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    address: Option<String>,
+    participants: Option<u32>,
+    timeout: Option<u64>,
+    grace_period: Option<u64>,
+    transcripts: Option<PathBuf>,
+    key: Option<PathBuf>,
+    protocols: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    fn load(path: &str) -> Result<ConfigFile, String> {
+        let file = File::open(path).map_err(|err| format!("unable to read {}: {}", path, err))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|err| format!("{} is malformed: {}", path, err))
+    }
+}
 
 fn arg_matches<'a>() -> ArgMatches<'a> {
     App::new("relay-server")
         .arg(
             Arg::with_name("address")
-                .default_value("127.0.0.1:8080")
-                .value_name("<HOST:PORT>"),
+                .value_name("<HOST:PORT>")
+                .help("Listens on this address, 127.0.0.1:8080 by default"),
         )
         .arg(
             Arg::with_name("capacity")
-                .default_value("2")
                 .short("P")
-                .long("participants"),
+                .long("participants")
+                .takes_value(true)
+                .help("Default number of peers in a session, 2 if not given"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads the options of the server from this JSON file"),
+        )
+        .arg(
+            Arg::with_name("protocols")
+                .short("p")
+                .long("protocols")
+                .value_name("FILE")
+                .help("Supports the protocols declared in this file, reloaded on SIGHUP"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .short("t")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Aborts a session when a turn or round takes longer than this"),
+        )
+        .arg(
+            Arg::with_name("grace-period")
+                .short("g")
+                .long("grace-period")
+                .value_name("SECONDS")
+                .help("Lets a peer that disconnects resume within this time, instead of aborting"),
+        )
+        .arg(
+            Arg::with_name("transcripts")
+                .short("T")
+                .long("transcripts")
+                .value_name("DIR")
+                .help("Writes the transcript of every session to a file in this directory"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .value_name("FILE")
+                .help("Signs chain heads with the key in this file, generated if missing"),
         )
         .arg(
             Arg::with_name("verbose")
@@ -44,8 +121,8 @@ fn setup_logging(verbosity: u64) -> Result<(), fern::InitError> {
         0 => base_config.level(log::LevelFilter::Info),
         1 => base_config
             .level(log::LevelFilter::Debug)
-            .level_for("tokio_core", log::LevelFilter::Warn) // filter out tokio
-            .level_for("tokio_reactor", log::LevelFilter::Warn),
+            .level_for("tokio", log::LevelFilter::Warn) // filter out tokio
+            .level_for("tokio_util", log::LevelFilter::Warn),
         _2_or_more => base_config.level(log::LevelFilter::Trace),
     };
 
@@ -92,25 +169,93 @@ fn setup_logging(verbosity: u64) -> Result<(), fern::InitError> {
     Ok(())
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let matches = arg_matches();
 
+    let config = match matches.value_of("config") {
+        Some(path) => ConfigFile::load(path).unwrap_or_else(|err| {
+            eprintln!("Invalid config file: {}", err);
+            process::exit(1);
+        }),
+        None => ConfigFile::default(),
+    };
+
     let addr: SocketAddr = matches
         .value_of("address")
-        .unwrap()
+        .map(String::from)
+        .or(config.address)
+        .unwrap_or_else(|| String::from("127.0.0.1:8080"))
         .parse()
         .expect("Unable to parse socket address");
 
     let capacity: u32 = matches
         .value_of("capacity")
-        .unwrap()
-        .parse()
-        .expect("Invalid number of participants");
+        .map(|capacity| capacity.parse().expect("Invalid number of participants"))
+        .or(config.participants)
+        .unwrap_or(2);
+
+    let timeout: Option<Duration> = matches
+        .value_of("timeout")
+        .map(|seconds| seconds.parse().expect("Invalid timeout in seconds"))
+        .or(config.timeout)
+        .map(Duration::from_secs);
+
+    let grace_period: Option<Duration> = matches
+        .value_of("grace-period")
+        .map(|seconds| seconds.parse().expect("Invalid grace period in seconds"))
+        .or(config.grace_period)
+        .map(Duration::from_secs);
+
+    let transcript_dir: Option<PathBuf> = matches
+        .value_of("transcripts")
+        .map(PathBuf::from)
+        .or(config.transcripts);
+
+    let identity = match matches.value_of("key").map(PathBuf::from).or(config.key) {
+        Some(path) => Identity::load_or_generate(path).expect("Unable to load server key"),
+        None => Identity::generate(),
+    };
 
     let verbosity: u64 = matches.occurrences_of("verbose");
 
     setup_logging(verbosity).expect("failed to initialize logging.");
 
+    // a broken protocols file is reported before any peer can connect
+    let protocols_path = matches
+        .value_of("protocols")
+        .map(PathBuf::from)
+        .or(config.protocols)
+        .unwrap_or_else(|| PathBuf::from(PROTOCOLS_FILE));
+    let protocols = match ProtocolRegistry::load(&protocols_path) {
+        Ok(protocols) => protocols,
+        Err(err) => {
+            error!("Unable to start the relay server: {}", err);
+            process::exit(1);
+        }
+    };
+    info!(
+        "Supporting {} protocols from {:?}",
+        protocols.protocols.len(),
+        protocols_path
+    );
+
+    // malformed payloads of multi-party EdDSA are rejected rather than relayed
+    let mut validators = PayloadValidators::default();
+    validators.register(EDDSA_SIGN_PROTOCOL_ID, EddsaValidator);
+    validators.register(EDDSA_KEYGEN_PROTOCOL_ID, EddsaValidator);
+
     let server = RelayServer::new(addr);
-    server.start_server(capacity);
+    server
+        .start_server(ServerConfig {
+            capacity,
+            timeout,
+            grace_period,
+            transcript_dir,
+            identity,
+            protocols,
+            validators,
+        })
+        .await
+        .expect("Relay server failed");
 }
@@ -0,0 +1,149 @@
+//! Dumps the transcript of a relay session, as written by the server with `--transcripts DIR`.
+//!
+//! To print the whole transcript:
+//!     cargo run --bin transcript -- transcripts/session-1-1590000000000.jsonl
+//! Only the events of peer 2 in round 1:
+//!     cargo run --bin transcript -- <FILE> --peer 2 --round 1
+//!
+use chrono::{Local, TimeZone};
+use clap::{App, Arg, ArgMatches};
+use relay_server::transcript::{read_transcript, TranscriptEntry, TranscriptEvent};
+use relay_server_common::PeerIdentifier;
+use std::path::Path;
+
+fn arg_matches<'a>() -> ArgMatches<'a> {
+    App::new("transcript")
+        .about("Inspects the transcript of a relay session")
+        .arg(
+            Arg::with_name("file")
+                .required(true)
+                .value_name("FILE")
+                .help("Transcript file of the session"),
+        )
+        .arg(
+            Arg::with_name("peer")
+                .short("p")
+                .long("peer")
+                .value_name("PEER")
+                .help("Shows only the events the peer sent, received or is blamed in"),
+        )
+        .arg(
+            Arg::with_name("round")
+                .short("r")
+                .long("round")
+                .value_name("ROUND")
+                .help("Shows only the messages relayed in the round"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Prints the entries as they are stored"),
+        )
+        .get_matches()
+}
+
+// Returns true if the peer took part in the event
+fn involves(event: &TranscriptEvent, peer: PeerIdentifier) -> bool {
+    match event {
+        TranscriptEvent::Register { peer_id, .. } | TranscriptEvent::Resume { peer_id } => {
+            *peer_id == peer
+        }
+        TranscriptEvent::Relay { sender, to, .. } => *sender == peer || to.contains(&peer),
+        TranscriptEvent::Echo { .. } => true,
+        TranscriptEvent::Abort(abort) => {
+            abort.peer_number == peer
+                || abort.accused == Some(peer)
+                || abort.stalled_peers.contains(&peer)
+        }
+        TranscriptEvent::Completed => true,
+    }
+}
+
+fn describe(event: &TranscriptEvent) -> String {
+    match event {
+        TranscriptEvent::Register { peer_id, identity } => {
+            format!("peer {} registered with identity {}", peer_id, identity)
+        }
+        TranscriptEvent::Relay {
+            sender,
+            round,
+            to,
+            message,
+            ..
+        } => format!("round {}: peer {} -> {:?}: {}", round, sender, to, message),
+        TranscriptEvent::Echo {
+            sender,
+            round,
+            digest,
+        } => format!("round {}: peer {} echoed {}", round, sender, digest),
+        TranscriptEvent::Resume { peer_id } => format!("peer {} resumed", peer_id),
+        TranscriptEvent::Abort(abort) => format!(
+            "aborted by peer {}: {:?}, accused {:?}, stalled peers {:?}",
+            abort.peer_number, abort.reason, abort.accused, abort.stalled_peers
+        ),
+        TranscriptEvent::Completed => String::from("completed"),
+    }
+}
+
+fn print_entry(entry: &TranscriptEntry) {
+    let time = Local
+        .timestamp_millis_opt(entry.timestamp as i64)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+        .unwrap_or_else(|| entry.timestamp.to_string());
+    println!(
+        "[{}][session {}] {}",
+        time,
+        entry.session_id,
+        describe(&entry.event)
+    );
+}
+
+fn main() {
+    let matches = arg_matches();
+
+    let path = Path::new(matches.value_of("file").unwrap());
+    let peer: Option<PeerIdentifier> = matches
+        .value_of("peer")
+        .map(|peer| peer.parse().expect("Invalid peer number"));
+    let round: Option<u32> = matches
+        .value_of("round")
+        .map(|round| round.parse().expect("Invalid round"));
+
+    let entries = read_transcript(path).unwrap_or_else(|e| {
+        eprintln!("Unable to read transcript {:?}: {}", path, e);
+        std::process::exit(1);
+    });
+
+    let mut relayed = 0;
+    for entry in entries.iter() {
+        if let Some(peer) = peer {
+            if !involves(&entry.event, peer) {
+                continue;
+            }
+        }
+        if let Some(round) = round {
+            match entry.event {
+                TranscriptEvent::Relay { round: r, .. }
+                | TranscriptEvent::Echo { round: r, .. }
+                    if r == round => {}
+                _ => continue,
+            }
+        }
+        if let TranscriptEvent::Relay { .. } = entry.event {
+            relayed += 1;
+        }
+        if matches.is_present("json") {
+            println!("{}", serde_json::to_string(entry).unwrap());
+        } else {
+            print_entry(entry);
+        }
+    }
+    if !matches.is_present("json") {
+        println!(
+            "{} entries, {} relayed messages shown",
+            entries.len(),
+            relayed
+        );
+    }
+}
@@ -0,0 +1,71 @@
+/// Validation of the payloads of multi-party EdDSA.
+/// Every round of key generation and signing carries a single tagged EdDSA message,
+/// which the relay decodes with the types of the peers to check its points are on the curve
+use curv::GE;
+use multi_party_ed25519::protocols::aggsig::{SignFirstMsg, SignSecondMsg, Signature};
+
+use relay_server_common::common::EddsaMessage;
+use relay_server_common::error::RelayError;
+use relay_server_common::validator::{eddsa_payload, PayloadValidator};
+use relay_server_common::{MessagePayload, ProtocolIdentifier, RelayMessage};
+
+/// Identifier of multi-party EdDSA signing in the protocols file
+pub const EDDSA_SIGN_PROTOCOL_ID: ProtocolIdentifier = 1;
+
+/// Identifier of multi-party EdDSA key generation in the protocols file
+pub const EDDSA_KEYGEN_PROTOCOL_ID: ProtocolIdentifier = 3;
+
+/// The messages of multi-party EdDSA, with the types the peers send:
+/// the public key, the commitment, the ephemeral R and the partial signature
+pub type EddsaPayload = EddsaMessage<GE, SignFirstMsg, SignSecondMsg, Signature>;
+
+/// Accepts the payloads multi-party EdDSA sends in each round
+pub struct EddsaValidator;
+
+impl PayloadValidator for EddsaValidator {
+    fn validate(&self, msg: &RelayMessage, payload: &MessagePayload) -> Result<(), RelayError> {
+        eddsa_payload::<GE, SignFirstMsg, SignSecondMsg, Signature>(msg.round, payload).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EddsaPayload, EddsaValidator};
+    use multi_party_ed25519::protocols::aggsig::{KeyPair, Signature};
+    use relay_server_common::error::RelayError;
+    use relay_server_common::validator::PayloadValidator;
+    use relay_server_common::{MessagePayload, RelayMessage};
+
+    fn validate(round: u32, payload: MessagePayload) -> Result<(), RelayError> {
+        let mut msg = RelayMessage::new(1, 1);
+        msg.set_message_params(round, vec![1, 2], payload.clone());
+        EddsaValidator.validate(&msg, &payload)
+    }
+
+    #[test]
+    fn test_eddsa_payloads() {
+        let key = KeyPair::create();
+        let (_, first_msg, second_msg) = Signature::create_ephemeral_key_and_commit(&key, b"msg");
+
+        let pk_payload = EddsaPayload::PublicKey(key.public_key).to_payload();
+        assert_eq!(Ok(()), validate(0, pk_payload.clone()));
+        assert_eq!(
+            Ok(()),
+            validate(1, EddsaPayload::Commitment(first_msg.clone()).to_payload())
+        );
+        assert_eq!(
+            Ok(()),
+            validate(2, EddsaPayload::RMessage(second_msg).to_payload())
+        );
+
+        // the public key is not the payload of the second round
+        assert_eq!(Err(RelayError::InvalidPayload), validate(1, pk_payload));
+        // a public key with the content of another round
+        let commitment = serde_json::to_value(&first_msg).unwrap();
+        let wrong_content = serde_json::json!({"type": "PublicKey", "content": commitment});
+        assert_eq!(Err(RelayError::InvalidPayload), validate(0, wrong_content));
+        // a public key that is not a point
+        let not_a_point = serde_json::json!({"type": "PublicKey", "content": "not a point"});
+        assert_eq!(Err(RelayError::InvalidPayload), validate(0, not_a_point));
+    }
+}
@@ -1,4 +1,7 @@
+pub mod eddsa_validator;
 mod relay_server;
 mod relay_session;
+mod session_registry;
+pub mod transcript;
 
-pub use crate::relay_server::RelayServer;
+pub use crate::relay_server::{RelayServer, ServerConfig};
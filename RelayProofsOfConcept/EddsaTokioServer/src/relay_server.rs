@@ -1,181 +1,331 @@
-use futures::stream;
-use futures::sync::mpsc;
-use futures::{Future, Sink, Stream};
+use futures::future::join_all;
+use futures::{SinkExt, StreamExt};
 use log::{debug, error, info, warn};
+use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::codec::Framed;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio_util::codec::{FramedRead, FramedWrite};
 
-use crate::relay_session::{Client, RelaySession};
-use relay_server_common::{ClientMessageType, ServerMessage, ServerToClientCodec};
+use crate::relay_session::{Client, Peer, RelaySession};
+use crate::session_registry::SessionRegistry;
+use relay_server_common::error::RelayError;
+use relay_server_common::identity::Identity;
+use relay_server_common::protocol::ProtocolRegistry;
+use relay_server_common::validator::PayloadValidators;
+use relay_server_common::{
+    AbortReason, ClientMessage, ClientMessageType, ServerMessage, ServerResponse,
+    ServerToClientCodec,
+};
+
+// Number of messages that can be queued for a single client
+// before senders have to wait for it to be written
+const CLIENT_CHANNEL_SIZE: usize = 32;
+
+// How often the deadlines of the sessions are checked, when a timeout is set
+const DEADLINE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The configuration of the sessions served by a relay server
+#[derive(Debug)]
+pub struct ServerConfig {
+    // number of peers in a session, unless the protocol of the session says otherwise
+    pub capacity: u32,
+
+    // a session is aborted when a turn or round takes longer than this
+    pub timeout: Option<Duration>,
+
+    // a peer that disconnects can resume within this time, otherwise a disconnect aborts the session
+    pub grace_period: Option<Duration>,
+
+    // the transcript of every session is written to this directory, none are written if None
+    pub transcript_dir: Option<PathBuf>,
+
+    // the heads of the hash chains of the sessions are signed with this identity
+    pub identity: Identity,
+
+    // peers can register with these protocols, which are reloaded from their file on SIGHUP
+    pub protocols: ProtocolRegistry,
+
+    // the payloads of the protocols are checked with these validators before they are relayed
+    pub validators: PayloadValidators,
+}
+
+impl ServerConfig {
+    /// A configuration of sessions with the given capacity, that never expire
+    /// and are not written to transcripts, signed with a newly generated identity.
+    /// Peers can not register before protocols are added to it
+    pub fn new(capacity: u32) -> ServerConfig {
+        ServerConfig {
+            capacity,
+            timeout: None,
+            grace_period: None,
+            transcript_dir: None,
+            identity: Identity::generate(),
+            protocols: ProtocolRegistry::default(),
+            validators: PayloadValidators::default(),
+        }
+    }
+}
 
 pub struct RelayServer {
-    pub rs: Option<RelaySession>,
     addr: std::net::SocketAddr,
 }
 
 impl RelayServer {
     pub fn new(addr: SocketAddr) -> RelayServer {
-        RelayServer {
-            rs: None,
-            addr: addr,
-        }
+        RelayServer { addr }
     }
 
     /// Starts the relay server
-    pub fn start_server(&self, capacity: u32) {
-        // Create the event loop and TCP listener we'll accept connections on.
-        // let mut core = Core::new().unwrap();
-        // let handle = core.handle();
-
-        let listener = TcpListener::bind(&self.addr).unwrap();
+    pub async fn start_server(&self, config: ServerConfig) -> io::Result<()> {
+        let listener = TcpListener::bind(&self.addr).await?;
         info!("Listening on: {}", &self.addr);
+        RelayServer::serve(listener, config).await
+    }
 
-        // Create the session fot the relay server
-        // TODO: Relay sessions should start when a new client connects
-        let relay_session = Arc::new(RelaySession::new(capacity));
-
-        let srv = listener
-            .incoming()
-            .for_each(move |socket| {
-                // Got a new connection
-                info!("Server got a new connection");
-                // TODO TODO TODO
-                let addr = socket.peer_addr().unwrap();
-
-                // Frame the socket with JSON codec
-                //let framed_socket = ServerToClientCodec::new(false).framed(socket);
-                let framed_socket = Framed::new(socket, ServerToClientCodec::new(false));
-
-                // obtain a clone of the RelaySession
-                let relay_session_inner = Arc::clone(&relay_session); //relay_session.clone();
-
-                // create a channel of communication with the (potential) peer
-                let (tx, rx) = mpsc::channel(0);
-
-                // insert this client to the servers active_connections
-                relay_session_inner.insert_new_connection(addr.clone(), Client::new(tx));
-
-                // split the socket to reading part (stream) and writing part (sink)
-                let (to_client, from_client) = framed_socket.split();
-
-                // define future for receiving half
-                let relay_session_inner = Arc::clone(&relay_session);
-                let reader = from_client.for_each(move |msg| {
-                    let msg_type = msg.msg_type();
-
-                    // this is our main logic for receiving messages from peer
-                    match msg_type {
-                        ClientMessageType::Register => {
-                            let register = msg.register.unwrap();
-                            info!(
-                                "Got register message. protocol id requested: {}",
-                                register.protocol_id
-                            );
-                            let messages_to_send = relay_session_inner.register(
-                                addr,
-                                register.protocol_id,
-                                register.capacity,
-                            );
-                            RelayServer::send_messages(&messages_to_send)
-                        }
-                        ClientMessageType::RelayMessage => {
-                            let peer = relay_session_inner
-                                .get_peer_by_address(&addr)
-                                .unwrap_or_else(|| panic!("not a peer"));
-                            info!("Got relay message from {}", peer.peer_id);
-                            let relay_msg = msg.relay_message.unwrap().clone();
-                            let messages_to_send =
-                                relay_session_inner.relay_message(&addr, relay_msg);
-                            RelayServer::send_messages(&messages_to_send)
-                        }
-                        ClientMessageType::Abort => {
-                            let peer = relay_session_inner
-                                .get_peer_by_address(&addr)
-                                .unwrap_or_else(|| panic!("not a peer"));
-                            debug!("Got abort message from {}", peer.peer_id);
-                            let messages_to_send = relay_session_inner.abort(addr);
-                            RelayServer::send_messages(&messages_to_send)
-                        }
-                        ClientMessageType::Test => {
-                            let sender = relay_session_inner
-                                .get_sender_by_address(&addr)
-                                .unwrap_or_else(|| panic!("not a peer"));
-                            let msg = ServerMessage::new();
-                            RelayServer::send_single_message(sender, msg)
-                        }
-                        ClientMessageType::Undefined => {
-                            warn!("Got unknown or empty message");
-                            let messages_to_send = relay_session_inner.abort(addr);
-                            RelayServer::send_messages(&messages_to_send)
-                        }
-                    }
-                });
-
-                // define future for sending half
-                let writer = rx
-                    .map_err(|()| unreachable!("rx can't fail"))
-                    // fold on a stream (rx) takes an initial value (to_client, a Sink)
-                    // and run the given closure, for each value passed from the stream (message to send to
-                    // the client)
-                    .fold(to_client, |to_client, msg| to_client.send(msg))
-                    // this map will cleanly drop the writing half of the socket when done with all processing
-                    .map(|_| ());
-
-                // if any of the reading/writing half is done - the whole connection is finished
-                // this makes select a sensible combinator
-                let connection = reader.select(writer);
-
-                // map & map_err here are used for the case reading half or writing half is dropped
-                // in which case we will be dropping the other half as well
-                let relay_session_inner = Arc::clone(&relay_session);
-                tokio::spawn(
-                    connection
-                        .map(|_| ())
-                        .map_err(|(err, _)| {
-                            error!("ERROR OCCURED: {:?}", err);
-                            err
-                        })
-                        .then(move |_| {
-                            // connection is closed
-                            warn!("Disconnected");
-
-                            // this means either a peer disconnected - same as abort,
-                            // or an active connection closed - which is allowed
-                            let messages_to_send = relay_session_inner.abort(addr);
-                            RelayServer::send_messages(&messages_to_send)
-                        }),
-                );
+    /// Serves relay sessions with the given configuration to all connections accepted by listener
+    pub async fn serve(listener: TcpListener, config: ServerConfig) -> io::Result<()> {
+        info!(
+            "Signing chain heads with server key {}",
+            config.identity.public_key()
+        );
+        let expires = config.timeout.is_some() || config.grace_period.is_some();
+        // Create the registry holding all relay sessions of the server.
+        // Sessions are created when the first peer registers to them
+        let registry = SessionRegistry::new(config);
+        if expires {
+            tokio::spawn(RelayServer::expire_sessions(registry.clone()));
+        }
+        #[cfg(unix)]
+        tokio::spawn(RelayServer::reload_protocols(registry.clone()));
 
-                Ok(())
-            })
-            .map_err(|e| debug!("Error occured {}", e));
+        loop {
+            let (socket, addr) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    debug!("Error occured {}", e);
+                    continue;
+                }
+            };
+            // Got a new connection
+            info!("Server got a new connection");
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                RelayServer::handle_connection(registry, socket, addr).await;
+            });
+        }
+    }
 
-        // execute server
-        tokio::run(srv);
+    // Periodically aborts the sessions that passed their deadline,
+    // or whose disconnected peers did not resume in time
+    async fn expire_sessions(registry: SessionRegistry) {
+        let mut interval = tokio::time::interval(DEADLINE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let messages_to_send = registry.expire_sessions(Instant::now());
+            RelayServer::send_messages(messages_to_send).await;
+        }
+    }
+
+    // Reloads the protocols from their file on every SIGHUP.
+    // If the file is broken, the protocols that were loaded before are kept
+    #[cfg(unix)]
+    async fn reload_protocols(registry: SessionRegistry) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                error!("Unable to reload protocols on SIGHUP: {}", err);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            match registry.protocols().reload() {
+                Ok(protocols) => {
+                    info!("Reloaded {} protocols", protocols.protocols.len());
+                    registry.set_protocols(protocols);
+                }
+                Err(err) => error!("Keeping the current protocols: {}", err),
+            }
+        }
+    }
+
+    // Runs a single connection until either the reading or the writing half is done
+    async fn handle_connection(registry: SessionRegistry, socket: TcpStream, addr: SocketAddr) {
+        // create a channel of communication with the (potential) peer
+        let (tx, mut rx) = mpsc::channel(CLIENT_CHANNEL_SIZE);
+
+        // insert this client to the servers active_connections
+        registry.insert_new_connection(addr, Client::new(tx));
+
+        // split the socket to reading part (stream) and writing part (sink),
+        // each framed with a codec that starts in JSON
+        let (reading, writing) = socket.into_split();
+        let mut from_client = FramedRead::new(reading, ServerToClientCodec::new(false));
+        let mut to_client = FramedWrite::new(writing, ServerToClientCodec::new(false));
+
+        // sending half: write every message passed on the channel to the client
+        let writer = async move {
+            while let Some(msg) = rx.recv().await {
+                let hello = match msg.response {
+                    Some(ServerResponse::Hello(format)) => Some(format),
+                    _ => None,
+                };
+                to_client.send(msg).await?;
+                // the answer to a hello is the last message in the previous format
+                if let Some(format) = hello {
+                    to_client.encoder_mut().set_format(format);
+                }
+            }
+            Ok::<(), io::Error>(())
+        };
+
+        // receiving half: handle every message read from the client
+        let reader = async {
+            while let Some(msg) = from_client.next().await {
+                let msg = msg?;
+                // the client writes everything after its hello in the format it asked for
+                if let Some(hello) = &msg.hello {
+                    from_client.decoder_mut().set_format(hello.format);
+                }
+                let messages_to_send = RelayServer::handle_client_message(&registry, addr, msg);
+                RelayServer::send_messages(messages_to_send).await;
+            }
+            Ok::<(), io::Error>(())
+        };
+
+        // if any of the reading/writing half is done - the whole connection is finished
+        let result = tokio::select! {
+            result = reader => result,
+            result = writer => result,
+        };
+        if let Err(err) = result {
+            error!("ERROR OCCURED: {:?}", err);
+        }
+
+        // connection is closed
+        warn!("Disconnected");
+
+        // this means either a peer disconnected - same as abort, unless it can still resume,
+        // or an active connection closed - which is allowed
+        let messages_to_send = registry.disconnect(addr, Instant::now());
+        registry.remove_connection(&addr);
+        RelayServer::send_messages(messages_to_send).await;
+    }
+
+    // this is our main logic for receiving messages from peer
+    // returns the messages to send as a result
+    fn handle_client_message(
+        registry: &SessionRegistry,
+        addr: SocketAddr,
+        msg: ClientMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match msg.msg_type() {
+            ClientMessageType::Register => {
+                let register = msg.register.unwrap();
+                info!(
+                    "Got register message. session id requested: {}, protocol id requested: {}",
+                    register.session_id, register.protocol_id
+                );
+                registry.request_challenge(addr, register)
+            }
+            ClientMessageType::ChallengeResponse => {
+                debug!("Got challenge response from {}", addr);
+                let challenge_response = msg.challenge_response.unwrap();
+                let messages_to_send =
+                    registry.respond_to_challenge(addr, &challenge_response.signature);
+                debug!(
+                    "Number of active sessions: {}",
+                    registry.number_of_sessions()
+                );
+                messages_to_send
+            }
+            ClientMessageType::RelayMessage => {
+                let (_, peer) = match RelayServer::get_peer(registry, &addr) {
+                    Some(found) => found,
+                    None => return registry.reject_unregistered(addr),
+                };
+                info!("Got relay message from {}", peer.peer_id);
+                registry.relay_message(addr, msg)
+            }
+            ClientMessageType::Echo => {
+                debug!("Got echo message from {}", addr);
+                registry.relay_echo(addr, msg)
+            }
+            ClientMessageType::Hello => {
+                let format = msg.hello.unwrap().format;
+                debug!("Got hello from {}, switching to {:?}", addr, format);
+                let mut response = ServerMessage::new();
+                response.response = Some(ServerResponse::Hello(format));
+                match registry.get_sender_by_address(&addr) {
+                    Some(sender) => vec![(response, sender)],
+                    None => vec![],
+                }
+            }
+            ClientMessageType::Abort => {
+                let (_, peer) = match RelayServer::get_peer(registry, &addr) {
+                    Some(found) => found,
+                    None => return registry.reject_unregistered(addr),
+                };
+                debug!("Got abort message from {}", peer.peer_id);
+                registry.relay_abort(addr, msg.abort.unwrap())
+            }
+            ClientMessageType::Resume => {
+                let resume = msg.resume.unwrap();
+                info!(
+                    "Got resume message. session id requested: {}",
+                    resume.session_id
+                );
+                registry.resume(addr, resume)
+            }
+            ClientMessageType::Done => {
+                debug!("Got done message from {}", addr);
+                registry.complete(addr)
+            }
+            ClientMessageType::Test => match registry.get_session_by_address(&addr) {
+                Some(_) => match registry.get_sender_by_address(&addr) {
+                    Some(sender) => vec![(ServerMessage::new(), sender)],
+                    None => vec![],
+                },
+                None => registry.reject_unregistered(addr),
+            },
+            ClientMessageType::Undefined => {
+                warn!("Got unknown or empty message");
+                match registry.get_session_by_address(&addr) {
+                    Some(_) => registry.abort(addr, AbortReason::InvalidMessage),
+                    None => registry.error_response(addr, RelayError::MalformedMessage),
+                }
+            }
+        }
     }
 
-    // Recieves a vector of tuples, of a message and a Sink,
-    // Sends the message to the the Sink
-    pub fn send_messages<E: 'static + Send>(
-        messages_to_send: &Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>,
-    ) -> Box<dyn Future<Item = (), Error = E> + Send> {
-        let sends = messages_to_send
-            .iter()
-            .map(|(msg, sink)| sink.clone().send(msg.clone()));
-        let send_stream = stream::futures_unordered(sends).then(|_| Ok(()));
-        Box::new(send_stream.for_each(|()| Ok(())))
+    // Returns the session of the peer in addr and the peer itself,
+    // or None if addr is not registered as a peer
+    fn get_peer(
+        registry: &SessionRegistry,
+        addr: &SocketAddr,
+    ) -> Option<(Arc<RelaySession>, Peer)> {
+        let relay_session = registry.get_session_by_address(addr)?;
+        let peer = relay_session.get_peer_by_address(addr)?;
+        Some((relay_session, peer))
     }
 
-    // Send a Server message to a specific Sink
-    pub fn send_single_message<E: 'static + Send>(
-        tx: mpsc::Sender<ServerMessage>,
-        response: ServerMessage,
-    ) -> Box<dyn Future<Item = (), Error = E> + Send> {
-        let sends = vec![tx.clone().send(response.clone())];
-        let send_stream = stream::futures_unordered(sends).then(|_| Ok(()));
-        Box::new(send_stream.for_each(|()| Ok(())))
+    // Recieves a vector of tuples, of a message and a Sender,
+    // Sends each message to its Sender
+    pub async fn send_messages(
+        messages_to_send: Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>,
+    ) {
+        let sends = messages_to_send.into_iter().map(|(msg, tx)| async move {
+            // the receiving connection might have already closed
+            if tx.send(msg).await.is_err() {
+                debug!("Failed to send message, receiver is closed");
+            }
+        });
+        join_all(sends).await;
     }
 }
@@ -1,21 +1,33 @@
-use futures::sync::mpsc;
 use log::{debug, info, warn};
-use std::collections::HashMap;
+use parking_lot::RwLock;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 
 use relay_server_common::{
-    AbortMessage, PeerIdentifier, ProtocolIdentifier, RelayMessage, ServerMessage, ServerResponse,
+    AbortMessage, AbortReason, ClientMessage, IdentityKey, PeerIdentifier, ProtocolIdentifier,
+    RelayMessage, Roster, RosterEntry, ServerMessage, ServerResponse, SessionIdentifier,
+    SignedChainHead, SignedEncryptionKey,
 };
 
-use relay_server_common::common::{NOT_A_PEER, NOT_YOUR_TURN, STATE_NOT_INITIALIZED};
+use relay_server_common::chain::HashChain;
+use relay_server_common::error::RelayError;
+use relay_server_common::identity::{verify_message_signature, Identity};
 
-use relay_server_common::protocol::ProtocolDescriptor;
+use relay_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry, RelayMode, RoundKind};
+use relay_server_common::validator::PayloadValidators;
+
+use crate::transcript::{Transcript, TranscriptEvent};
 
 // Represents the communication channel to remote client
 #[derive(Clone, Debug)]
 pub struct Client {
-    tx: mpsc::Sender<ServerMessage>,
+    pub(crate) tx: mpsc::Sender<ServerMessage>,
 }
 
 impl Client {
@@ -24,23 +36,69 @@ impl Client {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct Peer {
     pub peer_id: PeerIdentifier,
+    // the identity key the peer proved it holds when registering
+    pub identity: IdentityKey,
+    // the key other peers seal point to point payloads for this peer with
+    pub encryption_key: SignedEncryptionKey,
     client: Client,
     pub registered: bool,
+    // did the peer declare it is done with the protocol
+    pub done: bool,
+    // token the peer can resume its registration with, empty if resuming is not allowed
+    resume_token: String,
+    // relay messages and echoes delivered to the peer, replayed to it when it resumes
+    outbox: Vec<ServerMessage>,
+    // time the connection of the peer was lost, it may resume until the grace period ends
+    pub disconnected_at: Option<Instant>,
 }
 
 impl Peer {
     pub fn new(client: Client) -> Peer {
         Peer {
             peer_id: 0,
+            identity: IdentityKey::new(),
+            encryption_key: SignedEncryptionKey::default(),
             client,
             registered: false,
+            done: false,
+            resume_token: String::new(),
+            outbox: Vec::new(),
+            disconnected_at: None,
         }
     }
 }
 
+// The resumption token is left out, whoever holds it can take the place of the peer
+impl fmt::Debug for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Peer")
+            .field("peer_id", &self.peer_id)
+            .field("identity", &self.identity)
+            .field("encryption_key", &self.encryption_key)
+            .field("client", &self.client)
+            .field("registered", &self.registered)
+            .field("done", &self.done)
+            .field("resume_token", &"<redacted>")
+            .field("outbox", &self.outbox)
+            .field("disconnected_at", &self.disconnected_at)
+            .finish()
+    }
+}
+
+// A message of a round, by its sender and its recipient.
+// A broadcast message has no single recipient, a peer sends one in a round
+type RoundKey = (PeerIdentifier, Option<PeerIdentifier>);
+
+fn round_key(protocol: &ProtocolDescriptor, msg: &RelayMessage) -> RoundKey {
+    match protocol.round_kind(msg.round) {
+        Some(RoundKind::PointToPoint) => (msg.peer_number, msg.to.first().cloned()),
+        _ => (msg.peer_number, None),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum RelaySessionState {
     Empty,
@@ -50,31 +108,77 @@ pub enum RelaySessionState {
     Initialized,
 
     Aborted,
+
+    Completed,
+}
+
+/// The configuration a relay session is created with
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    // number of peers in the session, unless its protocol says otherwise
+    pub capacity: u32,
+
+    // time the peers have to send their message in each turn or round, no deadline if None
+    pub timeout: Option<Duration>,
+
+    // time a disconnected peer has to resume before the session is aborted,
+    // peers can not resume if None
+    pub grace_period: Option<Duration>,
+
+    // the identity the heads of the chain of the session are signed with
+    pub server_identity: Arc<Identity>,
+
+    // the protocols peers can register to the session with
+    pub protocols: Arc<ProtocolRegistry>,
+
+    // the validators of the payloads relayed in the session
+    pub validators: Arc<PayloadValidators>,
 }
 
 #[derive(Debug, Clone)]
 pub struct RelaySession {
-    peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
+    session_id: SessionIdentifier,
 
-    active_peers: Arc<RwLock<u32>>,
+    peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
 
     protocol: Arc<RwLock<ProtocolDescriptor>>,
 
     state: Arc<RwLock<RelaySessionState>>,
+
+    // messages of the current round by sender and recipient, relayed once every peer sent
+    // its messages (Round mode), or of the current turn (Turn mode)
+    round_messages: Arc<RwLock<BTreeMap<RoundKey, RelayMessage>>>,
+
+    // the rounds every peer echoed, as (peer, round)
+    echoes: Arc<RwLock<BTreeSet<(PeerIdentifier, u32)>>>,
+
+    // time the peers have to send their message in each turn or round, no deadline if None
+    timeout: Option<Duration>,
+
+    // deadline of the current turn or round, set once the session is initialized
+    deadline: Arc<RwLock<Option<Instant>>>,
+
+    // time a disconnected peer has to resume before the session is aborted,
+    // peers can not resume if None
+    grace_period: Option<Duration>,
+
+    // record of everything relayed in the session, none is kept if None
+    transcript: Option<Arc<Transcript>>,
+
+    // hash chain over every message relayed in the session
+    chain: Arc<RwLock<HashChain>>,
+
+    // the identity the server signs the heads of the chain with
+    server_identity: Arc<Identity>,
+
+    // the protocols peers can register to the session with
+    protocols: Arc<ProtocolRegistry>,
+
+    // the validators of the payloads of the protocols
+    validators: Arc<PayloadValidators>,
 }
 
 impl RelaySession {
-    /// Returns the current number of active peers.
-    /// If a peer disconnects, it should be removed from the active peers
-    fn get_number_of_active_peers(&self) -> u32 {
-        self.peers
-            .read()
-            .unwrap()
-            .iter()
-            .filter(|(_, p)| p.registered)
-            .fold(0, |acc, _| acc + 1)
-    }
-
     /// Register a new peer to this relay session
     /// after adding this address as a peer,
     /// the state might change to either Uninitialized (if this is the first peer registering)
@@ -82,56 +186,87 @@ impl RelaySession {
     pub fn register_new_peer(
         &self,
         addr: SocketAddr,
+        entry: RosterEntry,
         protocol_id: ProtocolIdentifier,
         capacity: u32,
     ) -> Option<u32> {
-        let _addr = &addr;
-        let number_of_active_peers = self.get_number_of_active_peers();
-
+        // the number of the peer and the state of the session are decided under the same locks,
+        // so concurrent registrations can not take the same number or miss the transition
+        let mut peers = self.peers.write();
+        let mut state = self.state.write();
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        info!("-----------------PEERS: {:?}---------------", self.peers);
-        match self.can_register(_addr, protocol_descriptor) {
-            true => {
-                let mut peers = self.peers.write().unwrap();
-                let peer = peers
-                    .get_mut(_addr)
-                    .unwrap_or_else(|| panic!("No conection"));
-
-                // activate this connection as a peer
-                peer.registered = true;
-                peer.peer_id = number_of_active_peers + 1;
-                // if needed, set the ProtocolDescriptor for this sessuib
-                // and change the state
-                let state = self.state();
-                match state {
-                    RelaySessionState::Empty => {
-                        self.set_protocol(ProtocolDescriptor::new(protocol_id, capacity));
-                        self.set_state(RelaySessionState::Uninitialized);
-                    }
-                    _ => {}
-                }
-                //if self.protocol.clone().into_inner().capacity == number_of_active_peers + 1 {
-                if self.protocol().capacity == number_of_active_peers + 1 {
-                    self.set_state(RelaySessionState::Initialized);
-                }
-                return Some(number_of_active_peers + 1); //peer_id
-            }
-            false => {
-                warn!("Unable to register {:}", addr); // error
-                None
-            }
+        if !self.can_register_with(&peers, &state, &addr, &entry.identity, protocol_descriptor) {
+            warn!("Unable to register {:}", addr); // error
+            return None;
+        }
+        // the lowest number no registered peer holds, numbers of peers that left are reused
+        let peer_id = (1..=capacity).find(|peer_id| {
+            !peers
+                .values()
+                .any(|peer| peer.registered && peer.peer_id == *peer_id)
+        })?;
+        // can_register checked there is a connection for this address
+        let peer = peers.get_mut(&addr)?;
+
+        // activate this connection as a peer
+        peer.registered = true;
+        peer.peer_id = peer_id;
+        peer.identity = entry.identity;
+        peer.encryption_key = entry.encryption_key;
+        self.record(TranscriptEvent::Register {
+            peer_id,
+            identity: peer.identity.clone(),
+        });
+        // if needed, set the ProtocolDescriptor for this sessuib
+        // and change the state
+        if *state == RelaySessionState::Empty {
+            let mut protocol = ProtocolDescriptor::with_mode(
+                protocol_id,
+                capacity,
+                self.protocols.relay_mode(protocol_id),
+            );
+            protocol.schedule = self.protocols.schedule(protocol_id);
+            protocol.echo = self.protocols.echo_broadcast(protocol_id);
+            self.set_protocol(protocol);
+            *state = RelaySessionState::Uninitialized;
+        }
+        let number_of_active_peers = peers.values().filter(|p| p.registered).count() as u32;
+        if self.protocol().capacity == number_of_active_peers {
+            *state = RelaySessionState::Initialized;
+            self.reset_deadline();
         }
+        Some(peer_id)
     }
 
     /// Checks if it is possible for this address
-    /// to register as a peer in this session
-    fn can_register(&self, addr: &SocketAddr, protocol: ProtocolDescriptor) -> bool {
-        match self.state() {
+    /// to register as a peer with the given identity in this session.
+    /// A registration checks this under the locks it registers the peer with
+    #[cfg(test)]
+    fn can_register(
+        &self,
+        addr: &SocketAddr,
+        identity: &IdentityKey,
+        protocol: ProtocolDescriptor,
+    ) -> bool {
+        let peers = self.peers.read();
+        self.can_register_with(&peers, &self.state(), addr, identity, protocol)
+    }
+
+    // Checks if the address can register with the given peers and state, held by the caller
+    fn can_register_with(
+        &self,
+        peers: &HashMap<SocketAddr, Peer>,
+        state: &RelaySessionState,
+        addr: &SocketAddr,
+        identity: &IdentityKey,
+        protocol: ProtocolDescriptor,
+    ) -> bool {
+        match state {
             // if this is the first peer to register
             // check that the protocol is valid
             RelaySessionState::Empty => {
                 debug!("Checking if protocol description is valid");
-                if !relay_server_common::protocol::is_valid_protocol(&protocol) {
+                if !self.protocols.is_valid_protocol(&protocol) {
                     warn!("Protocol is invalid");
 
                     return false;
@@ -152,16 +287,28 @@ impl RelaySession {
                 return false;
             }
         }
+        // an identity can be registered only once in a session
+        if peers
+            .values()
+            .any(|peer| peer.registered && &peer.identity == identity)
+        {
+            warn!("Identity {:} is already registered", identity);
+            return false;
+        }
         // register the peer iff it has an active connection and did not register yet
-        if let Some(peer) = self.peers.read().unwrap().get(addr) {
+        if let Some(peer) = peers.get(addr) {
             return !peer.registered;
         }
         false
     }
 
     /// Check if this relay message sent from the given SocketAddr
-    /// and is valid to send to rest of the peers
-    fn can_relay(&self, from: &SocketAddr, msg: &RelayMessage) -> Result<(), &'static str> {
+    /// is signed by the peer and is valid to send to rest of the peers
+    fn can_relay(&self, from: &SocketAddr, client_msg: &ClientMessage) -> Result<(), RelayError> {
+        let msg = client_msg
+            .relay_message
+            .as_ref()
+            .ok_or(RelayError::CantRelay)?;
         debug!("Checking if {:} can relay", msg.peer_number);
         debug!("Server state: {:?}", self.state());
         debug!("Turn of peer #: {:}", self.protocol().next());
@@ -172,7 +319,7 @@ impl RelaySession {
             }
             _ => {
                 debug!("Relay sessions state is not initialized");
-                return Err(STATE_NOT_INITIALIZED);
+                return Err(RelayError::NotInitialized);
             }
         }
         // validate the sender in the message (peer_number field) is the peer associated with this address
@@ -182,32 +329,117 @@ impl RelaySession {
         // if peer is present and registered
         if let Some(p) = peer {
             if p.registered && p.peer_id == sender {
+                // the message must be sent in the current round, which the protocol runs
+                let protocol = self.protocol();
+                if !protocol.is_valid_round(msg.round) {
+                    return Err(RelayError::RoundOutOfRange);
+                }
+                if msg.round != protocol.round() {
+                    return Err(RelayError::WrongRound);
+                }
+                // the recipients must fit the kind of the round, if the protocol declares it
+                if let Some(kind) = protocol.round_kind(msg.round) {
+                    if !kind.admits(msg, protocol.capacity) {
+                        return Err(RelayError::WrongRoundKind);
+                    }
+                }
+                // the message must be signed by the identity of the peer, for the current round
+                verify_message_signature(client_msg, self.session_id, msg.round, &p.identity)?;
+                // the relay must only ever see the ciphertext of point to point payloads
+                if msg.is_point_to_point() && !msg.message.is_sealed() {
+                    return Err(RelayError::UnsealedMessage);
+                }
+                // the payload must be one the protocol sends in the round
+                self.validators.validate(protocol.id, msg)?;
                 // check if it is this peers turn
-                if self.protocol().next() == p.peer_id {
-                    return Ok(());
+                if protocol.mode == RelayMode::Turn && protocol.next() != p.peer_id {
+                    return Err(RelayError::NotYourTurn);
+                }
+                // a peer sends a single message in each round, or one to each recipient
+                // in a point to point round
+                if self
+                    .round_messages
+                    .read()
+                    .contains_key(&round_key(&protocol, msg))
+                {
+                    return Err(RelayError::AlreadySentInRound);
                 } else {
-                    return Err(NOT_YOUR_TURN);
+                    return Ok(());
                 }
             }
         }
-        return Err(NOT_A_PEER);
+        Err(RelayError::NotAPeer)
     }
 }
 
 impl RelaySession {
-    /// Creates a new Relay Session with default (empty) fields
-    /// and an Empty state
-    pub fn new(capacity: u32) -> RelaySession {
+    /// Creates a new Relay Session with the given configuration,
+    /// default (empty) fields and an Empty state
+    pub fn new(session_id: SessionIdentifier, config: SessionConfig) -> RelaySession {
         RelaySession {
-            peers: Arc::new(RwLock::new(HashMap::new())),
+            session_id,
 
-            active_peers: Arc::new(RwLock::new(0)),
+            peers: Arc::new(RwLock::new(HashMap::new())),
 
             protocol: Arc::new(RwLock::new(
-                relay_server_common::protocol::ProtocolDescriptor::new(0, capacity),
+                relay_server_common::protocol::ProtocolDescriptor::new(0, config.capacity),
             )),
 
             state: Arc::new(RwLock::new(RelaySessionState::Empty)),
+
+            round_messages: Arc::new(RwLock::new(BTreeMap::new())),
+
+            echoes: Arc::new(RwLock::new(BTreeSet::new())),
+
+            timeout: config.timeout,
+
+            deadline: Arc::new(RwLock::new(None)),
+
+            grace_period: config.grace_period,
+
+            transcript: None,
+
+            chain: Arc::new(RwLock::new(HashChain::new(session_id))),
+
+            server_identity: config.server_identity,
+
+            protocols: config.protocols,
+
+            validators: config.validators,
+        }
+    }
+
+    /// Returns the current head of the chain, signed by the server
+    pub fn signed_head(&self) -> SignedChainHead {
+        let head = self.chain.read().head();
+        self.server_identity.sign_chain_head(head)
+    }
+
+    // Attach the current head of the chain to every message that does not carry a head yet
+    fn stamp(
+        &self,
+        mut messages_to_send: Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        if messages_to_send.is_empty() {
+            return messages_to_send;
+        }
+        let head = self.signed_head();
+        messages_to_send
+            .iter_mut()
+            .filter(|(msg, _)| msg.chain_head.is_none())
+            .for_each(|(msg, _)| msg.chain_head = Some(head.clone()));
+        messages_to_send
+    }
+
+    /// Records the events of the session to the given transcript from now on
+    pub fn set_transcript(&mut self, transcript: Transcript) {
+        self.transcript = Some(Arc::new(transcript));
+    }
+
+    // Appends the event to the transcript of the session, if it keeps one
+    fn record(&self, event: TranscriptEvent) {
+        if let Some(transcript) = &self.transcript {
+            transcript.record(event);
         }
     }
 
@@ -215,63 +447,196 @@ impl RelaySession {
     /// the connection is NOT an active peer until it is registered to the session
     /// by sending a register message
     pub fn insert_new_connection(&self, addr: SocketAddr, client: Client) {
-        self.peers.write().unwrap().insert(addr, Peer::new(client));
+        self.peers.write().insert(addr, Peer::new(client));
     }
 
     /// Removes a connection from the peers collection
-    fn remove(&self, addr: &SocketAddr) -> Option<Peer> {
-        self.peers.write().unwrap().remove(addr)
-    }
-
-    /// Try reutrn a Sender of a specific peer by its address.
-    /// The Sender can be used to send ServerMessages to the server
-    pub fn get_sender_by_address(&self, addr: &SocketAddr) -> Option<mpsc::Sender<ServerMessage>> {
-        if let Some(peer) = self.get_peer_by_address(&addr) {
-            Some(peer.client.tx.clone())
-        } else {
-            None
-        }
+    pub fn remove(&self, addr: &SocketAddr) -> Option<Peer> {
+        self.peers.write().remove(addr)
     }
 
-    /// Receives the sender's address and a message
+    /// Receives the sender's address and a signed client message
     /// If the message can be relayed, returns a vector of tupltes,
-    /// with the message as the first member, and a Sender to recipient as the second
+    /// with the message as the first member, and a Sender to recipient as the second.
+    /// In Round mode, the messages are held back until all the peers sent their messages for the round
     pub fn relay_message(
         &self,
         from: &SocketAddr,
-        msg: RelayMessage,
+        client_msg: ClientMessage,
     ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
         let mut server_msg = ServerMessage::new();
-        let sender = self.get_peer_by_address(from).unwrap();
+        let sender = match self.get_peer_by_address(from) {
+            Some(sender) => sender,
+            None => {
+                warn!("{:} is not a peer of this session", from);
+                return vec![];
+            }
+        };
         let sender_id = sender.peer_id;
-        let can_relay = self.can_relay(from, &msg);
-        match can_relay {
+        let can_relay = self.can_relay(from, &client_msg);
+        let messages_to_send = match can_relay {
             Ok(()) => {
-                server_msg.relay_message = Some(msg.clone());
-                let peers = self.peers.read().unwrap();
-                let messages_to_send = peers
-                    .values()
-                    .filter(|peer| {
-                        let id = &(peer.peer_id as PeerIdentifier);
-                        msg.to.contains(id) && peer.registered
-                    })
-                    .map(|peer| (server_msg.clone(), peer.client.tx.clone()))
-                    .collect();
-                self.protocol.write().unwrap().advance_turn();
-
-                debug!(
-                    "Sending relay message from peer {:?} to: {:?}",
-                    sender_id, msg.to
-                );
-                messages_to_send
+                let mut msg = client_msg.relay_message.unwrap();
+                // attribute the message to the identity of the sender
+                msg.from = sender.identity.clone();
+                let protocol = self.protocol();
+                let messages_per_peer = protocol.messages_per_peer(msg.round);
+                let mut round_messages = self.round_messages.write();
+                round_messages.insert(round_key(&protocol, &msg), msg.clone());
+                match protocol.mode {
+                    RelayMode::Turn => {
+                        // the turn passes once the peer sent all its messages of the round
+                        let sent = round_messages
+                            .keys()
+                            .filter(|(sender, _)| *sender == sender_id)
+                            .count();
+                        if sent == messages_per_peer as usize {
+                            round_messages.clear();
+                            self.protocol.write().advance_turn();
+                            self.reset_deadline();
+                        }
+                        drop(round_messages);
+                        let mut messages_to_send = self.deliver(&msg);
+                        messages_to_send.extend(self.complete_schedule());
+                        messages_to_send
+                    }
+                    RelayMode::Round => {
+                        if round_messages.len() < (protocol.capacity * messages_per_peer) as usize
+                        {
+                            debug!(
+                                "Holding message of peer {:?} until round is complete",
+                                sender_id
+                            );
+                            return vec![];
+                        }
+                        let round = std::mem::take(&mut *round_messages);
+                        let completed = self.protocol.write().advance_round();
+                        self.reset_deadline();
+                        info!("Round {} is complete, relaying all messages", completed - 1);
+                        let mut messages_to_send: Vec<_> =
+                            round.values().flat_map(|msg| self.deliver(msg)).collect();
+                        messages_to_send.extend(self.complete_schedule());
+                        messages_to_send
+                    }
+                }
             }
             Err(err_msg) => {
                 // send an error response to sender
                 warn!("Peer {:} can not relay", sender_id);
-                server_msg.response = Some(ServerResponse::ErrorResponse(String::from(err_msg)));
+                server_msg.response = Some(ServerResponse::ErrorResponse(err_msg));
                 vec![(server_msg, sender.client.tx.clone())]
             }
+        };
+        self.stamp(messages_to_send)
+    }
+
+    /// Receives the sender's address and its signed echo of the digest of a completed round.
+    /// If the protocol echoes broadcast messages, the echo is sent as it was signed
+    /// to all the other peers, which verify it themselves. Like relay messages, echoes are
+    /// kept for peers that may resume.
+    /// Otherwise an error response is returned to the sender.
+    /// Once all the peers echoed all the broadcast rounds of the protocol, the session is Completed
+    pub fn relay_echo(
+        &self,
+        from: &SocketAddr,
+        client_msg: ClientMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let sender = match self.get_peer_by_address(from) {
+            Some(sender) => sender,
+            None => return vec![],
+        };
+        let messages_to_send = match self.can_echo(&sender, &client_msg) {
+            Ok(()) => {
+                let echo = client_msg.echo.clone().unwrap();
+                self.record(TranscriptEvent::Echo {
+                    sender: sender.peer_id,
+                    round: echo.round,
+                    digest: echo.digest,
+                });
+                self.echoes.write().insert((sender.peer_id, echo.round));
+                let mut server_msg = ServerMessage::new();
+                server_msg.echo = Some(client_msg);
+                server_msg.chain_head = Some(self.signed_head());
+                let mut messages_to_send =
+                    self.send_to_peers(server_msg, |peer| peer.peer_id != sender.peer_id);
+                messages_to_send.extend(self.complete_schedule());
+                messages_to_send
+            }
+            Err(err_msg) => {
+                warn!("Peer {:} can not echo: {}", sender.peer_id, err_msg);
+                let mut server_msg = ServerMessage::new();
+                server_msg.response = Some(ServerResponse::ErrorResponse(err_msg));
+                vec![(server_msg, sender.client.tx.clone())]
+            }
+        };
+        self.stamp(messages_to_send)
+    }
+
+    // Check the echo is signed by the sender, for a round that was completed
+    fn can_echo(&self, sender: &Peer, client_msg: &ClientMessage) -> Result<(), RelayError> {
+        let echo = client_msg.echo.as_ref().ok_or(RelayError::CantRelay)?;
+        if self.state() != RelaySessionState::Initialized {
+            return Err(RelayError::NotInitialized);
+        }
+        if !sender.registered || echo.peer_number != sender.peer_id {
+            return Err(RelayError::NotAPeer);
+        }
+        let protocol = self.protocol();
+        if !protocol.echo {
+            return Err(RelayError::EchoNotEnabled);
+        }
+        if echo.round >= protocol.round() {
+            return Err(RelayError::WrongRound);
         }
+        verify_message_signature(client_msg, self.session_id, echo.round, &sender.identity)
+    }
+
+    // Returns the relay message with a Sender to each of its connected recipients.
+    // The message is appended to the chain, and carries the signed head that commits to it
+    fn deliver(&self, msg: &RelayMessage) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let head = self.chain.write().append(msg);
+        self.record(TranscriptEvent::Relay {
+            sender: msg.peer_number,
+            from: msg.from.clone(),
+            round: msg.round,
+            to: msg.to.clone(),
+            message: msg.message.clone(),
+        });
+        let mut server_msg = ServerMessage::new();
+        server_msg.relay_message = Some(msg.clone());
+        server_msg.chain_head = Some(self.server_identity.sign_chain_head(head));
+        debug!(
+            "Sending relay message from peer {:?} to: {:?}",
+            msg.peer_number, msg.to
+        );
+        self.send_to_peers(server_msg, |peer| msg.to.contains(&peer.peer_id))
+    }
+
+    // Returns the message with a Sender to each of the connected registered peers it is for.
+    // The message is kept for every such peer, in case it has to resume
+    fn send_to_peers<F>(
+        &self,
+        server_msg: ServerMessage,
+        is_recipient: F,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>
+    where
+        F: Fn(&Peer) -> bool,
+    {
+        let keep = self.grace_period.is_some();
+        self.peers
+            .write()
+            .values_mut()
+            .filter(|peer| peer.registered && is_recipient(peer))
+            .filter_map(|peer| {
+                if keep {
+                    peer.outbox.push(server_msg.clone());
+                }
+                match peer.disconnected_at {
+                    Some(_) => None,
+                    None => Some((server_msg.clone(), peer.client.tx.clone())),
+                }
+            })
+            .collect()
     }
 
     /// Register a new peer for the relay session.
@@ -279,51 +644,357 @@ impl RelaySession {
     pub fn register(
         &self,
         addr: SocketAddr,
+        entry: RosterEntry,
         protocol_id: ProtocolIdentifier,
         capacity: u32,
     ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
-        self.register_new_peer(addr, protocol_id, capacity);
-        // Send message to all
-        match self.state() {
-            RelaySessionState::Initialized => {
-                let peers = self.peers.read().unwrap();
-                let sends = peers
-                    .iter()
-                    .map(|(_addr, peer)| {
+        if self
+            .register_new_peer(addr, entry, protocol_id, capacity)
+            .is_none()
+        {
+            // let the connection know, rather than leave it waiting for a register response
+            return match self.peers.read().get(&addr) {
+                Some(connection) => {
+                    let mut server_msg = ServerMessage::new();
+                    server_msg.response =
+                        Some(ServerResponse::ErrorResponse(RelayError::CantRegister));
+                    vec![(server_msg, connection.client.tx.clone())]
+                }
+                None => vec![],
+            };
+        }
+        // if peers may resume, the new peer gets the token to resume with
+        let mut sends = match self.grace_period {
+            Some(_) => self.issue_resume_token(&addr),
+            None => vec![],
+        };
+        // Send message to all, with the keys of all the peers
+        if self.state() == RelaySessionState::Initialized {
+            let roster = self.roster();
+            let peers = self.peers.read();
+            sends.extend(
+                peers
+                    .values()
+                    .filter(|peer| peer.registered && peer.disconnected_at.is_none())
+                    .map(|peer| {
                         let mut server_msg = ServerMessage::new();
-                        server_msg.response = Some(ServerResponse::Register(peer.peer_id));
+                        server_msg.response =
+                            Some(ServerResponse::Register(peer.peer_id, roster.clone()));
                         (server_msg, peer.client.tx.clone())
-                    })
-                    .collect();
-                sends
+                    }),
+            );
+        }
+        self.stamp(sends)
+    }
+
+    // Generate a resumption token for the peer in addr,
+    // return the token to send to the peer
+    fn issue_resume_token(
+        &self,
+        addr: &SocketAddr,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let mut token = [0u8; 32];
+        OsRng.fill_bytes(&mut token);
+        let token = hex::encode(token);
+        match self.peers.write().get_mut(addr) {
+            Some(peer) => {
+                peer.resume_token = token.clone();
+                let mut server_msg = ServerMessage::new();
+                server_msg.response = Some(ServerResponse::ResumeToken(token));
+                vec![(server_msg, peer.client.tx.clone())]
+            }
+            None => vec![],
+        }
+    }
+
+    /// Handle the loss of the connection in addr.
+    /// If peers may resume, the peer has until the end of the grace period to resume,
+    /// otherwise the session is aborted
+    pub fn disconnect(
+        &self,
+        addr: SocketAddr,
+        now: Instant,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        if self.grace_period.is_some() && !self.is_finished() {
+            if let Some(peer) = self.peers.write().get_mut(&addr) {
+                if peer.registered {
+                    warn!(
+                        "Peer {:} disconnected, waiting for it to resume",
+                        peer.peer_id
+                    );
+                    peer.disconnected_at = Some(now);
+                    return vec![];
+                }
             }
-            _ => vec![],
         }
+        self.abort(addr, AbortReason::Disconnected)
+    }
+
+    /// Resume the registration of the peer holding the token on the connection in addr.
+    /// The peer takes back its peer number, and is sent every relay message and echo
+    /// it did not receive before it disconnected
+    pub fn resume(
+        &self,
+        addr: SocketAddr,
+        client: Client,
+        token: &str,
+        received: u32,
+    ) -> Result<Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>, RelayError> {
+        if self.is_finished() {
+            return Err(RelayError::InvalidResumeToken);
+        }
+        let mut peers = self.peers.write();
+        let old_addr = peers
+            .iter()
+            .find(|(_addr, peer)| {
+                peer.registered && !peer.resume_token.is_empty() && peer.resume_token == token
+            })
+            .map(|(addr, _peer)| *addr)
+            .ok_or(RelayError::InvalidResumeToken)?;
+        // the old connection might not have been closed yet, the new one replaces it
+        let mut peer = peers
+            .remove(&old_addr)
+            .ok_or(RelayError::InvalidResumeToken)?;
+        info!("Peer {:} resumed from {:}", peer.peer_id, addr);
+        peer.client = client;
+        peer.disconnected_at = None;
+        let peer_id = peer.peer_id;
+        let tx = peer.client.tx.clone();
+        let missed: Vec<ServerMessage> = peer
+            .outbox
+            .iter()
+            .skip(received as usize)
+            .cloned()
+            .collect();
+        peers.insert(addr, peer);
+        drop(peers);
+        self.record(TranscriptEvent::Resume { peer_id });
+
+        // the missed messages are replayed with the heads they were first sent with
+        let mut resumed = ServerMessage::new();
+        resumed.response = Some(ServerResponse::Resumed(peer_id, self.roster()));
+        resumed.chain_head = Some(self.signed_head());
+        Ok(std::iter::once(resumed)
+            .chain(missed)
+            .map(|server_msg| (server_msg, tx.clone()))
+            .collect())
     }
 
-    // Abort the current relay session
+    // Abort the current relay session because of the peer in addr, for the given reason.
+    // The peer is blamed for the abort.
     // Return an abort message to all connected peers
-    pub fn abort(&self, addr: SocketAddr) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
-        warn!("Received abort, sending abort messages to all");
-        let peer = self.get_peer_by_address(&addr);
-        let mut server_msg = ServerMessage::new();
-        match peer {
+    pub fn abort(
+        &self,
+        addr: SocketAddr,
+        reason: AbortReason,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_peer_by_address(&addr) {
+            Some(p) => self.broadcast_abort(AbortMessage::blame(
+                p.peer_id,
+                self.protocol().id,
+                reason,
+                Some(p.peer_id),
+                None,
+            )),
+            None => vec![],
+        }
+    }
+
+    /// Abort the current relay session as requested by the peer in addr,
+    /// with the reason, accused peer and evidence it sent.
+    /// Return the abort message to all connected peers
+    pub fn relay_abort(
+        &self,
+        addr: SocketAddr,
+        mut abort: AbortMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_peer_by_address(&addr) {
             Some(p) => {
-                server_msg.abort = Some(AbortMessage::new(p.peer_id, self.protocol().id));
-                self.set_state(RelaySessionState::Aborted);
-                let peers = self.peers.read().unwrap();
-                peers
-                    .iter()
-                    .map(|(_addr, peer)| (server_msg.clone(), peer.client.tx.clone()))
-                    .collect()
+                // a peer can only abort in its own name
+                abort.peer_number = p.peer_id;
+                abort.protocol_id = self.protocol().id;
+                self.broadcast_abort(abort)
             }
             None => vec![],
         }
     }
 
+    // Set the session as aborted, and send the abort message to all connected peers
+    fn broadcast_abort(
+        &self,
+        abort: AbortMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        warn!(
+            "Peer {} aborted the session: {:?}, accused {:?}",
+            abort.peer_number, abort.reason, abort.accused
+        );
+        self.set_state(RelaySessionState::Aborted);
+        self.record(TranscriptEvent::Abort(abort.clone()));
+        let mut server_msg = ServerMessage::new();
+        server_msg.abort = Some(abort);
+        server_msg.chain_head = Some(self.signed_head());
+        let peers = self.peers.read();
+        peers
+            .values()
+            .map(|peer| (server_msg.clone(), peer.client.tx.clone()))
+            .collect()
+    }
+
+    /// Abort the session if the deadline of the current turn or round has passed by now,
+    /// or if a disconnected peer did not resume within the grace period.
+    /// Return an abort message naming the stalled (or disconnected) peers to all registered peers
+    pub fn expire(&self, now: Instant) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        if let Some(addr) = self.expired_peer(now) {
+            return self.abort(addr, AbortReason::Disconnected);
+        }
+        if self.state() != RelaySessionState::Initialized {
+            return vec![];
+        }
+        match *self.deadline.read() {
+            Some(deadline) if now >= deadline => {}
+            _ => return vec![],
+        }
+        let stalled_peers = self.stalled_peers();
+        warn!(
+            "Deadline of session {:} passed, waiting for peers {:?}",
+            self.session_id, stalled_peers
+        );
+        self.set_state(RelaySessionState::Aborted);
+        let abort = AbortMessage::timeout(self.protocol().id, stalled_peers);
+        self.record(TranscriptEvent::Abort(abort.clone()));
+        let mut server_msg = ServerMessage::new();
+        server_msg.abort = Some(abort);
+        server_msg.chain_head = Some(self.signed_head());
+        self.peers
+            .read()
+            .values()
+            .filter(|peer| peer.registered)
+            .map(|peer| (server_msg.clone(), peer.client.tx.clone()))
+            .collect()
+    }
+
+    /// Returns the peers the session is waiting for in the current turn or round
+    pub fn stalled_peers(&self) -> Vec<PeerIdentifier> {
+        let protocol = self.protocol();
+        match protocol.mode {
+            RelayMode::Turn => vec![protocol.next()],
+            RelayMode::Round => {
+                let round_messages = self.round_messages.read();
+                let messages_per_peer = protocol.messages_per_peer(protocol.round()) as usize;
+                (1..=protocol.capacity)
+                    .filter(|peer_id| {
+                        round_messages
+                            .keys()
+                            .filter(|(sender, _)| sender == peer_id)
+                            .count()
+                            < messages_per_peer
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    // Returns the address of a disconnected peer whose grace period has passed by now
+    fn expired_peer(&self, now: Instant) -> Option<SocketAddr> {
+        let grace_period = self.grace_period?;
+        if self.is_finished() {
+            return None;
+        }
+        self.peers
+            .read()
+            .iter()
+            .find(|(_addr, peer)| {
+                matches!(peer.disconnected_at, Some(at) if now >= at + grace_period)
+            })
+            .map(|(addr, _peer)| *addr)
+    }
+
+    // Start the deadline of a new turn or round
+    fn reset_deadline(&self) {
+        *self.deadline.write() = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Mark the peer in addr as done with the protocol.
+    /// Once all the peers are done, the session is Completed
+    /// and a completion response is sent to all the peers
+    pub fn complete(&self, addr: SocketAddr) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        if self.state() != RelaySessionState::Initialized {
+            warn!("Can not complete a session that is not initialized");
+            return vec![];
+        }
+        let mut peers = self.peers.write();
+        match peers.get_mut(&addr) {
+            Some(peer) if peer.registered => {
+                debug!("Peer {:} is done", peer.peer_id);
+                peer.done = true;
+            }
+            _ => return vec![],
+        }
+        if peers.values().filter(|p| p.registered).all(|p| p.done) {
+            info!("All peers are done, session is completed");
+            self.finish(&peers)
+        } else {
+            vec![]
+        }
+    }
+
+    // Once the last round the protocol declares is relayed, the session is Completed
+    // without waiting for the peers to be done.
+    // If the protocol echoes broadcast messages, all the peers must echo all its broadcast rounds first
+    fn complete_schedule(&self) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let protocol = self.protocol();
+        if self.state() != RelaySessionState::Initialized || !protocol.is_last_round_completed() {
+            return vec![];
+        }
+        if protocol.echo {
+            let echoes = self.echoes.read();
+            let echoed = protocol
+                .schedule
+                .iter()
+                .flatten()
+                .enumerate()
+                .filter(|(_, kind)| **kind == RoundKind::Broadcast)
+                .all(|(round, _)| {
+                    (1..=protocol.capacity).all(|peer_id| echoes.contains(&(peer_id, round as u32)))
+                });
+            if !echoed {
+                debug!("Waiting for the echoes of all rounds before completing the session");
+                return vec![];
+            }
+        }
+        info!("Last round of the protocol is relayed, session is completed");
+        self.finish(&self.peers.read())
+    }
+
+    // Mark the session as Completed, and return a completion response to all the peers
+    fn finish(
+        &self,
+        peers: &HashMap<SocketAddr, Peer>,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        self.set_state(RelaySessionState::Completed);
+        self.record(TranscriptEvent::Completed);
+        let mut server_msg = ServerMessage::new();
+        server_msg.response = Some(ServerResponse::Completed);
+        server_msg.chain_head = Some(self.signed_head());
+        peers
+            .values()
+            .filter(|peer| peer.registered)
+            .map(|peer| (server_msg.clone(), peer.client.tx.clone()))
+            .collect()
+    }
+
+    /// Returns true if the session has reached its end,
+    /// either by completing the protocol or by an abort
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.state(),
+            RelaySessionState::Completed | RelaySessionState::Aborted
+        )
+    }
+
     /// get a copy of Peer that addr represents
     pub fn get_peer_by_address(&self, addr: &SocketAddr) -> Option<Peer> {
-        match self.peers.read().unwrap().get(addr) {
+        match self.peers.read().get(addr) {
             Some(p) => match p.registered {
                 true => Some(p.clone()),
                 false => None,
@@ -332,22 +1003,38 @@ impl RelaySession {
         }
     }
 
+    /// Returns the identity and encryption key of every registered peer
+    pub fn roster(&self) -> Roster {
+        self.peers
+            .read()
+            .values()
+            .filter(|peer| peer.registered)
+            .map(|peer| {
+                let entry = RosterEntry {
+                    identity: peer.identity.clone(),
+                    encryption_key: peer.encryption_key.clone(),
+                };
+                (peer.peer_id, entry)
+            })
+            .collect()
+    }
+
     // Return the current state of the relay session
     pub fn state(&self) -> RelaySessionState {
-        self.state.read().unwrap().clone()
+        self.state.read().clone()
     }
 
     // Set the current relay session state to a new state
     pub fn set_state(&self, new_state: RelaySessionState) {
-        *self.state.write().unwrap() = new_state;
+        *self.state.write() = new_state;
     }
 
     pub fn protocol(&self) -> ProtocolDescriptor {
-        self.protocol.read().unwrap().clone()
+        self.protocol.read().clone()
     }
 
     pub fn set_protocol(&self, protocol: ProtocolDescriptor) {
-        *self.protocol.write().unwrap() = protocol;
+        *self.protocol.write() = protocol;
     }
 }
 
@@ -356,29 +1043,101 @@ mod tests {
     use super::Client;
     use super::RelaySession;
     use super::RelaySessionState;
+    use super::SessionConfig;
+    use crate::transcript::{read_transcript, Transcript, TranscriptEvent};
 
-    use futures::sync::mpsc;
+    use tokio::sync::mpsc;
 
-    use relay_server_common::common::{NOT_A_PEER, NOT_YOUR_TURN, STATE_NOT_INITIALIZED};
-    use relay_server_common::protocol::ProtocolDescriptor;
+    use relay_server_common::chain::verify_message_head;
+    use relay_server_common::encryption::EncryptionKeypair;
+    use relay_server_common::error::RelayError;
+    use relay_server_common::identity::{verify_chain_head, verify_message_signature, Identity};
+    use relay_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry, RelayMode};
+    use relay_server_common::validator::{PayloadValidator, PayloadValidators};
     use relay_server_common::{
-        ClientMessage, PeerIdentifier, ProtocolIdentifier, RelayMessage, ServerMessageType,
+        AbortEvidence, AbortMessage, AbortReason, ClientMessage, IdentityKey, MessagePayload,
+        PeerIdentifier, ProtocolIdentifier, RelayMessage, RosterEntry, ServerMessageType,
+        ServerResponse, SessionIdentifier, SignedChainHead, SignedEncryptionKey,
     };
 
     use std::net::SocketAddr;
     use std::sync::Arc;
     use std::thread;
+    use std::time::{Duration, Instant};
+
+    const SESSION_ID: SessionIdentifier = 0;
+    // The shipped protocols, with the protocols the tests run
+    const TEST_PROTOCOLS_FILE: &str = "./tests/protocols.json";
+
+    // A configuration supporting the protocols of the test protocols file
+    fn config(capacity: u32) -> SessionConfig {
+        SessionConfig {
+            capacity,
+            timeout: None,
+            grace_period: None,
+            server_identity: Arc::new(Identity::generate()),
+            protocols: Arc::new(ProtocolRegistry::load(TEST_PROTOCOLS_FILE).unwrap()),
+            validators: Arc::new(PayloadValidators::default()),
+        }
+    }
+
+    fn identity(addr: &SocketAddr) -> IdentityKey {
+        format!("identity-{}", addr)
+    }
+
+    fn roster_entry(identity: IdentityKey) -> RosterEntry {
+        RosterEntry {
+            identity,
+            encryption_key: SignedEncryptionKey::default(),
+        }
+    }
+
+    // A session of the protocol with all its peers registered, peer n connecting from
+    // 127.0.0.1:808n. Returns the session with the identities of the peers, in their order
+    fn registered_session(
+        capacity: u32,
+        protocol_id: ProtocolIdentifier,
+    ) -> (RelaySession, Vec<Identity>) {
+        registered_session_with(config(capacity), protocol_id)
+    }
+
+    // A session with the given configuration and all its peers registered
+    fn registered_session_with(
+        session_config: SessionConfig,
+        protocol_id: ProtocolIdentifier,
+    ) -> (RelaySession, Vec<Identity>) {
+        let capacity = session_config.capacity;
+        let rs = RelaySession::new(SESSION_ID, session_config);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for (i, identity) in identities.iter().enumerate() {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i + 1).parse().unwrap();
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(client_addr, Client::new(tx));
+            rs.register(
+                client_addr,
+                roster_entry(identity.public_key()),
+                protocol_id,
+                capacity,
+            );
+        }
+        (rs, identities)
+    }
 
     #[test]
     fn test_add_peer() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 1;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
         let client_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
-        let (tx, _) = mpsc::channel(0);
-        rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-
-        let peer_num = rs.register_new_peer(client_addr, protocol_id, capacity);
+        let (tx, _) = mpsc::channel(1);
+        rs.insert_new_connection(client_addr, Client::new(tx));
+
+        let peer_num = rs.register_new_peer(
+            client_addr,
+            roster_entry(identity(&client_addr)),
+            protocol_id,
+            capacity,
+        );
         assert_eq!(peer_num, Some(1));
     }
 
@@ -386,15 +1145,20 @@ mod tests {
     fn test_add_multi_peers() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 5;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
 
         let mut peer_num: u32 = 0;
         for i in 0..capacity {
             let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
-            let (tx, _) = mpsc::channel(0);
-            rs.insert_new_connection(client_addr.clone(), Client::new(tx));
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(client_addr, Client::new(tx));
             peer_num = rs
-                .register_new_peer(client_addr, protocol_id, capacity)
+                .register_new_peer(
+                    client_addr,
+                    roster_entry(identity(&client_addr)),
+                    protocol_id,
+                    capacity,
+                )
                 .expect("Unable to register");
         }
 
@@ -407,72 +1171,102 @@ mod tests {
 
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 50;
-        let rs = Arc::new(RelaySession::new(capacity));
+        let rs = Arc::new(RelaySession::new(SESSION_ID, config(capacity)));
 
         for i in 0..capacity {
             let rs_inner = Arc::clone(&rs);
 
             let client_addr: SocketAddr = format!("127.0.0.1:80{}", 30 + i).parse().unwrap();
-            let (tx, _) = mpsc::channel(0);
+            let (tx, _) = mpsc::channel(1);
             children.push(thread::spawn(move || {
-                rs_inner.insert_new_connection(client_addr.clone(), Client::new(tx));
+                rs_inner.insert_new_connection(client_addr, Client::new(tx));
                 rs_inner
-                    .register_new_peer(client_addr, protocol_id, capacity)
-                    .expect("Unable to register");
+                    .register_new_peer(
+                        client_addr,
+                        roster_entry(identity(&client_addr)),
+                        protocol_id,
+                        capacity,
+                    )
+                    .expect("Unable to register")
             }));
         }
 
-        for child in children {
-            let _ = child.join();
-        }
-        let rs_inner = Arc::clone(&rs);
-        let number_of_active_peers = rs_inner.get_number_of_active_peers();
-        assert_eq!(number_of_active_peers, capacity);
+        // every peer gets a number of its own
+        let mut peer_ids: Vec<PeerIdentifier> = children
+            .into_iter()
+            .map(|child| child.join().unwrap())
+            .collect();
+        peer_ids.sort_unstable();
+        assert_eq!(peer_ids, (1..=capacity).collect::<Vec<_>>());
+        assert_eq!(rs.roster().len(), capacity as usize);
+        assert_eq!(RelaySessionState::Initialized, rs.state());
     }
 
     #[test]
     fn test_can_register_protocol_valid() {
-        let client_addr: SocketAddr = format!("127.0.0.1:8081").parse().unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
         let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
-        let (tx, _) = mpsc::channel(0);
-        rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-        assert!(rs.can_register(&client_addr, protocol_descriptor))
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
+        let (tx, _) = mpsc::channel(1);
+        rs.insert_new_connection(client_addr, Client::new(tx));
+        assert!(rs.can_register(&client_addr, &identity(&client_addr), protocol_descriptor))
     }
 
     #[test]
     fn test_can_register_protocol_invalid() {
-        let client_addr: SocketAddr = format!("127.0.0.1:8081").parse().unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
         let protocol_id: ProtocolIdentifier = 100 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
-        let (tx, _) = mpsc::channel(0);
-        rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-        assert!(!rs.can_register(&client_addr, protocol_descriptor))
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
+        let (tx, _) = mpsc::channel(1);
+        rs.insert_new_connection(client_addr, Client::new(tx));
+        assert!(!rs.can_register(&client_addr, &identity(&client_addr), protocol_descriptor))
     }
 
     #[test]
     fn test_can_register_no_connection() {
-        let client_addr: SocketAddr = format!("127.0.0.1:8081").parse().unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
         let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
-        assert!(!rs.can_register(&client_addr, protocol_descriptor))
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
+        assert!(!rs.can_register(&client_addr, &identity(&client_addr), protocol_descriptor))
     }
 
     #[test]
     fn test_can_register_already_connected() {
-        let client_addr: SocketAddr = format!("127.0.0.1:8081").parse().unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
         let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
-        rs.register(client_addr, protocol_id, capacity);
-        assert!(!rs.can_register(&client_addr, protocol_descriptor))
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
+        rs.register(
+            client_addr,
+            roster_entry(identity(&client_addr)),
+            protocol_id,
+            capacity,
+        );
+        assert!(!rs.can_register(&client_addr, &identity(&client_addr), protocol_descriptor))
+    }
+
+    #[test]
+    fn test_can_register_identity_in_use() {
+        let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
+        let capacity: u32 = 5;
+        let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
+        let first: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:8082".parse().unwrap();
+        for client_addr in [first, second].iter() {
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(*client_addr, Client::new(tx));
+        }
+        rs.register(first, roster_entry(identity(&first)), protocol_id, capacity);
+        // the same identity can not register again from another connection
+        assert!(!rs.can_register(&second, &identity(&first), protocol_descriptor))
     }
 
     /////////////////////////// test register ///////////////////////////////////
@@ -480,28 +1274,46 @@ mod tests {
     fn test_register_state() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
 
         // State is empty at first
         assert_eq!(RelaySessionState::Empty, rs.state());
         for i in 0..capacity - 1 {
             let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
-            let (tx, _) = mpsc::channel(0);
-            rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-            rs.register(client_addr, protocol_id, capacity);
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(client_addr, Client::new(tx));
+            rs.register(
+                client_addr,
+                roster_entry(identity(&client_addr)),
+                protocol_id,
+                capacity,
+            );
             // State is not initialized when not all are connected
             assert_eq!(RelaySessionState::Uninitialized, rs.state());
         }
         let client_addr: SocketAddr = format!("127.0.0.1:808{}", capacity - 1).parse().unwrap();
-        let (tx, _) = mpsc::channel(0);
-        rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-        let messages = rs.register(client_addr, protocol_id, capacity);
+        let (tx, _) = mpsc::channel(1);
+        rs.insert_new_connection(client_addr, Client::new(tx));
+        let messages = rs.register(
+            client_addr,
+            roster_entry(identity(&client_addr)),
+            protocol_id,
+            capacity,
+        );
         // Once all are connected, state should initialize
         assert_eq!(RelaySessionState::Initialized, rs.state());
 
         messages
             .iter()
             .for_each(|(msg, _)| assert_eq!(msg.msg_type(), ServerMessageType::Response));
+        // every peer gets the keys of all the peers
+        messages.iter().for_each(|(msg, _)| match msg.response {
+            Some(ServerResponse::Register(_, ref roster)) => {
+                assert_eq!(roster.len(), capacity as usize);
+                assert_eq!(roster[&capacity].identity, identity(&client_addr));
+            }
+            _ => panic!("Expected a register response"),
+        });
     }
 
     /////////////////////////// test abort ///////////////////////////////////
@@ -509,40 +1321,139 @@ mod tests {
     fn test_abort() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
 
         // State is empty at first
         for i in 0..capacity - 1 {
             let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
-            let (tx, _) = mpsc::channel(0);
-            rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-            rs.register(client_addr, protocol_id, capacity);
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(client_addr, Client::new(tx));
+            rs.register(
+                client_addr,
+                roster_entry(identity(&client_addr)),
+                protocol_id,
+                capacity,
+            );
             // State is not initialized when not all are connected
             assert_eq!(RelaySessionState::Uninitialized, rs.state());
         }
         let client_addr: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
 
-        let messages = rs.abort(client_addr);
+        let messages = rs.abort(client_addr, AbortReason::Disconnected);
         // Once all are connected, state should initialize
         assert_eq!(RelaySessionState::Aborted, rs.state());
 
+        messages.iter().for_each(|(msg, _)| {
+            assert_eq!(msg.msg_type(), ServerMessageType::Abort);
+            // the peer that disconnected is blamed for the abort
+            let abort = msg.abort.as_ref().unwrap();
+            assert_eq!(abort.reason, AbortReason::Disconnected);
+            assert_eq!(abort.accused, Some(2));
+        });
+    }
+
+    #[test]
+    fn test_relay_abort() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let (rs, identities) = registered_session(capacity, protocol_id);
+        // the first peer blames the second, but claims to be it
+        let offending = prepare_relay_message(&identities[1], 2, protocol_id, &[1, 2])
+            .relay_message
+            .unwrap();
+        let evidence = AbortEvidence {
+            round: 0,
+            message: offending,
+        };
+        let abort = AbortMessage::blame(
+            2,
+            protocol_id,
+            AbortReason::InvalidCommitment,
+            Some(2),
+            Some(evidence),
+        );
+        let client_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let messages = rs.relay_abort(client_addr, abort);
+        assert_eq!(messages.len(), capacity as usize);
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+
+        // the abort is relayed in the name of the peer that sent it, with its evidence
+        let abort = messages[0].0.abort.as_ref().unwrap();
+        assert_eq!(abort.peer_number, 1);
+        assert_eq!(abort.reason, AbortReason::InvalidCommitment);
+        assert_eq!(abort.accused, Some(2));
+        assert_eq!(abort.evidence.as_ref().unwrap().message.peer_number, 2);
+    }
+
+    /////////////////////////// test complete ///////////////////////////////////
+    #[test]
+    fn test_complete() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let (rs, _) = registered_session(capacity, protocol_id);
+
+        for peer_id in 1..capacity {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", peer_id).parse().unwrap();
+            assert!(rs.complete(client_addr).is_empty());
+            // Not all the peers are done yet
+            assert_eq!(RelaySessionState::Initialized, rs.state());
+        }
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", capacity).parse().unwrap();
+        let messages = rs.complete(client_addr);
+        assert_eq!(RelaySessionState::Completed, rs.state());
+        assert!(rs.is_finished());
+
+        assert_eq!(messages.len(), capacity as usize);
         messages
             .iter()
-            .for_each(|(msg, _)| assert_eq!(msg.msg_type(), ServerMessageType::Abort));
+            .for_each(|(msg, _)| assert_eq!(msg.msg_type(), ServerMessageType::Response));
+    }
+
+    #[test]
+    fn test_complete_not_initialized() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
+
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 0).parse().unwrap();
+        let (tx, _) = mpsc::channel(1);
+        rs.insert_new_connection(client_addr, Client::new(tx));
+        rs.register(
+            client_addr,
+            roster_entry(identity(&client_addr)),
+            protocol_id,
+            capacity,
+        );
+
+        assert!(rs.complete(client_addr).is_empty());
+        assert_eq!(RelaySessionState::Uninitialized, rs.state());
+        assert!(!rs.is_finished());
     }
 
     fn prepare_relay_message(
+        identity: &Identity,
+        peer_id: PeerIdentifier,
+        protocol_id: ProtocolIdentifier,
+        send_to: &[PeerIdentifier],
+    ) -> ClientMessage {
+        prepare_round_message(identity, peer_id, protocol_id, 0, send_to)
+    }
+
+    fn prepare_round_message(
+        identity: &Identity,
         peer_id: PeerIdentifier,
         protocol_id: ProtocolIdentifier,
-        send_to: &Vec<PeerIdentifier>,
+        round: u32,
+        send_to: &[PeerIdentifier],
     ) -> ClientMessage {
         let mut client_message = ClientMessage::new();
         let mut relay_message = RelayMessage::new(peer_id, protocol_id);
-        let to: Vec<_> = send_to.clone();
+        let to: Vec<_> = send_to.to_vec();
 
-        relay_message.set_message_params(to, format!("test"));
+        relay_message.set_message_params(round, to, "test");
         client_message.relay_message = Some(relay_message.clone());
-        client_message.clone()
+        identity.sign_message(&mut client_message, SESSION_ID, round);
+        client_message
     }
 
     /////////////////////////// test can_relay ///////////////////////////////////
@@ -550,44 +1461,196 @@ mod tests {
     fn test_can_relay() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let broadcast: Vec<PeerIdentifier> = (1..capacity + 1).collect();
 
         // Add all but the last peer to the session
         for i in 0..capacity - 1 {
             let client_addr: SocketAddr = format!("127.0.0.1:808{}", i + 1).parse().unwrap();
-            let (tx, _) = mpsc::channel(0);
-            rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-            rs.register(client_addr, protocol_id, capacity);
-            let msg = prepare_relay_message(i, protocol_id, &vec![]);
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(client_addr, Client::new(tx));
+            let identity = &identities[i as usize];
+            rs.register(
+                client_addr,
+                roster_entry(identity.public_key()),
+                protocol_id,
+                capacity,
+            );
+            let msg = prepare_relay_message(identity, i, protocol_id, &broadcast);
             assert_eq!(
-                Err(STATE_NOT_INITIALIZED),
-                rs.can_relay(&client_addr, &msg.relay_message.unwrap())
+                Err(RelayError::NotInitialized),
+                rs.can_relay(&client_addr, &msg)
             );
         }
         // Add the last peer to the session
         let client_addr: SocketAddr = format!("127.0.0.1:808{}", capacity).parse().unwrap();
-        let (tx, _) = mpsc::channel(0);
-        rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-        rs.register(client_addr, protocol_id, capacity);
+        let (tx, _) = mpsc::channel(1);
+        rs.insert_new_connection(client_addr, Client::new(tx));
+        let identity = &identities[capacity as usize - 1];
+        rs.register(
+            client_addr,
+            roster_entry(identity.public_key()),
+            protocol_id,
+            capacity,
+        );
         // Try to relay when not your turn
-        let msg = prepare_relay_message(capacity, protocol_id, &vec![]);
-        //rs.can_relay(&client_addr, &msg.relay_message.unwrap());
+        let msg = prepare_relay_message(identity, capacity, protocol_id, &broadcast);
         assert_eq!(
-            Err(NOT_YOUR_TURN),
-            rs.can_relay(&client_addr, &msg.relay_message.unwrap())
+            Err(RelayError::NotYourTurn),
+            rs.can_relay(&client_addr, &msg)
         );
         let client_addr: SocketAddr = format!("127.0.0.1:808{}", capacity + 1).parse().unwrap();
-        let msg = prepare_relay_message(capacity, protocol_id, &vec![]);
+        let msg = prepare_relay_message(identity, capacity, protocol_id, &broadcast);
+        assert_eq!(Err(RelayError::NotAPeer), rs.can_relay(&client_addr, &msg));
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        // the first round of the protocol is a broadcast round
+        let msg = prepare_relay_message(&identities[0], 1, protocol_id, &[1, 2]);
         assert_eq!(
-            Err(NOT_A_PEER),
-            rs.can_relay(&client_addr, &msg.relay_message.unwrap())
+            Err(RelayError::WrongRoundKind),
+            rs.can_relay(&client_addr, &msg)
         );
+        let msg = prepare_relay_message(&identities[0], 1, protocol_id, &broadcast);
+        assert_eq!(Ok(()), rs.can_relay(&client_addr, &msg));
+    }
+
+    #[test]
+    fn test_can_relay_forged_signature() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let (rs, identities) = registered_session(capacity, protocol_id);
         let client_addr: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
-        let msg = prepare_relay_message(1, protocol_id, &vec![]);
+        // a message signed by another peer
+        let msg = prepare_relay_message(&identities[1], 1, protocol_id, &[1, 2]);
         assert_eq!(
-            Ok(()),
-            rs.can_relay(&client_addr, &msg.relay_message.unwrap())
+            Err(RelayError::InvalidSignature),
+            rs.can_relay(&client_addr, &msg)
+        );
+        // a message whose payload was changed after it was signed
+        let mut msg = prepare_relay_message(&identities[0], 1, protocol_id, &[1, 2]);
+        msg.relay_message.as_mut().unwrap().message = "forged".into();
+        assert_eq!(
+            Err(RelayError::InvalidSignature),
+            rs.can_relay(&client_addr, &msg)
         );
+        // a message signed for another session
+        let mut msg = prepare_relay_message(&identities[0], 1, protocol_id, &[1, 2]);
+        identities[0].sign_message(&mut msg, SESSION_ID + 1, 0);
+        assert_eq!(
+            Err(RelayError::InvalidSignature),
+            rs.can_relay(&client_addr, &msg)
+        );
+        // an unsigned message
+        msg.signature = None;
+        assert_eq!(
+            Err(RelayError::InvalidSignature),
+            rs.can_relay(&client_addr, &msg)
+        );
+
+        // the forged message is answered with an error, and is not relayed
+        let mut msg = prepare_relay_message(&identities[1], 1, protocol_id, &[1, 2]);
+        msg.relay_message.as_mut().unwrap().peer_number = 1;
+        let messages_to_send = rs.relay_message(&client_addr, msg);
+        assert_eq!(messages_to_send.len(), 1);
+        match messages_to_send[0].0.response {
+            Some(ServerResponse::ErrorResponse(err)) => {
+                assert_eq!(err, RelayError::InvalidSignature)
+            }
+            _ => panic!("Expected an error response"),
+        }
+        assert_eq!(rs.protocol().next(), 1);
+    }
+
+    #[test]
+    fn test_can_relay_unsealed_point_to_point() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let keys: Vec<EncryptionKeypair> = (0..capacity)
+            .map(|_| EncryptionKeypair::generate())
+            .collect();
+
+        for i in 0..capacity {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i + 1).parse().unwrap();
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(client_addr, Client::new(tx));
+            let identity = &identities[i as usize];
+            let entry = RosterEntry {
+                identity: identity.public_key(),
+                encryption_key: identity.sign_encryption_key(&keys[i as usize]),
+            };
+            rs.register(client_addr, entry, protocol_id, capacity);
+        }
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        // a plaintext payload addressed to a single peer is rejected
+        let msg = prepare_relay_message(&identities[0], 1, protocol_id, &[2]);
+        assert_eq!(
+            Err(RelayError::UnsealedMessage),
+            rs.can_relay(&client_addr, &msg)
+        );
+
+        // the payload sealed for the recipient is relayed
+        let recipient = &rs.roster()[&2].encryption_key.key;
+        let mut msg = prepare_relay_message(&identities[0], 1, protocol_id, &[2]);
+        let sealed = keys[0].seal(recipient, 1, 2, &"test".into()).unwrap();
+        msg.relay_message.as_mut().unwrap().message = sealed.into();
+        identities[0].sign_message(&mut msg, SESSION_ID, 0);
+        assert_eq!(Ok(()), rs.can_relay(&client_addr, &msg));
+
+        // broadcast payloads are not sealed
+        let msg = prepare_relay_message(&identities[0], 1, protocol_id, &[1, 2]);
+        assert_eq!(Ok(()), rs.can_relay(&client_addr, &msg));
+    }
+
+    // Accepts only the payloads the test messages carry
+    struct TestPayloadValidator;
+
+    impl PayloadValidator for TestPayloadValidator {
+        fn validate(
+            &self,
+            _msg: &RelayMessage,
+            payload: &MessagePayload,
+        ) -> Result<(), RelayError> {
+            if payload == "test" {
+                Ok(())
+            } else {
+                Err(RelayError::InvalidPayload)
+            }
+        }
+    }
+
+    #[test]
+    fn test_can_relay_invalid_payload() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let mut validators = PayloadValidators::default();
+        validators.register(protocol_id, TestPayloadValidator);
+        let (rs, identities) = registered_session_with(
+            SessionConfig {
+                validators: Arc::new(validators),
+                ..config(capacity)
+            },
+            protocol_id,
+        );
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        let msg = prepare_relay_message(&identities[0], 1, protocol_id, &[1, 2]);
+        assert_eq!(Ok(()), rs.can_relay(&client_addr, &msg));
+
+        // a payload the validator rejects is not relayed
+        let mut msg = prepare_relay_message(&identities[0], 1, protocol_id, &[1, 2]);
+        msg.relay_message.as_mut().unwrap().message = "garbage".into();
+        identities[0].sign_message(&mut msg, SESSION_ID, 0);
+        assert_eq!(
+            Err(RelayError::InvalidPayload),
+            rs.can_relay(&client_addr, &msg)
+        );
+        let messages_to_send = rs.relay_message(&client_addr, msg);
+        assert_eq!(1, messages_to_send.len());
+        assert!(matches!(
+            messages_to_send[0].0.response,
+            Some(ServerResponse::ErrorResponse(RelayError::InvalidPayload))
+        ));
     }
 
     /////////////////////////// test rellay_message   ///////////////////////////////////
@@ -595,20 +1658,697 @@ mod tests {
     fn test_relay_message() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
-        let rs = RelaySession::new(capacity);
+        let (rs, identities) = registered_session(capacity, protocol_id);
+        let client_num = 1;
+        let sender = &identities[client_num as usize - 1];
+        let msg = prepare_relay_message(sender, client_num, protocol_id, &[2, 3, 4]);
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", client_num).parse().unwrap();
+        let messages_to_send = rs.relay_message(&client_addr, msg);
+        assert_eq!(messages_to_send.len(), 3);
+        // the relayed message is attributed to the identity of the sender
+        messages_to_send.iter().for_each(|(msg, _)| {
+            assert_eq!(
+                msg.relay_message.as_ref().unwrap().from,
+                sender.public_key()
+            )
+        });
+    }
+
+    #[test]
+    fn test_relay_message_round_mode() {
+        // the test round protocol is relayed in rounds
+        let protocol_id: ProtocolIdentifier = 2;
+        let capacity: u32 = 3;
+        let (rs, identities) = registered_session(capacity, protocol_id);
+        assert_eq!(rs.protocol().mode, RelayMode::Round);
+
+        // any peer can send its message, out of turn, and it is held until the round is complete
+        for peer_id in (2..capacity + 1).rev() {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", peer_id).parse().unwrap();
+            let identity = &identities[peer_id as usize - 1];
+            let msg = prepare_relay_message(identity, peer_id, protocol_id, &[1, 2, 3]);
+            assert!(rs.relay_message(&client_addr, msg.clone()).is_empty());
+            // but only once in a round
+            assert_eq!(
+                Err(RelayError::AlreadySentInRound),
+                rs.can_relay(&client_addr, &msg)
+            );
+        }
+        assert_eq!(rs.protocol().round(), 0);
+
+        // the last message of the round relays the whole round to all the recipients
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        let msg = prepare_relay_message(&identities[0], 1, protocol_id, &[1, 2, 3]);
+        let messages_to_send = rs.relay_message(&client_addr, msg.clone());
+        assert_eq!(messages_to_send.len(), (capacity * capacity) as usize);
+        assert_eq!(rs.protocol().round(), 1);
+
+        // a message of the completed round is rejected
+        assert_eq!(
+            Err(RelayError::WrongRound),
+            rs.can_relay(&client_addr, &msg)
+        );
+        // messages of the next round are sent and signed for it,
+        // and it is a point to point round
+        let msg = prepare_round_message(&identities[0], 1, protocol_id, 1, &[1, 2, 3]);
+        assert_eq!(
+            Err(RelayError::WrongRoundKind),
+            rs.can_relay(&client_addr, &msg)
+        );
+        let msg = prepare_round_message(&identities[0], 1, protocol_id, 1, &[2]);
+        assert_eq!(
+            Err(RelayError::UnsealedMessage),
+            rs.can_relay(&client_addr, &msg)
+        );
+        // the recipient must be another peer of the session
+        for recipient in [0, capacity + 1, 1] {
+            let msg = prepare_round_message(&identities[0], 1, protocol_id, 1, &[recipient]);
+            assert_eq!(
+                Err(RelayError::WrongRoundKind),
+                rs.can_relay(&client_addr, &msg)
+            );
+        }
+        // and the protocol runs only two rounds
+        let msg = prepare_round_message(&identities[0], 1, protocol_id, 2, &[1, 2, 3]);
+        assert_eq!(
+            Err(RelayError::RoundOutOfRange),
+            rs.can_relay(&client_addr, &msg)
+        );
+    }
+
+    #[test]
+    fn test_relay_point_to_point_round() {
+        // the test round protocol runs a broadcast round, then a point to point round
+        let protocol_id: ProtocolIdentifier = 2;
+        let capacity: u32 = 3;
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let keys: Vec<EncryptionKeypair> = (0..capacity)
+            .map(|_| EncryptionKeypair::generate())
+            .collect();
+        let addrs: Vec<SocketAddr> = (0..capacity)
+            .map(|i| format!("127.0.0.1:808{}", i + 1).parse().unwrap())
+            .collect();
+
+        for i in 0..capacity as usize {
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(addrs[i], Client::new(tx));
+            let entry = RosterEntry {
+                identity: identities[i].public_key(),
+                encryption_key: identities[i].sign_encryption_key(&keys[i]),
+            };
+            rs.register(addrs[i], entry, protocol_id, capacity);
+        }
+        for peer_id in 1..capacity + 1 {
+            let identity = &identities[peer_id as usize - 1];
+            let msg = prepare_relay_message(identity, peer_id, protocol_id, &[1, 2, 3]);
+            rs.relay_message(&addrs[peer_id as usize - 1], msg);
+        }
+        assert_eq!(rs.protocol().round(), 1);
+
+        let roster = rs.roster();
+        let sealed_message = |sender: PeerIdentifier, recipient: PeerIdentifier| {
+            let identity = &identities[sender as usize - 1];
+            let mut msg = prepare_round_message(identity, sender, protocol_id, 1, &[recipient]);
+            let recipient_key = &roster[&recipient].encryption_key.key;
+            msg.relay_message.as_mut().unwrap().message = keys[sender as usize - 1]
+                .seal(recipient_key, sender, recipient, &"test".into())
+                .unwrap()
+                .into();
+            identity.sign_message(&mut msg, SESSION_ID, 1);
+            msg
+        };
+
+        // every peer seals a message for each of the other peers, but only one for each
+        let msg = sealed_message(1, 2);
+        assert_eq!(Ok(()), rs.can_relay(&addrs[0], &msg));
+        assert!(rs.relay_message(&addrs[0], msg).is_empty());
+        assert_eq!(
+            Err(RelayError::AlreadySentInRound),
+            rs.can_relay(&addrs[0], &sealed_message(1, 2))
+        );
+        assert_eq!(rs.stalled_peers(), vec![1, 2, 3]);
+        assert!(rs.relay_message(&addrs[0], sealed_message(1, 3)).is_empty());
+        assert_eq!(rs.stalled_peers(), vec![2, 3]);
+
+        // the round is relayed once every peer sealed its messages for all the others
+        for (sender, recipient) in [(2, 1), (2, 3), (3, 1)] {
+            let msg = sealed_message(sender, recipient);
+            assert!(rs.relay_message(&addrs[sender as usize - 1], msg).is_empty());
+        }
+        let messages_to_send = rs.relay_message(&addrs[2], sealed_message(3, 2));
+        // each message is relayed to its recipient only
+        let relayed = messages_to_send
+            .iter()
+            .filter(|(msg, _)| msg.relay_message.is_some())
+            .count();
+        assert_eq!(relayed, (capacity * (capacity - 1)) as usize);
+        assert_eq!(rs.protocol().round(), 2);
+    }
+
+    #[test]
+    fn test_complete_after_last_round() {
+        // the test protocol runs a single broadcast round
+        let protocol_id: ProtocolIdentifier = 0;
+        let capacity: u32 = 2;
+        let (rs, identities) = registered_session(capacity, protocol_id);
+        for sender in 1..capacity + 1 {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", sender).parse().unwrap();
+            let identity = &identities[sender as usize - 1];
+            let msg = prepare_relay_message(identity, sender, protocol_id, &[1, 2]);
+            let messages_to_send = rs.relay_message(&client_addr, msg);
+            if sender == 1 {
+                assert_eq!(messages_to_send.len(), capacity as usize);
+                assert_eq!(RelaySessionState::Initialized, rs.state());
+            } else {
+                // the last message of the last round completes the session,
+                // without waiting for the peers to be done
+                assert_eq!(messages_to_send.len(), 2 * capacity as usize);
+                assert!(matches!(
+                    messages_to_send[capacity as usize].0.response,
+                    Some(ServerResponse::Completed)
+                ));
+                assert_eq!(RelaySessionState::Completed, rs.state());
+            }
+        }
+    }
+
+    #[test]
+    fn test_complete_after_echoes() {
+        // the test echo protocol echoes its two broadcast rounds
+        let protocol_id: ProtocolIdentifier = 4;
+        let capacity: u32 = 1;
+        let (rs, identities) = registered_session(capacity, protocol_id);
+        let identity = &identities[0];
+        let client_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+
+        for round in 0..2 {
+            let msg = prepare_round_message(identity, 1, protocol_id, round, &[1]);
+            assert_eq!(rs.relay_message(&client_addr, msg).len(), 1);
+        }
+        // the session waits for the echoes of all the rounds
+        assert_eq!(RelaySessionState::Initialized, rs.state());
+        let echo = prepare_echo_message(identity, 1, protocol_id, 0);
+        assert!(rs.relay_echo(&client_addr, echo).is_empty());
+        let echo = prepare_echo_message(identity, 1, protocol_id, 1);
+        let messages_to_send = rs.relay_echo(&client_addr, echo);
+        assert_eq!(messages_to_send.len(), 1);
+        assert!(matches!(
+            messages_to_send[0].0.response,
+            Some(ServerResponse::Completed)
+        ));
+        assert_eq!(RelaySessionState::Completed, rs.state());
+    }
+
+    fn prepare_echo_message(
+        identity: &Identity,
+        peer_id: PeerIdentifier,
+        protocol_id: ProtocolIdentifier,
+        round: u32,
+    ) -> ClientMessage {
+        let mut client_message = ClientMessage::new();
+        client_message.echo(peer_id, protocol_id, round, String::from("digest"));
+        identity.sign_message(&mut client_message, SESSION_ID, round);
+        client_message
+    }
 
-        // Add all peers to the session
+    #[test]
+    fn test_relay_echo() {
+        // the test echo protocol echoes broadcast messages
+        let protocol_id: ProtocolIdentifier = 4;
+        let capacity: u32 = 3;
+        let (rs, identities) = registered_session(capacity, protocol_id);
+        let addrs: Vec<SocketAddr> = (0..capacity)
+            .map(|i| format!("127.0.0.1:808{}", i + 1).parse().unwrap())
+            .collect();
+        assert!(rs.protocol().echo);
+
+        // a round can only be echoed once it is completed
+        let echo = prepare_echo_message(&identities[0], 1, protocol_id, 0);
+        let messages_to_send = rs.relay_echo(&addrs[0], echo.clone());
+        assert_eq!(messages_to_send.len(), 1);
+        match messages_to_send[0].0.response {
+            Some(ServerResponse::ErrorResponse(err_msg)) => {
+                assert_eq!(err_msg, RelayError::WrongRound)
+            }
+            ref response => panic!("Unexpected response {:?}", response),
+        }
+        for peer_id in 1..capacity + 1 {
+            let identity = &identities[peer_id as usize - 1];
+            let msg = prepare_relay_message(identity, peer_id, protocol_id, &[1, 2, 3]);
+            rs.relay_message(&addrs[peer_id as usize - 1], msg);
+        }
+
+        // the echo is sent as it was signed to all the other peers
+        let messages_to_send = rs.relay_echo(&addrs[0], echo);
+        assert_eq!(messages_to_send.len(), (capacity - 1) as usize);
+        for (msg, _) in messages_to_send.iter() {
+            assert_eq!(msg.msg_type(), ServerMessageType::Echo);
+            let echo = msg.echo.as_ref().unwrap();
+            assert_eq!(
+                Ok(()),
+                verify_message_signature(echo, SESSION_ID, 0, &identities[0].public_key())
+            );
+        }
+
+        // an echo in the name of another peer is rejected
+        let forged = prepare_echo_message(&identities[0], 2, protocol_id, 0);
+        let messages_to_send = rs.relay_echo(&addrs[0], forged);
+        assert_eq!(messages_to_send.len(), 1);
+        assert!(messages_to_send[0].0.echo.is_none());
+        // so is an echo signed by another identity
+        let forged = prepare_echo_message(&identities[1], 1, protocol_id, 0);
+        let messages_to_send = rs.relay_echo(&addrs[0], forged);
+        match messages_to_send[0].0.response {
+            Some(ServerResponse::ErrorResponse(err_msg)) => {
+                assert_eq!(err_msg, RelayError::InvalidSignature)
+            }
+            ref response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn test_relay_echo_not_enabled() {
+        // the test protocol does not echo broadcast messages
+        let protocol_id: ProtocolIdentifier = 0;
+        let capacity: u32 = 2;
+        let (rs, identities) = registered_session(capacity, protocol_id);
+        let first: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let echo = prepare_echo_message(&identities[0], 1, protocol_id, 0);
+        let messages_to_send = rs.relay_echo(&first, echo);
+        assert_eq!(messages_to_send.len(), 1);
+        match messages_to_send[0].0.response {
+            Some(ServerResponse::ErrorResponse(err_msg)) => {
+                assert_eq!(err_msg, RelayError::EchoNotEnabled)
+            }
+            ref response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn test_expire_turn() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let timeout = Duration::from_secs(10);
+        let (rs, identities) = registered_session_with(
+            SessionConfig {
+                timeout: Some(timeout),
+                ..config(capacity)
+            },
+            protocol_id,
+        );
+        // the first peer sends in time, which starts the turn of the second
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        let msg = prepare_relay_message(&identities[0], 1, protocol_id, &[2, 3]);
+        rs.relay_message(&client_addr, msg);
+        assert!(rs.expire(Instant::now()).is_empty());
+        assert_eq!(RelaySessionState::Initialized, rs.state());
+
+        // the second peer does not send before the deadline
+        let messages_to_send = rs.expire(Instant::now() + timeout);
+        assert_eq!(messages_to_send.len(), capacity as usize);
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+        messages_to_send.iter().for_each(|(msg, _)| {
+            assert_eq!(msg.msg_type(), ServerMessageType::Abort);
+            let abort = msg.abort.as_ref().unwrap();
+            assert_eq!(abort.peer_number, 0);
+            assert_eq!(abort.reason, AbortReason::Timeout);
+            assert_eq!(abort.stalled_peers, vec![2]);
+        });
+        // an aborted session is not aborted again
+        assert!(rs.expire(Instant::now() + timeout).is_empty());
+    }
+
+    #[test]
+    fn test_expire_round() {
+        let protocol_id: ProtocolIdentifier = 2;
+        let capacity: u32 = 3;
+        let timeout = Duration::from_secs(10);
+        let (rs, identities) = registered_session_with(
+            SessionConfig {
+                timeout: Some(timeout),
+                ..config(capacity)
+            },
+            protocol_id,
+        );
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 2).parse().unwrap();
+        let msg = prepare_relay_message(&identities[1], 2, protocol_id, &[1, 2, 3]);
+        rs.relay_message(&client_addr, msg);
+        assert_eq!(rs.stalled_peers(), vec![1, 3]);
+
+        // all the peers that did not send in the round are named
+        let messages_to_send = rs.expire(Instant::now() + timeout);
+        assert_eq!(messages_to_send.len(), capacity as usize);
+        let abort = messages_to_send[0].0.abort.as_ref().unwrap();
+        assert_eq!(abort.stalled_peers, vec![1, 3]);
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+    }
+
+    #[test]
+    fn test_no_deadline_without_timeout() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 1;
+        let (rs, _) = registered_session(capacity, protocol_id);
+        assert_eq!(RelaySessionState::Initialized, rs.state());
+        assert!(rs
+            .expire(Instant::now() + Duration::from_secs(3600))
+            .is_empty());
+        assert_eq!(RelaySessionState::Initialized, rs.state());
+    }
+
+    // Registers capacity peers to a session of the protocol in which peers can resume,
+    // returns the session with the token of every peer
+    fn resumable_session(
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        grace_period: Duration,
+        identities: &[Identity],
+    ) -> (RelaySession, Vec<String>) {
+        let rs = RelaySession::new(
+            SESSION_ID,
+            SessionConfig {
+                grace_period: Some(grace_period),
+                ..config(capacity)
+            },
+        );
+        let mut tokens = Vec::new();
         for i in 0..capacity {
-            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
-            let (tx, _) = mpsc::channel(0);
-            rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-            rs.register(client_addr, protocol_id, capacity);
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i + 1).parse().unwrap();
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(client_addr, Client::new(tx));
+            let messages = rs.register(
+                client_addr,
+                roster_entry(identities[i as usize].public_key()),
+                protocol_id,
+                capacity,
+            );
+            // the token is sent to the registering peer first
+            match messages[0].0.response {
+                Some(ServerResponse::ResumeToken(ref token)) => tokens.push(token.clone()),
+                _ => panic!("Expected a resumption token"),
+            }
         }
-        let client_num = 1;
-        let msg = prepare_relay_message(client_num, protocol_id, &vec![2, 3, 4]);
-        let client_addr: SocketAddr = format!("127.0.0.1:808{}", client_num - 1).parse().unwrap();
-        let messages_to_send = rs.relay_message(&client_addr, msg.relay_message.unwrap());
-        assert_eq!(messages_to_send.len(), 3);
+        (rs, tokens)
+    }
+
+    #[test]
+    fn test_resume_replays_missed_messages() {
+        let capacity: u32 = 3;
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let (rs, tokens) = resumable_session(1, capacity, Duration::from_secs(10), &identities);
+        assert_eq!(RelaySessionState::Initialized, rs.state());
+
+        // the third peer disconnects, which does not abort the session
+        let lost: SocketAddr = "127.0.0.1:8083".parse().unwrap();
+        assert!(rs.disconnect(lost, Instant::now()).is_empty());
+        assert_eq!(RelaySessionState::Initialized, rs.state());
+
+        // messages to the peer are not sent while it is away
+        for peer_id in 1..3 {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", peer_id).parse().unwrap();
+            let identity = &identities[peer_id as usize - 1];
+            let msg = prepare_relay_message(identity, peer_id, 1, &[1, 2, 3]);
+            assert_eq!(rs.relay_message(&client_addr, msg).len(), 2);
+        }
+
+        // it resumes on a new connection, and gets everything it missed
+        let resumed: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let (tx, _) = mpsc::channel(1);
+        let messages = rs.resume(resumed, Client::new(tx), &tokens[2], 0).unwrap();
+        assert_eq!(messages.len(), 3);
+        match messages[0].0.response {
+            Some(ServerResponse::Resumed(peer_id, ref roster)) => {
+                assert_eq!(peer_id, 3);
+                assert_eq!(roster.len(), capacity as usize);
+            }
+            _ => panic!("Expected a resumed response"),
+        }
+        let senders: Vec<PeerIdentifier> = messages[1..]
+            .iter()
+            .map(|(msg, _)| msg.relay_message.as_ref().unwrap().peer_number)
+            .collect();
+        assert_eq!(senders, vec![1, 2]);
+        assert_eq!(rs.get_peer_by_address(&resumed).unwrap().peer_id, 3);
+        assert!(rs.get_peer_by_address(&lost).is_none());
+
+        // messages the peer already received are not sent again
+        let again: SocketAddr = "127.0.0.1:8091".parse().unwrap();
+        let (tx, _) = mpsc::channel(1);
+        let messages = rs.resume(again, Client::new(tx), &tokens[2], 1).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].0.relay_message.as_ref().unwrap().peer_number, 2);
     }
 
+    #[test]
+    fn test_resume_replays_missed_echoes() {
+        // the test echo protocol echoes broadcast messages
+        let protocol_id: ProtocolIdentifier = 4;
+        let capacity: u32 = 2;
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let (rs, tokens) =
+            resumable_session(protocol_id, capacity, Duration::from_secs(10), &identities);
+        for peer_id in 1..capacity + 1 {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", peer_id).parse().unwrap();
+            let identity = &identities[peer_id as usize - 1];
+            let msg = prepare_relay_message(identity, peer_id, protocol_id, &[1, 2]);
+            assert_eq!(rs.relay_message(&client_addr, msg).len(), 2);
+        }
+
+        // the second peer disconnects, and misses the echo of the first round
+        let lost: SocketAddr = "127.0.0.1:8082".parse().unwrap();
+        assert!(rs.disconnect(lost, Instant::now()).is_empty());
+        let first: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let echo = prepare_echo_message(&identities[0], 1, protocol_id, 0);
+        assert!(rs.relay_echo(&first, echo).is_empty());
+
+        // it resumes after the two relay messages it received, and gets the echo
+        let resumed: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let (tx, _) = mpsc::channel(1);
+        let messages = rs.resume(resumed, Client::new(tx), &tokens[1], 2).unwrap();
+        assert_eq!(messages.len(), 2);
+        let echo = messages[1].0.echo.as_ref().unwrap();
+        assert_eq!(
+            Ok(()),
+            verify_message_signature(echo, SESSION_ID, 0, &identities[0].public_key())
+        );
+        assert!(messages[1].0.chain_head.is_some());
+    }
+
+    #[test]
+    fn test_resume_invalid_token() {
+        let capacity: u32 = 2;
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let (rs, tokens) = resumable_session(1, capacity, Duration::from_secs(10), &identities);
+        let lost: SocketAddr = "127.0.0.1:8082".parse().unwrap();
+        rs.disconnect(lost, Instant::now());
+
+        let client_addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let (tx, _) = mpsc::channel(1);
+        let forged = format!("{}00", tokens[1]);
+        assert_eq!(
+            RelayError::InvalidResumeToken,
+            rs.resume(client_addr, Client::new(tx.clone()), &forged, 0)
+                .unwrap_err()
+        );
+        assert!(rs.get_peer_by_address(&client_addr).is_none());
+
+        // an aborted session can not be resumed
+        rs.abort("127.0.0.1:8081".parse().unwrap(), AbortReason::Requested);
+        assert_eq!(
+            RelayError::InvalidResumeToken,
+            rs.resume(client_addr, Client::new(tx), &tokens[1], 0)
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_peer_debug_redacts_resume_token() {
+        let capacity: u32 = 2;
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let (rs, tokens) = resumable_session(1, capacity, Duration::from_secs(10), &identities);
+        let peer = rs
+            .get_peer_by_address(&"127.0.0.1:8081".parse().unwrap())
+            .unwrap();
+        let printed = format!("{:?}", peer);
+        assert!(!printed.contains(&tokens[0]));
+        assert!(printed.contains("<redacted>"));
+    }
+
+    #[test]
+    fn test_disconnect_without_grace_period() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let rs = RelaySession::new(SESSION_ID, config(capacity));
+        for i in 0..capacity {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i + 1).parse().unwrap();
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(client_addr, Client::new(tx));
+            // no token is handed out when peers can not resume
+            let messages = rs.register(
+                client_addr,
+                roster_entry(identity(&client_addr)),
+                protocol_id,
+                capacity,
+            );
+            assert!(!messages
+                .iter()
+                .any(|(msg, _)| matches!(msg.response, Some(ServerResponse::ResumeToken(_)))));
+        }
+        let messages = rs.disconnect("127.0.0.1:8082".parse().unwrap(), Instant::now());
+        assert_eq!(messages.len(), capacity as usize);
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+    }
+
+    #[test]
+    fn test_expire_disconnected() {
+        let capacity: u32 = 2;
+        let grace_period = Duration::from_secs(10);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let (rs, _) = resumable_session(1, capacity, grace_period, &identities);
+        let now = Instant::now();
+        rs.disconnect("127.0.0.1:8082".parse().unwrap(), now);
+        assert!(rs.expire(now).is_empty());
+
+        // the peer did not resume within the grace period, and is blamed for the abort
+        let messages = rs.expire(now + grace_period);
+        assert_eq!(messages.len(), capacity as usize);
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+        let abort = messages[0].0.abort.as_ref().unwrap();
+        assert_eq!(abort.reason, AbortReason::Disconnected);
+        assert_eq!(abort.accused, Some(2));
+    }
+
+    #[test]
+    fn test_transcript() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let dir = std::env::temp_dir().join(format!("relay-session-{}", std::process::id()));
+        let transcript = Transcript::create(&dir, SESSION_ID).unwrap();
+        let path = transcript.path().to_path_buf();
+        let mut rs = RelaySession::new(SESSION_ID, config(capacity));
+        rs.set_transcript(transcript);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+
+        for i in 0..capacity {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i + 1).parse().unwrap();
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(client_addr, Client::new(tx));
+            rs.register(
+                client_addr,
+                roster_entry(identities[i as usize].public_key()),
+                protocol_id,
+                capacity,
+            );
+        }
+        let first: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let msg = prepare_relay_message(&identities[0], 1, protocol_id, &[1, 2]);
+        rs.relay_message(&first, msg);
+        // a rejected message is not relayed, and not recorded
+        let msg = prepare_relay_message(&identities[0], 1, protocol_id, &[1, 2]);
+        rs.relay_message(&first, msg);
+        rs.abort("127.0.0.1:8082".parse().unwrap(), AbortReason::Requested);
+
+        let entries = read_transcript(&path).unwrap();
+        assert_eq!(entries.len(), 4);
+        match entries[1].event {
+            TranscriptEvent::Register {
+                peer_id,
+                ref identity,
+            } => {
+                assert_eq!(peer_id, 2);
+                assert_eq!(identity, &identities[1].public_key());
+            }
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+        match entries[2].event {
+            TranscriptEvent::Relay {
+                sender,
+                ref from,
+                ref to,
+                ..
+            } => {
+                assert_eq!(sender, 1);
+                assert_eq!(from, &identities[0].public_key());
+                assert_eq!(to, &vec![1, 2]);
+            }
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+        match entries[3].event {
+            TranscriptEvent::Abort(ref abort) => assert_eq!(abort.accused, Some(2)),
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_chain_heads() {
+        let protocol_id: ProtocolIdentifier = 2;
+        let capacity: u32 = 3;
+        let server = Arc::new(Identity::generate());
+        let rs = RelaySession::new(
+            SESSION_ID,
+            SessionConfig {
+                server_identity: server.clone(),
+                ..config(capacity)
+            },
+        );
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+
+        for i in 0..capacity {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i + 1).parse().unwrap();
+            let (tx, _) = mpsc::channel(1);
+            rs.insert_new_connection(client_addr, Client::new(tx));
+            let messages_to_send = rs.register(
+                client_addr,
+                roster_entry(identities[i as usize].public_key()),
+                protocol_id,
+                capacity,
+            );
+            // nothing was relayed yet, the responses carry the empty chain
+            assert!(messages_to_send.iter().all(|(msg, _)| {
+                let head = msg.chain_head.as_ref().unwrap();
+                head.head.length == 0 && verify_chain_head(head, &server.public_key()).is_ok()
+            }));
+        }
+        let mut messages_to_send = Vec::new();
+        for peer_id in 1..capacity + 1 {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", peer_id).parse().unwrap();
+            let identity = &identities[peer_id as usize - 1];
+            let msg = prepare_relay_message(identity, peer_id, protocol_id, &[1, 2, 3]);
+            messages_to_send.extend(rs.relay_message(&client_addr, msg));
+        }
+        assert_eq!(messages_to_send.len(), (capacity * capacity) as usize);
+
+        // every relayed message carries the head that commits to it
+        let mut heads: Vec<SignedChainHead> = messages_to_send
+            .iter()
+            .map(|(msg, _)| {
+                let head = msg.chain_head.clone().unwrap();
+                let relay_message = msg.relay_message.as_ref().unwrap();
+                assert_eq!(
+                    Ok(()),
+                    verify_message_head(&head, &server.public_key(), relay_message)
+                );
+                head
+            })
+            .collect();
+        // and the heads link into a single chain, one link per message
+        heads.sort_by_key(|head| head.head.length);
+        heads.dedup_by_key(|head| head.head.length);
+        assert_eq!(heads.len(), capacity as usize);
+        assert_eq!(heads[0].head.length, 1);
+        assert!(heads
+            .windows(2)
+            .all(|pair| pair[0].head.hash == pair[1].head.prev));
+        assert_eq!(rs.signed_head().head, heads[capacity as usize - 1].head);
+
+        // a forged head does not verify
+        let mut forged = heads[0].clone();
+        forged.head.hash = heads[1].head.hash.clone();
+        let relay_message = messages_to_send[0].0.relay_message.as_ref().unwrap();
+        assert_eq!(
+            Err(RelayError::InvalidChainHead),
+            verify_message_head(&forged, &server.public_key(), relay_message)
+        );
+    }
 }
@@ -0,0 +1,1003 @@
+use log::{debug, error, info, warn};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use relay_server_common::error::RelayError;
+use relay_server_common::identity::{self, Identity};
+use relay_server_common::protocol::ProtocolRegistry;
+use relay_server_common::validator::PayloadValidators;
+use relay_server_common::{
+    AbortMessage, AbortReason, ClientMessage, ProtocolIdentifier, RegisterMessage, ResumeMessage,
+    RosterEntry, ServerMessage, ServerResponse, SessionIdentifier,
+};
+
+use crate::relay_server::ServerConfig;
+use crate::relay_session::{Client, RelaySession, SessionConfig};
+use crate::transcript::Transcript;
+
+// A register request waiting for the challenge to be signed
+#[derive(Clone, Debug)]
+struct PendingRegistration {
+    register: RegisterMessage,
+    challenge: String,
+}
+
+// A connection to the server, and the session it is registered to (if any)
+#[derive(Clone, Debug)]
+struct Connection {
+    client: Client,
+    session_id: Option<SessionIdentifier>,
+    pending: Option<PendingRegistration>,
+}
+
+impl Connection {
+    fn new(client: Client) -> Connection {
+        Connection {
+            client,
+            session_id: None,
+            pending: None,
+        }
+    }
+}
+
+/// Holds all the relay sessions served by a single relay server.
+/// Every connection is bound to at most one session, which is determined
+/// by the session identifier in its register message
+#[derive(Debug, Clone)]
+pub struct SessionRegistry {
+    sessions: Arc<RwLock<HashMap<SessionIdentifier, Arc<RelaySession>>>>,
+
+    connections: Arc<RwLock<HashMap<SocketAddr, Connection>>>,
+
+    capacity: u32,
+
+    // time the peers of every session have to send their message in each turn or round
+    timeout: Option<Duration>,
+
+    // time a disconnected peer has to resume its registration
+    grace_period: Option<Duration>,
+
+    // directory the transcripts of the sessions are written to, none are written if None
+    transcript_dir: Option<PathBuf>,
+
+    // the identity the server signs the heads of the chain of every session with
+    server_identity: Arc<Identity>,
+
+    // the protocols new sessions support, replaced when the protocols are reloaded
+    protocols: Arc<RwLock<Arc<ProtocolRegistry>>>,
+
+    // the validators of the payloads relayed in every session
+    validators: Arc<PayloadValidators>,
+}
+
+impl SessionRegistry {
+    /// Creates an empty registry, whose sessions are created with the given configuration
+    pub fn new(config: ServerConfig) -> SessionRegistry {
+        SessionRegistry {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+
+            connections: Arc::new(RwLock::new(HashMap::new())),
+
+            capacity: config.capacity,
+
+            timeout: config.timeout,
+
+            grace_period: config.grace_period,
+
+            transcript_dir: config.transcript_dir,
+
+            server_identity: Arc::new(config.identity),
+
+            protocols: Arc::new(RwLock::new(Arc::new(config.protocols))),
+
+            validators: Arc::new(config.validators),
+        }
+    }
+
+    /// Lets peers register new sessions with any of the given protocols.
+    /// Sessions that already exist keep the protocols they were created with
+    pub fn set_protocols(&self, protocols: ProtocolRegistry) {
+        *self.protocols.write() = Arc::new(protocols);
+    }
+
+    /// Returns the protocols new sessions support
+    pub fn protocols(&self) -> Arc<ProtocolRegistry> {
+        self.protocols.read().clone()
+    }
+
+    /// Inserts a new connection to the registry.
+    /// The connection is not bound to any session until it sends a register message
+    pub fn insert_new_connection(&self, addr: SocketAddr, client: Client) {
+        self.connections
+            .write()
+            .insert(addr, Connection::new(client));
+    }
+
+    /// Removes a connection from the registry
+    pub fn remove_connection(&self, addr: &SocketAddr) {
+        self.connections.write().remove(addr);
+    }
+
+    /// Returns the session this address is registered to
+    pub fn get_session_by_address(&self, addr: &SocketAddr) -> Option<Arc<RelaySession>> {
+        let session_id = self.connections.read().get(addr)?.session_id?;
+        self.get_session(session_id)
+    }
+
+    /// Returns the session with the given identifier
+    pub fn get_session(&self, session_id: SessionIdentifier) -> Option<Arc<RelaySession>> {
+        self.sessions.read().get(&session_id).cloned()
+    }
+
+    /// Returns the number of sessions currently held by the registry
+    pub fn number_of_sessions(&self) -> usize {
+        self.sessions.read().len()
+    }
+
+    /// Try reutrn a Sender of a specific connection by its address,
+    /// regardless of whether it registered to a session
+    pub fn get_sender_by_address(&self, addr: &SocketAddr) -> Option<mpsc::Sender<ServerMessage>> {
+        self.connections
+            .read()
+            .get(addr)
+            .map(|connection| connection.client.tx.clone())
+    }
+
+    /// Reject a message of the connection in addr, which is not registered to a session.
+    /// The connection stays open, and is sent a NotRegistered error
+    pub fn reject_unregistered(
+        &self,
+        addr: SocketAddr,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        warn!("{:} sent a message without registering", addr);
+        self.error_response(addr, RelayError::NotRegistered)
+    }
+
+    /// Returns an error response to the connection in addr, if it is still connected
+    pub fn error_response(
+        &self,
+        addr: SocketAddr,
+        err: RelayError,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_sender_by_address(&addr) {
+            Some(sender) => {
+                let mut server_msg = ServerMessage::new();
+                server_msg.response = Some(ServerResponse::ErrorResponse(err));
+                vec![(server_msg, sender)]
+            }
+            None => vec![],
+        }
+    }
+
+    /// Handle a register request of the connection in addr.
+    /// The connection is not registered until it signs the returned challenge
+    /// with the identity key it requested to register with
+    pub fn request_challenge(
+        &self,
+        addr: SocketAddr,
+        mut register: RegisterMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let protocols = self.protocols();
+        let protocol = match &register.protocol {
+            Some(requested) => {
+                protocols.resolve(&requested.name, &requested.version, register.capacity)
+            }
+            None => protocols
+                .check(register.protocol_id, register.capacity)
+                .map(|()| register.protocol_id),
+        };
+        let mut connections = self.connections.write();
+        let connection = match connections.get_mut(&addr) {
+            Some(connection) => connection,
+            None => {
+                warn!("No connection for {:}", addr);
+                return vec![];
+            }
+        };
+        if let Some(current_id) = connection.session_id {
+            warn!("{:} is already registered to session {:}", addr, current_id);
+            return vec![];
+        }
+        let mut server_msg = ServerMessage::new();
+        // the protocol must be supported,
+        // and the encryption key must be signed by the identity it is registered with
+        let valid_request = protocol.and_then(|protocol_id| {
+            register.protocol_id = protocol_id;
+            identity::parse_identity_key(&register.identity)?;
+            identity::verify_encryption_key(&register.identity, &register.encryption_key)
+        });
+        match valid_request {
+            Ok(()) => {
+                let challenge = identity::new_challenge();
+                server_msg.response = Some(ServerResponse::Challenge(challenge.clone()));
+                connection.pending = Some(PendingRegistration {
+                    register,
+                    challenge,
+                });
+            }
+            Err(err_msg) => {
+                warn!(
+                    "{:} sent an invalid registration request: {}",
+                    addr, err_msg
+                );
+                server_msg.response = Some(ServerResponse::ErrorResponse(err_msg));
+            }
+        }
+        vec![(server_msg, connection.client.tx.clone())]
+    }
+
+    /// Verify the connection in addr signed its pending challenge,
+    /// and if so, register it as requested
+    pub fn respond_to_challenge(
+        &self,
+        addr: SocketAddr,
+        signature: &str,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let (pending, tx) = match self.connections.write().get_mut(&addr) {
+            Some(connection) => (connection.pending.take(), connection.client.tx.clone()),
+            None => {
+                warn!("No connection for {:}", addr);
+                return vec![];
+            }
+        };
+        let verified = match pending {
+            Some(pending) => identity::verify_challenge_response(
+                &pending.register.identity,
+                &pending.challenge,
+                signature,
+            )
+            .map(|()| pending.register),
+            None => Err(RelayError::ChallengeFailed),
+        };
+        match verified {
+            Ok(register) => self.register(
+                addr,
+                RosterEntry {
+                    identity: register.identity,
+                    encryption_key: register.encryption_key,
+                },
+                register.session_id,
+                register.protocol_id,
+                register.capacity,
+            ),
+            Err(err_msg) => {
+                warn!("{:} failed the challenge", addr);
+                let mut server_msg = ServerMessage::new();
+                server_msg.response = Some(ServerResponse::ErrorResponse(err_msg));
+                vec![(server_msg, tx)]
+            }
+        }
+    }
+
+    /// Register the connection in addr as a peer with the given (verified) identity
+    /// and encryption key in the requested session.
+    /// The session is created if this is the first peer to request it.
+    /// Returns the messages to send, as returned by the session
+    pub fn register(
+        &self,
+        addr: SocketAddr,
+        entry: RosterEntry,
+        session_id: SessionIdentifier,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let client = match self.connections.read().get(&addr) {
+            Some(connection) => {
+                if let Some(current_id) = connection.session_id {
+                    warn!("{:} is already registered to session {:}", addr, current_id);
+                    return vec![];
+                }
+                connection.client.clone()
+            }
+            None => {
+                warn!("No connection for {:}", addr);
+                return vec![];
+            }
+        };
+
+        let relay_session = Arc::clone(
+            self.sessions
+                .write()
+                .entry(session_id)
+                .or_insert_with(|| Arc::new(self.new_session(session_id))),
+        );
+
+        relay_session.insert_new_connection(addr, client);
+        let messages_to_send = relay_session.register(addr, entry, protocol_id, capacity);
+
+        if relay_session.get_peer_by_address(&addr).is_some() {
+            debug!("{:} registered to session {:}", addr, session_id);
+            if let Some(connection) = self.connections.write().get_mut(&addr) {
+                connection.session_id = Some(session_id);
+            }
+        } else {
+            // the connection could not register, it should not remain in the session
+            relay_session.remove(&addr);
+        }
+        messages_to_send
+    }
+
+    /// Abort the session addr is registered to, blaming addr for the given reason.
+    /// Return an abort message to all peers of that session
+    pub fn abort(
+        &self,
+        addr: SocketAddr,
+        reason: AbortReason,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_session_by_address(&addr) {
+            Some(relay_session) => {
+                let messages_to_send = relay_session.abort(addr, reason);
+                self.remove_if_finished(&addr, &relay_session);
+                messages_to_send
+            }
+            None => vec![],
+        }
+    }
+
+    // Creates a new session with the configuration of the registry
+    fn new_session(&self, session_id: SessionIdentifier) -> RelaySession {
+        info!("Creating session {:}", session_id);
+        let mut relay_session = RelaySession::new(
+            session_id,
+            SessionConfig {
+                capacity: self.capacity,
+                timeout: self.timeout,
+                grace_period: self.grace_period,
+                server_identity: self.server_identity.clone(),
+                protocols: self.protocols(),
+                validators: self.validators.clone(),
+            },
+        );
+        if let Some(dir) = &self.transcript_dir {
+            match Transcript::create(dir, session_id) {
+                Ok(transcript) => {
+                    info!(
+                        "Writing transcript of session {:} to {:?}",
+                        session_id,
+                        transcript.path()
+                    );
+                    relay_session.set_transcript(transcript);
+                }
+                Err(err) => error!(
+                    "Unable to create transcript of session {:}: {}",
+                    session_id, err
+                ),
+            }
+        }
+        relay_session
+    }
+
+    /// Handle the loss of the connection in addr.
+    /// The session it is registered to is aborted, unless its peer can still resume.
+    /// Return an abort message to all peers of that session, if it was aborted
+    pub fn disconnect(
+        &self,
+        addr: SocketAddr,
+        now: Instant,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_session_by_address(&addr) {
+            Some(relay_session) => {
+                let messages_to_send = relay_session.disconnect(addr, now);
+                self.remove_if_finished(&addr, &relay_session);
+                messages_to_send
+            }
+            None => vec![],
+        }
+    }
+
+    /// Resume the registration of a peer that disconnected on the connection in addr.
+    /// Return the messages the peer missed, or an error response if it can not resume
+    pub fn resume(
+        &self,
+        addr: SocketAddr,
+        resume: ResumeMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let client = match self.connections.read().get(&addr) {
+            Some(connection) => {
+                if let Some(current_id) = connection.session_id {
+                    warn!("{:} is already registered to session {:}", addr, current_id);
+                    return vec![];
+                }
+                connection.client.clone()
+            }
+            None => {
+                warn!("No connection for {:}", addr);
+                return vec![];
+            }
+        };
+        let session_id = resume.session_id;
+        let resumed = self
+            .get_session(session_id)
+            .ok_or(RelayError::InvalidResumeToken)
+            .and_then(|relay_session| {
+                relay_session
+                    .resume(addr, client.clone(), &resume.token, resume.received)
+                    .map(|messages_to_send| (relay_session, messages_to_send))
+            });
+        match resumed {
+            Ok((relay_session, messages_to_send)) => {
+                let mut connections = self.connections.write();
+                // the connection the peer resumed from no longer belongs to the session
+                connections
+                    .iter_mut()
+                    .filter(|(other, connection)| {
+                        connection.session_id == Some(session_id)
+                            && relay_session.get_peer_by_address(other).is_none()
+                    })
+                    .for_each(|(_addr, connection)| connection.session_id = None);
+                if let Some(connection) = connections.get_mut(&addr) {
+                    connection.session_id = Some(session_id);
+                }
+                messages_to_send
+            }
+            Err(err_msg) => {
+                warn!("{:} failed to resume session {:}", addr, session_id);
+                let mut server_msg = ServerMessage::new();
+                server_msg.response = Some(ServerResponse::ErrorResponse(err_msg));
+                vec![(server_msg, client.tx)]
+            }
+        }
+    }
+
+    /// Relay the message addr sent to the peers of its session.
+    /// Once the last round of the protocol is relayed the session is completed, and removed
+    pub fn relay_message(
+        &self,
+        addr: SocketAddr,
+        msg: ClientMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_session_by_address(&addr) {
+            Some(relay_session) => {
+                let messages_to_send = relay_session.relay_message(&addr, msg);
+                self.remove_if_finished(&addr, &relay_session);
+                messages_to_send
+            }
+            None => self.reject_unregistered(addr),
+        }
+    }
+
+    /// Relay the echo addr sent to the other peers of its session.
+    /// The session may complete once the peers echoed all the rounds of the protocol
+    pub fn relay_echo(
+        &self,
+        addr: SocketAddr,
+        msg: ClientMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_session_by_address(&addr) {
+            Some(relay_session) => {
+                let messages_to_send = relay_session.relay_echo(&addr, msg);
+                self.remove_if_finished(&addr, &relay_session);
+                messages_to_send
+            }
+            None => self.reject_unregistered(addr),
+        }
+    }
+
+    /// Abort the session addr is registered to, as requested by addr.
+    /// Return the abort message to all peers of that session
+    pub fn relay_abort(
+        &self,
+        addr: SocketAddr,
+        abort: AbortMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_session_by_address(&addr) {
+            Some(relay_session) => {
+                let messages_to_send = relay_session.relay_abort(addr, abort);
+                self.remove_if_finished(&addr, &relay_session);
+                messages_to_send
+            }
+            None => vec![],
+        }
+    }
+
+    /// Mark the peer in addr as done with the protocol of its session.
+    /// Return a completion message to all peers of that session, if all are done
+    pub fn complete(&self, addr: SocketAddr) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_session_by_address(&addr) {
+            Some(relay_session) => {
+                let messages_to_send = relay_session.complete(addr);
+                self.remove_if_finished(&addr, &relay_session);
+                messages_to_send
+            }
+            None => self.reject_unregistered(addr),
+        }
+    }
+
+    /// Abort every session whose turn or round deadline has passed by now,
+    /// or in which a disconnected peer did not resume in time, and remove it from the registry.
+    /// Return the abort messages to all peers of those sessions
+    pub fn expire_sessions(
+        &self,
+        now: Instant,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let sessions: Vec<(SessionIdentifier, Arc<RelaySession>)> = self
+            .sessions
+            .read()
+            .iter()
+            .map(|(session_id, relay_session)| (*session_id, Arc::clone(relay_session)))
+            .collect();
+        let mut messages_to_send = vec![];
+        for (session_id, relay_session) in sessions {
+            messages_to_send.extend(relay_session.expire(now));
+            if relay_session.is_finished() {
+                self.remove_session(session_id);
+            }
+        }
+        messages_to_send
+    }
+
+    // Once a session is completed or aborted it is removed from the registry,
+    // so its identifier can be used by a new group
+    fn remove_if_finished(&self, addr: &SocketAddr, relay_session: &RelaySession) {
+        if !relay_session.is_finished() {
+            return;
+        }
+        let session_id = match self.connections.read().get(addr) {
+            Some(connection) => connection.session_id,
+            None => None,
+        };
+        if let Some(session_id) = session_id {
+            self.remove_session(session_id);
+        }
+    }
+
+    /// Removes a session from the registry,
+    /// and release all the connections that were registered to it
+    pub fn remove_session(&self, session_id: SessionIdentifier) {
+        info!("Removing session {:}", session_id);
+        self.sessions.write().remove(&session_id);
+        self.connections
+            .write()
+            .values_mut()
+            .filter(|connection| connection.session_id == Some(session_id))
+            .for_each(|connection| connection.session_id = None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionRegistry;
+    use crate::relay_server::ServerConfig;
+    use crate::relay_session::{Client, RelaySessionState};
+
+    use tokio::sync::mpsc;
+
+    use relay_server_common::encryption::EncryptionKeypair;
+    use relay_server_common::error::RelayError;
+    use relay_server_common::identity::Identity;
+    use relay_server_common::protocol::ProtocolRegistry;
+    use relay_server_common::{
+        AbortReason, ClientMessage, ProtocolIdentifier, ProtocolVersion, RegisterMessage,
+        RosterEntry, ServerMessage, ServerMessageType, ServerResponse, SessionIdentifier,
+        SignedEncryptionKey,
+    };
+
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    // The shipped protocols, with the protocols the tests run
+    const TEST_PROTOCOLS_FILE: &str = "./tests/protocols.json";
+
+    // A configuration supporting the protocols of the test protocols file
+    fn config(capacity: u32) -> ServerConfig {
+        ServerConfig {
+            protocols: ProtocolRegistry::load(TEST_PROTOCOLS_FILE).unwrap(),
+            ..ServerConfig::new(capacity)
+        }
+    }
+
+    fn roster_entry(addr: &SocketAddr) -> RosterEntry {
+        RosterEntry {
+            identity: format!("identity-{}", addr),
+            encryption_key: SignedEncryptionKey::default(),
+        }
+    }
+
+    fn register_message(
+        session_id: SessionIdentifier,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        identity: &Identity,
+    ) -> RegisterMessage {
+        let mut msg = ClientMessage::new();
+        let encryption_key = identity.sign_encryption_key(&EncryptionKeypair::generate());
+        msg.register(
+            session_id,
+            protocol_id,
+            capacity,
+            identity.public_key(),
+            encryption_key,
+        );
+        msg.register.unwrap()
+    }
+
+    fn challenge_of(messages: &[(ServerMessage, mpsc::Sender<ServerMessage>)]) -> String {
+        match messages[0].0.response {
+            Some(ServerResponse::Challenge(ref challenge)) => challenge.clone(),
+            ref response => panic!("Expected a challenge, got {:?}", response),
+        }
+    }
+
+    fn error_of(messages: &[(ServerMessage, mpsc::Sender<ServerMessage>)]) -> RelayError {
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(err_msg)) => err_msg,
+            ref response => panic!("Expected an error, got {:?}", response),
+        }
+    }
+
+    fn connect(registry: &SessionRegistry, port: u32) -> SocketAddr {
+        let client_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (tx, _) = mpsc::channel(1);
+        registry.insert_new_connection(client_addr, Client::new(tx));
+        client_addr
+    }
+
+    #[test]
+    fn test_register_creates_session() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let registry = SessionRegistry::new(config(capacity));
+        let client_addr = connect(&registry, 8081);
+
+        registry.register(
+            client_addr,
+            roster_entry(&client_addr),
+            7,
+            protocol_id,
+            capacity,
+        );
+        assert_eq!(registry.number_of_sessions(), 1);
+        let relay_session = registry
+            .get_session_by_address(&client_addr)
+            .expect("Not registered to a session");
+        assert_eq!(RelaySessionState::Uninitialized, relay_session.state());
+    }
+
+    #[test]
+    fn test_register_with_challenge() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 1;
+        let registry = SessionRegistry::new(config(capacity));
+        let client_addr = connect(&registry, 8081);
+        let peer_identity = Identity::generate();
+
+        let messages = registry.request_challenge(
+            client_addr,
+            register_message(1, protocol_id, capacity, &peer_identity),
+        );
+        // not registered until the challenge is signed
+        assert!(registry.get_session_by_address(&client_addr).is_none());
+        let challenge = challenge_of(&messages);
+
+        let messages =
+            registry.respond_to_challenge(client_addr, &peer_identity.sign_challenge(&challenge));
+        assert_eq!(messages.len(), 1);
+        let relay_session = registry.get_session_by_address(&client_addr).unwrap();
+        let peer = relay_session.get_peer_by_address(&client_addr).unwrap();
+        assert_eq!(peer.identity, peer_identity.public_key());
+    }
+
+    #[test]
+    fn test_register_challenge_failed() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 1;
+        let registry = SessionRegistry::new(config(capacity));
+        let client_addr = connect(&registry, 8081);
+        let peer_identity = Identity::generate();
+
+        // a challenge signed by another identity is rejected
+        let messages = registry.request_challenge(
+            client_addr,
+            register_message(1, protocol_id, capacity, &peer_identity),
+        );
+        let challenge = challenge_of(&messages);
+        let signature = Identity::generate().sign_challenge(&challenge);
+        let messages = registry.respond_to_challenge(client_addr, &signature);
+        assert_eq!(error_of(&messages), RelayError::ChallengeFailed);
+        assert!(registry.get_session_by_address(&client_addr).is_none());
+
+        // the challenge can not be answered again after a failure
+        let signature = peer_identity.sign_challenge(&challenge);
+        let messages = registry.respond_to_challenge(client_addr, &signature);
+        assert_eq!(error_of(&messages), RelayError::ChallengeFailed);
+        assert_eq!(registry.number_of_sessions(), 0);
+    }
+
+    #[test]
+    fn test_register_invalid_identity_key() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 1;
+        let registry = SessionRegistry::new(config(capacity));
+        let client_addr = connect(&registry, 8081);
+
+        let mut register = register_message(1, protocol_id, capacity, &Identity::generate());
+        register.identity = client_addr.to_string();
+        let messages = registry.request_challenge(client_addr, register);
+        assert_eq!(error_of(&messages), RelayError::InvalidIdentityKey);
+    }
+
+    #[test]
+    fn test_register_invalid_encryption_key() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 1;
+        let registry = SessionRegistry::new(config(capacity));
+        let client_addr = connect(&registry, 8081);
+
+        // an encryption key signed by another identity is rejected
+        let mut register = register_message(1, protocol_id, capacity, &Identity::generate());
+        register.encryption_key =
+            Identity::generate().sign_encryption_key(&EncryptionKeypair::generate());
+        let messages = registry.request_challenge(client_addr, register);
+        assert_eq!(error_of(&messages), RelayError::InvalidEncryptionKey);
+        assert!(registry.get_session_by_address(&client_addr).is_none());
+    }
+
+    #[test]
+    fn test_register_by_protocol_name() {
+        let capacity: u32 = 2;
+        let registry = SessionRegistry::new(config(capacity));
+        let client_addr = connect(&registry, 8081);
+        let peer_identity = Identity::generate();
+
+        let mut register = register_message(1, 0, capacity, &peer_identity);
+        register.protocol = Some(ProtocolVersion {
+            name: String::from("test-round-protocol"),
+            version: String::from("1.0.0"),
+        });
+        let messages = registry.request_challenge(client_addr, register.clone());
+        let challenge = challenge_of(&messages);
+        registry.respond_to_challenge(client_addr, &peer_identity.sign_challenge(&challenge));
+        // the session runs the protocol the name resolved to
+        let relay_session = registry.get_session_by_address(&client_addr).unwrap();
+        assert_eq!(relay_session.protocol().id, 2);
+
+        let unsupported = [
+            ("ecdsa", "1.0.0", capacity, RelayError::UnknownProtocol),
+            (
+                "test-round-protocol",
+                "2.0.0",
+                capacity,
+                RelayError::UnsupportedVersion,
+            ),
+            (
+                "test-round-protocol",
+                "1.0.0",
+                5,
+                RelayError::UnsupportedCapacity,
+            ),
+        ];
+        for (i, (name, version, capacity, err)) in unsupported.iter().enumerate() {
+            let client_addr = connect(&registry, 8082 + i as u32);
+            let mut register = register.clone();
+            register.capacity = *capacity;
+            register.protocol = Some(ProtocolVersion {
+                name: String::from(*name),
+                version: String::from(*version),
+            });
+            let messages = registry.request_challenge(client_addr, register);
+            assert_eq!(error_of(&messages), *err);
+        }
+
+        // protocols requested by identifier are checked as well
+        let client_addr = connect(&registry, 8090);
+        let register = register_message(1, 7, capacity, &Identity::generate());
+        let messages = registry.request_challenge(client_addr, register);
+        assert_eq!(error_of(&messages), RelayError::UnknownProtocol);
+    }
+
+    #[test]
+    fn test_independent_sessions() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let registry = SessionRegistry::new(config(capacity));
+        let sessions: Vec<SessionIdentifier> = vec![1, 2, 3];
+
+        for (i, session_id) in sessions.iter().enumerate() {
+            for j in 0..capacity {
+                let client_addr = connect(&registry, 8080 + (i as u32) * 10 + j);
+                registry.register(
+                    client_addr,
+                    roster_entry(&client_addr),
+                    *session_id,
+                    protocol_id,
+                    capacity,
+                );
+            }
+        }
+        assert_eq!(registry.number_of_sessions(), sessions.len());
+        for session_id in sessions {
+            let relay_session = registry.get_session(session_id).unwrap();
+            assert_eq!(RelaySessionState::Initialized, relay_session.state());
+        }
+    }
+
+    #[test]
+    fn test_full_session_does_not_block_others() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 1;
+        let registry = SessionRegistry::new(config(capacity));
+
+        let first = connect(&registry, 8081);
+        registry.register(first, roster_entry(&first), 1, protocol_id, capacity);
+        let second = connect(&registry, 8082);
+        // session 1 is full, this peer can not join it
+        let messages = registry.register(second, roster_entry(&second), 1, protocol_id, capacity);
+        assert_eq!(error_of(&messages), RelayError::CantRegister);
+        assert!(registry.get_session_by_address(&second).is_none());
+        // but it can start a new session
+        let messages = registry.register(second, roster_entry(&second), 2, protocol_id, capacity);
+        assert_eq!(messages.len(), 1);
+        messages
+            .iter()
+            .for_each(|(msg, _)| assert_eq!(msg.msg_type(), ServerMessageType::Response));
+    }
+
+    #[test]
+    fn test_abort_only_affects_own_session() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let registry = SessionRegistry::new(config(capacity));
+
+        let first = connect(&registry, 8081);
+        registry.register(first, roster_entry(&first), 1, protocol_id, capacity);
+        let second = connect(&registry, 8082);
+        registry.register(second, roster_entry(&second), 2, protocol_id, capacity);
+
+        let messages = registry.abort(first, AbortReason::Disconnected);
+        assert_eq!(messages.len(), 1);
+        assert!(registry.get_session(1).is_none());
+        assert_eq!(
+            RelaySessionState::Uninitialized,
+            registry.get_session(2).unwrap().state()
+        );
+    }
+
+    #[test]
+    fn test_session_reuse_after_abort() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let registry = SessionRegistry::new(config(capacity));
+
+        let first = connect(&registry, 8081);
+        registry.register(first, roster_entry(&first), 1, protocol_id, capacity);
+        registry.abort(first, AbortReason::Disconnected);
+        assert_eq!(registry.number_of_sessions(), 0);
+        assert!(registry.get_session_by_address(&first).is_none());
+
+        // the same identifier can be used by a new group
+        for port in 8082..8084 {
+            let client_addr = connect(&registry, port);
+            registry.register(
+                client_addr,
+                roster_entry(&client_addr),
+                1,
+                protocol_id,
+                capacity,
+            );
+        }
+        assert_eq!(
+            RelaySessionState::Initialized,
+            registry.get_session(1).unwrap().state()
+        );
+    }
+
+    #[test]
+    fn test_reject_unregistered() {
+        let registry = SessionRegistry::new(config(2));
+        let client_addr = connect(&registry, 8081);
+        // a connection that did not register can not complete a session
+        let messages = registry.complete(client_addr);
+        assert_eq!(error_of(&messages), RelayError::NotRegistered);
+        let messages = registry.reject_unregistered(client_addr);
+        assert_eq!(error_of(&messages), RelayError::NotRegistered);
+        // the connection is kept, and can still register
+        registry.register(client_addr, roster_entry(&client_addr), 1, 1, 2);
+        assert!(registry.get_session_by_address(&client_addr).is_some());
+    }
+
+    #[test]
+    fn test_session_removed_after_completion() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let registry = SessionRegistry::new(config(capacity));
+
+        let first = connect(&registry, 8081);
+        registry.register(first, roster_entry(&first), 1, protocol_id, capacity);
+        let second = connect(&registry, 8082);
+        registry.register(second, roster_entry(&second), 1, protocol_id, capacity);
+
+        assert!(registry.complete(first).is_empty());
+        assert_eq!(registry.number_of_sessions(), 1);
+        let messages = registry.complete(second);
+        assert_eq!(messages.len(), capacity as usize);
+        assert_eq!(registry.number_of_sessions(), 0);
+
+        // connections are released, and can register to a new session
+        registry.register(first, roster_entry(&first), 2, protocol_id, capacity);
+        assert!(registry.get_session_by_address(&first).is_some());
+        // a disconnect after completion does not abort anything
+        assert!(registry.abort(second, AbortReason::Disconnected).is_empty());
+    }
+
+    #[test]
+    fn test_expire_sessions() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 1;
+        let timeout = Duration::from_secs(10);
+        let registry = SessionRegistry::new(ServerConfig {
+            timeout: Some(timeout),
+            ..config(capacity)
+        });
+
+        let first = connect(&registry, 8081);
+        registry.register(first, roster_entry(&first), 1, protocol_id, capacity);
+        // a session that did not start yet has no deadline
+        let second = connect(&registry, 8082);
+        registry.register(second, roster_entry(&second), 2, protocol_id, 2);
+
+        assert!(registry.expire_sessions(Instant::now()).is_empty());
+        let messages = registry.expire_sessions(Instant::now() + timeout);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0.abort.as_ref().unwrap().stalled_peers, vec![1]);
+        assert!(registry.get_session(1).is_none());
+        assert!(registry.get_session_by_address(&first).is_none());
+        assert!(registry.get_session(2).is_some());
+    }
+
+    #[test]
+    fn test_resume_rebinds_connection() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let grace_period = Duration::from_secs(10);
+        let registry = SessionRegistry::new(ServerConfig {
+            grace_period: Some(grace_period),
+            ..config(capacity)
+        });
+
+        let first = connect(&registry, 8081);
+        let messages = registry.register(first, roster_entry(&first), 1, protocol_id, capacity);
+        let token = match messages[0].0.response {
+            Some(ServerResponse::ResumeToken(ref token)) => token.clone(),
+            _ => panic!("Expected a resumption token"),
+        };
+        let second = connect(&registry, 8082);
+        registry.register(second, roster_entry(&second), 1, protocol_id, capacity);
+
+        // the session is kept while the peer may resume
+        assert!(registry.disconnect(first, Instant::now()).is_empty());
+        assert_eq!(registry.number_of_sessions(), 1);
+
+        let third = connect(&registry, 8083);
+        let mut msg = ClientMessage::new();
+        msg.resume(1, String::from("not a token"), 0);
+        let messages = registry.resume(third, msg.resume.unwrap());
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(err_msg)) => {
+                assert_eq!(err_msg, RelayError::InvalidResumeToken)
+            }
+            _ => panic!("Expected an error response"),
+        }
+        assert!(registry.get_session_by_address(&third).is_none());
+
+        let mut msg = ClientMessage::new();
+        msg.resume(1, token, 0);
+        let messages = registry.resume(third, msg.resume.unwrap());
+        match messages[0].0.response {
+            Some(ServerResponse::Resumed(peer_id, _)) => assert_eq!(peer_id, 1),
+            _ => panic!("Expected a resumed response"),
+        }
+        // the new connection took the place of the old one in the session
+        assert!(registry.get_session_by_address(&third).is_some());
+        assert!(registry.get_session_by_address(&first).is_none());
+        assert!(registry.disconnect(first, Instant::now()).is_empty());
+        assert_eq!(
+            RelaySessionState::Initialized,
+            registry.get_session(1).unwrap().state()
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Append-only transcript of a relay session.
+//!
+//! The relay is not trusted, but it is accountable: everything it does in a session
+//! is written to the transcript of the session, so it can be inspected after the fact.
+//!
+//! A transcript is a file with one JSON object per line, in the order the events happened:
+//!
+//! ```text
+//! {"timestamp":1590000000000,"session_id":1,"event":{"Register":{"peer_id":1,"identity":"<hex>"}}}
+//! {"timestamp":1590000000120,"session_id":1,"event":{"Relay":{"sender":1,"from":"<hex>","round":0,"to":[2],"message":"<payload>"}}}
+//! {"timestamp":1590000000300,"session_id":1,"event":{"Abort":{"peer_number":2,"reason":"Disconnected",...}}}
+//! ```
+//!
+//! `timestamp` is the time of the event in milliseconds since the unix epoch.
+//! The transcript of a session is written to `session-<session id>-<timestamp>.jsonl`
+//! in the transcript directory, where the timestamp is the time the session was created.
+use log::error;
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use relay_server_common::{
+    AbortMessage, IdentityKey, PeerIdentifier, RelayPayload, SessionIdentifier,
+};
+
+/// An event of a relay session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TranscriptEvent {
+    // a peer registered to the session with the given identity
+    Register {
+        peer_id: PeerIdentifier,
+        identity: IdentityKey,
+    },
+
+    // a message was relayed from the sender to the recipients
+    Relay {
+        sender: PeerIdentifier,
+        from: IdentityKey,
+        round: u32,
+        to: Vec<PeerIdentifier>,
+        message: RelayPayload,
+    },
+
+    // a peer echoed the digest of the broadcast messages it received in a round
+    Echo {
+        sender: PeerIdentifier,
+        round: u32,
+        digest: String,
+    },
+
+    // a disconnected peer resumed its registration
+    Resume {
+        peer_id: PeerIdentifier,
+    },
+
+    // the session was aborted
+    Abort(AbortMessage),
+
+    // all the peers are done, the session is completed
+    Completed,
+}
+
+/// A single line of a transcript
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TranscriptEntry {
+    // milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub session_id: SessionIdentifier,
+    pub event: TranscriptEvent,
+}
+
+/// The transcript file of a single session. Entries are only ever appended to it
+#[derive(Debug)]
+pub struct Transcript {
+    session_id: SessionIdentifier,
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Transcript {
+    /// Creates the transcript of a new session in the given directory
+    pub fn create(dir: &Path, session_id: SessionIdentifier) -> io::Result<Transcript> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("session-{}-{}.jsonl", session_id, now_millis()));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Transcript {
+            session_id,
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Returns the path of the transcript file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends the event to the transcript, with the current time.
+    /// A failure to write is logged, it does not stop the session
+    pub fn record(&self, event: TranscriptEvent) {
+        let entry = TranscriptEntry {
+            timestamp: now_millis(),
+            session_id: self.session_id,
+            event,
+        };
+        let mut file = self.file.lock();
+        let written = serde_json::to_string(&entry)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(file, "{}", line))
+            .and_then(|()| file.flush());
+        if let Err(err) = written {
+            error!("Failed writing to transcript {:?}: {}", self.path, err);
+        }
+    }
+}
+
+/// Reads all the entries of the transcript file in path
+pub fn read_transcript(path: &Path) -> io::Result<Vec<TranscriptEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+    reader
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+// Current time in milliseconds since the unix epoch
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_transcript, Transcript, TranscriptEvent};
+    use relay_server_common::{AbortMessage, AbortReason};
+    use std::fs;
+
+    #[test]
+    fn test_record_and_read() {
+        let dir = std::env::temp_dir().join(format!("relay-transcript-{}", std::process::id()));
+        let transcript = Transcript::create(&dir, 7).unwrap();
+        transcript.record(TranscriptEvent::Register {
+            peer_id: 1,
+            identity: String::from("identity"),
+        });
+        transcript.record(TranscriptEvent::Relay {
+            sender: 1,
+            from: String::from("identity"),
+            round: 0,
+            to: vec![2, 3],
+            message: "hello".into(),
+        });
+        transcript.record(TranscriptEvent::Abort(AbortMessage::blame(
+            2,
+            1,
+            AbortReason::Requested,
+            None,
+            None,
+        )));
+
+        let entries = read_transcript(transcript.path()).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().all(|entry| entry.session_id == 7));
+        assert!(entries
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        match entries[1].event {
+            TranscriptEvent::Relay {
+                sender,
+                ref to,
+                ref message,
+                ..
+            } => {
+                assert_eq!(sender, 1);
+                assert_eq!(to, &vec![2, 3]);
+                assert_eq!(message, &"hello".into());
+            }
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+        match entries[2].event {
+            TranscriptEvent::Abort(ref abort) => assert_eq!(abort.peer_number, 2),
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,423 @@
-//use futures::sync::mpsc;
-//use relay_server::Client;
-//use relay_server::RelayServer;
-//use relay_server_common::ProtocolIdentifier;
-//use std::net::SocketAddr;
+use futures::{SinkExt, StreamExt};
+use relay_client::{Client, ClientError, Peer, RelayConnection, TcpBoard};
+use relay_server::{RelayServer, ServerConfig};
+use relay_server_common::board::{self, RunError};
+use relay_server_common::codec::WireFormat;
+use relay_server_common::encryption::{verify_roster, EncryptionKeypair};
+use relay_server_common::error::RelayError;
+use relay_server_common::identity::Identity;
+use relay_server_common::protocol::ProtocolRegistry;
+use relay_server_common::{
+    AbortMessage, Blame, ClientMessage, ClientToServerCodec, MessagePayload, PeerIdentifier,
+    RelayMessage, ServerMessageType, ServerResponse,
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::thread;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::Framed;
+
+// The shipped protocols, with the protocols the tests run
+const TEST_PROTOCOLS_FILE: &str = "./tests/protocols.json";
+
+// A configuration of sessions with the given capacity and protocols
+fn config(capacity: u32, protocols: ProtocolRegistry) -> ServerConfig {
+    ServerConfig {
+        protocols,
+        ..ServerConfig::new(capacity)
+    }
+}
 
 #[test]
 fn test_server_add_peer() {
     assert_eq!(1, 1);
 }
+
+#[tokio::test]
+async fn test_register_over_tcp() {
+    let capacity = 2;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(RelayServer::serve(
+        listener,
+        config(
+            capacity,
+            ProtocolRegistry::load(TEST_PROTOCOLS_FILE).unwrap(),
+        ),
+    ));
+
+    let mut clients = Vec::new();
+    for _ in 0..capacity {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(stream, ClientToServerCodec::new(false));
+        let identity = Identity::generate();
+        let mut msg = ClientMessage::new();
+        let encryption_key = identity.sign_encryption_key(&EncryptionKeypair::generate());
+        msg.register(1, 1, capacity, identity.public_key(), encryption_key);
+        client.send(msg).await.unwrap();
+
+        // registration completes once the challenge is signed
+        let challenge = match client.next().await.unwrap().unwrap().response {
+            Some(ServerResponse::Challenge(challenge)) => challenge,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        let mut msg = ClientMessage::new();
+        msg.challenge_response(identity.sign_challenge(&challenge));
+        client.send(msg).await.unwrap();
+        clients.push(client);
+    }
+
+    // once the session is full, every peer gets its peer number and the keys of all the peers
+    let mut peer_numbers = Vec::new();
+    for client in clients.iter_mut() {
+        let msg = client.next().await.unwrap().unwrap();
+        assert_eq!(msg.msg_type(), ServerMessageType::Response);
+        match msg.response.unwrap() {
+            ServerResponse::Register(peer_number, roster) => {
+                assert_eq!(roster.len(), capacity as usize);
+                assert_eq!(verify_roster(&roster), Ok(()));
+                peer_numbers.push(peer_number)
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+    peer_numbers.sort();
+    assert_eq!(peer_numbers, vec![1, 2]);
+}
+
+#[tokio::test]
+async fn test_message_from_unregistered_connection() {
+    let capacity = 1;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(RelayServer::serve(
+        listener,
+        config(
+            capacity,
+            ProtocolRegistry::load(TEST_PROTOCOLS_FILE).unwrap(),
+        ),
+    ));
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut client = Framed::new(stream, ClientToServerCodec::new(false));
+    let mut msg = ClientMessage::new();
+    let mut relay_message = RelayMessage::new(1, 1);
+    relay_message.set_message_params(0, vec![2], "not registered");
+    msg.relay_message = Some(relay_message);
+    client.send(msg).await.unwrap();
+    // the server answers with an error rather than going down
+    match client.next().await.unwrap().unwrap().response {
+        Some(ServerResponse::ErrorResponse(err)) => assert_eq!(err, RelayError::NotRegistered),
+        response => panic!("Unexpected response {:?}", response),
+    }
+    let mut msg = ClientMessage::new();
+    msg.abort = Some(AbortMessage::new(1, 1));
+    client.send(msg).await.unwrap();
+    match client.next().await.unwrap().unwrap().response {
+        Some(ServerResponse::ErrorResponse(err)) => assert_eq!(err, RelayError::NotRegistered),
+        response => panic!("Unexpected response {:?}", response),
+    }
+    client.send(ClientMessage::new()).await.unwrap();
+    match client.next().await.unwrap().unwrap().response {
+        Some(ServerResponse::ErrorResponse(err)) => assert_eq!(err, RelayError::MalformedMessage),
+        response => panic!("Unexpected response {:?}", response),
+    }
+
+    // the connection is still open, and can register
+    let identity = Identity::generate();
+    let mut msg = ClientMessage::new();
+    let encryption_key = identity.sign_encryption_key(&EncryptionKeypair::generate());
+    msg.register(1, 1, capacity, identity.public_key(), encryption_key);
+    client.send(msg).await.unwrap();
+    match client.next().await.unwrap().unwrap().response {
+        Some(ServerResponse::Challenge(_)) => {}
+        response => panic!("Unexpected response {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn test_resume_over_tcp() {
+    let capacity = 2;
+    let session_id = 1;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let grace_period = Duration::from_secs(10);
+    tokio::spawn(RelayServer::serve(
+        listener,
+        ServerConfig {
+            grace_period: Some(grace_period),
+            ..config(
+                capacity,
+                ProtocolRegistry::load(TEST_PROTOCOLS_FILE).unwrap(),
+            )
+        },
+    ));
+
+    let mut clients = Vec::new();
+    for _ in 0..capacity {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(stream, ClientToServerCodec::new(false));
+        let identity = Identity::generate();
+        let mut msg = ClientMessage::new();
+        let encryption_key = identity.sign_encryption_key(&EncryptionKeypair::generate());
+        msg.register(
+            session_id,
+            1,
+            capacity,
+            identity.public_key(),
+            encryption_key,
+        );
+        client.send(msg).await.unwrap();
+        let challenge = match client.next().await.unwrap().unwrap().response {
+            Some(ServerResponse::Challenge(challenge)) => challenge,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        let mut msg = ClientMessage::new();
+        msg.challenge_response(identity.sign_challenge(&challenge));
+        client.send(msg).await.unwrap();
+
+        // a registered peer gets the token it can resume with
+        let token = match client.next().await.unwrap().unwrap().response {
+            Some(ServerResponse::ResumeToken(token)) => token,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        clients.push((client, identity, token));
+    }
+    let mut peers = HashMap::new();
+    for (mut client, identity, token) in clients {
+        match client.next().await.unwrap().unwrap().response {
+            Some(ServerResponse::Register(peer_number, _)) => {
+                peers.insert(peer_number, (client, identity, token));
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    // the second peer loses its connection
+    let (lost, _, token) = peers.remove(&2).unwrap();
+    drop(lost);
+
+    // the first peer sends its message in the meantime
+    let (mut first, identity, _) = peers.remove(&1).unwrap();
+    let mut msg = ClientMessage::new();
+    let mut relay_message = RelayMessage::new(1, 1);
+    relay_message.set_message_params(0, vec![1, 2], "sent while away");
+    msg.relay_message = Some(relay_message);
+    identity.sign_message(&mut msg, session_id, 0);
+    first.send(msg).await.unwrap();
+    let msg = first.next().await.unwrap().unwrap();
+    assert_eq!(msg.msg_type(), ServerMessageType::RelayMessage);
+
+    // the second peer reconnects and gets the message it missed
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut resumed = Framed::new(stream, ClientToServerCodec::new(false));
+    let mut msg = ClientMessage::new();
+    msg.resume(session_id, token, 0);
+    resumed.send(msg).await.unwrap();
+    match resumed.next().await.unwrap().unwrap().response {
+        Some(ServerResponse::Resumed(peer_number, roster)) => {
+            assert_eq!(peer_number, 2);
+            assert_eq!(roster.len(), capacity as usize);
+        }
+        response => panic!("Unexpected response {:?}", response),
+    }
+    let msg = resumed.next().await.unwrap().unwrap();
+    let relay_message = msg.relay_message.unwrap();
+    assert_eq!(relay_message.peer_number, 1);
+    assert_eq!(relay_message.message, "sent while away".into());
+}
+
+// A peer that broadcasts its number and the step in every round
+struct TestPeer {
+    peer_id: PeerIdentifier,
+    capacity: u32,
+    rounds: u32,
+    current_step: u32,
+    // the peers whose payload was received in every step
+    received: HashMap<u32, Vec<PeerIdentifier>>,
+}
+
+impl TestPeer {
+    fn new(capacity: u32, rounds: u32) -> TestPeer {
+        TestPeer {
+            peer_id: 0,
+            capacity,
+            rounds,
+            current_step: 0,
+            received: HashMap::new(),
+        }
+    }
+}
+
+impl Peer for TestPeer {
+    type Error = Infallible;
+
+    fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+        self.peer_id = peer_id;
+        self.get_next_item()
+    }
+
+    fn do_step(&mut self) {
+        let received = self.received.get(&self.current_step).map_or(0, Vec::len);
+        if received == self.capacity as usize {
+            self.current_step += 1;
+        }
+    }
+
+    fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        assert_eq!(payload, format!("{}:{}", from, self.current_step));
+        self.received
+            .entry(self.current_step)
+            .or_default()
+            .push(from);
+    }
+
+    fn get_next_item(&mut self) -> Option<MessagePayload> {
+        if self.current_step < self.rounds {
+            Some(format!("{}:{}", self.peer_id, self.current_step).into())
+        } else {
+            None
+        }
+    }
+
+    fn finalize(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn is_done(&mut self) -> bool {
+        self.current_step == self.rounds
+    }
+
+    fn current_step(&self) -> u32 {
+        self.current_step
+    }
+
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn peer_id(&self) -> PeerIdentifier {
+        self.peer_id
+    }
+
+    fn blame(&self) -> Option<Blame> {
+        None
+    }
+}
+
+#[tokio::test]
+async fn test_relay_client_runs_protocol() {
+    let capacity = 2;
+    // the test peers run the broadcast rounds of the test echo protocol, with echoes
+    let protocol_id = 4;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let protocols = ProtocolRegistry::load(TEST_PROTOCOLS_FILE).unwrap();
+    tokio::spawn(RelayServer::serve(
+        listener,
+        config(capacity, protocols.clone()),
+    ));
+
+    let mut peers = Vec::new();
+    for _ in 0..capacity {
+        let rounds = protocols.protocol_rounds(protocol_id).unwrap();
+        let mut client =
+            Client::new(&protocols, protocol_id, TestPeer::new(capacity, rounds)).unwrap();
+        client.set_resend_delay(Duration::from_millis(10));
+        peers.push(tokio::spawn(async move {
+            let mut connection = RelayConnection::connect(addr, Identity::generate(), 1).await?;
+            client.run(&mut connection).await?;
+            Ok::<_, ClientError>((client, connection))
+        }));
+    }
+    let rounds = protocols.protocol_rounds(protocol_id).unwrap();
+    let mut peer_ids = Vec::new();
+    for peer in peers {
+        let (mut client, connection) = peer.await.unwrap().unwrap();
+        assert!(client.data_manager.data_holder.is_done());
+        assert_eq!(client.peer().current_step(), rounds);
+        assert_eq!(connection.roster().len(), capacity as usize);
+        assert!(connection.chain_head().is_some());
+        assert_eq!(client.peer().peer_id(), connection.peer_id());
+        peer_ids.push(connection.peer_id());
+    }
+    peer_ids.sort();
+    assert_eq!(peer_ids, vec![1, 2]);
+}
+
+#[tokio::test]
+async fn test_peers_run_protocol_in_different_wire_formats() {
+    let capacity = 2;
+    let protocol_id = 1;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let protocols = ProtocolRegistry::load(TEST_PROTOCOLS_FILE).unwrap();
+    tokio::spawn(RelayServer::serve(
+        listener,
+        config(capacity, protocols.clone()),
+    ));
+
+    // one peer switches its connection to CBOR, the other stays in JSON
+    let mut peers = Vec::new();
+    for format in [WireFormat::Cbor, WireFormat::Json] {
+        let rounds = protocols.protocol_rounds(protocol_id).unwrap();
+        let mut client =
+            Client::new(&protocols, protocol_id, TestPeer::new(capacity, rounds)).unwrap();
+        client.set_resend_delay(Duration::from_millis(10));
+        peers.push(tokio::spawn(async move {
+            let mut connection = RelayConnection::connect(addr, Identity::generate(), 1).await?;
+            if format != WireFormat::Json {
+                connection.hello(format).await?;
+            }
+            client.run(&mut connection).await?;
+            Ok::<_, ClientError>((client, connection))
+        }));
+    }
+    for peer in peers {
+        let (client, connection) = peer.await.unwrap().unwrap();
+        assert_eq!(client.peer().current_step(), 4);
+        assert!(connection.chain_head().is_some());
+    }
+}
+
+#[test]
+fn test_peer_runs_over_tcp_board() {
+    let capacity = 2;
+    let protocol_id = 1;
+    let protocols = ProtocolRegistry::load(TEST_PROTOCOLS_FILE).unwrap();
+    assert!(!protocols.echo_broadcast(protocol_id));
+    let rounds = protocols.protocol_rounds(protocol_id).unwrap();
+    // the server runs on its own runtime, the board blocks the threads of the peers
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    runtime.spawn(RelayServer::serve(listener, config(capacity, protocols)));
+
+    let peers: Vec<_> = (0..capacity)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut board = TcpBoard::connect(addr, Identity::generate(), 1, protocol_id)?;
+                board.set_resend_delay(Duration::from_millis(10));
+                let peer = match board::run(&mut board, TestPeer::new(capacity, rounds)) {
+                    Ok(peer) => peer,
+                    Err(RunError::Board(err)) => return Err(err),
+                    Err(RunError::Peer(err)) => match err {},
+                };
+                // the protocol does not echo, the server completes the session after its last round
+                board.wait_completed()?;
+                Ok::<_, ClientError>((peer, board.connection().peer_id()))
+            })
+        })
+        .collect();
+    let mut peer_ids = Vec::new();
+    for peer in peers {
+        let (mut peer, peer_id) = peer.join().unwrap().unwrap();
+        assert!(peer.is_done());
+        assert_eq!(peer.peer_id(), peer_id);
+        assert_eq!(peer.received.len(), rounds as usize);
+        peer_ids.push(peer_id);
+    }
+    peer_ids.sort();
+    assert_eq!(peer_ids, vec![1, 2]);
+}
@@ -0,0 +1,387 @@
+//! A bulletin board the peers of a session post their payloads to, round by round.
+//! The board hides how payloads travel between the peers, so a peer runs the same against
+//! the relay server, the Tendermint application, the HTTP store, or a board kept in memory
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::peer::{Peer, ProtocolDataManager};
+use crate::{Blame, MessagePayload, PeerIdentifier};
+
+// How long subscribe waits between two fetches of a round
+const POLL_DELAY: Duration = Duration::from_millis(10);
+/// Times subscribe fetches a round before giving up on it
+pub const MAX_POLLS: u32 = 6000;
+
+/// A payload a peer posted to the board
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoardMessage {
+    pub from: PeerIdentifier,
+    pub round: u32,
+    pub payload: MessagePayload,
+}
+
+/// Why a peer could not run its protocol over a board.
+/// Every board reports its own errors, which can be made out of these
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoardError {
+    // the peer could not join the session of the board
+    CantJoin,
+
+    // the peer posted to the board before it joined the session
+    NotJoined,
+
+    // the payload could not be posted
+    CantPost,
+
+    // the peer already posted its payload in the round
+    AlreadyPosted,
+
+    // the peer found misbehavior, or could not move on with the payloads of the round
+    ProtocolFailed,
+
+    // the payloads of the round did not all arrive in time
+    TimedOut,
+}
+
+impl BoardError {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BoardError::CantJoin => "Unable to join the session",
+            BoardError::NotJoined => "Peer did not join the session",
+            BoardError::CantPost => "Unable to post the payload",
+            BoardError::AlreadyPosted => "Peer already posted its payload in this round",
+            BoardError::ProtocolFailed => "Peer could not continue the protocol",
+            BoardError::TimedOut => "Timed out waiting for the payloads of the round",
+        }
+    }
+}
+
+impl fmt::Display for BoardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::error::Error for BoardError {}
+
+/// Why a peer could not be run over a board: the board failed, or the peer could not
+/// compute the output of the protocol once the last round was done
+#[derive(Debug, PartialEq)]
+pub enum RunError<B, P> {
+    Board(B),
+    Peer(P),
+}
+
+impl<B: fmt::Display, P: fmt::Display> fmt::Display for RunError<B, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Board(err) => write!(f, "{}", err),
+            RunError::Peer(err) => write!(f, "Unable to finalize the protocol: {}", err),
+        }
+    }
+}
+
+impl<B, P> std::error::Error for RunError<B, P>
+where
+    B: fmt::Debug + fmt::Display,
+    P: fmt::Debug + fmt::Display,
+{
+}
+
+impl<B, P> From<B> for RunError<B, P> {
+    fn from(err: B) -> RunError<B, P> {
+        RunError::Board(err)
+    }
+}
+
+pub trait BulletinBoard {
+    type Error: From<BoardError>;
+
+    /// Joins the session of the board, returns the number given to this peer
+    fn join(&mut self, capacity: u32) -> Result<PeerIdentifier, Self::Error>;
+
+    /// Posts the payload of this peer in the given round
+    fn post(&mut self, round: u32, payload: MessagePayload) -> Result<(), Self::Error>;
+
+    /// Returns the payloads posted so far in the given round, in the order of their peers
+    fn fetch(&mut self, round: u32) -> Result<Vec<BoardMessage>, Self::Error>;
+
+    /// Waits until `count` payloads were posted in the given round, and returns them.
+    /// Gives up once the round was fetched `MAX_POLLS` times
+    fn subscribe(&mut self, round: u32, count: usize) -> Result<Vec<BoardMessage>, Self::Error> {
+        for _ in 0..MAX_POLLS {
+            let messages = self.fetch(round)?;
+            if messages.len() >= count {
+                return Ok(messages);
+            }
+            thread::sleep(POLL_DELAY);
+        }
+        Err(BoardError::TimedOut.into())
+    }
+
+    /// Aborts the session, blaming the peer this peer found misbehaving.
+    /// A board that can not abort its session leaves the other peers to give up on it
+    fn abort(&mut self, _blame: Blame) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Runs a protocol over a board until the peer is done, and returns the peer with its output.
+/// Every round the peer posts its payload, then moves on with the payloads of all the peers.
+/// Returns an error once the peer blamed another peer, after aborting the session, and the
+/// error of the peer if it could not finalize the protocol after the last round
+pub fn run<P: Peer, B: BulletinBoard>(
+    board: &mut B,
+    peer: P,
+) -> Result<P, RunError<B::Error, P::Error>> {
+    let capacity = peer.capacity();
+    let peer_id = board.join(capacity)?;
+    let mut manager = ProtocolDataManager::new(peer);
+    let mut next = manager.initialize_data(peer_id);
+    while let Some(payload) = next.take() {
+        // every payload is posted in the step the peer is in
+        let round = manager.data_holder.current_step();
+        board.post(round, payload)?;
+        for message in board.subscribe(round, capacity as usize)? {
+            next = manager.get_next_message(message.from, message.payload);
+        }
+        if let Some(blame) = manager.data_holder.blame() {
+            board.abort(blame)?;
+            return Err(RunError::Board(BoardError::ProtocolFailed.into()));
+        }
+        if manager.data_holder.current_step() == round {
+            return Err(RunError::Board(BoardError::ProtocolFailed.into()));
+        }
+    }
+    if !manager.data_holder.is_done() {
+        return Err(RunError::Board(BoardError::ProtocolFailed.into()));
+    }
+    manager.data_holder.finalize().map_err(RunError::Peer)?;
+    Ok(manager.data_holder)
+}
+
+#[derive(Default)]
+struct BoardState {
+    capacity: u32,
+    peers: u32,
+    rounds: BTreeMap<u32, BTreeMap<PeerIdentifier, MessagePayload>>,
+}
+
+/// A board kept in memory, shared by the peers of a single process. Used in tests
+#[derive(Default)]
+pub struct InMemoryBoard {
+    state: Arc<Mutex<BoardState>>,
+    peer_id: Option<PeerIdentifier>,
+}
+
+impl InMemoryBoard {
+    pub fn new() -> InMemoryBoard {
+        InMemoryBoard::default()
+    }
+
+    /// Returns a board for another peer of the same session
+    pub fn handle(&self) -> InMemoryBoard {
+        InMemoryBoard {
+            state: Arc::clone(&self.state),
+            peer_id: None,
+        }
+    }
+}
+
+impl BulletinBoard for InMemoryBoard {
+    type Error = BoardError;
+
+    fn join(&mut self, capacity: u32) -> Result<PeerIdentifier, BoardError> {
+        let mut state = self.state.lock().unwrap();
+        if state.peers == 0 {
+            state.capacity = capacity;
+        }
+        if self.peer_id.is_some() || state.capacity != capacity || state.peers == capacity {
+            return Err(BoardError::CantJoin);
+        }
+        state.peers += 1;
+        self.peer_id = Some(state.peers);
+        Ok(state.peers)
+    }
+
+    fn post(&mut self, round: u32, payload: MessagePayload) -> Result<(), BoardError> {
+        let peer_id = self.peer_id.ok_or(BoardError::NotJoined)?;
+        let mut state = self.state.lock().unwrap();
+        let messages = state.rounds.entry(round).or_default();
+        if messages.contains_key(&peer_id) {
+            return Err(BoardError::AlreadyPosted);
+        }
+        messages.insert(peer_id, payload);
+        Ok(())
+    }
+
+    fn fetch(&mut self, round: u32) -> Result<Vec<BoardMessage>, BoardError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .rounds
+            .get(&round)
+            .map(|messages| {
+                messages
+                    .iter()
+                    .map(|(from, payload)| BoardMessage {
+                        from: *from,
+                        round,
+                        payload: payload.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, BoardError, BulletinBoard, InMemoryBoard, RunError};
+    use crate::peer::Peer;
+    use crate::{Blame, MessagePayload, PeerIdentifier};
+    use std::thread;
+
+    #[derive(Debug, PartialEq)]
+    struct FinalizeFailed;
+
+    // Sends its number in every step, and keeps what the other peers sent
+    struct CountingPeer {
+        peer_id: PeerIdentifier,
+        capacity: u32,
+        current_step: u32,
+        received: Vec<MessagePayload>,
+        // finalize fails once the last step is done
+        fails: bool,
+    }
+
+    impl CountingPeer {
+        fn new(capacity: u32) -> CountingPeer {
+            CountingPeer {
+                peer_id: 0,
+                capacity,
+                current_step: 0,
+                received: Vec::new(),
+                fails: false,
+            }
+        }
+    }
+
+    impl Peer for CountingPeer {
+        type Error = FinalizeFailed;
+
+        fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+            self.peer_id = peer_id;
+            self.get_next_item()
+        }
+
+        fn do_step(&mut self) {
+            if self.received.len() as u32 == self.capacity * (self.current_step + 1) {
+                self.current_step += 1;
+            }
+        }
+
+        fn update_data(&mut self, _from: PeerIdentifier, payload: MessagePayload) {
+            self.received.push(payload);
+        }
+
+        fn get_next_item(&mut self) -> Option<MessagePayload> {
+            if self.current_step < 2 {
+                Some(format!("{}:{}", self.peer_id, self.current_step).into())
+            } else {
+                None
+            }
+        }
+
+        fn finalize(&mut self) -> Result<(), FinalizeFailed> {
+            if self.fails {
+                return Err(FinalizeFailed);
+            }
+            Ok(())
+        }
+
+        fn is_done(&mut self) -> bool {
+            self.current_step == 2
+        }
+
+        fn current_step(&self) -> u32 {
+            self.current_step
+        }
+
+        fn capacity(&self) -> u32 {
+            self.capacity
+        }
+
+        fn peer_id(&self) -> PeerIdentifier {
+            self.peer_id
+        }
+
+        fn blame(&self) -> Option<Blame> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_in_memory_board() {
+        let mut first = InMemoryBoard::new();
+        let mut second = first.handle();
+        let mut third = first.handle();
+        assert_eq!(first.post(0, "early".into()), Err(BoardError::NotJoined));
+        assert_eq!(first.join(2), Ok(1));
+        assert_eq!(second.join(2), Ok(2));
+        assert_eq!(third.join(2), Err(BoardError::CantJoin));
+
+        second.post(0, "2:0".into()).unwrap();
+        assert_eq!(
+            second.post(0, "again".into()),
+            Err(BoardError::AlreadyPosted)
+        );
+        assert_eq!(first.fetch(0).unwrap().len(), 1);
+        first.post(0, "1:0".into()).unwrap();
+        let messages = second.subscribe(0, 2).unwrap();
+        assert_eq!(
+            messages.iter().map(|m| m.from).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(messages[0].payload, "1:0");
+        assert!(first.fetch(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_run_over_board() {
+        let board = InMemoryBoard::new();
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let mut board = board.handle();
+                thread::spawn(move || run(&mut board, CountingPeer::new(3)).unwrap())
+            })
+            .collect();
+        for handle in handles {
+            let peer = handle.join().unwrap();
+            assert_eq!(peer.current_step(), 2);
+            let mut received: Vec<_> = peer.received.iter().filter_map(|p| p.as_str()).collect();
+            received.sort();
+            assert_eq!(received, vec!["1:0", "1:1", "2:0", "2:1", "3:0", "3:1"]);
+        }
+    }
+
+    #[test]
+    fn test_run_surfaces_finalize_error() {
+        let board = InMemoryBoard::new();
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let mut board = board.handle();
+                thread::spawn(move || {
+                    let mut peer = CountingPeer::new(2);
+                    peer.fails = true;
+                    run(&mut board, peer).map(|peer| peer.current_step())
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), Err(RunError::Peer(FinalizeFailed)));
+        }
+    }
+}
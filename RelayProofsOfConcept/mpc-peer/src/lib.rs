@@ -0,0 +1,48 @@
+//! The side of a protocol a single peer runs, shared by the clients of all the backends.
+//! A protocol is written once as a `Peer`, and run over the relay, the Tendermint application
+//! or the HTTP store by the driver or bulletin board of that backend
+use serde::{Deserialize, Serialize};
+
+pub mod board;
+pub mod peer;
+
+pub type PeerIdentifier = u32;
+// A payload is a structured value rather than text, so it is encoded in the format of the
+// channel it travels on, and a protocol decodes it into its own messages
+pub type MessagePayload = serde_json::Value;
+
+/// Why a session was aborted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub enum AbortReason {
+    // a peer asked to abort, without blaming another peer
+    #[default]
+    Requested,
+
+    // a peer disconnected before the session was completed
+    Disconnected,
+
+    // peers did not send their message before the deadline of the turn or round
+    Timeout,
+
+    // a peer sent a message that could not be interpreted
+    InvalidMessage,
+
+    // a peer revealed a value that does not open the commitment it sent
+    InvalidCommitment,
+
+    // the output of the protocol failed verification
+    InvalidSignature,
+
+    // peers received different broadcast messages in the same round
+    Equivocation,
+}
+
+/// Misbehavior a peer found while running the protocol, which it can not recover from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blame {
+    pub reason: AbortReason,
+    // the peer that misbehaved, if it is known
+    pub accused: Option<PeerIdentifier>,
+    // the round of the offending message
+    pub round: u32,
+}
@@ -0,0 +1,165 @@
+//! The state machine of a protocol, as run by a single peer.
+//! A peer knows nothing of how its payloads reach the other peers. A driver registers it,
+//! feeds it the payloads of every round, and publishes the payloads it produces, over the
+//! relay, the Tendermint bulletin board or the HTTP store alike
+use crate::{Blame, MessagePayload, PeerIdentifier};
+
+/// A protocol run by a single peer, one step per round
+pub trait Peer {
+    /// Why the output of the protocol could not be computed
+    type Error;
+
+    /// Starts the protocol as the given peer, returns the payload of the first round
+    fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload>;
+
+    /// Moves to the next step, once all the payloads of the current one were received
+    fn do_step(&mut self);
+
+    /// Keeps the payload a peer sent in the current step
+    fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload);
+
+    /// Returns the payload this peer has to send next, None once it is done
+    fn get_next_item(&mut self) -> Option<MessagePayload>;
+
+    /// Computes the output of the protocol
+    fn finalize(&mut self) -> Result<(), Self::Error>;
+
+    fn is_done(&mut self) -> bool;
+
+    fn current_step(&self) -> u32;
+
+    /// The number of peers running the protocol
+    fn capacity(&self) -> u32;
+
+    /// The number of this peer, 0 until the protocol started
+    fn peer_id(&self) -> PeerIdentifier;
+
+    /// The misbehavior this peer found, if the protocol can not continue
+    fn blame(&self) -> Option<Blame>;
+}
+
+pub struct ProtocolDataManager<T: Peer> {
+    pub data_holder: T, // will be filled when initializing, and on each new step
+    pub client_data: Option<MessagePayload>, // new data calculated by this peer at the beginning of a step (that needs to be sent to other peers)
+}
+
+impl<T: Peer> ProtocolDataManager<T> {
+    pub fn new(peer: T) -> ProtocolDataManager<T> {
+        ProtocolDataManager {
+            data_holder: peer,
+            client_data: None,
+        }
+    }
+
+    /// set manager with the initial values that a local peer holds at the beginning of
+    /// the protocol session
+    /// return: first message
+    pub fn initialize_data(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+        self.client_data = self.data_holder.zero_step(peer_id);
+        self.client_data.clone()
+    }
+
+    /// Get the next message this client needs to send
+    pub fn get_next_message(
+        &mut self,
+        from: PeerIdentifier,
+        payload: MessagePayload,
+    ) -> Option<MessagePayload> {
+        self.data_holder.update_data(from, payload);
+        self.data_holder.do_step();
+        self.data_holder.get_next_item()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Peer, ProtocolDataManager};
+    use crate::{Blame, MessagePayload, PeerIdentifier};
+    use std::convert::Infallible;
+
+    // Sends its number in every step, and moves on once every peer sent theirs
+    struct CountingPeer {
+        peer_id: PeerIdentifier,
+        capacity: u32,
+        current_step: u32,
+        received: u32,
+    }
+
+    impl Peer for CountingPeer {
+        type Error = Infallible;
+
+        fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+            self.peer_id = peer_id;
+            self.get_next_item()
+        }
+
+        fn do_step(&mut self) {
+            if self.received == self.capacity {
+                self.received = 0;
+                self.current_step += 1;
+            }
+        }
+
+        fn update_data(&mut self, _from: PeerIdentifier, _payload: MessagePayload) {
+            self.received += 1;
+        }
+
+        fn get_next_item(&mut self) -> Option<MessagePayload> {
+            if self.current_step < 2 {
+                Some(format!("{}:{}", self.peer_id, self.current_step).into())
+            } else {
+                None
+            }
+        }
+
+        fn finalize(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn is_done(&mut self) -> bool {
+            self.current_step == 2
+        }
+
+        fn current_step(&self) -> u32 {
+            self.current_step
+        }
+
+        fn capacity(&self) -> u32 {
+            self.capacity
+        }
+
+        fn peer_id(&self) -> PeerIdentifier {
+            self.peer_id
+        }
+
+        fn blame(&self) -> Option<Blame> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_data_manager_steps() {
+        let peer = CountingPeer {
+            peer_id: 0,
+            capacity: 2,
+            current_step: 0,
+            received: 0,
+        };
+        let mut manager = ProtocolDataManager::new(peer);
+        assert_eq!(manager.initialize_data(2), Some("2:0".into()));
+        assert_eq!(manager.data_holder.peer_id(), 2);
+
+        // the peer sends the same payload until every peer sent theirs
+        assert_eq!(
+            manager.get_next_message(1, "1:0".into()),
+            Some("2:0".into())
+        );
+        assert_eq!(
+            manager.get_next_message(2, "2:0".into()),
+            Some("2:1".into())
+        );
+        manager.get_next_message(1, "1:1".into());
+        assert_eq!(manager.get_next_message(2, "2:1".into()), None);
+        assert!(manager.data_holder.is_done());
+    }
+}